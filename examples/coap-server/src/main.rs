@@ -14,6 +14,27 @@ const NETWORK_CONFIG: ariel_os::reexports::embassy_net::Config = {
     })
 };
 
+// The credential that `client.diag` expects; without this, a credential would be generated at
+// first boot, and could be retrieved through `ariel_os::coap::own_credential()`.
+//
+// Requests are denied unless allowed by the configuration; this example allows any client to do
+// anything, whether it uses EDHOC and OSCORE or not.
+#[ariel_os::config(coap)]
+const COAP_CONFIG: ariel_os::coap::Config = {
+    use ariel_os::coap::{Config, OwnCredential, PeerTable, Scope};
+
+    Config::new()
+        .with_own_credential(OwnCredential {
+            ccs: &hexlit::hex!("A2026008A101A5010202410A2001215820BBC34960526EA4D32E940CAD2A234148DDC21791A12AFBCBAC93622046DD44F02258204519E257236B2A0CE2023F0931F1F386CA7AFDA64FCDE0108C224C51EABF6072"),
+            private_key: &hexlit::hex!("72cc4761dbd4c78f758931aa589d348d1ef874a7e303ede2f140dcf3e6aa4aac"),
+        })
+        .with_peers(
+            PeerTable::new(&[])
+                .with_unauthenticated(Scope::AllowAll)
+                .with_nosec(Scope::AllowAll),
+        )
+};
+
 #[ariel_os::task(autostart)]
 async fn coap_run() {
     use coap_handler_implementations::{
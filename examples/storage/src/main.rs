@@ -109,6 +109,16 @@ async fn main() {
     if let Some(bytes) = bytes.as_ref() {
         info!("got bytes as heapless vec arr: {:x}", bytes);
     }
+
+    // Using a queue partition (declared in `laze.yml`) for records
+    let samples = storage::queue("samples").unwrap();
+    samples.push(&[1, 2, 3], true).await.unwrap();
+
+    let mut buf = [0u8; 16];
+    if let Some(record) = samples.pop(&mut buf).await.unwrap() {
+        info!("popped record: {:x}", record);
+    }
+
     info!("bye from storage test!");
 
     exit(ExitCode::SUCCESS);
@@ -0,0 +1,280 @@
+//! Configuration of the CoAP server.
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use coapcore::{
+    ace::AuthorizationServer, authorization::PeerTable, ead::EadProcessor, eviction::EvictionPolicy,
+};
+
+use crate::OwnCredential;
+
+/// Default UDP port for CoAP.
+pub const DEFAULT_PORT: u16 = 5683;
+
+/// IPv4 "All CoAP Nodes" multicast address (RFC 7252 Section 12.8).
+pub const ALL_COAP_NODES_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 187);
+/// IPv6 link-local "All CoAP Nodes" multicast address (RFC 7252 Section 12.8).
+pub const ALL_COAP_NODES_V6_LINK_LOCAL: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfd);
+/// IPv6 site-local "All CoAP Nodes" multicast address (RFC 7252 Section 12.8).
+pub const ALL_COAP_NODES_V6_SITE_LOCAL: Ipv6Addr = Ipv6Addr::new(0xff05, 0, 0, 0, 0, 0, 0, 0xfd);
+
+/// Configuration of the CoAP server run by [`coap_run()`](crate::coap_run).
+///
+/// A custom configuration can be provided through the `ariel_os::config` attribute macro with the
+/// `coap` kind, and the `override-coap-config` Cargo feature:
+///
+/// ```ignore
+/// #[ariel_os::config(coap)]
+/// const COAP_CONFIG: ariel_os::coap::Config = ariel_os::coap::Config::new().with_port(61616);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Local address to bind to.
+    ///
+    /// If `None`, requests are served on all addresses of the network stack, IPv4 and IPv6.
+    pub address: Option<IpAddr>,
+    /// UDP port to listen on.
+    pub port: u16,
+    /// Whether to join the "All CoAP Nodes" multicast groups, for the address families enabled
+    /// in the network stack.
+    ///
+    /// This has no effect when bound to a specific [`address`](Self::address).
+    pub join_all_coap_nodes: bool,
+    /// EDHOC credential of the device.
+    ///
+    /// If `None`, a credential is generated at first boot, and kept in storage where available;
+    /// see [`own_credential()`](crate::own_credential).
+    pub own_credential: Option<OwnCredential>,
+    /// Secret that, along with the device identity, keys the sealing of a generated credential
+    /// in storage.
+    ///
+    /// Without a secret unknown to an attacker, sealing only binds the stored credential to the
+    /// device, and does not keep its private key confidential from someone who can read the
+    /// flash. Sealing requires the `storage-encryption` feature.
+    pub credential_secret: &'static [u8],
+    /// Known peers and their permissions, along with the permissions of unknown peers and of
+    /// unprotected requests.
+    ///
+    /// By default, no peers are known, and no requests are allowed; applications grant access
+    /// explicitly. With the `storage` feature, peers kept in storage take precedence; see
+    /// [`provision_peer()`](crate::provision_peer). With the `resources` feature, `/ariel` is
+    /// restricted to known peers unless other restricted paths are set.
+    pub peers: PeerTable<'static>,
+    /// ACE-OAuth authorization server whose tokens are accepted, with the key shared with it and
+    /// the audience that identifies this device in its tokens.
+    ///
+    /// If set, clients can obtain access tokens from the authorization server and post them to
+    /// the `/authz-info` resource; see [`coapcore::ace`].
+    pub authorization_server: Option<AuthorizationServer<'static>>,
+    /// Processors for EAD items received in EDHOC, e.g., for voucher-based enrollment.
+    ///
+    /// By default, critical items are rejected, and elective items ignored; see
+    /// [`coapcore::ead`].
+    pub ead: EadProcessor,
+    /// Whether to send EDHOC message 4 in response to a message 3 that is not combined with an
+    /// OSCORE request.
+    pub send_message_4: bool,
+    /// Policy deciding which security contexts are kept when all slots are taken.
+    ///
+    /// By default, contexts of known peers are preferred over those of unknown peers; see
+    /// [`coapcore::eviction`]. The number of slots is set through the
+    /// `CONFIG_COAP_MAX_SECURITY_CONTEXTS` environment variable at build time.
+    pub eviction: EvictionPolicy,
+    /// Resource Directory to register the server's resources at.
+    ///
+    /// If `None`, the device does not register. Registration requires the `resource-directory`
+    /// feature.
+    pub resource_directory: Option<ResourceDirectory>,
+    /// Endpoint to connect to over TCP, which can then send requests to the server.
+    ///
+    /// If `None`, the server is only reachable over UDP. Connecting requires the `tcp` feature.
+    pub tcp_endpoint: Option<SocketAddr>,
+}
+
+impl Config {
+    /// Creates the default configuration: all addresses on the default port, and joining the
+    /// "All CoAP Nodes" multicast groups.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            address: None,
+            port: DEFAULT_PORT,
+            join_all_coap_nodes: true,
+            own_credential: None,
+            credential_secret: &[],
+            peers: PeerTable::new(&[]),
+            authorization_server: None,
+            ead: EadProcessor::NONE,
+            send_message_4: false,
+            eviction: coapcore::eviction::prefer_authenticated,
+            resource_directory: None,
+            tcp_endpoint: None,
+        }
+    }
+
+    /// Sets the local address to bind to.
+    #[must_use]
+    pub const fn with_address(mut self, address: IpAddr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Sets the UDP port to listen on.
+    #[must_use]
+    pub const fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Sets whether to join the "All CoAP Nodes" multicast groups.
+    #[must_use]
+    pub const fn with_all_coap_nodes(mut self, join: bool) -> Self {
+        self.join_all_coap_nodes = join;
+        self
+    }
+
+    /// Sets the EDHOC credential of the device.
+    #[must_use]
+    pub const fn with_own_credential(mut self, credential: OwnCredential) -> Self {
+        self.own_credential = Some(credential);
+        self
+    }
+
+    /// Sets the secret that keys the sealing of a generated credential in storage.
+    #[must_use]
+    pub const fn with_credential_secret(mut self, secret: &'static [u8]) -> Self {
+        self.credential_secret = secret;
+        self
+    }
+
+    /// Sets the known peers and the permissions of all peers.
+    #[must_use]
+    pub const fn with_peers(mut self, peers: PeerTable<'static>) -> Self {
+        self.peers = peers;
+        self
+    }
+
+    /// Sets the ACE-OAuth authorization server whose tokens are accepted.
+    #[must_use]
+    pub const fn with_authorization_server(mut self, server: AuthorizationServer<'static>) -> Self {
+        self.authorization_server = Some(server);
+        self
+    }
+
+    /// Sets the processors for EAD items received in EDHOC.
+    #[must_use]
+    pub const fn with_ead_processor(mut self, ead: EadProcessor) -> Self {
+        self.ead = ead;
+        self
+    }
+
+    /// Sets whether to send EDHOC message 4 in response to a message 3 that is not combined with
+    /// an OSCORE request.
+    #[must_use]
+    pub const fn with_message_4(mut self, send: bool) -> Self {
+        self.send_message_4 = send;
+        self
+    }
+
+    /// Sets the policy deciding which security contexts are kept when all slots are taken.
+    #[must_use]
+    pub const fn with_eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.eviction = policy;
+        self
+    }
+
+    /// Sets the Resource Directory to register the server's resources at.
+    #[must_use]
+    pub const fn with_resource_directory(mut self, rd: ResourceDirectory) -> Self {
+        self.resource_directory = Some(rd);
+        self
+    }
+
+    /// Sets the endpoint to connect to over TCP (RFC 8323), which can then send requests to the
+    /// server.
+    #[must_use]
+    pub const fn with_tcp_endpoint(mut self, endpoint: SocketAddr) -> Self {
+        self.tcp_endpoint = Some(endpoint);
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Registration at a CoAP Resource Directory (RFC 9176).
+///
+/// With the `resource-directory` feature, the server registers the links of its
+/// `/.well-known/core` resource at the RD, refreshes the registration before its lifetime
+/// expires, and registers anew when the network configuration changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceDirectory {
+    /// Address of the RD.
+    ///
+    /// If `None`, the RD is discovered by a multicast request to the "All CoAP Nodes" groups.
+    pub address: Option<SocketAddr>,
+    /// Endpoint name to register as.
+    ///
+    /// If `None`, the name is `ariel-` followed by the device identity in hexadecimal (see
+    /// `ariel_os::identity::device_id_bytes()`), or `ariel` on devices without one.
+    pub endpoint: Option<&'static str>,
+    /// Lifetime of the registration in seconds.
+    pub lifetime: u32,
+}
+
+impl ResourceDirectory {
+    /// Creates a configuration that discovers the RD, registers under a name derived from the
+    /// device identity, and with the default lifetime of 25 hours.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            address: None,
+            endpoint: None,
+            lifetime: coapcore::rd::DEFAULT_LIFETIME,
+        }
+    }
+
+    /// Sets the address of the RD.
+    #[must_use]
+    pub const fn with_address(mut self, address: SocketAddr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Sets the endpoint name to register as.
+    #[must_use]
+    pub const fn with_endpoint(mut self, endpoint: &'static str) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    /// Sets the lifetime of the registration in seconds.
+    #[must_use]
+    pub const fn with_lifetime(mut self, lifetime: u32) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+}
+
+impl Default for ResourceDirectory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the configuration for the CoAP server.
+pub(crate) fn config() -> Config {
+    #[cfg(not(feature = "override-coap-config"))]
+    {
+        Config::new()
+    }
+    #[cfg(feature = "override-coap-config")]
+    {
+        extern "Rust" {
+            fn __ariel_os_coap_config() -> Config;
+        }
+        unsafe { __ariel_os_coap_config() }
+    }
+}
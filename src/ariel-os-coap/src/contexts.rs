@@ -0,0 +1,158 @@
+//! Persistence of the OSCORE security contexts across reboots.
+//!
+//! With the `storage` feature, contexts established through EDHOC are kept in storage (see
+//! [`coapcore::persistence`] for how sequence numbers are kept safe), and restored when
+//! [`coap_run()`](crate::coap_run) starts, so that peers need not run EDHOC again after a reboot.
+//! Without it, this does nothing.
+use coapcore::{authorization::PeerTable, eviction::EvictionPolicy};
+
+use crate::Pool;
+
+/// The storage slots of the contexts, as produced by [`restore()`].
+pub(crate) struct Slots {
+    #[cfg(feature = "storage")]
+    slots: stored::Slots,
+}
+
+/// Places the stored contexts into the pool.
+#[cfg_attr(
+    not(feature = "storage"),
+    expect(clippy::unused_async, reason = "awaits storage when enabled")
+)]
+pub(crate) async fn restore(pool: &Pool, peers: &PeerTable<'_>, policy: EvictionPolicy) -> Slots {
+    #[cfg(feature = "storage")]
+    return Slots {
+        slots: stored::restore(pool, peers, policy).await,
+    };
+    #[cfg(not(feature = "storage"))]
+    {
+        let _ = (pool, peers, policy);
+        Slots {}
+    }
+}
+
+/// Keeps storing contexts whenever the pool needs any stored.
+pub(crate) async fn run(pool: &Pool, peers: &PeerTable<'_>, slots: Slots) -> ! {
+    #[cfg(feature = "storage")]
+    stored::run(pool, peers, slots.slots).await;
+    #[cfg(not(feature = "storage"))]
+    {
+        let _ = (pool, peers, slots);
+        core::future::pending().await
+    }
+}
+
+#[cfg(feature = "storage")]
+mod stored {
+    use core::fmt::Write as _;
+
+    use ariel_os_debug::log::info;
+    use coapcore::{
+        authorization::PeerTable,
+        eviction::EvictionPolicy,
+        persistence::{self, PersistedContext, RECORD_LEN},
+    };
+    use embassy_time::{Duration, Timer};
+
+    use crate::{Pool, MAX_CONTEXTS};
+
+    /// Returns the storage key of a context slot.
+    ///
+    /// There are as many slots as there are contexts in the pool, so that every context that is
+    /// in the pool can be kept.
+    fn storage_key(slot: usize) -> heapless::String<40> {
+        let mut key = heapless::String::new();
+        write!(key, "ariel-os.coap.oscore{slot}").expect("Key fits for any usize");
+        key
+    }
+
+    /// Interval at which the pool is checked for contexts to store.
+    ///
+    /// Limits are requested half a window before they are reached, so this only needs to be short
+    /// compared to the time it takes a context to use up half a window.
+    const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    type StoredContext = heapless::Vec<u8, RECORD_LEN>;
+
+    /// The records of the slots, as they are in storage.
+    pub(super) type Slots = [Option<PersistedContext>; MAX_CONTEXTS];
+
+    pub(super) async fn restore(
+        pool: &Pool,
+        peers: &PeerTable<'_>,
+        policy: EvictionPolicy,
+    ) -> Slots {
+        let mut slots: Slots = [const { None }; MAX_CONTEXTS];
+        for (index, slot) in slots.iter_mut().enumerate() {
+            let Ok(Some(stored)) =
+                ariel_os_storage::get::<StoredContext>(&storage_key(index)).await
+            else {
+                continue;
+            };
+            let Some(record) = PersistedContext::from_bytes(&stored) else {
+                info!("Ignoring unknown stored OSCORE context");
+                continue;
+            };
+            match persistence::restore(pool, peers, policy, &record) {
+                Ok(()) => *slot = Some(record),
+                Err(_) => info!("Could not restore OSCORE context"),
+            }
+        }
+        let restored = slots.iter().flatten().count();
+        if restored > 0 {
+            info!("Restored {} OSCORE contexts from storage", restored);
+        }
+        slots
+    }
+
+    pub(super) async fn run(pool: &Pool, peers: &PeerTable<'_>, mut slots: Slots) -> ! {
+        // A record that could not be written; it is not produced again.
+        let mut unwritten = None;
+        loop {
+            let Some(record) = unwritten
+                .take()
+                .or_else(|| persistence::next_record(pool, peers))
+            else {
+                Timer::after(POLL_INTERVAL).await;
+                continue;
+            };
+
+            // Records supersede those of the same context, or go into a slot whose context is
+            // gone.
+            let slot = slots
+                .iter()
+                .position(|s| {
+                    s.as_ref()
+                        .is_some_and(|s| s.recipient_id() == record.recipient_id())
+                })
+                .or_else(|| {
+                    slots
+                        .iter()
+                        .position(|s| s.as_ref().is_none_or(|s| !persistence::is_current(pool, s)))
+                });
+            let Some(slot) = slot else {
+                info!("No storage slot for OSCORE context");
+                unwritten = Some(record);
+                Timer::after(POLL_INTERVAL).await;
+                continue;
+            };
+
+            let stored =
+                StoredContext::from_slice(record.as_bytes()).expect("Buffer is sized for records");
+            if ariel_os_storage::insert(&storage_key(slot), stored)
+                .await
+                .is_err()
+            {
+                info!("Could not store OSCORE context, retrying");
+                unwritten = Some(record);
+                Timer::after(POLL_INTERVAL).await;
+                continue;
+            }
+            persistence::confirm(pool, &record);
+            #[expect(clippy::indexing_slicing, reason = "slot position was found in slots")]
+            {
+                slots[slot] = Some(record);
+            }
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! The device's own EDHOC credential.
+//!
+//! The credential is, in order of preference:
+//!
+//! * supplied by the application through the [`Config`](crate::Config),
+//! * loaded from storage (with the `storage` feature), or
+//! * generated from [`ariel_os_random::crypto_rng()`] at boot, and persisted into storage if
+//!   available.
+//!
+//! Generated credentials are CWT Claims Sets (CCS, RFC 8392) containing a P-256 public key, and a
+//! random key ID of [`KID_LEN`] bytes, so that devices of a deployment are unlikely to share one.
+//! With the `storage-encryption` feature, the stored private key is sealed with a key derived from
+//! the device identity and the [`Config::credential_secret`](crate::Config::credential_secret).
+use ariel_os_debug::log::info;
+use embassy_sync::once_lock::OnceLock;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+
+/// Length of a P-256 private key, as used by EDHOC.
+pub const PRIVATE_KEY_LEN: usize = 32;
+
+/// Length of the key ID of a generated credential.
+pub const KID_LEN: usize = 8;
+
+/// Length of the CCS of a generated credential.
+const GENERATED_CCS_LEN: usize = 83 + KID_LEN;
+
+/// Storage key under which a generated credential is kept, as its key ID and private key.
+#[cfg(feature = "storage")]
+const STORAGE_KEY: &str = "ariel-os.coap.edhoc-credential";
+
+/// Key ID and private key of a generated credential.
+type Generated = ([u8; KID_LEN], [u8; PRIVATE_KEY_LEN]);
+
+/// An EDHOC credential along with its private key, supplied by the application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OwnCredential {
+    /// The credential, as a CWT Claims Set (CCS, RFC 8392) containing a P-256 public key.
+    pub ccs: &'static [u8],
+    /// The P-256 private key matching the public key of the credential.
+    pub private_key: &'static [u8; PRIVATE_KEY_LEN],
+}
+
+enum PublicCredential {
+    Supplied(&'static [u8]),
+    Generated([u8; GENERATED_CCS_LEN]),
+}
+
+/// The credential in use, once [`coap_run()`](crate::coap_run) has selected it.
+static PUBLIC_CREDENTIAL: OnceLock<PublicCredential> = OnceLock::new();
+
+/// Returns the device's public EDHOC credential, as a CWT Claims Set (CCS).
+///
+/// This is meant for enrollment of the device with its peers, e.g., by printing it to the debug
+/// output or sending it to a commissioning tool.
+///
+/// This asynchronously blocks until [`coap_run()`](crate::coap_run) has selected the credential.
+pub async fn own_credential() -> &'static [u8] {
+    match PUBLIC_CREDENTIAL.get().await {
+        PublicCredential::Supplied(ccs) => ccs,
+        PublicCredential::Generated(ccs) => ccs,
+    }
+}
+
+/// Selects the device's credential, and returns it along with its private key.
+///
+/// # Panics
+///
+/// Panics if the credential supplied by the application cannot be processed, or if this is called
+/// more than once.
+pub(crate) async fn init(
+    supplied: Option<OwnCredential>,
+    secret: &[u8],
+) -> (lakers::Credential, [u8; PRIVATE_KEY_LEN]) {
+    let (public, private_key) = match supplied {
+        Some(supplied) => (
+            PublicCredential::Supplied(supplied.ccs),
+            *supplied.private_key,
+        ),
+        None => {
+            let (kid, private_key) = load_or_generate(secret).await;
+            (
+                PublicCredential::Generated(build_ccs(&kid, &private_key)),
+                private_key,
+            )
+        }
+    };
+
+    let ccs: &[u8] = match &public {
+        PublicCredential::Supplied(ccs) => ccs,
+        PublicCredential::Generated(ccs) => ccs,
+    };
+    let credential = lakers::Credential::parse_ccs(ccs).expect("Credential should be processable");
+
+    assert!(
+        PUBLIC_CREDENTIAL.init(public).is_ok(),
+        "The credential can only be selected once"
+    );
+
+    (credential, private_key)
+}
+
+/// Returns the key ID and private key of the generated credential, generating them if needed.
+///
+/// `secret` goes into the key sealing the stored credential with the `storage-encryption` feature.
+async fn load_or_generate(secret: &[u8]) -> Generated {
+    #[cfg(feature = "storage-encryption")]
+    let sealer = sealer(secret);
+    #[cfg(not(feature = "storage-encryption"))]
+    let _ = secret;
+
+    #[cfg(feature = "storage")]
+    {
+        #[cfg(feature = "storage-encryption")]
+        let stored = ariel_os_storage::get_encrypted::<Generated>(STORAGE_KEY, &sealer).await;
+        #[cfg(not(feature = "storage-encryption"))]
+        let stored = ariel_os_storage::get::<Generated>(STORAGE_KEY).await;
+        match stored {
+            Ok(Some(stored)) => return stored,
+            Ok(None) => {}
+            Err(_) => info!("Failed to read the EDHOC credential from storage"),
+        }
+    }
+
+    info!("Generating an EDHOC credential");
+    let mut rng = ariel_os_random::crypto_rng();
+    let private_key = p256::SecretKey::random(&mut rng);
+    let mut kid = [0; KID_LEN];
+    rand_core::RngCore::fill_bytes(&mut rng, &mut kid);
+    let generated = (kid, private_key.to_bytes().into());
+
+    #[cfg(feature = "storage-encryption")]
+    let stored = ariel_os_storage::insert_encrypted(STORAGE_KEY, &generated, &sealer).await;
+    #[cfg(all(feature = "storage", not(feature = "storage-encryption")))]
+    let stored = ariel_os_storage::insert(STORAGE_KEY, generated).await;
+    #[cfg(feature = "storage")]
+    if stored.is_err() {
+        info!("Failed to persist the EDHOC credential, it will change at the next boot");
+    }
+    #[cfg(not(feature = "storage"))]
+    info!("No storage available, the EDHOC credential will change at the next boot");
+
+    generated
+}
+
+/// Returns the sealer for the stored credential, keyed by the device identity and `secret`.
+///
+/// Devices without an identity use a key derived from `secret` alone.
+#[cfg(feature = "storage-encryption")]
+fn sealer(secret: &[u8]) -> ariel_os_storage::Sealer {
+    match ariel_os_identity::device_id_bytes() {
+        Ok(id) => ariel_os_storage::Sealer::from_device_id(id.as_ref(), secret),
+        Err(_) => ariel_os_storage::Sealer::from_device_id(&[], secret),
+    }
+}
+
+/// Builds the CCS of a generated credential.
+///
+/// The CCS has an empty subject, and contains the public key as a COSE key with the given key ID.
+fn build_ccs(kid: &[u8; KID_LEN], private_key: &[u8; PRIVATE_KEY_LEN]) -> [u8; GENERATED_CCS_LEN] {
+    // Head of a byte string of `KID_LEN` bytes, which is less than 24
+    #[allow(clippy::cast_possible_truncation, reason = "KID_LEN is less than 24")]
+    const KID_HEAD: u8 = 0x40 | KID_LEN as u8;
+    const X_START: usize = 16 + KID_LEN;
+    const Y_START: usize = X_START + 35;
+
+    let secret = p256::SecretKey::from_bytes(private_key.into())
+        .expect("Stored or generated private keys are valid");
+    let point = secret.public_key().to_encoded_point(false);
+
+    let mut ccs = [0; GENERATED_CCS_LEN];
+    // {2: "", 8: {1: {1: 2, 2: h'<kid>', -1: 1, -2: h'<x>', -3: h'<y>'}}}
+    ccs[..11].copy_from_slice(&[
+        0xa2, 0x02, 0x60, 0x08, 0xa1, 0x01, 0xa5, 0x01, 0x02, 0x02, KID_HEAD,
+    ]);
+    ccs[11..11 + KID_LEN].copy_from_slice(kid);
+    ccs[11 + KID_LEN..X_START].copy_from_slice(&[0x20, 0x01, 0x21, 0x58, 0x20]);
+    ccs[X_START..X_START + 32].copy_from_slice(point.x().expect("Public key is not the identity"));
+    ccs[X_START + 32..Y_START].copy_from_slice(&[0x22, 0x58, 0x20]);
+    ccs[Y_START..].copy_from_slice(point.y().expect("Public key is uncompressed"));
+    ccs
+}
@@ -0,0 +1,274 @@
+//! A CoAP client that can be used from any executor or thread.
+//!
+//! The client of [`coap_client()`](crate::coap_client) can only be used on the executor that runs
+//! the network stack. A [`CoapClientHandle`] instead copies requests into a static slot, from
+//! which the task running [`coap_run()`](crate::coap_run) sends them through that client; the
+//! response is copied back once it arrived.
+//!
+//! Requests of all handles are sent one at a time. A requester that stops waiting does not block
+//! later ones: the response to its request is discarded.
+use core::cell::RefCell;
+use core::net::SocketAddr;
+
+use coapcore::block::{request_blockwise, BlockwiseError, BlockwiseRequest, BlockwiseResponse};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    mutex::Mutex as AsyncMutex,
+    signal::Signal,
+};
+
+/// Size of the buffers holding a request's option values and payload, and a response's payload.
+pub const MAX_HANDLE_MESSAGE_LEN: usize = ariel_os_utils::usize_from_env_or!(
+    "CONFIG_COAP_CLIENT_HANDLE_BUFFER_LEN",
+    1024,
+    "size of the request and response buffers of the CoAP client handle"
+);
+
+/// Number of options a request sent through a handle can have.
+const MAX_OPTIONS: usize = 8;
+
+/// Size of the blocks in which requests and responses are transferred.
+const BLOCK_SIZE: usize = 512;
+
+/// Serializes the requests of all handles.
+static LOCK: AsyncMutex<CriticalSectionRawMutex, ()> = AsyncMutex::new(());
+
+static SLOT: Mutex<CriticalSectionRawMutex, RefCell<Slot>> = Mutex::new(RefCell::new(Slot {
+    id: 0,
+    state: State::Idle,
+}));
+
+static REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static ANSWERED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The request currently handed to the network executor.
+struct Slot {
+    /// Incremented with every request, so that responses are only picked up by their requester.
+    id: u32,
+    state: State,
+}
+
+enum State {
+    Idle,
+    Requested(Job),
+    Running,
+    Answered(Result<Answer, BlockwiseError<()>>),
+}
+
+/// A request copied out of a [`BlockwiseRequest`].
+struct Job {
+    address: SocketAddr,
+    code: u8,
+    /// Option numbers, and the end of their values in `data`
+    options: heapless::Vec<(u16, usize), MAX_OPTIONS>,
+    /// The option values, followed by the payload
+    data: heapless::Vec<u8, MAX_HANDLE_MESSAGE_LEN>,
+}
+
+/// A response, with a copy of its payload.
+struct Answer {
+    code: u8,
+    payload: heapless::Vec<u8, MAX_HANDLE_MESSAGE_LEN>,
+}
+
+/// Returns a CoAP client handle that can be used from any executor or thread.
+///
+/// Unlike [`coap_client()`](crate::coap_client), the handle is [`Send`] and [`Sync`]; its requests
+/// are sent once [`coap_run()`](crate::coap_run) is running.
+#[must_use]
+pub fn coap_client_handle() -> CoapClientHandle {
+    CoapClientHandle { _private: () }
+}
+
+/// A CoAP client that can be used from any executor or thread, see [`coap_client_handle()`].
+///
+/// Requests are transferred block-wise like with [`request_blockwise()`], with blocks of 512
+/// bytes. Their option values and payload, as well as the response payload, are limited to 1024
+/// bytes each, unless configured otherwise through the `CONFIG_COAP_CLIENT_HANDLE_BUFFER_LEN`
+/// environment variable; requests can have up to 8 options.
+///
+/// ```ignore
+/// use ariel_os::coap::BlockwiseRequest;
+///
+/// let request = BlockwiseRequest {
+///     code: coap_numbers::code::GET,
+///     options: &[(coap_numbers::option::URI_PATH, b"time")],
+///     payload: &[],
+/// };
+/// let mut buffer = [0; 64];
+/// // From a thread:
+/// let response = ariel_os::coap::coap_client_handle()
+///     .request_blocking(server, &request, &mut buffer)?;
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct CoapClientHandle {
+    _private: (),
+}
+
+impl CoapClientHandle {
+    /// Sends `request` to `address`, placing the response payload in `response`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`BlockwiseError::TooLarge`] if the request or the response exceed the handle's
+    /// buffers, or `response`. Other errors are those of [`request_blockwise()`], without details
+    /// of transport errors.
+    pub async fn request(
+        &self,
+        address: SocketAddr,
+        request: &BlockwiseRequest<'_>,
+        response: &mut [u8],
+    ) -> Result<BlockwiseResponse, BlockwiseError<()>> {
+        let job = Job::new(address, request)?;
+
+        let _guard = LOCK.lock().await;
+        let id = SLOT.lock(|slot| {
+            let mut slot = slot.borrow_mut();
+            slot.id = slot.id.wrapping_add(1);
+            slot.state = State::Requested(job);
+            slot.id
+        });
+        REQUESTED.signal(());
+
+        let answer = loop {
+            ANSWERED.wait().await;
+            let answered = SLOT.lock(|slot| {
+                let mut slot = slot.borrow_mut();
+                if slot.id != id {
+                    return None;
+                }
+                match core::mem::replace(&mut slot.state, State::Idle) {
+                    State::Answered(answer) => Some(answer),
+                    other => {
+                        slot.state = other;
+                        None
+                    }
+                }
+            });
+            if let Some(answer) = answered {
+                break answer?;
+            }
+        };
+
+        let len = answer.payload.len();
+        response
+            .get_mut(..len)
+            .ok_or(BlockwiseError::TooLarge)?
+            .copy_from_slice(&answer.payload);
+        Ok(BlockwiseResponse {
+            code: answer.code,
+            len,
+        })
+    }
+
+    /// Sends `request` to `address` like [`request()`](Self::request), blocking the current
+    /// thread until the response arrived.
+    ///
+    /// # Errors
+    ///
+    /// See [`request()`](Self::request).
+    ///
+    /// # Panics
+    ///
+    /// Panics when not called from a thread.
+    #[cfg(feature = "threading")]
+    pub fn request_blocking(
+        &self,
+        address: SocketAddr,
+        request: &BlockwiseRequest<'_>,
+        response: &mut [u8],
+    ) -> Result<BlockwiseResponse, BlockwiseError<()>> {
+        ariel_os_embassy::asynch::blocker::block_on(self.request(address, request, response))
+    }
+}
+
+impl Job {
+    fn new(
+        address: SocketAddr,
+        request: &BlockwiseRequest<'_>,
+    ) -> Result<Self, BlockwiseError<()>> {
+        let mut job = Self {
+            address,
+            code: request.code,
+            options: heapless::Vec::new(),
+            data: heapless::Vec::new(),
+        };
+        for (number, value) in request.options {
+            job.data
+                .extend_from_slice(value)
+                .map_err(|()| BlockwiseError::TooLarge)?;
+            job.options
+                .push((*number, job.data.len()))
+                .map_err(|_| BlockwiseError::TooLarge)?;
+        }
+        job.data
+            .extend_from_slice(request.payload)
+            .map_err(|()| BlockwiseError::TooLarge)?;
+        Ok(job)
+    }
+
+    /// Sends the request through the client of [`coap_client()`](crate::coap_client).
+    async fn run(&self) -> Result<Answer, BlockwiseError<()>> {
+        let mut options = heapless::Vec::<(u16, &[u8]), MAX_OPTIONS>::new();
+        let mut start = 0;
+        for (number, end) in &self.options {
+            let value = self.data.get(start..*end).ok_or(BlockwiseError::Protocol)?;
+            // The number of options was checked when copying them.
+            let _ = options.push((*number, value));
+            start = *end;
+        }
+        let request = BlockwiseRequest {
+            code: self.code,
+            options: &options,
+            payload: self.data.get(start..).ok_or(BlockwiseError::Protocol)?,
+        };
+
+        let mut payload = heapless::Vec::new();
+        payload
+            .resize_default(MAX_HANDLE_MESSAGE_LEN)
+            .expect("the buffer has that capacity");
+        let mut stack = crate::coap_client().await.to(self.address);
+        let response = request_blockwise(&mut stack, &request, BLOCK_SIZE, &mut payload)
+            .await
+            .map_err(|error| match error {
+                BlockwiseError::Transport(_) => BlockwiseError::Transport(()),
+                BlockwiseError::TooLarge => BlockwiseError::TooLarge,
+                BlockwiseError::Protocol => BlockwiseError::Protocol,
+            })?;
+        payload.truncate(response.len);
+        Ok(Answer {
+            code: response.code,
+            payload,
+        })
+    }
+}
+
+/// Sends the requests of the handles.
+pub(crate) async fn run() -> ! {
+    loop {
+        REQUESTED.wait().await;
+        let requested = SLOT.lock(|slot| {
+            let mut slot = slot.borrow_mut();
+            match core::mem::replace(&mut slot.state, State::Running) {
+                State::Requested(job) => Some((slot.id, job)),
+                other => {
+                    slot.state = other;
+                    None
+                }
+            }
+        });
+        let Some((id, job)) = requested else {
+            continue;
+        };
+
+        let answer = job.run().await;
+        SLOT.lock(|slot| {
+            let mut slot = slot.borrow_mut();
+            // Otherwise, the requester stopped waiting, and another request took its place.
+            if slot.id == id {
+                slot.state = State::Answered(answer);
+            }
+        });
+        ANSWERED.signal(());
+    }
+}
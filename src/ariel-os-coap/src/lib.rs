@@ -9,35 +9,116 @@
 #![deny(missing_docs)]
 #![deny(clippy::pedantic)]
 
-// Moving work from https://github.com/embassy-rs/embassy/pull/2519 in here for the time being
-mod udp_nal;
+mod config;
+mod contexts;
+mod credential;
+mod handle;
+mod observe;
+mod peers;
+#[cfg(feature = "resource-directory")]
+mod rd;
+#[cfg(feature = "resources")]
+mod resources;
+#[cfg(feature = "tcp")]
+mod tcp;
+// Moving work from https://github.com/embassy-rs/embassy/pull/2519 in here for the time being;
+// public so that libraries can create their own sockets through its `UdpStack`.
+pub mod udp_nal;
+
+pub use coapcore::ace::AuthorizationServer;
+pub use coapcore::authorization::{AifValue, Peer, PeerCredential, PeerTable, Scope};
+pub use coapcore::block::{
+    request_blockwise, Block1Handler, Block2Handler, BlockwiseError, BlockwiseRequest,
+    BlockwiseResponse,
+};
+pub use coapcore::client::{ClientError, OscoreEdhocClient, ProtectedStack, SecurityContext};
+pub use coapcore::ead::{EadFn, EadProcessor, EadRejected};
+pub use coapcore::eviction::{ContextAuthorization, EvictionPolicy, Retention};
+pub use coapcore::observe::Observable;
+pub use config::{
+    Config, ResourceDirectory, ALL_COAP_NODES_V4, ALL_COAP_NODES_V6_LINK_LOCAL,
+    ALL_COAP_NODES_V6_SITE_LOCAL, DEFAULT_PORT,
+};
+pub use credential::{own_credential, OwnCredential, KID_LEN, PRIVATE_KEY_LEN};
+pub use handle::{coap_client_handle, CoapClientHandle, MAX_HANDLE_MESSAGE_LEN};
+pub use observe::{notify, ObservableHandler, ObservableRecord};
+#[cfg(feature = "storage")]
+pub use peers::{provision_peer, ProvisionError, MAX_STORED_AIF_LEN, MAX_STORED_PEERS};
+#[cfg(feature = "resources")]
+pub use resources::with_device_resources;
+#[cfg(all(feature = "resources", feature = "storage"))]
+pub use resources::{with_storage_resource, MAX_STORAGE_VALUE_LEN};
+
+use core::cell::RefCell;
+use core::net::{IpAddr, Ipv6Addr, SocketAddr};
 
 use ariel_os_debug::log::info;
-use ariel_os_embassy::sendcell::SendCell;
+use ariel_os_embassy::{network::NetworkStack, sendcell::SendCell};
 use coap_handler_implementations::ReportingHandlerBuilder;
-use coapcore::seccontext;
+use coapcore::{client::OscoreEdhocClient, seccontext};
+use embassy_futures::select::{select4, Either4};
 use embassy_net::udp::{PacketMetadata, UdpSocket};
 use embassy_sync::once_lock::OnceLock;
 use static_cell::StaticCell;
 
 const CONCURRENT_REQUESTS: usize = 3;
 
+/// Number of security contexts the server and the secure client can keep.
+const MAX_CONTEXTS: usize = ariel_os_utils::usize_from_env_or!(
+    "CONFIG_COAP_MAX_SECURITY_CONTEXTS",
+    4,
+    "number of OSCORE security contexts kept by the CoAP stack"
+);
+
+/// The pool of security contexts shared by the server and the secure client.
+type Pool = RefCell<seccontext::SecContextPool<Crypto, MAX_CONTEXTS>>;
+
+/// The cryptography backend used for EDHOC.
+type Crypto = lakers_crypto_rustcrypto::Crypto<ariel_os_random::CryptoRng>;
+
 static CLIENT: OnceLock<
     SendCell<embedded_nal_coap::CoAPRuntimeClient<'static, CONCURRENT_REQUESTS>>,
 > = OnceLock::new();
 
+static SECURE_CLIENT: OnceLock<SendCell<OscoreEdhocClient<'static, Crypto, MAX_CONTEXTS>>> =
+    OnceLock::new();
+
 /// Runs a CoAP server with the given handler on the system's CoAP transports.
 ///
-/// As the CoAP stack gets ready, it also unblocks [`coap_client`].
+/// The server listens on the addresses and port given by the [`Config`], which by default are all
+/// addresses of the network stack (including the "All CoAP Nodes" multicast groups) on the default
+/// CoAP port.
+///
+/// The device authenticates with the EDHOC credential given in the [`Config`], or one that is
+/// generated at first boot; see [`own_credential()`]. Requests are authorized according to the
+/// peers of the [`Config`], or those kept in storage.
+///
+/// With the `storage` feature, OSCORE contexts established through EDHOC are kept in storage, and
+/// restored at startup, so that peers can continue using them after a reboot.
+///
+/// Resources wrapped in an [`ObservableHandler`] can be observed; their observers get notified
+/// whenever [`notify()`] is called.
+///
+/// With the `resources` feature, the handler can be extended by resources for inspecting the
+/// device remotely, see `with_device_resources()`.
+///
+/// With the `resource-directory` feature and a [`ResourceDirectory`] set in the [`Config`], the
+/// links of the server's resources are registered at a CoAP Resource Directory (RFC 9176), and the
+/// registration is kept alive.
+///
+/// With the `tcp` feature and a TCP endpoint set in the [`Config`], the device also connects to
+/// that endpoint with CoAP over TCP (RFC 8323), and serves the requests it sends.
+///
+/// As the CoAP stack gets ready, it also unblocks [`coap_client`] and [`secure_coap_client`], and
+/// starts sending the requests of [`coap_client_handle()`].
 ///
 /// # Panics
 ///
 /// This can only be run once, as it sets up a system wide CoAP handler.
 pub async fn coap_run(handler: impl coap_handler::Handler + coap_handler::Reporting) -> ! {
-    use hexlit::hex;
-    const R: [u8; 32] = hex!("72cc4761dbd4c78f758931aa589d348d1ef874a7e303ede2f140dcf3e6aa4aac");
-
     static COAP: StaticCell<embedded_nal_coap::CoAPShared<CONCURRENT_REQUESTS>> = StaticCell::new();
+    static IDENTITY: StaticCell<(lakers::Credential, [u8; PRIVATE_KEY_LEN])> = StaticCell::new();
+    static POOL: StaticCell<Pool> = StaticCell::new();
 
     let stack = ariel_os_embassy::network::network_stack().await.unwrap();
 
@@ -57,24 +138,48 @@ pub async fn coap_run(handler: impl coap_handler::Handler + coap_handler::Report
 
     info!("Starting up CoAP server");
 
-    // Can't that even bind to the Any address??
-    // let local_any = "0.0.0.0:5683".parse().unwrap(); // shame
-    let local_any = "10.42.0.61:5683".parse().unwrap(); // shame
-    let mut unconnected = udp_nal::UnconnectedUdp::bind_multiple(socket, local_any)
+    let config = config::config();
+    // Binding to the unspecified address serves requests on all addresses of the stack, no
+    // matter their family.
+    let local = SocketAddr::new(
+        config.address.unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        config.port,
+    );
+    if config.address.is_none() && config.join_all_coap_nodes {
+        join_all_coap_nodes(stack);
+    }
+    let unconnected = udp_nal::UnconnectedUdp::bind_multiple(socket, local)
         .await
         .unwrap();
 
-    let own_identity = (
-        &lakers::Credential::parse_ccs(&hex!("A2026008A101A5010202410A2001215820BBC34960526EA4D32E940CAD2A234148DDC21791A12AFBCBAC93622046DD44F02258204519E257236B2A0CE2023F0931F1F386CA7AFDA64FCDE0108C224C51EABF6072")).expect("Credential should be processable"),
-        &R,
-        );
+    let identity: &'static _ =
+        IDENTITY.init(credential::init(config.own_credential, config.credential_secret).await);
+    let own_identity = (&identity.0, &identity.1);
+    let peers = peers::peers(config.peers).await;
+    // Shared between the server and the secure client, which both run in this thread.
+    let pool: &'static _ = POOL.init_with(|| RefCell::new(seccontext::SecContextPool::new()));
+    let crypto_factory = || lakers_crypto_rustcrypto::Crypto::new(ariel_os_random::crypto_rng());
+    let slots = contexts::restore(pool, &peers, config.eviction).await;
 
     // FIXME: Should we allow users to override that? After all, this is just convenience and may
     // be limiting in special applications.
-    let handler = handler.with_wkc();
-    let mut handler = seccontext::OscoreEdhocHandler::new(own_identity, handler, || {
-        lakers_crypto_rustcrypto::Crypto::new(ariel_os_random::crypto_rng())
-    });
+    #[cfg_attr(
+        not(feature = "resource-directory"),
+        expect(unused_mut, reason = "only used to render the registered links")
+    )]
+    let mut handler = handler.with_wkc();
+    #[cfg(feature = "resource-directory")]
+    let links = rd::links(&mut handler);
+    let mut handler =
+        seccontext::OscoreEdhocHandler::new(own_identity, peers, pool, handler, crypto_factory)
+            .with_ead_processor(config.ead)
+            .with_eviction_policy(config.eviction);
+    if config.send_message_4 {
+        handler = handler.with_message_4();
+    }
+    if let Some(server) = config.authorization_server {
+        handler = handler.with_authorization_server(server);
+    }
 
     info!("Server is ready.");
 
@@ -84,20 +189,93 @@ pub async fn coap_run(handler: impl coap_handler::Handler + coap_handler::Report
         .init(SendCell::new_async(client).await)
         .ok()
         .expect("CLIENT can not be populated when COAP was just not populated.");
-
-    server
-        .run(
-            &mut unconnected,
-            &mut handler,
-            &mut ariel_os_random::fast_rng(),
+    SECURE_CLIENT
+        .init(
+            SendCell::new_async(
+                OscoreEdhocClient::new(own_identity, peers, pool, crypto_factory)
+                    .with_eviction_policy(config.eviction),
+            )
+            .await,
         )
-        .await
-        .expect("UDP error");
+        .ok()
+        .expect("SECURE_CLIENT can not be populated when COAP was just not populated.");
+
+    // Shared between the server and the sender of notifications.
+    let handler = RefCell::new(handler);
+    let mut transport = observe::Transport(&unconnected);
+    let mut shared_handler = observe::SharedHandler(&handler);
+    let mut rng = ariel_os_random::fast_rng();
+    let server = server.run(&mut transport, &mut shared_handler, &mut rng);
+    let storage_resource = async {
+        #[cfg(all(feature = "resources", feature = "storage"))]
+        resources::run().await;
+        #[cfg(not(all(feature = "resources", feature = "storage")))]
+        core::future::pending().await
+    };
+    let resource_directory = async {
+        #[cfg(feature = "resource-directory")]
+        if let Some(rd_config) = config.resource_directory {
+            if let Some(links) = &links {
+                rd::run(rd_config, stack, &unconnected, links).await;
+            }
+            info!("Links of the server do not fit into a Resource Directory registration");
+        }
+        core::future::pending().await
+    };
+    let tcp_connection = async {
+        #[cfg(feature = "tcp")]
+        if let Some(endpoint) = config.tcp_endpoint {
+            tcp::run(stack, endpoint, &handler).await;
+        }
+        core::future::pending().await
+    };
+    // All run in this thread, as they share the pool.
+    match select4(
+        server,
+        contexts::run(pool, &peers, slots),
+        observe::run(&unconnected, &handler),
+        select4(
+            storage_resource,
+            resource_directory,
+            handle::run(),
+            tcp_connection,
+        ),
+    )
+    .await
+    {
+        Either4::First(result) => result.expect("UDP error"),
+        Either4::Second(never)
+        | Either4::Third(never)
+        | Either4::Fourth(
+            Either4::First(never)
+            | Either4::Second(never)
+            | Either4::Third(never)
+            | Either4::Fourth(never),
+        ) => never,
+    }
     unreachable!("embassy-net's sockets do not get closed (but embedded-nal-coap can't know that)");
 }
 
+/// Joins the "All CoAP Nodes" multicast groups of the address families the stack supports.
+fn join_all_coap_nodes(stack: NetworkStack) {
+    use embassy_net::IpAddress;
+
+    for group in [
+        IpAddress::Ipv4(ALL_COAP_NODES_V4),
+        IpAddress::Ipv6(ALL_COAP_NODES_V6_LINK_LOCAL),
+        IpAddress::Ipv6(ALL_COAP_NODES_V6_SITE_LOCAL),
+    ] {
+        if stack.join_multicast_group(group).is_err() {
+            info!("Could not join an All-CoAP-Nodes multicast group");
+        }
+    }
+}
+
 /// Returns a CoAP client requester.
 ///
+/// Representations that exceed a single message can be transferred with [`request_blockwise()`]
+/// over the stack returned by the client's `to()` method.
+///
 /// This asynchronously blocks until [`coap_run`] has been called, and the CoAP stack is
 /// operational.
 ///
@@ -105,7 +283,8 @@ pub async fn coap_run(handler: impl coap_handler::Handler + coap_handler::Report
 ///
 /// This is currently only available from the thread that hosts the network stack, and panics
 /// otherwise. This restriction will be lifted in the future (by generalization in
-/// [`embedded_nal_coap`] to allow different mutexes).
+/// [`embedded_nal_coap`] to allow different mutexes); until then, other executors and threads can
+/// send requests through [`coap_client_handle()`].
 pub async fn coap_client(
 ) -> &'static embedded_nal_coap::CoAPRuntimeClient<'static, CONCURRENT_REQUESTS> {
     CLIENT
@@ -115,3 +294,37 @@ pub async fn coap_client(
         .await // Not an actual await, just a convenient way to see which executor is running
         .expect("CoAP client can currently only be used from the thread the network is bound to")
 }
+
+/// Returns a client that runs EDHOC with servers, and protects requests to them with OSCORE.
+///
+/// The client authenticates with the device's own credential (see [`own_credential()`]), and
+/// shares its security contexts with the server run by [`coap_run`], which therefore also serves
+/// requests the peer sends through them. Requests are sent through the stack of [`coap_client`]:
+///
+/// ```ignore
+/// use coap_request::Stack;
+///
+/// let mut server = ariel_os::coap::coap_client().await.to(address);
+/// let secure = ariel_os::coap::secure_coap_client().await;
+/// let mut context = secure.establish(&mut server, SERVER_CCS).await?;
+/// let response = secure
+///     .protect(&mut server, &mut context)
+///     .request(coap_request_implementations::Code::get().with_path("/time"))
+///     .await;
+/// ```
+///
+/// This asynchronously blocks until [`coap_run`] has been called, and the CoAP stack is
+/// operational.
+///
+/// # Panics
+///
+/// Like [`coap_client`], this is currently only available from the thread that hosts the network
+/// stack, and panics otherwise.
+pub async fn secure_coap_client() -> &'static OscoreEdhocClient<'static, Crypto, MAX_CONTEXTS> {
+    SECURE_CLIENT
+        .get()
+        .await
+        .get_async()
+        .await // Not an actual await, just a convenient way to see which executor is running
+        .expect("CoAP client can currently only be used from the thread the network is bound to")
+}
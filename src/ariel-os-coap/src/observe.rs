@@ -0,0 +1,504 @@
+//! Observable resources (RFC 7641) of the CoAP server.
+//!
+//! Resources become observable by wrapping their handler in an [`ObservableHandler`], and their
+//! changes are announced through [`notify()`]. The observations are kept in a registry that the
+//! server fills while processing requests, and that the sender of notifications, which runs
+//! alongside the server in [`coap_run()`](crate::coap_run), works through whenever resources
+//! changed.
+//!
+//! Registering an observation takes information from all layers: the transport knows the client's
+//! addresses and the token, the [`ObservableHandler`] the resource and the request, and the
+//! [`OscoreEdhocHandler`] how the response was protected. The observation is therefore only
+//! completed once the response is built.
+use core::cell::RefCell;
+use core::net::SocketAddr;
+
+use ariel_os_debug::log::{debug, info, warn};
+use coap_handler::{Attribute, Handler, Record, Reporting};
+use coap_message::{
+    MessageOption as _, MinimalWritableMessage, MutableWritableMessage, ReadableMessage,
+};
+use coap_message_implementations::inmemory_write;
+use coapcore::{
+    observe::{
+        self, NotificationError, Observable, Observation, ObserveRequest, Observers, Protection,
+        MAX_TOKEN_LEN,
+    },
+    seccontext::{OrInner, OscoreEdhocHandler},
+};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use rand_core::RngCore as _;
+
+use crate::{udp_nal, Crypto, MAX_CONTEXTS};
+
+/// Number of observations the server keeps.
+const MAX_OBSERVATIONS: usize = ariel_os_utils::usize_from_env_or!(
+    "CONFIG_COAP_MAX_OBSERVATIONS",
+    4,
+    "number of observations kept by the CoAP server"
+);
+
+/// Size of notifications, which is the size of the server's responses.
+const MAX_MESSAGE_LEN: usize = 1152;
+
+/// Message type of notifications: non-confirmable (RFC 7252 Section 3).
+const TYPE_NON: u8 = 1;
+/// Message type of resets, by which clients reject notifications.
+const TYPE_RST: u8 = 3;
+
+/// The handler of the server, which is shared with the sender of notifications.
+pub(crate) type Server<'a, H> = OscoreEdhocHandler<'a, H, Crypto, MAX_CONTEXTS>;
+
+static REGISTRY: Mutex<CriticalSectionRawMutex, RefCell<Registry>> =
+    Mutex::new(RefCell::new(Registry::new()));
+
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Notifies the observers of `resource` that it changed.
+///
+/// This can be called from any task or thread. The notifications are sent by the task running
+/// [`coap_run()`](crate::coap_run); if the resource changes again before they are, they are sent
+/// only once, showing the latest state.
+pub fn notify(resource: &Observable) {
+    resource.mark_changed();
+    CHANGED.signal(());
+}
+
+/// Addresses of a client, as seen by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Endpoints {
+    local: SocketAddr,
+    remote: SocketAddr,
+}
+
+/// The request the server is processing, as received by the transport.
+struct Incoming {
+    endpoints: Endpoints,
+    token: heapless::Vec<u8, MAX_TOKEN_LEN>,
+    /// Whether the request was protected with OSCORE.
+    protected: bool,
+}
+
+struct Registry {
+    observers: Observers<'static, Endpoints, MAX_OBSERVATIONS>,
+    incoming: Option<Incoming>,
+    /// The observation registered by the request being processed, until its response is built.
+    pending: Option<Observation<'static, Endpoints>>,
+    /// Whether a notification is being rendered, during which the request it is rendered from
+    /// does not register anything.
+    rendering: bool,
+}
+
+impl Registry {
+    const fn new() -> Self {
+        Self {
+            observers: Observers::new(),
+            incoming: None,
+            pending: None,
+            rendering: false,
+        }
+    }
+
+    /// Takes note of a message received on the server's socket.
+    fn received(&mut self, endpoints: Endpoints, datagram: &[u8]) {
+        let [first, code, id_high, id_low, rest @ ..] = datagram else {
+            return;
+        };
+        if (first >> 4) & 0x03 == TYPE_RST {
+            if self
+                .observers
+                .reset(&endpoints, u16::from_be_bytes([*id_high, *id_low]))
+            {
+                debug!("Observation ended by reset");
+            }
+        } else if *code != 0 && code >> 5 == 0 {
+            // Responses to the client sharing the socket are of no interest here.
+            self.pending = None;
+            self.incoming = rest
+                .get(..usize::from(first & 0x0f))
+                .and_then(|token| heapless::Vec::from_slice(token).ok())
+                .map(|token| Incoming {
+                    endpoints,
+                    token,
+                    protected: false,
+                });
+        }
+    }
+
+    /// Registers an observation of `resource` through the request being processed, pending
+    /// until its response is built; returns whether the response should carry an Observe
+    /// option.
+    fn register(&mut self, resource: &'static Observable, request: &impl ReadableMessage) -> bool {
+        if self.rendering {
+            return true;
+        }
+        let Some(incoming) = &self.incoming else {
+            return false;
+        };
+        if !self.observers.accepts(&incoming.endpoints, &incoming.token) {
+            info!("No room for another observation");
+            return false;
+        }
+        self.pending = Observation::new(resource, incoming.endpoints, &incoming.token, request);
+        self.pending.is_some()
+    }
+
+    /// Ends the observation of the request being processed.
+    fn deregister(&mut self) {
+        if let Some(incoming) = &self.incoming {
+            self.observers
+                .deregister(&incoming.endpoints, &incoming.token);
+        }
+    }
+
+    /// Completes the pending registration once the response is built with `protection`.
+    fn complete(&mut self, protection: Option<Protection>) {
+        let Some(mut observation) = self.pending.take() else {
+            return;
+        };
+        if self.incoming.as_ref().is_some_and(|i| i.protected) {
+            let Some(protection) = protection else {
+                // Notifications could not be protected either.
+                info!("Not registering observation whose response was not protected");
+                return;
+            };
+            observation.set_protection(protection);
+        }
+        if self.observers.register(observation).is_err() {
+            info!("No room for another observation");
+        }
+    }
+}
+
+/// A handler that makes the resource of the wrapped handler observable.
+///
+/// GET requests with an Observe option of 0 register an observation of the `resource`, and their
+/// responses carry an Observe option; those with an Observe option of 1 end it. Whenever the
+/// resource is announced to have changed through [`notify()`], its observers get a notification
+/// rendered by the wrapped handler.
+///
+/// Up to 4 observations are kept, unless configured otherwise through the
+/// `CONFIG_COAP_MAX_OBSERVATIONS` environment variable; further registrations are answered
+/// without an Observe option. The wrapped handler must not add options that precede the Observe
+/// option (such as `ETag`) to responses.
+///
+/// ```ignore
+/// static TEMPERATURE: ariel_os::coap::Observable = ariel_os::coap::Observable::new();
+///
+/// let handler = new_dispatcher().at(
+///     &["temperature"],
+///     ariel_os::coap::ObservableHandler::new(&TEMPERATURE, temperature_handler),
+/// );
+///
+/// // Elsewhere, whenever the temperature changed:
+/// ariel_os::coap::notify(&TEMPERATURE);
+/// ```
+pub struct ObservableHandler<H> {
+    resource: &'static Observable,
+    inner: H,
+}
+
+impl<H> ObservableHandler<H> {
+    /// Creates a handler that makes `inner` observable as `resource`.
+    #[must_use]
+    pub const fn new(resource: &'static Observable, inner: H) -> Self {
+        Self { resource, inner }
+    }
+}
+
+impl<H: Handler> Handler for ObservableHandler<H> {
+    /// The inner request data, and whether the response carries an Observe option.
+    type RequestData = (bool, H::RequestData);
+    type ExtractRequestError = H::ExtractRequestError;
+    type BuildResponseError<M: MinimalWritableMessage> =
+        OrInner<M::UnionError, H::BuildResponseError<M>>;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Self::ExtractRequestError> {
+        let extracted = self.inner.extract_request_data(request)?;
+        let observing = match observe::observe_request(request) {
+            Some(ObserveRequest::Register) => {
+                REGISTRY.lock(|r| r.borrow_mut().register(self.resource, request))
+            }
+            Some(ObserveRequest::Deregister) => {
+                REGISTRY.lock(|r| r.borrow_mut().deregister());
+                false
+            }
+            None => false,
+        };
+        Ok((observing, extracted))
+    }
+
+    fn estimate_length(&mut self, (_, request): &Self::RequestData) -> usize {
+        // Observe option with up to 3 bytes of value
+        4 + self.inner.estimate_length(request)
+    }
+
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        (observing, request): Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        if observing {
+            observe::add_observe_option(response, self.resource.sequence())
+                .map_err(OrInner::Own)?;
+        }
+        self.inner.build_response(response, request).map_err(|e| {
+            // The error response does not establish an observation.
+            REGISTRY.lock(|r| r.borrow_mut().pending = None);
+            OrInner::Inner(e)
+        })
+    }
+}
+
+impl<H: Reporting> Reporting for ObservableHandler<H> {
+    type Record<'res>
+        = ObservableRecord<H::Record<'res>>
+    where
+        Self: 'res;
+    type Reporter<'res>
+        =
+        core::iter::Map<H::Reporter<'res>, fn(H::Record<'res>) -> ObservableRecord<H::Record<'res>>>
+    where
+        Self: 'res;
+
+    fn report(&self) -> Self::Reporter<'_> {
+        self.inner
+            .report()
+            .map(ObservableRecord as fn(H::Record<'_>) -> ObservableRecord<H::Record<'_>>)
+    }
+}
+
+/// A record of a resource reported by an [`ObservableHandler`], which is marked observable.
+pub struct ObservableRecord<R>(R);
+
+impl<R: Record> Record for ObservableRecord<R> {
+    type PathElement = R::PathElement;
+    type PathElements = R::PathElements;
+    type Attributes = core::iter::Chain<R::Attributes, core::iter::Once<Attribute>>;
+
+    fn path(&self) -> Self::PathElements {
+        self.0.path()
+    }
+
+    fn rel(&self) -> Option<&str> {
+        self.0.rel()
+    }
+
+    fn attributes(&self) -> Self::Attributes {
+        self.0
+            .attributes()
+            .chain(core::iter::once(Attribute::Observable))
+    }
+}
+
+/// The server's socket, which takes note of the requests it receives in the registry.
+pub(crate) struct Transport<'s, 'a>(pub(crate) &'s udp_nal::UnconnectedUdp<'a>);
+
+impl embedded_nal_async::UnconnectedUdp for Transport<'_, '_> {
+    type Error = udp_nal::Error;
+
+    async fn send(
+        &mut self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        buf: &[u8],
+    ) -> Result<(), Self::Error> {
+        self.0.send_shared(local, remote, buf).await
+    }
+
+    async fn receive_into(
+        &mut self,
+        buf: &mut [u8],
+    ) -> Result<(usize, SocketAddr, SocketAddr), Self::Error> {
+        let (len, local, remote) = self.0.receive_shared(buf).await?;
+        if let Some(datagram) = buf.get(..len) {
+            REGISTRY.lock(|r| {
+                r.borrow_mut()
+                    .received(Endpoints { local, remote }, datagram);
+            });
+            #[cfg(feature = "resource-directory")]
+            crate::rd::received(remote, datagram);
+        }
+        Ok((len, local, remote))
+    }
+}
+
+/// The server's handler, shared with the sender of notifications.
+///
+/// Borrows are only held while the server calls into the handler, which it does without
+/// awaiting anything in between.
+pub(crate) struct SharedHandler<'h, 'a, H: Handler>(pub(crate) &'h RefCell<Server<'a, H>>);
+
+impl<'a, H: Handler> Handler for SharedHandler<'_, 'a, H> {
+    type RequestData = <Server<'a, H> as Handler>::RequestData;
+    type ExtractRequestError = <Server<'a, H> as Handler>::ExtractRequestError;
+    type BuildResponseError<M: MinimalWritableMessage> =
+        <Server<'a, H> as Handler>::BuildResponseError<M>;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Self::ExtractRequestError> {
+        let protected = request
+            .options()
+            .any(|o| o.number() == coap_numbers::option::OSCORE);
+        REGISTRY.lock(|r| {
+            if let Some(incoming) = &mut r.borrow_mut().incoming {
+                incoming.protected = protected;
+            }
+        });
+        self.0.borrow_mut().extract_request_data(request)
+    }
+
+    fn estimate_length(&mut self, request: &Self::RequestData) -> usize {
+        self.0.borrow_mut().estimate_length(request)
+    }
+
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        request: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        let mut handler = self.0.borrow_mut();
+        let result = handler.build_response(response, request);
+        let protection = handler.take_observation_protection();
+        REGISTRY.lock(|r| r.borrow_mut().complete(protection));
+        result
+    }
+}
+
+/// Takes note that the server processes a request that did not arrive on its socket, and can
+/// therefore not register observations.
+#[cfg(feature = "tcp")]
+pub(crate) fn received_elsewhere() {
+    REGISTRY.lock(|r| {
+        let mut registry = r.borrow_mut();
+        registry.incoming = None;
+        registry.pending = None;
+    });
+}
+
+/// Sends notifications through `socket` whenever observed resources changed.
+pub(crate) async fn run<H: Handler>(
+    socket: &udp_nal::UnconnectedUdp<'_>,
+    handler: &RefCell<Server<'_, H>>,
+) -> ! {
+    let mut rng = ariel_os_random::fast_rng();
+    let mut buffer = [0u8; MAX_MESSAGE_LEN];
+    loop {
+        CHANGED.wait().await;
+
+        // FIXME: Observations are out of the registry until their notification is rendered, so
+        // deregistrations arriving while earlier notifications are sent are missed.
+        let changed = REGISTRY.lock(|r| r.borrow_mut().observers.take_changed());
+        for mut observation in changed {
+            // FIXME: At least every 24 hours, notifications should be confirmable, so that
+            // clients that are gone are noticed (RFC 7641 Section 4.5).
+            #[expect(
+                clippy::cast_possible_truncation,
+                reason = "any 16 bits make a message ID"
+            )]
+            let message_id = rng.next_u32() as u16;
+            let endpoints = *observation.peer();
+
+            let len = match render(handler, &mut observation, message_id, &mut buffer) {
+                Ok((len, continues)) => {
+                    if continues {
+                        observation.set_message_id(message_id);
+                        restore(observation);
+                    }
+                    len
+                }
+                Err(NotificationError::Unpersisted) => {
+                    info!("Skipping notification until sequence numbers are stored");
+                    restore(observation);
+                    continue;
+                }
+                Err(_) => {
+                    info!("Ending observation that can not be notified");
+                    continue;
+                }
+            };
+
+            #[expect(
+                clippy::indexing_slicing,
+                reason = "length was written into the buffer"
+            )]
+            let notification = &buffer[..len];
+            if socket
+                .send_shared(endpoints.local, endpoints.remote, notification)
+                .await
+                .is_err()
+            {
+                info!("Could not send notification");
+            }
+        }
+    }
+}
+
+/// Places an observation back after its notification was rendered.
+fn restore(observation: Observation<'static, Endpoints>) {
+    if REGISTRY.lock(|r| r.borrow_mut().observers.restore(observation).is_err()) {
+        warn!("Ending observation whose slot was taken by new registrations");
+    }
+}
+
+/// Renders a notification of `observation` into `buffer`.
+///
+/// Returns the length of the notification, and whether the observation continues after it.
+fn render<H: Handler>(
+    handler: &RefCell<Server<'_, H>>,
+    observation: &mut Observation<'static, Endpoints>,
+    message_id: u16,
+    buffer: &mut [u8],
+) -> Result<(usize, bool), NotificationError> {
+    let sequence = observation.resource().sequence();
+    let token: heapless::Vec<u8, MAX_TOKEN_LEN> =
+        heapless::Vec::from_slice(observation.token()).expect("Tokens are at most that long");
+    let (header, tail) = buffer.split_at_mut(4 + token.len());
+
+    let mut code = 0;
+    let mut message = inmemory_write::Message::new(&mut code, tail);
+    REGISTRY.lock(|r| r.borrow_mut().rendering = true);
+    let rendered = {
+        let mut handler = handler.borrow_mut();
+        let (request, protection) = observation.request_with_protection();
+        match protection {
+            Some(protection) => handler
+                .build_protected_notification(protection, &request, sequence, &mut message)
+                .map(|()| true),
+            None => {
+                let built = handler
+                    .extract_request_data(&request)
+                    .ok()
+                    .and_then(|extracted| handler.build_response(&mut message, extracted).ok());
+                // A response without an Observe option ends the observation (RFC 7641 Section
+                // 3.2), e.g. when the client is no longer allowed to read the resource.
+                built
+                    .map(|()| {
+                        message
+                            .options()
+                            .any(|o| o.number() == coap_numbers::option::OBSERVE)
+                    })
+                    .ok_or(NotificationError::NotAllowed)
+            }
+        }
+    };
+    REGISTRY.lock(|r| r.borrow_mut().rendering = false);
+    let continues = rendered?;
+    let len = message.finish();
+
+    let (fixed, token_part) = header.split_at_mut(4);
+    let [id_high, id_low] = message_id.to_be_bytes();
+    let token_len = u8::try_from(token.len()).expect("Tokens are at most 8 bytes long");
+    fixed.copy_from_slice(&[0x40 | (TYPE_NON << 4) | token_len, code, id_high, id_low]);
+    token_part.copy_from_slice(&token);
+
+    Ok((header.len() + len, continues))
+}
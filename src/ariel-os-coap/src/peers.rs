@@ -0,0 +1,228 @@
+//! Known peers and their permissions, from the [`Config`](crate::Config) or from storage.
+//!
+//! With the `storage` feature, peers can be provisioned into storage through
+//! [`provision_peer()`]. If any peer is in storage at startup, the stored peers are used instead
+//! of the [`peers`](coapcore::authorization::PeerTable::peers) of the configuration; the
+//! permissions of unknown peers and of unprotected requests always come from the configuration.
+//!
+//! Only the thumbprint of a stored peer's credential is kept, so stored peers are only recognized
+//! when they send their credential by value in EDHOC.
+use coapcore::authorization::PeerTable;
+
+#[cfg(feature = "storage")]
+pub use stored::*;
+
+/// Returns the table of peers to use, given the one from the configuration.
+#[cfg_attr(
+    not(feature = "storage"),
+    expect(clippy::unused_async, reason = "awaits storage when enabled")
+)]
+pub(crate) async fn peers(configured: PeerTable<'static>) -> PeerTable<'static> {
+    // The device resources are for known peers only.
+    #[cfg(feature = "resources")]
+    let configured = if configured.restricted.is_empty() {
+        configured.with_restricted(crate::resources::RESTRICTED_PATHS)
+    } else {
+        configured
+    };
+
+    #[cfg(feature = "storage")]
+    if let Some(stored) = stored::load().await {
+        return PeerTable {
+            peers: stored,
+            ..configured
+        };
+    }
+
+    configured
+}
+
+#[cfg(feature = "storage")]
+mod stored {
+    use ariel_os_debug::log::info;
+    use coapcore::authorization::{AifValue, Peer, PeerCredential, Scope, THUMBPRINT_LEN};
+    use static_cell::StaticCell;
+
+    /// Number of peers that can be kept in storage.
+    pub const MAX_STORED_PEERS: usize = 4;
+    /// Maximum length of the AIF value of a peer kept in storage.
+    pub const MAX_STORED_AIF_LEN: usize = 64;
+
+    /// Storage keys of the credential and the scope of each slot.
+    ///
+    /// Credentials and scopes are kept separately, so that each fits into a storage item.
+    /// Credentials are kept as thumbprints, as whole CCSs would not fit.
+    const STORAGE_KEYS: [(&str, &str); MAX_STORED_PEERS] = [
+        ("ariel-os.coap.peer0.cred", "ariel-os.coap.peer0.scope"),
+        ("ariel-os.coap.peer1.cred", "ariel-os.coap.peer1.scope"),
+        ("ariel-os.coap.peer2.cred", "ariel-os.coap.peer2.scope"),
+        ("ariel-os.coap.peer3.cred", "ariel-os.coap.peer3.scope"),
+    ];
+
+    /// Thumbprint of the credential of a stored peer.
+    type StoredCredential = [u8; THUMBPRINT_LEN];
+
+    /// AIF value of a stored peer; `None` allows everything.
+    type StoredScope = Option<heapless::Vec<u8, MAX_STORED_AIF_LEN>>;
+
+    type StoredPeers = heapless::Vec<(StoredCredential, StoredScope), MAX_STORED_PEERS>;
+
+    /// Error returned by [`provision_peer()`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ProvisionError {
+        /// The slot is not below [`MAX_STORED_PEERS`].
+        InvalidSlot,
+        /// The scope is too long to be kept in storage.
+        TooLong,
+        /// Writing to storage failed.
+        Storage,
+    }
+
+    impl core::fmt::Display for ProvisionError {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            match self {
+                Self::InvalidSlot => write!(f, "invalid slot"),
+                Self::TooLong => write!(f, "scope too long"),
+                Self::Storage => write!(f, "storage error"),
+            }
+        }
+    }
+
+    impl core::error::Error for ProvisionError {}
+
+    /// Provisions a peer into a storage slot, or clears the slot if `peer` is `None`.
+    ///
+    /// Stored peers are loaded when [`coap_run()`](crate::coap_run) starts, so changes take effect
+    /// at the next boot.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the slot does not exist, if the scope does not fit into storage (see
+    /// [`MAX_STORED_AIF_LEN`]), or if writing to storage failed.
+    pub async fn provision_peer(
+        slot: usize,
+        peer: Option<&Peer<'_>>,
+    ) -> Result<(), ProvisionError> {
+        let (credential_key, scope_key) =
+            STORAGE_KEYS.get(slot).ok_or(ProvisionError::InvalidSlot)?;
+
+        let Some(peer) = peer else {
+            ariel_os_storage::remove(credential_key)
+                .await
+                .map_err(|_| ProvisionError::Storage)?;
+            return ariel_os_storage::remove(scope_key)
+                .await
+                .map_err(|_| ProvisionError::Storage);
+        };
+
+        let (credential, scope) = to_stored(peer)?;
+
+        // The scope goes first, so that a half-provisioned peer does not get the scope of the
+        // peer that was in the slot before.
+        ariel_os_storage::insert(scope_key, scope)
+            .await
+            .map_err(|_| ProvisionError::Storage)?;
+        ariel_os_storage::insert(credential_key, credential)
+            .await
+            .map_err(|_| ProvisionError::Storage)
+    }
+
+    /// Loads the stored peers, if there are any.
+    pub(super) async fn load() -> Option<&'static [Peer<'static>]> {
+        static DATA: StaticCell<StoredPeers> = StaticCell::new();
+        static PEERS: StaticCell<heapless::Vec<Peer<'static>, MAX_STORED_PEERS>> =
+            StaticCell::new();
+
+        let mut data = StoredPeers::new();
+        for (credential_key, scope_key) in STORAGE_KEYS {
+            let Ok(Some(credential)) = ariel_os_storage::get(credential_key).await else {
+                continue;
+            };
+            let Ok(Some(scope)) = ariel_os_storage::get::<StoredScope>(scope_key).await else {
+                info!("Ignoring stored CoAP peer without scope");
+                continue;
+            };
+            let _ = data.push((credential, scope));
+        }
+        if data.is_empty() {
+            return None;
+        }
+        info!("Using {} CoAP peers from storage", data.len());
+
+        let data = DATA.init(data);
+        let peers = data
+            .iter()
+            .map(|(credential, scope)| from_stored(credential, scope))
+            .collect();
+        Some(PEERS.init(peers))
+    }
+
+    /// Converts a peer into the form in which it is stored.
+    fn to_stored(peer: &Peer<'_>) -> Result<(StoredCredential, StoredScope), ProvisionError> {
+        let credential = match peer.credential {
+            PeerCredential::Ccs(ccs) => PeerCredential::thumbprint(ccs),
+            PeerCredential::Thumbprint(thumbprint) => thumbprint,
+        };
+        let scope = match peer.scope {
+            Scope::AllowAll => None,
+            Scope::Aif(aif) => Some(
+                heapless::Vec::from_slice(aif.as_cbor()).map_err(|()| ProvisionError::TooLong)?,
+            ),
+        };
+        Ok((credential, scope))
+    }
+
+    /// Converts a stored peer back.
+    fn from_stored<'a>(credential: &StoredCredential, scope: &'a StoredScope) -> Peer<'a> {
+        Peer {
+            credential: PeerCredential::Thumbprint(*credential),
+            scope: scope
+                .as_ref()
+                .map_or(Scope::AllowAll, |aif| Scope::Aif(AifValue::new(aif))),
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        use ariel_os_storage::{emulated_flash::EmulatedFlash, Storage};
+        use coapcore::authorization::PeerTable;
+        use embassy_futures::block_on;
+
+        /// The credential of the client in the Ariel OS CoAP tests, whose KID is `h'2b'`.
+        const CCS: &[u8] = &hexlit::hex!("A2027734322D35302D33312D46462D45462D33372D33322D333908A101A5010202412B2001215820AC75E9ECE3E50BFC8ED60399889522405C47BF16DF96660A41298CB4307F7EB62258206E5DE611388A4B8A8211334AC7D37ECB52A387D257E6DB3C2A93DF21FF3AFFC8");
+
+        // [["/", 1]]
+        const AIF: &[u8] = &hexlit::hex!("8182612f01");
+
+        #[test]
+        fn round_trip() {
+            const PAGE_SIZE: usize = 1024;
+            let mut storage = Storage::new(EmulatedFlash::<PAGE_SIZE>::new(2), 0..2048);
+            let peer = Peer {
+                credential: PeerCredential::Ccs(CCS),
+                scope: Scope::Aif(AifValue::new(AIF)),
+            };
+            let (credential_key, scope_key) = STORAGE_KEYS.last().unwrap();
+
+            let (credential, scope) = to_stored(&peer).unwrap();
+            block_on(async {
+                storage.insert(credential_key, credential).await.unwrap();
+                storage.insert(scope_key, scope).await.unwrap();
+            });
+            let (credential, scope): (StoredCredential, StoredScope) = block_on(async {
+                (
+                    storage.get(credential_key).await.unwrap().unwrap(),
+                    storage.get(scope_key).await.unwrap().unwrap(),
+                )
+            });
+
+            let loaded = from_stored(&credential, &scope);
+            assert_eq!(loaded.scope, peer.scope);
+            let loaded = [loaded];
+            let table = PeerTable::new(&loaded);
+            assert_eq!(table.find_by_ccs(CCS), Some(0));
+        }
+    }
+}
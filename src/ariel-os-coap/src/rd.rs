@@ -0,0 +1,303 @@
+//! Registration of the server at a CoAP Resource Directory (RFC 9176).
+//!
+//! The links of the server's `/.well-known/core` resource are rendered once at startup, and
+//! registered at the RD configured in [`Config::resource_directory`](crate::Config), or at one
+//! discovered by a multicast request. The registration is refreshed before its lifetime expires,
+//! and made anew whenever the network configuration changes (eg. when the device got a new
+//! address) or the RD forgot about it.
+//!
+//! Requests to the RD are sent through [`coap_client()`](crate::coap_client); only the multicast
+//! discovery bypasses it, as the answering RD is not known in advance. Its responses are picked up
+//! by the server's transport through [`received()`].
+use core::cell::RefCell;
+use core::fmt::Write as _;
+use core::net::{IpAddr, SocketAddr};
+
+use ariel_os_debug::log::info;
+use ariel_os_embassy::network::NetworkStack;
+use coap_handler::Handler;
+use coap_message::{MinimalWritableMessage, ReadableMessage};
+use coap_message_implementations::inmemory_write;
+use coap_numbers::{code, option};
+use coapcore::rd::{self, RdError};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Timer};
+use rand_core::RngCore as _;
+
+use crate::{
+    config::ResourceDirectory, udp_nal, ALL_COAP_NODES_V4, ALL_COAP_NODES_V6_LINK_LOCAL,
+    DEFAULT_PORT,
+};
+
+/// Longest link-format document of the server that can be registered.
+const MAX_LINKS_LEN: usize = ariel_os_utils::usize_from_env_or!(
+    "CONFIG_COAP_MAX_RD_LINKS_LEN",
+    512,
+    "size of the link-format document the CoAP server registers at a Resource Directory"
+);
+
+/// The links registered at the RD.
+pub(crate) type Links = heapless::Vec<u8, MAX_LINKS_LEN>;
+
+/// Endpoint name derived from the device identity.
+type EndpointName = heapless::String<{ rd::MAX_PATH_LEN }>;
+
+/// Time waited for RDs to answer a multicast discovery.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time waited before retrying a failed discovery or registration.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Interval at which the network configuration is checked for changes.
+const NETWORK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Message type of multicast discovery requests: non-confirmable (RFC 7252 Section 3).
+const TYPE_NON: u8 = 1;
+
+/// Token of the pending multicast discovery.
+static DISCOVERY: Mutex<CriticalSectionRawMutex, RefCell<Option<[u8; 4]>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Address of an RD that answered the pending multicast discovery.
+static DISCOVERED: Signal<CriticalSectionRawMutex, SocketAddr> = Signal::new();
+
+/// Renders the links of the server's `/.well-known/core` resource.
+///
+/// Returns `None` if the handler does not provide them, or they exceed [`MAX_LINKS_LEN`].
+pub(crate) fn links(handler: &mut impl Handler) -> Option<Links> {
+    let mut request_code = 0;
+    let mut request_buffer = [0u8; 32];
+    let mut request = inmemory_write::Message::new(&mut request_code, &mut request_buffer);
+    write_wkc_request(&mut request, None).ok()?;
+
+    let mut response_code = 0;
+    // Leaves room for the Content-Format option.
+    let mut buffer = [0u8; MAX_LINKS_LEN + 8];
+    let mut response = inmemory_write::Message::new(&mut response_code, &mut buffer);
+    let extracted = handler.extract_request_data(&request).ok()?;
+    handler.build_response(&mut response, extracted).ok()?;
+    if response.code() != code::CONTENT {
+        return None;
+    }
+    Links::from_slice(response.payload()).ok()
+}
+
+/// Takes note of a message received on the server's socket, which may be the answer of an RD to
+/// the pending multicast discovery.
+pub(crate) fn received(remote: SocketAddr, datagram: &[u8]) {
+    let [first, response_code, _, _, rest @ ..] = datagram else {
+        return;
+    };
+    // Only 2.05 Content responses announce an RD.
+    if *response_code != code::CONTENT {
+        return;
+    }
+    let token = rest.get(..usize::from(first & 0x0f));
+    let matches = DISCOVERY.lock(|d| {
+        let pending = d.borrow();
+        pending.is_some_and(|pending| token == Some(&pending[..]))
+    });
+    if matches {
+        DISCOVERED.signal(remote);
+    }
+}
+
+/// Keeps the server registered at the RD configured in `config`.
+///
+/// `socket` is the server's socket, through which multicast discoveries are sent.
+pub(crate) async fn run(
+    config: ResourceDirectory,
+    stack: NetworkStack,
+    socket: &udp_nal::UnconnectedUdp<'_>,
+    links: &[u8],
+) -> ! {
+    let endpoint = endpoint_name(&config);
+    let mut rng = ariel_os_random::fast_rng();
+    loop {
+        stack.wait_config_up().await;
+        let network = NetworkState::of(stack);
+
+        let address = match config.address {
+            Some(address) => Some(address),
+            None => discover_multicast(stack, socket, &mut rng).await,
+        };
+        let Some(address) = address else {
+            info!("No Resource Directory found");
+            Timer::after(RETRY_DELAY).await;
+            continue;
+        };
+
+        let mut client = crate::coap_client().await.to(address);
+        let registered = match rd::discover(&mut client).await {
+            Ok(interface) => {
+                rd::register(&mut client, &interface, &endpoint, config.lifetime, links).await
+            }
+            Err(error) => Err(error),
+        };
+        let location = match registered {
+            Ok(location) => location,
+            Err(error) => {
+                log_error("register", &error);
+                Timer::after(RETRY_DELAY).await;
+                continue;
+            }
+        };
+        info!("Registered at Resource Directory as {}", endpoint.as_str());
+
+        // Refreshing at three quarters of the lifetime leaves time for retransmissions.
+        let refresh_interval = Duration::from_secs(u64::from(config.lifetime) * 3 / 4);
+        loop {
+            match select(Timer::after(refresh_interval), network.wait_changed(stack)).await {
+                Either::First(()) => {
+                    if let Err(error) = rd::refresh(&mut client, &location).await {
+                        log_error("refresh", &error);
+                        break;
+                    }
+                }
+                Either::Second(()) => {
+                    info!("Network changed, registering anew");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the configured endpoint name, or one derived from the device identity.
+fn endpoint_name(config: &ResourceDirectory) -> EndpointName {
+    let mut name = EndpointName::new();
+    if let Some(endpoint) = config.endpoint {
+        // Names that are too long get rejected by the RD.
+        let _ = name.push_str(endpoint);
+        return name;
+    }
+    let _ = name.push_str("ariel");
+    if let Ok(id) = ariel_os_identity::device_id_bytes() {
+        let _ = name.push('-');
+        for byte in id.as_ref() {
+            let _ = write!(name, "{byte:02x}");
+        }
+    }
+    name
+}
+
+/// Sends a multicast request for RDs to the "All CoAP Nodes" groups of the address families that
+/// are configured, and returns the address of the first RD that answers.
+async fn discover_multicast(
+    stack: NetworkStack,
+    socket: &udp_nal::UnconnectedUdp<'_>,
+    rng: &mut impl rand_core::RngCore,
+) -> Option<SocketAddr> {
+    let token = rng.next_u32().to_be_bytes();
+    #[expect(
+        clippy::cast_possible_truncation,
+        reason = "any 16 bits make a message ID"
+    )]
+    let message_id = rng.next_u32() as u16;
+
+    let mut buffer = [0u8; 64];
+    let (header, tail) = buffer.split_at_mut(4 + token.len());
+    let mut request_code = 0;
+    let mut message = inmemory_write::Message::new(&mut request_code, tail);
+    write_wkc_request(&mut message, Some("rt=core.rd")).ok()?;
+    let len = header.len() + message.finish();
+    let [id_high, id_low] = message_id.to_be_bytes();
+    #[expect(clippy::cast_possible_truncation, reason = "the token has 4 bytes")]
+    let token_len = token.len() as u8;
+    let (fixed, token_part) = header.split_at_mut(4);
+    fixed.copy_from_slice(&[
+        0x40 | (TYPE_NON << 4) | token_len,
+        request_code,
+        id_high,
+        id_low,
+    ]);
+    token_part.copy_from_slice(&token);
+    #[expect(
+        clippy::indexing_slicing,
+        reason = "length was written into the buffer"
+    )]
+    let request = &buffer[..len];
+
+    DISCOVERY.lock(|d| *d.borrow_mut() = Some(token));
+    DISCOVERED.reset();
+    let unspecified = SocketAddr::new(IpAddr::V6(core::net::Ipv6Addr::UNSPECIFIED), 0);
+    let mut groups = heapless::Vec::<IpAddr, 2>::new();
+    if stack.config_v6().is_some() {
+        let _ = groups.push(IpAddr::V6(ALL_COAP_NODES_V6_LINK_LOCAL));
+    }
+    if stack.config_v4().is_some() {
+        let _ = groups.push(IpAddr::V4(ALL_COAP_NODES_V4));
+    }
+    for group in groups {
+        let group = SocketAddr::new(group, DEFAULT_PORT);
+        if socket
+            .send_shared(unspecified, group, request)
+            .await
+            .is_err()
+        {
+            info!("Could not send Resource Directory discovery");
+        }
+    }
+
+    let discovered = match select(DISCOVERED.wait(), Timer::after(DISCOVERY_TIMEOUT)).await {
+        Either::First(address) => Some(address),
+        Either::Second(()) => None,
+    };
+    DISCOVERY.lock(|d| *d.borrow_mut() = None);
+    discovered
+}
+
+/// Writes a GET request to `/.well-known/core` with an optional `query` into `message`.
+fn write_wkc_request<M: MinimalWritableMessage<Code = u8, OptionNumber = u16>>(
+    message: &mut M,
+    query: Option<&str>,
+) -> Result<(), M::UnionError> {
+    message.set_code(code::GET);
+    message.add_option(option::URI_PATH, b".well-known")?;
+    message.add_option(option::URI_PATH, b"core")?;
+    if let Some(query) = query {
+        message.add_option(option::URI_QUERY, query.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Logs a failed RD `operation`.
+fn log_error<T>(operation: &str, error: &RdError<T>) {
+    match error {
+        RdError::Transport(_) => {
+            info!("Could not {} at Resource Directory: no response", operation)
+        }
+        RdError::Rejected(code) => {
+            info!("Resource Directory rejected {}: code {}", operation, code);
+        }
+        _ => info!("Could not {} at Resource Directory", operation),
+    }
+}
+
+/// The parts of the network configuration a registration depends on.
+#[derive(PartialEq, Eq)]
+struct NetworkState {
+    v4: Option<embassy_net::StaticConfigV4>,
+    v6: Option<embassy_net::StaticConfigV6>,
+}
+
+impl NetworkState {
+    fn of(stack: NetworkStack) -> Self {
+        Self {
+            v4: stack.config_v4(),
+            v6: stack.config_v6(),
+        }
+    }
+
+    /// Waits until the network configuration of `stack` differs from `self`.
+    async fn wait_changed(&self, stack: NetworkStack) {
+        loop {
+            Timer::after(NETWORK_CHECK_INTERVAL).await;
+            if Self::of(stack) != *self {
+                return;
+            }
+        }
+    }
+}
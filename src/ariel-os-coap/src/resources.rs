@@ -0,0 +1,362 @@
+//! Ready-made resources for inspecting a device remotely.
+//!
+//! The resources are placed below `/ariel/`. As they reveal details of the device (and the
+//! storage resource can alter it), they are only accessible to known peers (and holders of access
+//! tokens) whose scope allows it: Unless the configured
+//! [`PeerTable`](coapcore::authorization::PeerTable) lists restricted paths itself, `/ariel` is
+//! restricted, so that neither unprotected requests nor unknown peers can access them.
+
+use core::fmt::Write;
+
+use coap_handler::{Handler, Reporting};
+use coap_handler_implementations::{HandlerBuilder as _, SimpleRenderable, SimpleRendered};
+
+/// Paths that only known peers may access, see
+/// [`PeerTable::restricted`](coapcore::authorization::PeerTable::restricted).
+pub(crate) const RESTRICTED_PATHS: &[&str] = &["/ariel"];
+
+/// Adds resources for inspecting the device to `handler`:
+///
+/// * `/ariel/build`: the OS name and the board the firmware was built for.
+/// * `/ariel/id`: the device identity (see [`ariel_os_identity::device_id_bytes()`]) in
+///   hexadecimal; empty if the device has none.
+/// * `/ariel/threads` (with the `threading` feature): a line for every thread with its ID,
+///   priority, state, and the bytes of stack it used so far out of the stack's size (e.g.,
+///   `3 2 paused 712/2048`).
+///
+/// All are read with GET, and represented as plain text.
+pub fn with_device_resources(handler: impl Handler + Reporting) -> impl Handler + Reporting {
+    let handler = handler
+        .at(&["ariel", "build"], SimpleRendered(BuildInfo))
+        .at(&["ariel", "id"], SimpleRendered(DeviceId));
+    #[cfg(feature = "threading")]
+    let handler = handler.at(&["ariel", "threads"], SimpleRendered(Threads));
+    handler
+}
+
+/// The content format of plain text.
+const TEXT_PLAIN: u16 = 0;
+
+/// Representation of `/ariel/build`.
+struct BuildInfo;
+
+impl SimpleRenderable for BuildInfo {
+    fn render<W: Write>(&mut self, writer: &mut W) {
+        let _ = write!(
+            writer,
+            "os: {}\nboard: {}\n",
+            ariel_os_buildinfo::OS_NAME,
+            ariel_os_buildinfo::BOARD
+        );
+    }
+
+    fn content_format(&self) -> Option<u16> {
+        Some(TEXT_PLAIN)
+    }
+}
+
+/// Representation of `/ariel/id`.
+struct DeviceId;
+
+impl SimpleRenderable for DeviceId {
+    fn render<W: Write>(&mut self, writer: &mut W) {
+        let Ok(id) = ariel_os_identity::device_id_bytes() else {
+            return;
+        };
+        for byte in id.as_ref() {
+            let _ = write!(writer, "{byte:02x}");
+        }
+    }
+
+    fn content_format(&self) -> Option<u16> {
+        Some(TEXT_PLAIN)
+    }
+}
+
+/// Representation of `/ariel/threads`.
+#[cfg(feature = "threading")]
+struct Threads;
+
+#[cfg(feature = "threading")]
+impl SimpleRenderable for Threads {
+    fn render<W: Write>(&mut self, writer: &mut W) {
+        use ariel_os_threads::{
+            get_priority, get_state, stack_usage, ThreadId, ThreadState, THREADS_NUMOF,
+        };
+
+        for id in 0..THREADS_NUMOF {
+            let id = u8::try_from(id).expect("Thread IDs fit in a u8");
+            let thread = ThreadId::new(id);
+            let (Some(priority), Some(state), Some(stack)) =
+                (get_priority(thread), get_state(thread), stack_usage(thread))
+            else {
+                continue;
+            };
+            let state = match state {
+                ThreadState::Invalid => continue,
+                ThreadState::Running => "running",
+                ThreadState::Paused => "paused",
+                ThreadState::LockBlocked => "lock-blocked",
+                ThreadState::FlagBlocked(_) => "flag-blocked",
+                ThreadState::ChannelRxBlocked(_) | ThreadState::ChannelTxBlocked(_) => {
+                    "channel-blocked"
+                }
+            };
+            let _ = writeln!(
+                writer,
+                "{id} {} {state} {}/{}",
+                usize::from(priority),
+                stack.used,
+                stack.size
+            );
+        }
+    }
+
+    fn content_format(&self) -> Option<u16> {
+        Some(TEXT_PLAIN)
+    }
+}
+
+#[cfg(feature = "storage")]
+pub(crate) use storage::run;
+#[cfg(feature = "storage")]
+pub use storage::{with_storage_resource, MAX_STORAGE_VALUE_LEN};
+
+#[cfg(feature = "storage")]
+mod storage {
+    use core::cell::RefCell;
+
+    use coap_handler::{Attribute, Handler, Record, Reporting};
+    use coap_handler_implementations::HandlerBuilder as _;
+    use coap_message::{
+        Code as _, MessageOption as _, MinimalWritableMessage, MutableWritableMessage,
+        OptionNumber as _, ReadableMessage,
+    };
+    use coap_message_utils::{Error as CoAPError, OptionsExt as _};
+    use coap_numbers::{code, option};
+    use embassy_sync::{
+        blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+        signal::Signal,
+    };
+
+    /// Longest value that can be read or written through the storage resource.
+    pub const MAX_STORAGE_VALUE_LEN: usize = 128;
+
+    /// The type of values that are read and written.
+    type Value = heapless::Vec<u8, MAX_STORAGE_VALUE_LEN>;
+
+    /// The content format of values, application/octet-stream.
+    const OCTET_STREAM: u8 = 42;
+
+    /// Seconds after which a client should retry a request whose storage operation is pending.
+    const RETRY_SECONDS: u8 = 1;
+
+    /// The storage operation requested through the resource.
+    ///
+    /// Storage is accessed asynchronously, while requests are handled synchronously: A request
+    /// starts an operation and is answered with 5.03 Service Unavailable; the client's retry of
+    /// the same request picks up the operation's result.
+    #[derive(Clone)]
+    enum Operation {
+        Idle,
+        Get(&'static str),
+        Put(&'static str, Value),
+        Got(&'static str, Option<Value>),
+        Stored(&'static str, Value),
+        Failed(&'static str),
+    }
+
+    static OPERATION: Mutex<CriticalSectionRawMutex, RefCell<Operation>> =
+        Mutex::new(RefCell::new(Operation::Idle));
+    static REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+    /// Adds the resource `/ariel/storage` to `handler`, through which the storage `keys` can be
+    /// read (GET) and written (PUT).
+    ///
+    /// The key is selected with a `k=` query parameter, eg. `/ariel/storage?k=name`. Values are
+    /// byte strings of up to [`MAX_STORAGE_VALUE_LEN`] bytes (stored as `heapless::Vec<u8,
+    /// MAX_STORAGE_VALUE_LEN>`), so only keys holding values of that type should be listed.
+    ///
+    /// As storage is accessed in the background, requests are first answered with 5.03 Service
+    /// Unavailable and a Max-Age of one second, after which a retry of the same request receives
+    /// the result.
+    pub fn with_storage_resource(
+        handler: impl Handler + Reporting,
+        keys: &'static [&'static str],
+    ) -> impl Handler + Reporting {
+        handler.at(&["ariel", "storage"], StorageResource { keys })
+    }
+
+    /// Performs the storage operations requested through the resource.
+    pub(crate) async fn run() -> ! {
+        loop {
+            REQUESTED.wait().await;
+            let requested = OPERATION.lock(|o| o.borrow().clone());
+            let done = match requested {
+                Operation::Get(key) => match ariel_os_storage::get::<Value>(key).await {
+                    Ok(value) => Operation::Got(key, value),
+                    Err(_) => Operation::Failed(key),
+                },
+                Operation::Put(key, value) => {
+                    match ariel_os_storage::insert(key, value.clone()).await {
+                        Ok(()) => Operation::Stored(key, value),
+                        Err(_) => Operation::Failed(key),
+                    }
+                }
+                _ => continue,
+            };
+            OPERATION.lock(|o| *o.borrow_mut() = done);
+        }
+    }
+
+    struct StorageResource {
+        keys: &'static [&'static str],
+    }
+
+    /// The response of the storage resource.
+    enum Response {
+        Value(Value),
+        Code(u8),
+    }
+
+    impl StorageResource {
+        /// Answers a request, starting a storage operation if it is not the retry of a completed
+        /// one.
+        fn respond(key: &'static str, written: Option<Value>) -> Response {
+            OPERATION.lock(|o| {
+                let mut operation = o.borrow_mut();
+                let completed = match (&*operation, &written) {
+                    (Operation::Get(_) | Operation::Put(..), _) => {
+                        return Response::Code(code::SERVICE_UNAVAILABLE);
+                    }
+                    (Operation::Got(k, Some(value)), None) if *k == key => {
+                        Some(Response::Value(value.clone()))
+                    }
+                    (Operation::Got(k, None), None) if *k == key => {
+                        Some(Response::Code(code::NOT_FOUND))
+                    }
+                    (Operation::Stored(k, value), Some(written))
+                        if *k == key && value == written =>
+                    {
+                        Some(Response::Code(code::CHANGED))
+                    }
+                    (Operation::Failed(k), _) if *k == key => {
+                        Some(Response::Code(code::INTERNAL_SERVER_ERROR))
+                    }
+                    // Idle, or a result nobody came back for
+                    _ => None,
+                };
+                if let Some(response) = completed {
+                    *operation = Operation::Idle;
+                    return response;
+                }
+                *operation = match written {
+                    Some(value) => Operation::Put(key, value),
+                    None => Operation::Get(key),
+                };
+                REQUESTED.signal(());
+                Response::Code(code::SERVICE_UNAVAILABLE)
+            })
+        }
+    }
+
+    impl Handler for StorageResource {
+        type RequestData = Response;
+        type ExtractRequestError = CoAPError;
+        type BuildResponseError<M: MinimalWritableMessage> = M::UnionError;
+
+        fn extract_request_data<M: ReadableMessage>(
+            &mut self,
+            request: &M,
+        ) -> Result<Self::RequestData, Self::ExtractRequestError> {
+            let method: u8 = request.code().into();
+            if !matches!(method, code::GET | code::PUT) {
+                return Err(CoAPError::method_not_allowed());
+            }
+            let mut key = None;
+            request
+                .options()
+                .filter(|o| match o.number() {
+                    option::URI_PATH | option::CONTENT_FORMAT => false,
+                    option::URI_QUERY => {
+                        if let Some(name) = o.value().strip_prefix(b"k=") {
+                            key = self.keys.iter().find(|k| k.as_bytes() == name).copied();
+                        }
+                        false
+                    }
+                    _ => true,
+                })
+                .ignore_elective_others()?;
+            let key = key.ok_or_else(CoAPError::not_found)?;
+
+            let written = if method == code::PUT {
+                let Ok(value) = Value::from_slice(request.payload()) else {
+                    return Ok(Response::Code(code::REQUEST_ENTITY_TOO_LARGE));
+                };
+                Some(value)
+            } else {
+                None
+            };
+            Ok(Self::respond(key, written))
+        }
+
+        fn estimate_length(&mut self, _request: &Self::RequestData) -> usize {
+            8 + MAX_STORAGE_VALUE_LEN
+        }
+
+        fn build_response<M: MutableWritableMessage>(
+            &mut self,
+            response: &mut M,
+            request: Self::RequestData,
+        ) -> Result<(), Self::BuildResponseError<M>> {
+            match request {
+                Response::Value(value) => {
+                    response.set_code(M::Code::new(code::CONTENT)?);
+                    response.add_option(
+                        M::OptionNumber::new(option::CONTENT_FORMAT)?,
+                        &[OCTET_STREAM],
+                    )?;
+                    response.set_payload(&value)?;
+                }
+                Response::Code(response_code) => {
+                    response.set_code(M::Code::new(response_code)?);
+                    if response_code == code::SERVICE_UNAVAILABLE {
+                        response
+                            .add_option(M::OptionNumber::new(option::MAX_AGE)?, &[RETRY_SECONDS])?;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Reporting for StorageResource {
+        type Record<'res> = StorageRecord;
+        type Reporter<'res> = core::iter::Once<StorageRecord>;
+
+        fn report(&self) -> Self::Reporter<'_> {
+            core::iter::once(StorageRecord)
+        }
+    }
+
+    /// The record of the storage resource, whose path is provided by the enclosing handler.
+    struct StorageRecord;
+
+    impl Record for StorageRecord {
+        type PathElement = &'static str;
+        type PathElements = core::iter::Empty<&'static str>;
+        type Attributes = core::iter::Empty<Attribute>;
+
+        fn path(&self) -> Self::PathElements {
+            core::iter::empty()
+        }
+
+        fn rel(&self) -> Option<&str> {
+            None
+        }
+
+        fn attributes(&self) -> Self::Attributes {
+            core::iter::empty()
+        }
+    }
+}
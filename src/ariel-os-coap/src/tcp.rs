@@ -0,0 +1,108 @@
+//! CoAP over TCP (RFC 8323) to a configured endpoint.
+//!
+//! The device connects to the [`Config::tcp_endpoint`](crate::Config), typically a cloud service
+//! behind a firewall that would not let requests over UDP reach the device, and serves the
+//! requests the endpoint sends over the connection with the server's handler. Requests are
+//! processed by the same OSCORE/EDHOC layer as those arriving over UDP; observations can not be
+//! registered over TCP. The connection is established anew whenever it is lost.
+use core::cell::RefCell;
+use core::net::SocketAddr;
+
+use ariel_os_debug::log::info;
+use ariel_os_embassy::network::NetworkStack;
+use coap_handler::Handler;
+use coap_message::{MinimalWritableMessage, MutableWritableMessage, ReadableMessage};
+use coapcore::tcp::{self, TcpError, MAX_HEADER_LEN};
+use embassy_net::tcp::TcpSocket;
+use embassy_time::{Duration, Timer};
+
+use crate::observe::{self, Server};
+
+/// Longest message sent or received over TCP.
+const MAX_MESSAGE_LEN: usize = 1152;
+
+/// Time waited before connecting again after the connection failed or was closed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+/// Interval of TCP keep-alive segments, by which a lost connection is noticed.
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+/// Time after which an unresponsive connection is considered lost.
+const TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Keeps a connection to `endpoint`, serving the requests that arrive on it with `handler`.
+pub(crate) async fn run<H: Handler>(
+    stack: NetworkStack,
+    endpoint: SocketAddr,
+    handler: &RefCell<Server<'_, H>>,
+) -> ! {
+    let mut rx_buffer = [0; MAX_MESSAGE_LEN];
+    let mut tx_buffer = [0; MAX_MESSAGE_LEN];
+    let mut buffer = [0; MAX_MESSAGE_LEN];
+    let mut output = [0; MAX_HEADER_LEN + MAX_MESSAGE_LEN];
+    loop {
+        stack.wait_config_up().await;
+
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_keep_alive(Some(KEEP_ALIVE));
+        socket.set_timeout(Some(TIMEOUT));
+        if socket.connect(endpoint).await.is_err() {
+            info!("Could not connect to CoAP endpoint over TCP");
+        } else {
+            info!("Connected to CoAP endpoint over TCP");
+            let served = tcp::serve(
+                &mut socket,
+                &mut TcpHandler(handler),
+                &mut buffer,
+                &mut output,
+            )
+            .await;
+            match served {
+                Ok(()) => info!("CoAP endpoint released the TCP connection"),
+                Err(TcpError::Closed) => info!("CoAP endpoint closed the TCP connection"),
+                Err(TcpError::Io(_)) => info!("TCP connection to CoAP endpoint failed"),
+                Err(TcpError::Frame(_)) => info!("CoAP endpoint sent an unprocessable message"),
+            }
+            socket.close();
+            // Gives the stack a chance to send the FIN before the socket is dropped.
+            let _ = socket.flush().await;
+        }
+        drop(socket);
+
+        Timer::after(RECONNECT_DELAY).await;
+    }
+}
+
+/// The server's handler, used for requests arriving over TCP.
+///
+/// Like [`observe::SharedHandler`], this only borrows the handler while it is called into.
+struct TcpHandler<'h, 'a, H: Handler>(&'h RefCell<Server<'a, H>>);
+
+impl<'a, H: Handler> Handler for TcpHandler<'_, 'a, H> {
+    type RequestData = <Server<'a, H> as Handler>::RequestData;
+    type ExtractRequestError = <Server<'a, H> as Handler>::ExtractRequestError;
+    type BuildResponseError<M: MinimalWritableMessage> =
+        <Server<'a, H> as Handler>::BuildResponseError<M>;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Self::ExtractRequestError> {
+        observe::received_elsewhere();
+        self.0.borrow_mut().extract_request_data(request)
+    }
+
+    fn estimate_length(&mut self, request: &Self::RequestData) -> usize {
+        self.0.borrow_mut().estimate_length(request)
+    }
+
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        request: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        let mut handler = self.0.borrow_mut();
+        let result = handler.build_response(response, request);
+        // Observations are not registered over TCP.
+        let _ = handler.take_observation_protection();
+        result
+    }
+}
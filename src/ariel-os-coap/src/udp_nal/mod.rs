@@ -1,20 +1,14 @@
 //! UDP sockets usable through [`embedded_nal_async`]
 //!
-//! The full [`embedded_nal_async::UdpStack`] is *not* implemented at the moment: As its API allows
-//! arbitrary creation of movable sockets, embassy's [`udp::UdpSocket`] type could only be crated if
-//! the NAL stack had a pre-allocated pool of sockets with their respective buffers. Nothing rules
-//! out such a type, but at the moment, only the bound or connected socket types are implemented
-//! with their own constructors from an embassy [`crate::Stack`] -- for many applications, those are
-//! useful enough. (FIXME: Given we construct from Socket, Stack could really be implemented on
-//! `Cell<Option<Socket>>` by `.take()`ing, couldn't it?)
+//! The full [`embedded_nal_async::UdpStack`] is implemented by [`UdpSocketPool`]: As its API
+//! allows arbitrary creation of movable sockets, embassy's [`udp::UdpSocket`] type can only be
+//! created from a pre-allocated pool of sockets with their respective buffers, which the pool
+//! holds. Sockets return to the pool when dropped.
 //!
-//! The constructors of the various socket types mimic the [`UdpStack`]'s socket creation functions,
-//! but take an owned (uninitialized) Socket instead of a shared stack.
-//!
-//! No `bind_single` style constructor is currently provided. FIXME: Not sure we have all the
-//! information at bind time to specialize a wildcard address into a concrete address and return
-//! it. Should the NAL trait be updated to disallow using wildcard addresses on `bind_single`, and
-//! merely allow unspecified ports to get an ephemeral one?
+//! The bound or connected socket types can also be created with their own constructors, which
+//! mimic the [`UdpStack`](nal::UdpStack)'s socket creation functions, but take an owned
+//! (uninitialized) Socket instead of a shared stack -- for applications that manage their sockets
+//! themselves, those are useful enough.
 
 use core::future::poll_fn;
 use core::net::SocketAddr;
@@ -22,21 +16,24 @@ use core::net::SocketAddr;
 use embassy_net::{udp, IpAddress, IpEndpoint};
 use embedded_nal_async as nal;
 
+mod pool;
 mod util;
+pub use pool::{PoolSocket, UdpSocketPool};
 pub use util::Error;
 use util::{is_unspec_ip, sockaddr_nal2smol, sockaddr_smol2nal};
 
-#[expect(
-    dead_code,
-    reason = "pub item is being prepared for embedded-nal-async where it will be reachable publicly"
-)]
+/// A UDP socket that has been bound locally and to a remote address
+///
+/// Its operations are accessible through the [`nal::ConnectedUdp`] trait. Datagrams from other
+/// addresses than the remote are discarded.
 pub struct ConnectedUdp<'a> {
     remote: IpEndpoint,
-    // The local port is stored in the socket, as it gets bound. This value is populated lazily:
-    // embassy only decides at udp::Socket::dispatch time whence to send, and while we could
-    // duplicate the code for the None case of the local_address by calling the right
-    // get_source_address function, we'd still need an interface::Context / an interface to call
-    // this through, and AFAICT we don't get access to that.
+    // The local port is stored in the socket, as it gets bound. If the socket was bound to the
+    // unspecified address, this is None: embassy only decides at udp::Socket::dispatch time
+    // whence to send, and while we could duplicate the code for the None case of the
+    // local_address by calling the right get_source_address function, we'd still need an
+    // interface::Context / an interface to call this through, and AFAICT we don't get access to
+    // that. The [`UdpSocketPool`] therefore specializes unspecified addresses before binding.
     local: Option<IpAddress>,
     socket: udp::UdpSocket<'a>,
 }
@@ -49,7 +46,6 @@ pub struct UnconnectedUdp<'a> {
 }
 
 #[allow(
-    dead_code,
     clippy::unused_async,
     clippy::missing_errors_doc,
     reason = "pub item is being prepared for embedded-nal-async where it will be reachable publicly"
@@ -57,6 +53,9 @@ pub struct UnconnectedUdp<'a> {
 impl<'a> ConnectedUdp<'a> {
     /// Create a [`ConnectedUdp`] by assigning it a remote and a concrete local address
     ///
+    /// The local address may also have an unspecified IP address, in which case embassy picks the
+    /// source address of every datagram sent.
+    ///
     /// ## Prerequisites
     ///
     /// The `socket` must be open (in the sense of smoltcp's `.is_open()`) -- unbound and
@@ -66,33 +65,22 @@ impl<'a> ConnectedUdp<'a> {
         local: SocketAddr,
         remote: SocketAddr,
     ) -> Result<Self, Error> {
-        socket.bind(sockaddr_nal2smol(local)?)?;
+        let local_endpoint = sockaddr_nal2smol(local)?;
+        if is_unspec_ip(local) {
+            socket.bind(local.port())?;
+        } else {
+            socket.bind(local_endpoint)?;
+        }
 
         Ok(ConnectedUdp {
             remote: sockaddr_nal2smol(remote)?,
-            // FIXME: We could check if local was fully (or sufficiently, picking the port from the
-            // socket) specified and store if yes -- for a first iteration, leaving that to the
-            // fallback path we need anyway in case local is [::].
-            local: None,
+            local: (!is_unspec_ip(local)).then_some(local_endpoint.addr),
             socket,
         })
     }
-
-    /// Create a [`ConnectedUdp`] by assigning it a remote and a local address (the latter may
-    /// happen lazily)
-    ///
-    /// ## Prerequisites
-    ///
-    /// The `socket` must be open (in the sense of smoltcp's `.is_open()`) -- unbound and
-    /// unconnected.
-    pub async fn connect(socket: udp::UdpSocket<'a>, /*, ... */) -> Result<Self, udp::BindError> {
-        // This is really just a copy of the provided `embedded_nal::udp::UdpStack::connect` method
-        todo!("use {:p}", &socket)
-    }
 }
 
 #[allow(
-    dead_code,
     clippy::unused_async,
     clippy::missing_errors_doc,
     reason = "pub item is being prepared for embedded-nal-async where it will be reachable publicly"
@@ -111,16 +99,46 @@ impl<'a> UnconnectedUdp<'a> {
         mut socket: udp::UdpSocket<'a>,
         local: SocketAddr,
     ) -> Result<Self, Error> {
+        if is_unspec_ip(local) {
+            // Binding to the unspecified address itself would only accept packets sent to that
+            // address; leaving the address out accepts packets to any local address.
+            socket.bind(local.port())?;
+        } else {
+            socket.bind(sockaddr_nal2smol(local)?)?;
+        }
+
+        Ok(UnconnectedUdp { socket })
+    }
+
+    /// Create an [`UnconnectedUdp`] bound to a single concrete `local` address and port.
+    ///
+    /// Unlike with [`UdpStack::bind_single`](nal::UdpStack::bind_single), the address can not be
+    /// a wildcard address, as the socket alone can not tell the stack's addresses; a
+    /// [`UdpSocketPool`] picks a concrete address for a wildcard address.
+    ///
+    /// ## Prerequisites
+    ///
+    /// The `socket` must be open (in the sense of smoltcp's `.is_open()`) -- unbound and
+    /// unconnected.
+    pub async fn bind_single(
+        mut socket: udp::UdpSocket<'a>,
+        local: SocketAddr,
+    ) -> Result<Self, Error> {
+        if is_unspec_ip(local) {
+            return Err(Error::NoAddress);
+        }
         socket.bind(sockaddr_nal2smol(local)?)?;
 
         Ok(UnconnectedUdp { socket })
     }
 }
 
-impl nal::UnconnectedUdp for UnconnectedUdp<'_> {
-    type Error = Error;
-    async fn send(
-        &mut self,
+impl UnconnectedUdp<'_> {
+    /// Sends a datagram like [`nal::UnconnectedUdp::send`], but through a shared reference.
+    ///
+    /// This allows sending while another task is receiving on the same socket.
+    pub(crate) async fn send_shared(
+        &self,
         local: SocketAddr,
         remote: SocketAddr,
         buf: &[u8],
@@ -134,7 +152,9 @@ impl nal::UnconnectedUdp for UnconnectedUdp<'_> {
         );
 
         let remote_endpoint = udp::UdpMetadata {
-            local_address: if is_unspec_ip(local) {
+            // Responses to multicast requests are sent from a unicast address picked by the
+            // stack.
+            local_address: if is_unspec_ip(local) || local.ip().is_multicast() {
                 None
             } else {
                 // A conversion of the addr part only might be cheaper, but would also mean we need
@@ -146,8 +166,11 @@ impl nal::UnconnectedUdp for UnconnectedUdp<'_> {
         poll_fn(move |cx| self.socket.poll_send_to(buf, remote_endpoint, cx)).await?;
         Ok(())
     }
-    async fn receive_into(
-        &mut self,
+
+    /// Receives a datagram like [`nal::UnconnectedUdp::receive_into`], but through a shared
+    /// reference.
+    pub(crate) async fn receive_shared(
+        &self,
         buf: &mut [u8],
     ) -> Result<(usize, SocketAddr, SocketAddr), Error> {
         // FIXME: The truncation is an issue -- we may need to change poll_recv_from to poll_recv
@@ -165,3 +188,43 @@ impl nal::UnconnectedUdp for UnconnectedUdp<'_> {
         ))
     }
 }
+
+impl nal::UnconnectedUdp for UnconnectedUdp<'_> {
+    type Error = Error;
+    async fn send(
+        &mut self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        self.send_shared(local, remote, buf).await
+    }
+    async fn receive_into(
+        &mut self,
+        buf: &mut [u8],
+    ) -> Result<(usize, SocketAddr, SocketAddr), Error> {
+        self.receive_shared(buf).await
+    }
+}
+
+impl nal::ConnectedUdp for ConnectedUdp<'_> {
+    type Error = Error;
+
+    async fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        let metadata = udp::UdpMetadata {
+            local_address: self.local,
+            ..self.remote.into()
+        };
+        poll_fn(|cx| self.socket.poll_send_to(data, metadata, cx)).await?;
+        Ok(())
+    }
+
+    async fn receive_into(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            let (size, metadata) = poll_fn(|cx| self.socket.poll_recv_from(buffer, cx)).await?;
+            if metadata.endpoint == self.remote {
+                return Ok(size);
+            }
+        }
+    }
+}
@@ -0,0 +1,380 @@
+//! [`UdpStack`](nal::UdpStack) over a pre-allocated pool of sockets
+
+use core::cell::{Cell, UnsafeCell};
+use core::net::{IpAddr, SocketAddr};
+
+use embassy_net::udp::{self, PacketMetadata};
+use embedded_nal_async as nal;
+
+use super::{is_unspec_ip, ConnectedUdp, Error, UnconnectedUdp};
+
+/// First port of the dynamic port range (RFC 6335 Section 6), from which ephemeral ports are
+/// picked.
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+/// A pool of `N` UDP sockets, each with `BUFFER_LEN` bytes of receive and transmit buffer for up
+/// to `PACKETS` datagrams each, which implements [`UdpStack`](nal::UdpStack) through shared
+/// references.
+///
+/// Sockets are created by the trait's methods (with at most `N` in use at any time), and returned
+/// to the pool when dropped.
+///
+/// Local addresses with port 0 are assigned an ephemeral port, which no socket of the pool is
+/// bound to. [`bind_single()`](nal::UdpStack::bind_single) and
+/// [`connect_from()`](nal::UdpStack::connect_from) specialize a wildcard address into the
+/// stack's address of the respective family, which they report as the local address.
+///
+/// ```ignore
+/// use embedded_nal_async::UdpStack as _;
+///
+/// let stack = ariel_os::network::network_stack().await.unwrap();
+/// let pool = UdpSocketPool::<'_, 2, 1280, 4>::new(stack);
+/// // The stack is implemented by shared references to the pool.
+/// let (local, mut socket) = (&pool).connect(remote).await?;
+/// ```
+pub struct UdpSocketPool<'d, const N: usize, const BUFFER_LEN: usize, const PACKETS: usize> {
+    stack: embassy_net::Stack<'d>,
+    slots: [Slot<BUFFER_LEN, PACKETS>; N],
+    /// The ephemeral port to try next
+    next_port: Cell<u16>,
+}
+
+/// A socket's place in the pool.
+struct Slot<const BUFFER_LEN: usize, const PACKETS: usize> {
+    buffers: UnsafeCell<Buffers<BUFFER_LEN, PACKETS>>,
+    in_use: Cell<bool>,
+    /// The port the socket is bound to, or 0
+    port: Cell<u16>,
+}
+
+struct Buffers<const BUFFER_LEN: usize, const PACKETS: usize> {
+    rx_meta: [PacketMetadata; PACKETS],
+    rx: [u8; BUFFER_LEN],
+    tx_meta: [PacketMetadata; PACKETS],
+    tx: [u8; BUFFER_LEN],
+}
+
+impl<const BUFFER_LEN: usize, const PACKETS: usize> Slot<BUFFER_LEN, PACKETS> {
+    const fn new() -> Self {
+        Self {
+            buffers: UnsafeCell::new(Buffers {
+                rx_meta: [PacketMetadata::EMPTY; PACKETS],
+                rx: [0; BUFFER_LEN],
+                tx_meta: [PacketMetadata::EMPTY; PACKETS],
+                tx: [0; BUFFER_LEN],
+            }),
+            in_use: Cell::new(false),
+            port: Cell::new(0),
+        }
+    }
+}
+
+/// Marks a slot as in use while it exists.
+struct SlotGuard<'a> {
+    in_use: &'a Cell<bool>,
+    port: &'a Cell<u16>,
+}
+
+impl Drop for SlotGuard<'_> {
+    fn drop(&mut self) {
+        self.port.set(0);
+        self.in_use.set(false);
+    }
+}
+
+impl<'d, const N: usize, const BUFFER_LEN: usize, const PACKETS: usize>
+    UdpSocketPool<'d, N, BUFFER_LEN, PACKETS>
+{
+    /// Creates a pool of sockets on `stack`.
+    #[must_use]
+    pub const fn new(stack: embassy_net::Stack<'d>) -> Self {
+        Self {
+            stack,
+            slots: [const { Slot::new() }; N],
+            next_port: Cell::new(FIRST_EPHEMERAL_PORT),
+        }
+    }
+
+    /// Takes an unused socket out of the pool, along with the guard that returns its slot.
+    ///
+    /// The guard needs to outlive the socket; this holds when they are bound by destructuring the
+    /// returned tuple, as bindings are dropped in reverse order.
+    fn take(&self) -> Result<(SlotGuard<'_>, udp::UdpSocket<'_>), Error> {
+        let slot = self
+            .slots
+            .iter()
+            .find(|slot| !slot.in_use.get())
+            .ok_or(Error::NoSocket)?;
+        slot.in_use.set(true);
+        let guard = SlotGuard {
+            in_use: &slot.in_use,
+            port: &slot.port,
+        };
+        // SAFETY: The slot was not in use, and is now marked as in use until the guard is
+        // dropped, which happens only after the socket (the only user of the buffers) is dropped.
+        let buffers = unsafe { &mut *slot.buffers.get() };
+        let socket = udp::UdpSocket::new(
+            self.stack,
+            &mut buffers.rx_meta,
+            &mut buffers.rx,
+            &mut buffers.tx_meta,
+            &mut buffers.tx,
+        );
+        Ok((guard, socket))
+    }
+
+    /// Returns an ephemeral port that no socket of the pool is bound to.
+    ///
+    /// FIXME: Ports are assigned sequentially; RFC 6056 recommends randomizing them.
+    fn ephemeral_port(&self) -> u16 {
+        let (port, next) = next_free_port(self.next_port.get(), |port| {
+            self.slots.iter().any(|slot| slot.port.get() == port)
+        });
+        self.next_port.set(next);
+        port
+    }
+
+    /// Assigns an ephemeral port to `local` if it has none, and with `concrete`, replaces a
+    /// wildcard address with the stack's address of the family of `remote` (or of `local`).
+    fn specialize(
+        &self,
+        local: SocketAddr,
+        remote: Option<SocketAddr>,
+        concrete: bool,
+    ) -> Result<SocketAddr, Error> {
+        specialize(
+            local,
+            remote,
+            concrete,
+            || self.ephemeral_port(),
+            |family| match family {
+                SocketAddr::V4(_) => self
+                    .stack
+                    .config_v4()
+                    .map(|config| IpAddr::V4(config.address.address())),
+                SocketAddr::V6(_) => self
+                    .stack
+                    .config_v6()
+                    .map(|config| IpAddr::V6(config.address.address())),
+            },
+        )
+    }
+}
+
+/// Returns the first ephemeral port from `next` on for which `is_bound` is false, along with the
+/// port to try next time.
+///
+/// Ports wrap around to the start of the dynamic port range after its end.
+fn next_free_port(mut next: u16, is_bound: impl Fn(u16) -> bool) -> (u16, u16) {
+    // As the pool has fewer sockets than there are ephemeral ports, this terminates.
+    loop {
+        let port = next;
+        next = port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+        if !is_bound(port) {
+            return (port, next);
+        }
+    }
+}
+
+/// Assigns a port from `ephemeral_port` to `local` if it has none, and with `concrete`, replaces a
+/// wildcard address with the one `stack_address` returns for the family of `remote` (or of
+/// `local`).
+fn specialize(
+    mut local: SocketAddr,
+    remote: Option<SocketAddr>,
+    concrete: bool,
+    ephemeral_port: impl FnOnce() -> u16,
+    stack_address: impl FnOnce(SocketAddr) -> Option<IpAddr>,
+) -> Result<SocketAddr, Error> {
+    if local.port() == 0 {
+        local.set_port(ephemeral_port());
+    }
+    if concrete && is_unspec_ip(local) {
+        let address = stack_address(remote.unwrap_or(local));
+        local.set_ip(address.ok_or(Error::NoAddress)?);
+    }
+    Ok(local)
+}
+
+impl<'a, const N: usize, const BUFFER_LEN: usize, const PACKETS: usize> nal::UdpStack
+    for &'a UdpSocketPool<'_, N, BUFFER_LEN, PACKETS>
+{
+    type Error = Error;
+    type Connected = PoolSocket<'a, ConnectedUdp<'a>>;
+    type UniquelyBound = PoolSocket<'a, UnconnectedUdp<'a>>;
+    type MultiplyBound = PoolSocket<'a, UnconnectedUdp<'a>>;
+
+    async fn connect_from(
+        &self,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> Result<(SocketAddr, Self::Connected), Error> {
+        let pool = *self;
+        let local = pool.specialize(local, Some(remote), true)?;
+        let (slot, socket) = pool.take()?;
+        let socket = ConnectedUdp::connect_from(socket, local, remote).await?;
+        slot.port.set(local.port());
+        Ok((
+            local,
+            PoolSocket {
+                socket,
+                _slot: slot,
+            },
+        ))
+    }
+
+    async fn bind_single(
+        &self,
+        local: SocketAddr,
+    ) -> Result<(SocketAddr, Self::UniquelyBound), Error> {
+        let pool = *self;
+        let local = pool.specialize(local, None, true)?;
+        let (slot, socket) = pool.take()?;
+        let socket = UnconnectedUdp::bind_single(socket, local).await?;
+        slot.port.set(local.port());
+        Ok((
+            local,
+            PoolSocket {
+                socket,
+                _slot: slot,
+            },
+        ))
+    }
+
+    async fn bind_multiple(&self, local: SocketAddr) -> Result<Self::MultiplyBound, Error> {
+        let pool = *self;
+        let local = pool.specialize(local, None, false)?;
+        let (slot, socket) = pool.take()?;
+        let socket = UnconnectedUdp::bind_multiple(socket, local).await?;
+        slot.port.set(local.port());
+        Ok(PoolSocket {
+            socket,
+            _slot: slot,
+        })
+    }
+}
+
+/// A socket taken from a [`UdpSocketPool`], which returns to the pool when dropped.
+pub struct PoolSocket<'a, S> {
+    // Declared first to be dropped first: The socket uses the slot's buffers.
+    socket: S,
+    _slot: SlotGuard<'a>,
+}
+
+impl<S: nal::ConnectedUdp> nal::ConnectedUdp for PoolSocket<'_, S> {
+    type Error = S::Error;
+
+    async fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.socket.send(data).await
+    }
+
+    async fn receive_into(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        self.socket.receive_into(buffer).await
+    }
+}
+
+impl<S: nal::UnconnectedUdp> nal::UnconnectedUdp for PoolSocket<'_, S> {
+    type Error = S::Error;
+
+    async fn send(
+        &mut self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        data: &[u8],
+    ) -> Result<(), Self::Error> {
+        self.socket.send(local, remote, data).await
+    }
+
+    async fn receive_into(
+        &mut self,
+        buffer: &mut [u8],
+    ) -> Result<(usize, SocketAddr, SocketAddr), Self::Error> {
+        self.socket.receive_into(buffer).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    const V4: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+
+    /// Returns the address of a stack that has `V4`, and `V6` if `with_v6`.
+    fn stack_address(with_v6: bool) -> impl FnOnce(SocketAddr) -> Option<IpAddr> {
+        move |family| match family {
+            SocketAddr::V4(_) => Some(IpAddr::V4(V4)),
+            SocketAddr::V6(_) => with_v6.then_some(IpAddr::V6(V6)),
+        }
+    }
+
+    #[test]
+    fn ports() {
+        assert_eq!(
+            next_free_port(FIRST_EPHEMERAL_PORT, |_| false),
+            (FIRST_EPHEMERAL_PORT, FIRST_EPHEMERAL_PORT + 1)
+        );
+        // Bound ports are skipped.
+        assert_eq!(
+            next_free_port(50000, |port| port == 50000 || port == 50001),
+            (50002, 50003)
+        );
+        // Ports wrap around at the end of the range, also while skipping bound ones.
+        assert_eq!(
+            next_free_port(u16::MAX, |_| false),
+            (u16::MAX, FIRST_EPHEMERAL_PORT)
+        );
+        assert_eq!(
+            next_free_port(u16::MAX, |port| port == u16::MAX),
+            (FIRST_EPHEMERAL_PORT, FIRST_EPHEMERAL_PORT + 1)
+        );
+    }
+
+    #[test]
+    fn addresses() {
+        let unspecified_v4 = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+        let unspecified_v6 = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 5683);
+        let remote_v4 = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 2).into(), 5683);
+
+        // Only missing ports are assigned.
+        let local = specialize(unspecified_v4, None, false, || 50000, stack_address(true));
+        assert_eq!(
+            local.unwrap(),
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 50000)
+        );
+        let local = specialize(
+            unspecified_v6,
+            None,
+            false,
+            || unreachable!(),
+            stack_address(true),
+        );
+        assert_eq!(local.unwrap(), unspecified_v6);
+
+        // Wildcards are replaced with the stack's address of the family of the remote, or of the
+        // local address.
+        let local = specialize(
+            unspecified_v6,
+            Some(remote_v4),
+            true,
+            || unreachable!(),
+            stack_address(true),
+        );
+        assert_eq!(local.unwrap(), SocketAddr::new(V4.into(), 5683));
+        let local = specialize(unspecified_v6, None, true, || 50000, stack_address(true));
+        assert_eq!(local.unwrap(), SocketAddr::new(V6.into(), 5683));
+
+        // Concrete addresses are kept.
+        let concrete = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 3).into(), 5683);
+        let local = specialize(concrete, None, true, || unreachable!(), |_| unreachable!());
+        assert_eq!(local.unwrap(), concrete);
+
+        // Without an address of the family, there is nothing to specialize into.
+        assert!(matches!(
+            specialize(unspecified_v6, None, true, || 50000, stack_address(false)),
+            Err(Error::NoAddress)
+        ));
+    }
+}
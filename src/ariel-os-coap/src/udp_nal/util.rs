@@ -51,8 +51,12 @@ pub enum Error {
     BindError(udp::BindError),
     /// Error stemming from failure to represent the given address family for lack of enabled
     /// embassy-net features
-    #[expect(dead_code, reason = "feature selection currently disabled")]
     AddressFamilyUnavailable,
+    /// Error stemming from the lack of a concrete local address to bind to, as the stack has no
+    /// address of the requested family
+    NoAddress,
+    /// Error stemming from all sockets of a [`super::UdpSocketPool`] being in use
+    NoSocket,
 }
 
 impl embedded_io_async::Error for Error {
@@ -61,7 +65,10 @@ impl embedded_io_async::Error for Error {
             Self::SendError(udp::SendError::NoRoute) | Self::BindError(udp::BindError::NoRoute) => {
                 embedded_io_async::ErrorKind::AddrNotAvailable
             }
-            Self::AddressFamilyUnavailable => embedded_io_async::ErrorKind::AddrNotAvailable,
+            Self::AddressFamilyUnavailable | Self::NoAddress => {
+                embedded_io_async::ErrorKind::AddrNotAvailable
+            }
+            Self::NoSocket => embedded_io_async::ErrorKind::OutOfMemory,
             // These should not happen b/c our sockets are typestated.
             Self::SendError(udp::SendError::SocketNotBound) |
                 Self::BindError(udp::BindError::InvalidState) |
@@ -0,0 +1,148 @@
+//! Device identity, with an optional fallback to an identity kept in storage.
+//!
+//! Without the `identity-storage` feature, [`DeviceId`] is the HAL's device identity.
+//!
+//! With it, devices whose HAL does not provide a device identity use one that was provisioned
+//! into storage, e.g., at manufacturing time through [`provision()`]. If none was provisioned, an
+//! identity is generated from the cryptographically secure RNG at first boot, and persisted.
+
+#[cfg(not(feature = "identity-storage"))]
+pub use crate::hal::identity::DeviceId;
+
+#[cfg(feature = "identity-storage")]
+pub use stored::*;
+
+#[cfg(feature = "identity-storage")]
+mod stored {
+    use core::cell::Cell;
+
+    use ariel_os_debug::log::{debug, info};
+    use ariel_os_embassy_common::identity::DeviceId as _;
+    use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+    use rand_core::RngCore;
+
+    use crate::hal::identity::DeviceId as HalDeviceId;
+
+    /// Length of an identity kept in storage.
+    pub const STORED_ID_LEN: usize = 16;
+
+    /// Storage key under which the identity is kept.
+    const STORAGE_KEY: &str = "ariel-os.identity";
+
+    /// Identity loaded from storage at boot, if the HAL does not provide one.
+    static STORED_ID: Mutex<CriticalSectionRawMutex, Cell<Option<[u8; STORED_ID_LEN]>>> =
+        Mutex::new(Cell::new(None));
+
+    /// The device identity: provided by the HAL where available, otherwise kept in storage.
+    pub enum DeviceId {
+        /// Identity provided by the HAL.
+        Hardware(HalDeviceId),
+        /// Identity kept in storage.
+        Stored([u8; STORED_ID_LEN]),
+    }
+
+    /// Serialized form of a [`DeviceId`].
+    pub enum DeviceIdBytes<B> {
+        /// Serialized identity provided by the HAL.
+        Hardware(B),
+        /// Identity kept in storage.
+        Stored([u8; STORED_ID_LEN]),
+    }
+
+    impl<B: AsRef<[u8]>> AsRef<[u8]> for DeviceIdBytes<B> {
+        fn as_ref(&self) -> &[u8] {
+            match self {
+                Self::Hardware(bytes) => bytes.as_ref(),
+                Self::Stored(bytes) => bytes,
+            }
+        }
+    }
+
+    impl ariel_os_embassy_common::identity::DeviceId for DeviceId {
+        type Bytes =
+            DeviceIdBytes<<HalDeviceId as ariel_os_embassy_common::identity::DeviceId>::Bytes>;
+
+        fn get() -> Result<Self, impl core::error::Error> {
+            match HalDeviceId::get() {
+                Ok(id) => Ok(Self::Hardware(id)),
+                Err(err) => STORED_ID.lock(Cell::get).map(Self::Stored).ok_or(err),
+            }
+        }
+
+        fn bytes(&self) -> Self::Bytes {
+            match self {
+                Self::Hardware(id) => DeviceIdBytes::Hardware(id.bytes()),
+                Self::Stored(bytes) => DeviceIdBytes::Stored(*bytes),
+            }
+        }
+
+        fn interface_eui48(&self, if_index: u32) -> ariel_os_embassy_common::identity::Eui48 {
+            match self {
+                // The HAL may know of globally unique addresses.
+                Self::Hardware(id) => id.interface_eui48(if_index),
+                // The default implementation, which cannot be called from here directly.
+                Self::Stored(bytes) => StoredOnly(*bytes).interface_eui48(if_index),
+            }
+        }
+    }
+
+    /// Helper to reach the default implementation of
+    /// [`interface_eui48()`](ariel_os_embassy_common::identity::DeviceId::interface_eui48).
+    struct StoredOnly<B>(B);
+
+    impl<B: AsRef<[u8]> + Clone> ariel_os_embassy_common::identity::DeviceId for StoredOnly<B> {
+        type Bytes = B;
+
+        fn get() -> Result<Self, impl core::error::Error> {
+            Err::<Self, _>(ariel_os_embassy_common::identity::NotAvailable)
+        }
+
+        fn bytes(&self) -> B {
+            self.0.clone()
+        }
+    }
+
+    /// Loads the identity from storage, generating and persisting one if needed.
+    ///
+    /// Does nothing if the HAL provides a device identity. Must run after storage and the RNG
+    /// have been initialized.
+    pub(crate) async fn init() {
+        if HalDeviceId::get().is_ok() {
+            return;
+        }
+
+        match ariel_os_storage::get::<[u8; STORED_ID_LEN]>(STORAGE_KEY).await {
+            Ok(Some(id)) => {
+                debug!("identity: using identity from storage");
+                STORED_ID.lock(|stored| stored.set(Some(id)));
+            }
+            Ok(None) => {
+                info!("identity: generating device identity");
+                let mut id = [0; STORED_ID_LEN];
+                ariel_os_random::crypto_rng().fill_bytes(&mut id);
+                if provision(&id).await.is_err() {
+                    info!("identity: failed to persist device identity");
+                }
+            }
+            Err(_) => info!("identity: failed to read device identity from storage"),
+        }
+    }
+
+    /// Provisions the identity kept in storage, overwriting any previous one.
+    ///
+    /// The identity is used from now on if the HAL does not provide a device identity.
+    /// Identifiers that were derived from the previous identity (e.g., MAC addresses of network
+    /// interfaces that are already up) only change after a reboot.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the identity could not be written to storage; the identity in use does
+    /// not change in that case.
+    pub async fn provision(
+        id: &[u8; STORED_ID_LEN],
+    ) -> Result<(), sequential_storage::Error<crate::hal::storage::FlashError>> {
+        ariel_os_storage::insert(STORAGE_KEY, *id).await?;
+        STORED_ID.lock(|stored| stored.set(Some(*id)));
+        Ok(())
+    }
+}
@@ -6,6 +6,7 @@
 #![feature(doc_auto_cfg)]
 
 pub mod gpio;
+pub mod identity;
 
 pub use ariel_os_hal as hal;
 
@@ -24,6 +25,9 @@ pub mod network;
 #[cfg(feature = "wifi")]
 mod wifi;
 
+#[cfg(any(feature = "random-seed-storage", feature = "random-reseed"))]
+mod random;
+
 use ariel_os_debug::log::debug;
 
 // re-exports
@@ -198,6 +202,15 @@ async fn init_task(mut peripherals: hal::OptionalPeripherals) {
     #[cfg(feature = "storage")]
     ariel_os_storage::init(&mut peripherals).await;
 
+    #[cfg(feature = "random-seed-storage")]
+    random::seed_from_storage().await;
+
+    #[cfg(feature = "identity-storage")]
+    identity::init().await;
+
+    #[cfg(feature = "random-reseed")]
+    spawner.spawn(random::reseed_task()).unwrap();
+
     #[cfg(all(feature = "usb", context = "nrf"))]
     hal::usb::init();
 
@@ -245,7 +258,7 @@ async fn init_task(mut peripherals: hal::OptionalPeripherals) {
         use static_cell::StaticCell;
 
         // Host's MAC addr. This is the MAC the host "thinks" its USB-to-ethernet adapter has.
-        let host_mac_addr = crate::hal::identity::DeviceId::get()
+        let host_mac_addr = crate::identity::DeviceId::get()
             .map(|d| d.interface_eui48(1).0)
             .unwrap_or([0x8A, 0x88, 0x88, 0x88, 0x88, 0x88]);
 
@@ -258,7 +271,7 @@ async fn init_task(mut peripherals: hal::OptionalPeripherals) {
             64,
         );
 
-        let our_mac_addr = crate::hal::identity::DeviceId::get()
+        let our_mac_addr = crate::identity::DeviceId::get()
             .map(|d| d.interface_eui48(0).0)
             .unwrap_or([0xCA, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC]);
 
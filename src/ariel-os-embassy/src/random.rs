@@ -0,0 +1,138 @@
+//! Keeps the global RNG of `ariel-os-random` supplied with entropy beyond the initial seeding.
+//!
+//! - With the `random-seed-storage` feature, a seed is persisted in storage: it is mixed into the
+//!   global RNG at boot (along with the hardware RNG's output where there is one), and immediately
+//!   replaced with a fresh one, so that entropy accumulates across reboots even without a
+//!   hardware RNG.
+//! - With the `random-reseed` feature, the global RNG is periodically reseeded from timing jitter,
+//!   and the persisted seed (if any) is refreshed.
+
+use ariel_os_debug::log::debug;
+use ariel_os_random::entropy::{EntropyPool, EntropySource, HealthTestError, HealthTested};
+use embassy_time::Instant;
+
+/// Storage key under which the seed is persisted.
+#[cfg(feature = "random-seed-storage")]
+const SEED_KEY: &str = "ariel-os.random.seed";
+
+#[cfg(feature = "random-reseed")]
+const RESEED_INTERVAL_SECS: usize = ariel_os_utils::usize_from_env_or!(
+    "CONFIG_RANDOM_RESEED_INTERVAL",
+    3600,
+    "interval between reseedings of the global RNG, in seconds"
+);
+
+/// Mixes the persisted seed into the global RNG, and replaces it with a fresh one.
+///
+/// Where the hardware RNG seeded the global RNG already, the seed is mixed into that state, so
+/// neither source weakens the other.
+///
+/// Must run after storage has been initialized, and after the hardware RNG (if any).
+#[cfg(feature = "random-seed-storage")]
+pub(crate) async fn seed_from_storage() {
+    match ariel_os_storage::get::<[u8; ariel_os_random::PERSISTED_SEED_LEN]>(SEED_KEY).await {
+        Ok(Some(seed)) => {
+            debug!("random: mixing in persisted seed");
+            ariel_os_random::mix_seed(&seed);
+        }
+        Ok(None) => ariel_os_debug::log::info!("random: no persisted seed yet"),
+        Err(_) => ariel_os_debug::log::info!("random: failed to read persisted seed"),
+    }
+
+    if !ariel_os_random::is_initialized() {
+        // First boot without a hardware RNG: timing jitter is all there is.
+        ariel_os_debug::log::warn!(
+            "random: no entropy source available, seeding from timing jitter only"
+        );
+        // Health tests can fail spuriously; a source that is actually broken keeps failing.
+        for _ in 0..JITTER_ATTEMPTS {
+            if mix_jitter().is_ok() {
+                break;
+            }
+            ariel_os_debug::log::info!("random: timing jitter failed the health tests");
+        }
+    }
+
+    refresh_persisted_seed().await;
+}
+
+/// Replaces the persisted seed, so that the same seed is never used twice.
+///
+/// Nothing is persisted while the global RNG is not seeded.
+#[cfg(feature = "random-seed-storage")]
+async fn refresh_persisted_seed() {
+    if !ariel_os_random::is_initialized() {
+        ariel_os_debug::log::info!("random: RNG not seeded, not persisting a seed");
+        return;
+    }
+    if ariel_os_storage::insert(SEED_KEY, ariel_os_random::seed_for_next_boot())
+        .await
+        .is_err()
+    {
+        ariel_os_debug::log::info!("random: failed to persist seed");
+    }
+}
+
+/// Periodically reseeds the global RNG.
+#[cfg(feature = "random-reseed")]
+#[embassy_executor::task]
+pub(crate) async fn reseed_task() {
+    let interval = embassy_time::Duration::from_secs(RESEED_INTERVAL_SECS as u64);
+    loop {
+        embassy_time::Timer::after(interval).await;
+
+        debug!("random: reseeding");
+        if mix_jitter().is_err() {
+            ariel_os_debug::log::info!("random: timing jitter failed the health tests");
+        }
+
+        #[cfg(feature = "random-seed-storage")]
+        refresh_persisted_seed().await;
+    }
+}
+
+/// Number of jitter samples mixed in at a time.
+///
+/// This is enough for 256 bits of entropy at 1/8 bit per sample, see [`JitterSource`].
+const JITTER_SAMPLES: usize = 8 * 256;
+
+/// Number of times collecting timing jitter is attempted when it is the only source of entropy.
+#[cfg(feature = "random-seed-storage")]
+const JITTER_ATTEMPTS: usize = 3;
+
+/// Entropy source based on the jitter between the CPU clock and the timer clock.
+///
+/// Each sample counts the busy-loop iterations until the timer ticks; the low bits of that count
+/// vary with clock drift, interrupts and bus contention.
+/// Taking a sample blocks for up to one timer tick.
+///
+/// This is a last-resort source: How much the count varies has not been assessed on any
+/// platform, and on a quiet system with both clocks derived from the same oscillator it may
+/// hardly vary at all. Only 1/8 bit per sample is relied on, by collecting
+/// [`JITTER_SAMPLES`] samples.
+struct JitterSource;
+
+impl EntropySource for JitterSource {
+    fn min_entropy_bits(&self) -> u8 {
+        // The lowest assessment the health tests support, which makes them the most lenient; the
+        // amount of samples accounts for the actual (lower) credit.
+        1
+    }
+
+    fn sample(&mut self) -> u8 {
+        let start = Instant::now().as_ticks();
+        let mut count: u32 = 0;
+        while Instant::now().as_ticks() == start {
+            count = count.wrapping_add(1);
+        }
+        count.to_le_bytes()[0]
+    }
+}
+
+/// Mixes health-tested timing jitter into the global RNG.
+fn mix_jitter() -> Result<(), HealthTestError> {
+    let mut pool = EntropyPool::new();
+    pool.add_samples(&mut HealthTested::new(JitterSource), JITTER_SAMPLES)?;
+    pool.seed_global_rng();
+    Ok(())
+}
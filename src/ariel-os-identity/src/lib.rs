@@ -19,17 +19,30 @@
 //!
 //! Constructing an identifier fails rather than produce a dummy identifier.
 //!
+//! With the `storage` feature, devices that do not provide an identifier use one kept in storage
+//! instead: either one provisioned by the application through `provision()` (e.g., at
+//! manufacturing time), or a random identifier generated at first boot. Such identifiers are lost
+//! when the storage is erased.
+//!
 //! It is considered a breaking change in Ariel OS if a device's identifier changes or becomes an
 //! error. Errors changing to valid identifiers is a compatible change.
 //!
 //! Other identifiers, such as the EUI-48 addresses provided by [`interface_eui48()`], are usually
 //! derived from the main identity, but have different properties.
+//!
+//! Identifiers that do not expose the device identity, scoped to an application, can be derived
+//! through the [`scoped`] module.
 #![no_std]
 #![deny(missing_docs)]
 #![deny(clippy::pedantic)]
 
+pub mod scoped;
+
 pub use ariel_os_embassy_common::identity::Eui48;
 
+#[cfg(feature = "storage")]
+pub use ariel_os_embassy::identity::{provision, STORED_ID_LEN};
+
 /// Obtains a unique identifier of the device in its byte serialized form.
 ///
 /// See module level documentation for that identifier's properties.
@@ -43,7 +56,7 @@ pub use ariel_os_embassy_common::identity::Eui48;
 pub fn device_id_bytes() -> Result<impl AsRef<[u8]>, impl core::error::Error> {
     use ariel_os_embassy_common::identity::DeviceId;
 
-    ariel_os_embassy::hal::identity::DeviceId::get().map(|d| d.bytes())
+    ariel_os_embassy::identity::DeviceId::get().map(|d| d.bytes())
 }
 
 /// Generates an EUI-48 identifier ("6-byte MAC address") based on the device identity.
@@ -71,5 +84,5 @@ pub fn device_id_bytes() -> Result<impl AsRef<[u8]>, impl core::error::Error> {
 pub fn interface_eui48(if_index: u32) -> Result<Eui48, impl core::error::Error> {
     use ariel_os_embassy_common::identity::DeviceId;
 
-    ariel_os_embassy::hal::identity::DeviceId::get().map(|d| d.interface_eui48(if_index))
+    ariel_os_embassy::identity::DeviceId::get().map(|d| d.interface_eui48(if_index))
 }
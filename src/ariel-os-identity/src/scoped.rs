@@ -0,0 +1,191 @@
+//! Application-scoped identifiers derived from the device identity.
+//!
+//! The functions of this module derive identifiers from [`device_id_bytes()`](crate::device_id_bytes)
+//! using HKDF-SHA256, with an application-supplied salt. The identifiers are stable for a given
+//! device and salt, but do not expose the device's serial number, and different applications (or
+//! different uses within an application) using different salts obtain unrelated identifiers.
+//!
+//! <div class="warning">
+//! Many device identifiers have little variable content, and could be recovered from a derived
+//! identifier by trying all possibilities. If the derived identifiers should not be linkable to
+//! the device identity, the salt needs to be kept secret.
+//! </div>
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+/// Prefix of the HKDF `info` of all identifiers derived by this module.
+const INFO_PREFIX: &[u8] = b"ariel-os-identity ";
+
+/// An EUI-64 identifier.
+///
+/// The identifiers derived by [`eui64()`] are locally administered unicast addresses.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Eui64(pub [u8; 8]);
+
+impl core::fmt::Debug for Eui64 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            self.0[0], self.0[1], self.0[2], self.0[3], self.0[4], self.0[5], self.0[6], self.0[7]
+        )
+    }
+}
+
+impl core::fmt::Display for Eui64 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Derives `out.len()` bytes from the device identity.
+///
+/// `salt` scopes the result to an application, and `info` to a particular use within it.
+///
+/// # Errors
+///
+/// Same as in [`device_id_bytes()`](crate::device_id_bytes).
+///
+/// # Panics
+///
+/// Panics if `out` is longer than 8160 bytes.
+pub fn derive_bytes(
+    salt: &[u8],
+    info: &[u8],
+    out: &mut [u8],
+) -> Result<(), impl core::error::Error> {
+    crate::device_id_bytes().map(|id| {
+        Hkdf::<Sha256>::new(Some(salt), id.as_ref())
+            .expand_multi_info(&[INFO_PREFIX, b"app ", info], out)
+            .expect("output length is within HKDF-SHA256 limits");
+    })
+}
+
+/// Derives a UUID version 8 (RFC 9562) from the device identity.
+///
+/// # Errors
+///
+/// Same as in [`device_id_bytes()`](crate::device_id_bytes).
+pub fn uuid_v8(salt: &[u8]) -> Result<[u8; 16], impl core::error::Error> {
+    crate::device_id_bytes().map(|id| uuid_v8_from(id.as_ref(), salt))
+}
+
+/// Derives a name-based UUID version 5 (RFC 9562) in the given namespace from the device identity.
+///
+/// The name hashed into the UUID is derived from the device identity rather than being the
+/// identity itself.
+///
+/// # Errors
+///
+/// Same as in [`device_id_bytes()`](crate::device_id_bytes).
+pub fn uuid_v5(namespace: &[u8; 16], salt: &[u8]) -> Result<[u8; 16], impl core::error::Error> {
+    crate::device_id_bytes().map(|id| uuid_v5_from(id.as_ref(), namespace, salt))
+}
+
+/// Derives a locally administered unicast EUI-64 from the device identity.
+///
+/// # Errors
+///
+/// Same as in [`device_id_bytes()`](crate::device_id_bytes).
+pub fn eui64(salt: &[u8]) -> Result<Eui64, impl core::error::Error> {
+    crate::device_id_bytes().map(|id| eui64_from(id.as_ref(), salt))
+}
+
+/// Derives an IPv6 interface identifier, e.g., for 6LoWPAN, from the device identity.
+///
+/// This is the modified EUI-64 form (RFC 4291) of [`eui64()`] for the same salt, so that the
+/// link-layer address and the IID of an interface match, as 6LoWPAN header compression expects.
+///
+/// # Errors
+///
+/// Same as in [`device_id_bytes()`](crate::device_id_bytes).
+pub fn lowpan_iid(salt: &[u8]) -> Result<[u8; 8], impl core::error::Error> {
+    crate::device_id_bytes().map(|id| iid_from_eui64(eui64_from(id.as_ref(), salt)))
+}
+
+fn expand(device_id: &[u8], salt: &[u8], label: &[u8], out: &mut [u8]) {
+    Hkdf::<Sha256>::new(Some(salt), device_id)
+        .expand_multi_info(&[INFO_PREFIX, label], out)
+        .expect("output length is within HKDF-SHA256 limits");
+}
+
+fn uuid_v8_from(device_id: &[u8], salt: &[u8]) -> [u8; 16] {
+    let mut uuid = [0; 16];
+    expand(device_id, salt, b"uuid-v8", &mut uuid);
+    set_version(&mut uuid, 8);
+    uuid
+}
+
+fn uuid_v5_from(device_id: &[u8], namespace: &[u8; 16], salt: &[u8]) -> [u8; 16] {
+    let mut name = [0; 32];
+    expand(device_id, salt, b"uuid-v5", &mut name);
+
+    let mut input = [0; 48];
+    input[..16].copy_from_slice(namespace);
+    input[16..].copy_from_slice(&name);
+    let hash = const_sha1::sha1(&input).as_bytes();
+
+    let mut uuid = *hash.first_chunk().expect("SHA-1 is longer than a UUID");
+    set_version(&mut uuid, 5);
+    uuid
+}
+
+fn set_version(uuid: &mut [u8; 16], version: u8) {
+    uuid[6] = (uuid[6] & 0x0f) | (version << 4);
+    // RFC 9562 variant
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+}
+
+fn eui64_from(device_id: &[u8], salt: &[u8]) -> Eui64 {
+    let mut eui = [0; 8];
+    expand(device_id, salt, b"eui-64", &mut eui);
+    // Locally administered, unicast
+    eui[0] = (eui[0] & 0xfc) | 0x02;
+    Eui64(eui)
+}
+
+fn iid_from_eui64(eui: Eui64) -> [u8; 8] {
+    let mut iid = eui.0;
+    // Modified EUI-64: the universal/local bit is inverted.
+    iid[0] ^= 0x02;
+    iid
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DEVICE: &[u8] = &[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0];
+
+    #[test]
+    fn uuids() {
+        let v8 = uuid_v8_from(DEVICE, b"app");
+        assert_eq!(v8[6] >> 4, 8);
+        assert_eq!(v8[8] >> 6, 0b10);
+
+        let v5 = uuid_v5_from(DEVICE, &[0; 16], b"app");
+        assert_eq!(v5[6] >> 4, 5);
+        assert_eq!(v5[8] >> 6, 0b10);
+        assert_ne!(v5, uuid_v5_from(DEVICE, &[1; 16], b"app"));
+    }
+
+    #[test]
+    fn scoping() {
+        assert_eq!(uuid_v8_from(DEVICE, b"app"), uuid_v8_from(DEVICE, b"app"));
+        assert_ne!(uuid_v8_from(DEVICE, b"app"), uuid_v8_from(DEVICE, b"other"));
+        assert_ne!(
+            uuid_v8_from(DEVICE, b"app"),
+            uuid_v8_from(&DEVICE[1..], b"app")
+        );
+    }
+
+    #[test]
+    fn eui64_and_iid() {
+        let eui = eui64_from(DEVICE, b"app");
+        assert_eq!(eui.0[0] & 0x03, 0x02);
+
+        let iid = iid_from_eui64(eui);
+        assert_eq!(iid[0] & 0x02, 0);
+        assert_eq!(iid[1..], eui.0[1..]);
+    }
+}
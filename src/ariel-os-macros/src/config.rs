@@ -13,6 +13,7 @@
 /// | --------- | ------------------------------ | ------------------------- |
 /// | `network` | `embassy_net::Config`          | `override-network-config` |
 /// | `usb`     | `embassy_usb::Config`          | `override-usb-config`     |
+/// | `coap`    | `ariel_os::coap::Config`       | `override-coap-config`    |
 ///
 /// # Note
 ///
@@ -66,6 +67,10 @@ pub fn config(args: TokenStream, item: TokenStream) -> TokenStream {
             format_ident!("__ariel_os_usb_config"),
             quote! {#ariel_os_crate::reexports::embassy_usb::Config<'static>},
         ),
+        Some(ConfigKind::Coap) => (
+            format_ident!("__ariel_os_coap_config"),
+            quote! {#ariel_os_crate::coap::Config},
+        ),
         None => {
             panic!("a configuration kind must be specified");
         }
@@ -134,6 +139,7 @@ mod config_macro {
     pub enum ConfigKind {
         Network,
         Usb,
+        Coap,
     }
 
     impl ConfigKind {
@@ -141,6 +147,7 @@ mod config_macro {
             match self {
                 Self::Network => "network",
                 Self::Usb => "usb",
+                Self::Coap => "coap",
             }
         }
     }
@@ -0,0 +1,346 @@
+//! Collection of entropy from multiple sources into a hash-based pool.
+//!
+//! Raw samples of each [`EntropySource`] go through the continuous health tests of
+//! [NIST SP 800-90B](https://doi.org/10.6028/NIST.SP.800-90B) (section 4.4) before being mixed
+//! into an [`EntropyPool`]: the repetition count test catches a source that got stuck, the
+//! adaptive proportion test catches a source that lost most of its entropy. A source that fails a
+//! test stays failed, and all further samples are rejected with a [`HealthTestError`].
+//!
+//! ```ignore
+//! let mut hwrng = HealthTested::new(RngSource(hwrng));
+//! let mut jitter = HealthTested::new(jitter_source);
+//!
+//! let mut pool = EntropyPool::new();
+//! pool.add_samples(&mut hwrng, 32)?;
+//! pool.add_samples(&mut jitter, 256)?;
+//! pool.seed_global_rng();
+//! ```
+use rand_core::RngCore;
+use sha2::{Digest, Sha256};
+
+/// Negative binary logarithm of the false positive probability of the health tests, as
+/// recommended by SP 800-90B.
+const ALPHA_LOG2: u32 = 20;
+
+/// Window size of the adaptive proportion test for non-binary samples.
+const APT_WINDOW: u16 = 512;
+
+/// A source of raw noise samples.
+///
+/// Implementations should return the samples as they come out of the noise source (e.g., the low
+/// bits of ADC readings, or timing jitter), without any conditioning: the health tests can only
+/// detect failures in unprocessed samples.
+pub trait EntropySource {
+    /// Returns the assessed min-entropy of each sample, in bits (between 1 and 8).
+    ///
+    /// This needs to be a conservative estimate: it determines how many samples are needed to
+    /// seed the global RNG, and the cutoffs of the health tests.
+    fn min_entropy_bits(&self) -> u8;
+
+    /// Returns the next raw sample.
+    fn sample(&mut self) -> u8;
+}
+
+/// An [`EntropySource`] reading bytes from an [`RngCore`], typically a hardware RNG.
+///
+/// Hardware RNGs usually condition their output, which makes the health tests much less
+/// effective; they still catch an RNG that is stuck.
+pub struct RngSource<R: RngCore>(pub R);
+
+impl<R: RngCore> EntropySource for RngSource<R> {
+    fn min_entropy_bits(&self) -> u8 {
+        8
+    }
+
+    fn sample(&mut self) -> u8 {
+        let mut byte = [0];
+        self.0.fill_bytes(&mut byte);
+        byte[0]
+    }
+}
+
+/// Error raised when an [`EntropySource`] fails a health test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthTestError {
+    /// The same sample was repeated too many times in a row.
+    RepetitionCount,
+    /// A sample value occurred too often within a window.
+    AdaptiveProportion,
+}
+
+impl core::fmt::Display for HealthTestError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::RepetitionCount => write!(f, "repetition count test failed"),
+            Self::AdaptiveProportion => write!(f, "adaptive proportion test failed"),
+        }
+    }
+}
+
+impl core::error::Error for HealthTestError {}
+
+/// An [`EntropySource`] with continuous health tests running on its samples.
+pub struct HealthTested<S: EntropySource> {
+    source: S,
+    rct_cutoff: u16,
+    apt_cutoff: u16,
+    /// Last sample, and how many times in a row it was seen.
+    rct: Option<(u8, u16)>,
+    /// First sample of the current window, how many times it was seen, and the number of samples
+    /// of the window so far.
+    apt: Option<(u8, u16, u16)>,
+    failure: Option<HealthTestError>,
+}
+
+impl<S: EntropySource> HealthTested<S> {
+    /// Wraps `source`, deriving the test cutoffs from its claimed min-entropy.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the min-entropy claimed by `source` is not between 1 and 8.
+    pub fn new(source: S) -> Self {
+        let bits = source.min_entropy_bits();
+        assert!(
+            (1..=8).contains(&bits),
+            "min-entropy per sample must be between 1 and 8 bits"
+        );
+        Self {
+            source,
+            rct_cutoff: rct_cutoff(bits),
+            apt_cutoff: apt_cutoff(bits),
+            rct: None,
+            apt: None,
+            failure: None,
+        }
+    }
+
+    /// Returns the wrapped source.
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+
+    /// Returns the error of the health test the source failed, if any.
+    pub fn failure(&self) -> Option<HealthTestError> {
+        self.failure
+    }
+
+    /// Returns the next sample, after running the health tests on it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if this or any previous sample failed a health test.
+    pub fn sample(&mut self) -> Result<u8, HealthTestError> {
+        if let Some(failure) = self.failure {
+            return Err(failure);
+        }
+
+        let sample = self.source.sample();
+
+        let repetitions = match self.rct {
+            Some((last, count)) if last == sample => count + 1,
+            _ => 1,
+        };
+        self.rct = Some((sample, repetitions));
+        if repetitions >= self.rct_cutoff {
+            return Err(*self.failure.insert(HealthTestError::RepetitionCount));
+        }
+
+        self.apt = match self.apt {
+            Some((first, count, len)) if len < APT_WINDOW => {
+                let count = count + u16::from(sample == first);
+                if count >= self.apt_cutoff {
+                    return Err(*self.failure.insert(HealthTestError::AdaptiveProportion));
+                }
+                Some((first, count, len + 1))
+            }
+            _ => Some((sample, 1, 1)),
+        };
+
+        Ok(sample)
+    }
+}
+
+/// Cutoff of the repetition count test: `1 + ceil(ALPHA_LOG2 / bits)`.
+fn rct_cutoff(bits: u8) -> u16 {
+    1 + u16::try_from(ALPHA_LOG2.div_ceil(u32::from(bits))).unwrap()
+}
+
+/// Cutoff of the adaptive proportion test: the smallest count that a sample value with
+/// probability `2^-bits` reaches within a window with probability at most `2^-ALPHA_LOG2`.
+///
+/// This is `1 + CRITBINOM(W, 2^-bits, 1 - 2^-ALPHA_LOG2)` in SP 800-90B.
+fn apt_cutoff(bits: u8) -> u16 {
+    let p = 1.0 / f64::from(1u16 << bits);
+    let alpha = 1.0 / f64::from(1u32 << ALPHA_LOG2);
+    let n = f64::from(APT_WINDOW);
+
+    // Walk up the binomial distribution until the remaining upper tail is small enough.
+    let mut pmf = (0..APT_WINDOW).fold(1.0, |acc, _| acc * (1.0 - p));
+    let mut cdf = pmf;
+    let mut k = 0;
+    while 1.0 - cdf > alpha {
+        pmf *= (n - f64::from(k)) / f64::from(k + 1) * p / (1.0 - p);
+        cdf += pmf;
+        k += 1;
+    }
+    k + 1
+}
+
+/// A pool accumulating samples from [`EntropySource`]s into a SHA-256 state.
+pub struct EntropyPool {
+    hasher: Sha256,
+    entropy_bits: u32,
+}
+
+impl Default for EntropyPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EntropyPool {
+    /// Creates an empty pool.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            hasher: Sha256::new(),
+            entropy_bits: 0,
+        }
+    }
+
+    /// Mixes `count` health-tested samples of `source` into the pool.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source fails a health test; the samples taken so far are still
+    /// mixed in, but not accounted for.
+    pub fn add_samples<S: EntropySource>(
+        &mut self,
+        source: &mut HealthTested<S>,
+        count: usize,
+    ) -> Result<(), HealthTestError> {
+        let mut buffer = [0; 32];
+        let mut remaining = count;
+        while remaining > 0 {
+            let len = remaining.min(buffer.len());
+            for i in 0..len {
+                match source.sample() {
+                    Ok(sample) => buffer[i] = sample,
+                    Err(err) => {
+                        self.hasher.update(&buffer[..i]);
+                        return Err(err);
+                    }
+                }
+            }
+            self.hasher.update(&buffer[..len]);
+            remaining -= len;
+        }
+
+        let bits = u32::from(source.source.min_entropy_bits());
+        self.entropy_bits = self
+            .entropy_bits
+            .saturating_add(bits.saturating_mul(u32::try_from(count).unwrap_or(u32::MAX)));
+        Ok(())
+    }
+
+    /// Returns the estimated amount of entropy in the pool, in bits.
+    ///
+    /// The pool cannot output more than 256 bits of entropy, no matter how many samples were
+    /// added.
+    #[must_use]
+    pub fn entropy_bits(&self) -> u32 {
+        self.entropy_bits
+    }
+
+    /// Returns the hash of all samples mixed in.
+    #[must_use]
+    pub fn finish(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+
+    /// Mixes the content of the pool into the global RNG, populating it if needed.
+    pub fn seed_global_rng(self) {
+        crate::mix_seed(&self.finish());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Constant(u8);
+
+    impl EntropySource for Constant {
+        fn min_entropy_bits(&self) -> u8 {
+            1
+        }
+
+        fn sample(&mut self) -> u8 {
+            self.0
+        }
+    }
+
+    /// Cycles through `0..period`, which passes the RCT but not the APT if the period is short.
+    struct Counter(u8, u8);
+
+    impl EntropySource for Counter {
+        fn min_entropy_bits(&self) -> u8 {
+            8
+        }
+
+        fn sample(&mut self) -> u8 {
+            self.0 = (self.0 + 1) % self.1;
+            self.0
+        }
+    }
+
+    #[test]
+    fn cutoffs() {
+        // Cutoffs for W = 512, computed with exact arithmetic.
+        assert_eq!(rct_cutoff(1), 21);
+        assert_eq!(rct_cutoff(8), 4);
+        assert_eq!(apt_cutoff(1), 311);
+        assert_eq!(apt_cutoff(2), 177);
+        assert_eq!(apt_cutoff(4), 62);
+        assert_eq!(apt_cutoff(8), 13);
+    }
+
+    #[test]
+    fn stuck_source() {
+        let mut source = HealthTested::new(Constant(0x42));
+        for _ in 0..20 {
+            assert_eq!(source.sample(), Ok(0x42));
+        }
+        assert_eq!(source.sample(), Err(HealthTestError::RepetitionCount));
+        // Failures are sticky.
+        assert_eq!(source.sample(), Err(HealthTestError::RepetitionCount));
+    }
+
+    #[test]
+    fn biased_source() {
+        let mut source = HealthTested::new(Counter(0, 16));
+        let result = (0..APT_WINDOW).try_for_each(|_| source.sample().map(drop));
+        assert_eq!(result, Err(HealthTestError::AdaptiveProportion));
+    }
+
+    #[test]
+    fn pool() {
+        let mut good = HealthTested::new(Counter(0, 255));
+        let mut pool = EntropyPool::new();
+        pool.add_samples(&mut good, 100).unwrap();
+        assert_eq!(pool.entropy_bits(), 800);
+
+        let mut stuck = HealthTested::new(Constant(0));
+        assert_eq!(
+            pool.add_samples(&mut stuck, 100),
+            Err(HealthTestError::RepetitionCount)
+        );
+        assert_eq!(pool.entropy_bits(), 800);
+
+        let mut other = EntropyPool::new();
+        other
+            .add_samples(&mut HealthTested::new(Counter(0, 255)), 100)
+            .unwrap();
+        assert_ne!(pool.finish(), other.finish());
+    }
+}
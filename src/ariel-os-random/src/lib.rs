@@ -12,21 +12,39 @@
 //! No matter the choices taken (eventually through the application's setup), all is hidden behind
 //! the [`FastRng`] and [`CryptoRng`] types.
 //!
-//! Before accessing the RNG, it needs to be initialized through the [`construct_rng()`] function.
+//! Before accessing the RNG, it needs to be initialized through the [`construct_rng()`] or
+//! [`mix_seed()`] functions.
 //! This is taken care of by the `ariel-os-embassy` initialization functions. Applications can
 //! ensure that this has happened by depending on the laze feature `random`.
 //!
+//! On devices without a hardware RNG, entropy can be carried across reboots by persisting a seed
+//! (see [`seed_for_next_boot()`]), and the global RNG can be refreshed at run time through
+//! [`reseed()`].
+//!
 //! ---
 //!
-//! Currently, this provides very little choice, and little fanciness: It (more or less
+//! Currently, this provides little choice, and little fanciness: It (more or less
 //! arbitrarily) uses the [`rand_chacha::ChaCha20Rng`] generator as a shared global RNG, and
 //! [`rand_pcg::Pcg32`] is decided yet for the fast one. Neither the algorithm nor the size of
 //! [`FastRng`] or [`CryptoRng`] is guaranteed.
+//!
+//! Where RNG state is kept can be selected through Cargo features:
+//! * `fast-rng-per-thread`: each thread keeps a fast RNG state, from which [`fast_rng()`] forks
+//!   its RNGs without locking the global RNG.
+//! * `csprng-per-core`: each core has its own CSPRNG, forked from the global one, which backs the
+//!   [`CryptoRng`]s used on that core.
+//!
+//! Local states are forked again after the global RNG gets reseeded.
 #![no_std]
 #![deny(clippy::pedantic)]
 
 use core::marker::PhantomData;
 
+#[cfg(feature = "entropy-pool")]
+pub mod entropy;
+#[cfg(any(test, feature = "fast-rng-per-thread", feature = "csprng-per-core"))]
+mod local;
+
 use rand_core::{RngCore, SeedableRng};
 
 /// A global RNG.
@@ -106,22 +124,34 @@ pub struct CryptoRng {
 
 #[cfg(feature = "csprng")]
 mod csprng {
-    use super::{with_global, CryptoRng, RngCore, SelectedRng};
+    use super::{CryptoRng, RngCore, SelectedRng};
+
+    #[cfg(not(feature = "csprng-per-core"))]
+    use super::with_global as with_crypto;
+    #[cfg(feature = "csprng-per-core")]
+    use crate::local::with_core_rng as with_crypto;
+
+    /// Number of bytes produced per lock of the underlying RNG, so that long requests do not
+    /// hold off interrupts for long.
+    const CHUNK_SIZE: usize = 64;
 
     // Re-implementing the trait rather than Deref'ing into inner: This avoids leaking implementation
     // details to users who might otherwise come to depend on platform specifics of the CryptoRng.
     impl RngCore for CryptoRng {
         fn next_u32(&mut self) -> u32 {
-            with_global(RngCore::next_u32)
+            with_crypto(RngCore::next_u32)
         }
         fn next_u64(&mut self) -> u64 {
-            with_global(RngCore::next_u64)
+            with_crypto(RngCore::next_u64)
         }
         fn fill_bytes(&mut self, dest: &mut [u8]) {
-            with_global(|i| i.fill_bytes(dest));
+            for chunk in dest.chunks_mut(CHUNK_SIZE) {
+                with_crypto(|i| i.fill_bytes(chunk));
+            }
         }
         fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
-            with_global(|i| i.try_fill_bytes(dest))
+            dest.chunks_mut(CHUNK_SIZE)
+                .try_for_each(|chunk| with_crypto(|i| i.try_fill_bytes(chunk)))
         }
     }
 
@@ -136,22 +166,123 @@ mod csprng {
 ///
 /// This is called by Ariel OS's initialization functions.
 ///
+/// If the global RNG was already populated (e.g., from a seed persisted in storage), the previous
+/// state is mixed into the new seed instead of being discarded.
+///
+/// With the `entropy-pool` feature, the output of `hwrng` is health-tested, see [`entropy`].
+///
 /// # Panics
 ///
-/// Panics if the underlying RNG returns an error.
+/// Panics if the underlying RNG returns an error, or fails a health test.
 pub fn construct_rng(hwrng: impl RngCore) {
+    #[cfg(feature = "entropy-pool")]
+    {
+        let mut source = entropy::HealthTested::new(entropy::RngSource(hwrng));
+        let mut pool = entropy::EntropyPool::new();
+        pool.add_samples(&mut source, HWRNG_SAMPLES)
+            .expect("Hardware RNG failed a health test");
+        pool.seed_global_rng();
+    }
+
+    #[cfg(not(feature = "entropy-pool"))]
+    reseed(hwrng);
+}
+
+/// Number of bytes taken from the hardware RNG at initialization when health-testing it.
+#[cfg(feature = "entropy-pool")]
+const HWRNG_SAMPLES: usize = 64;
+
+/// Reseeds the global RNG from an entropy source.
+///
+/// The new state is derived from both the current state (if any) and fresh output of `source`,
+/// so reseeding from a weak source never makes the global RNG worse.
+/// This can be called periodically by anything that has access to a source of entropy, e.g., an
+/// application that owns a hardware RNG peripheral.
+///
+/// # Panics
+///
+/// Panics if `source` returns an error.
+pub fn reseed(mut source: impl RngCore) {
+    let mut entropy = <SelectedRng as SeedableRng>::Seed::default();
+    source
+        .try_fill_bytes(entropy.as_mut())
+        .expect("Entropy source failed to provide entropy");
+    mix_seed(entropy.as_ref());
+}
+
+/// Mixes `entropy` into the global RNG, populating it if needed.
+///
+/// Input longer than the RNG's seed is folded into it, so the bytes do not need to be uniformly
+/// distributed; they do need to be unpredictable to be of any use though.
+pub fn mix_seed(entropy: &[u8]) {
     RNG.lock(|r| {
-        r.replace(Some(
-            SelectedRng::from_rng(hwrng).expect("Hardware RNG failed to provide entropy"),
-        ))
+        let mut r = r.borrow_mut();
+
+        let mut seed = <SelectedRng as SeedableRng>::Seed::default();
+        let seed_bytes = seed.as_mut();
+        if let Some(rng) = r.as_mut() {
+            rng.fill_bytes(seed_bytes);
+        }
+        // `chunks()` panics on a length of 0, which a seed type could in principle have.
+        if !seed_bytes.is_empty() {
+            for chunk in entropy.chunks(seed_bytes.len()) {
+                for (seed_byte, byte) in seed_bytes.iter_mut().zip(chunk) {
+                    *seed_byte ^= byte;
+                }
+            }
+        }
+
+        r.replace(SelectedRng::from_seed(seed));
     });
+
+    #[cfg(any(feature = "fast-rng-per-thread", feature = "csprng-per-core"))]
+    local::reset();
+}
+
+/// Returns whether the global RNG has been populated, and thus, whether [`fast_rng()`] and
+/// [`crypto_rng()`] can be used.
+#[must_use]
+pub fn is_initialized() -> bool {
+    RNG.lock(|r| r.borrow().is_some())
+}
+
+/// Length of the seeds produced by [`seed_for_next_boot()`].
+pub const PERSISTED_SEED_LEN: usize = 32;
+
+/// Produces a fresh seed from the global RNG, to be persisted and mixed into the global RNG
+/// through [`mix_seed()`] on next boot.
+///
+/// This allows devices without a hardware RNG to build up entropy across reboots. The seed has to
+/// be replaced in persistent storage every time it is used, and has to be kept secret.
+///
+/// # Panics
+///
+/// … if initialization did not happen.
+#[must_use]
+pub fn seed_for_next_boot() -> [u8; PERSISTED_SEED_LEN] {
+    let mut seed = [0; PERSISTED_SEED_LEN];
+    with_global(|i| i.fill_bytes(&mut seed));
+    seed
 }
 
 /// Returns a suitably initialized fast random number generator.
+///
+/// The returned RNG is independent: it is seeded once, and producing numbers from it does not
+/// involve any locking.
+/// With the `fast-rng-per-thread` feature, it is seeded from a state kept for the current thread,
+/// so that creating it only touches the global RNG the first time in each thread.
 #[expect(clippy::missing_panics_doc, reason = "does not panic")]
 #[must_use]
 #[inline]
 pub fn fast_rng() -> FastRng {
+    #[cfg(feature = "fast-rng-per-thread")]
+    if let Some(inner) = local::fork_fast_rng() {
+        return FastRng {
+            inner,
+            _private: PhantomData,
+        };
+    }
+
     FastRng {
         inner: with_global(|i| rand_pcg::Pcg32::from_rng(i).expect("Global RNG is infallible")),
         _private: PhantomData,
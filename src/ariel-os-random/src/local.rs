@@ -0,0 +1,173 @@
+//! RNG state kept per thread or per core, to take load off the global RNG.
+//!
+//! No lock is held while a state is used: Each state has a busy flag, which is taken with a
+//! compare-and-swap before the state is accessed. Whoever finds the flag taken (an interrupt
+//! handler that interrupted an access, or a thread that was preempted by the one holding the
+//! flag of its core) falls back to the global RNG.
+//!
+//! Instances are forked from the global RNG on first use, and again after the global RNG was
+//! reseeded, so that fresh entropy reaches them as well.
+use core::cell::UnsafeCell;
+
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+#[cfg(feature = "csprng-per-core")]
+use rand_core::RngCore as _;
+#[cfg(any(feature = "fast-rng-per-thread", feature = "csprng-per-core"))]
+use rand_core::SeedableRng;
+
+/// Changed whenever the global RNG is reseeded; states forked before are stale.
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+
+/// Fast RNG state of each thread.
+#[cfg(feature = "fast-rng-per-thread")]
+static THREAD_RNGS: [Slot<rand_pcg::Pcg32>; ariel_os_threads::THREADS_NUMOF] =
+    [const { Slot::new() }; ariel_os_threads::THREADS_NUMOF];
+
+/// Key of the CSPRNG of each core.
+#[cfg(feature = "csprng-per-core")]
+static CORE_KEYS: [Slot<<crate::SelectedRng as SeedableRng>::Seed>; ariel_os_threads::CORES_NUMOF] =
+    [const { Slot::new() }; ariel_os_threads::CORES_NUMOF];
+
+/// State kept for a thread or a core.
+struct Slot<T> {
+    busy: AtomicBool,
+    /// The state, along with the generation it was forked in
+    state: UnsafeCell<Option<(u32, T)>>,
+}
+
+// SAFETY: The state is only accessed while holding the busy flag, see `Slot::with()`.
+unsafe impl<T: Send> Sync for Slot<T> {}
+
+impl<T> Slot<T> {
+    const fn new() -> Self {
+        Self {
+            busy: AtomicBool::new(false),
+            state: UnsafeCell::new(None),
+        }
+    }
+
+    /// Runs `action` on the state, forking the state through `fork` if it is missing or stale.
+    ///
+    /// Returns `action` back if the state is in use, e.g. because this interrupted an access.
+    fn with<R, A: FnOnce(&mut T) -> R>(&self, fork: impl FnOnce() -> T, action: A) -> Result<R, A> {
+        if self
+            .busy
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(action);
+        }
+
+        // SAFETY: Holding the busy flag, this is the only access to the state.
+        let state = unsafe { &mut *self.state.get() };
+        let generation = GENERATION.load(Ordering::SeqCst);
+        if state
+            .as_ref()
+            .is_some_and(|(forked, _)| *forked != generation)
+        {
+            *state = None;
+        }
+        let (_, state) = state.get_or_insert_with(|| (generation, fork()));
+        let result = action(state);
+
+        self.busy.store(false, Ordering::Release);
+        Ok(result)
+    }
+}
+
+/// Forks a fast RNG from the state of the current thread.
+///
+/// Returns `None` when no thread is running, or when this interrupted an access to the state.
+#[cfg(feature = "fast-rng-per-thread")]
+pub(crate) fn fork_fast_rng() -> Option<rand_pcg::Pcg32> {
+    let thread = usize::from(ariel_os_threads::current_pid()?);
+    THREAD_RNGS
+        .get(thread)?
+        .with(
+            || {
+                crate::with_global(|i| {
+                    rand_pcg::Pcg32::from_rng(i).expect("Global RNG is infallible")
+                })
+            },
+            |rng| rand_pcg::Pcg32::from_rng(rng).expect("Thread RNG is infallible"),
+        )
+        .ok()
+}
+
+/// Runs `action` on the CSPRNG of the current core, or on the global one when that is in use.
+///
+/// Only the key of the core's CSPRNG is kept: Every time, a CSPRNG is created from it, and before
+/// it produces any output for `action`, the key is replaced with output of the CSPRNG ("fast key
+/// erasure"), so that a key read from memory does not reveal earlier output.
+///
+/// # Panics
+///
+/// … if initialization did not happen.
+#[cfg(feature = "csprng-per-core")]
+pub(crate) fn with_core_rng<R>(action: impl FnOnce(&mut crate::SelectedRng) -> R) -> R {
+    let fork = || {
+        let mut key = <crate::SelectedRng as SeedableRng>::Seed::default();
+        crate::with_global(|i| i.fill_bytes(&mut key));
+        key
+    };
+    let action = |key: &mut <crate::SelectedRng as SeedableRng>::Seed| {
+        let mut rng = crate::SelectedRng::from_seed(*key);
+        rng.fill_bytes(key);
+        action(&mut rng)
+    };
+
+    // The current thread may move to another core after this, which is fine: The slot is only
+    // used while holding its busy flag, no matter on which core.
+    let core = usize::from(ariel_os_threads::core_id());
+    match CORE_KEYS.get(core) {
+        Some(slot) => slot.with(fork, action),
+        None => Err(action),
+    }
+    .unwrap_or_else(|action| action(&mut fork()))
+}
+
+/// Marks all local states as stale, so they get forked again from the (reseeded) global RNG.
+pub(crate) fn reset() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn busy() {
+        let slot = Slot::new();
+
+        let outcome = slot
+            .with(
+                || 0u32,
+                |outer| {
+                    *outer += 1;
+                    // Like an interrupt handler while the state is in use
+                    let nested = slot.with(|| 10, |inner| *inner += 1).is_err();
+                    assert_eq!(*outer, 1);
+                    nested
+                },
+            )
+            .ok();
+        assert_eq!(outcome, Some(true), "Nested access was not rejected");
+
+        // The flag was released.
+        assert!(slot.with(|| 10, |_| ()).is_ok());
+    }
+
+    #[test]
+    fn reseeded() {
+        let slot = Slot::new();
+
+        assert_eq!(slot.with(|| 1u32, |state| *state).ok(), Some(1));
+        // Not forked again while current
+        assert_eq!(slot.with(|| 2, |state| *state).ok(), Some(1));
+
+        reset();
+
+        assert_eq!(slot.with(|| 3, |state| *state).ok(), Some(3));
+        assert_eq!(slot.with(|| 4, |state| *state).ok(), Some(3));
+    }
+}
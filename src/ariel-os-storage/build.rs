@@ -1,24 +1,155 @@
-use std::{env, path::PathBuf};
+use std::{env, fmt::Write, path::PathBuf};
+
+/// Name of the partition used by the global storage functions.
+const DEFAULT_PARTITION: &str = "default";
 
 fn main() {
     // TODO: These should be configurable. Like this, it works for MCUs with
     // a flash page size <= 4KiB.
     const FLASH_PAGE_SIZE: u32 = 0x1000;
-    const STORAGE_SIZE_TOTAL: u32 = 0x2000;
+    const DEFAULT_PARTITION_SIZE: u32 = 0x2000;
+
+    let partitions = partitions(DEFAULT_PARTITION_SIZE);
+
+    for Partition { name, size, .. } in &partitions {
+        assert!(
+            size % FLASH_PAGE_SIZE == 0,
+            "storage partition `{name}` size {size:#x} is not a multiple of the flash page size {FLASH_PAGE_SIZE:#x}"
+        );
+        // need at least two flash pages
+        // TODO: uncomment once this is not always true
+        //assert!(size / FLASH_PAGE_SIZE >= 2);
+    }
 
-    // need at least two flash pages
-    // TODO: uncomment once this is not always true
-    //assert!(STORAGE_SIZE_TOTAL / FLASH_PAGE_SIZE >= 2);
+    let storage_size_total: u32 = partitions.iter().map(|p| p.size).sum();
 
     // Put the linker script somewhere the linker can find it
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
 
     let mut storage_template = std::fs::read_to_string("storage.ld.in").unwrap();
     storage_template = storage_template.replace("${ALIGNMENT}", &format!("{FLASH_PAGE_SIZE}"));
-    storage_template = storage_template.replace("${SIZE}", &format!("{STORAGE_SIZE_TOTAL}"));
+    storage_template = storage_template.replace("${SIZE}", &format!("{storage_size_total}"));
 
     std::fs::write(out.join("storage.x"), &storage_template).unwrap();
 
+    // Lay out the partitions back to back within the storage section.
+    let mut maps = Vec::new();
+    let mut queues = Vec::new();
+    let mut offset = 0;
+    for Partition { name, size, kind } in &partitions {
+        let range = format!("{offset:#x}..{:#x}", offset + size);
+        match kind {
+            Kind::Map => maps.push(format!("    Partition::new({name:?}, {range}),")),
+            Kind::Queue => queues.push(format!("    QueuePartition::new({name:?}, {range}),")),
+        }
+        offset += size;
+    }
+
+    let mut config = String::new();
+    writeln!(config, "static PARTITIONS: [Partition; {}] = [", maps.len()).unwrap();
+    for line in maps {
+        writeln!(config, "{line}").unwrap();
+    }
+    writeln!(config, "];").unwrap();
+    writeln!(
+        config,
+        "static QUEUES: [QueuePartition; {}] = [",
+        queues.len()
+    )
+    .unwrap();
+    for line in queues {
+        writeln!(config, "{line}").unwrap();
+    }
+    writeln!(config, "];").unwrap();
+
+    // Sizes the (optional) page-indexed caches, which are shared by all partitions.
+    if env::var_os("CARGO_FEATURE_CACHE").is_some() {
+        let max_page_count = partitions
+            .iter()
+            .map(|p| p.size / FLASH_PAGE_SIZE)
+            .max()
+            .unwrap();
+        writeln!(
+            config,
+            "const STORAGE_PAGE_COUNT: usize = {max_page_count};"
+        )
+        .unwrap();
+    }
+
+    std::fs::write(out.join("storage_config.rs"), config).unwrap();
+
     println!("cargo:rerun-if-changed=storage.ld.in");
+    println!("cargo:rerun-if-env-changed=CONFIG_STORAGE_PARTITIONS");
     println!("cargo:rustc-link-search={}", out.display());
 }
+
+/// Kind of data a partition holds.
+enum Kind {
+    /// Key-value map.
+    Map,
+    /// FIFO queue of records.
+    Queue,
+}
+
+struct Partition {
+    name: String,
+    size: u32,
+    kind: Kind,
+}
+
+/// Parses `CONFIG_STORAGE_PARTITIONS`, a comma-separated list of
+/// `name:size[:kind]` entries, where `kind` is either `map` (the default) or
+/// `queue`.
+///
+/// The default partition always comes first; its size can be overridden by
+/// listing it explicitly.
+fn partitions(default_size: u32) -> Vec<Partition> {
+    let mut partitions = vec![Partition {
+        name: DEFAULT_PARTITION.to_string(),
+        size: default_size,
+        kind: Kind::Map,
+    }];
+
+    let Ok(config) = env::var("CONFIG_STORAGE_PARTITIONS") else {
+        return partitions;
+    };
+
+    for entry in config.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let mut fields = entry.split(':').map(str::trim);
+        let (Some(name), Some(size)) = (fields.next(), fields.next()) else {
+            panic!("invalid storage partition `{entry}`, expected `name:size[:kind]`");
+        };
+        let kind = match fields.next() {
+            None | Some("map") => Kind::Map,
+            Some("queue") => Kind::Queue,
+            Some(kind) => panic!("invalid kind `{kind}` for storage partition `{name}`"),
+        };
+
+        let size = if let Some(hex) = size.strip_prefix("0x") {
+            u32::from_str_radix(hex, 16)
+        } else {
+            size.parse()
+        }
+        .unwrap_or_else(|_| panic!("invalid size `{size}` for storage partition `{name}`"));
+
+        assert!(!name.is_empty(), "storage partition names cannot be empty");
+
+        if name == DEFAULT_PARTITION {
+            assert!(
+                matches!(kind, Kind::Map),
+                "the default storage partition must be a map"
+            );
+            partitions[0].size = size;
+        } else if partitions.iter().any(|p| p.name == name) {
+            panic!("storage partition `{name}` declared more than once");
+        } else {
+            partitions.push(Partition {
+                name: name.to_string(),
+                size,
+                kind,
+            });
+        }
+    }
+
+    partitions
+}
@@ -0,0 +1,290 @@
+//! Host-side flash emulator, for testing storage code without hardware.
+//!
+//! [`EmulatedFlash`] behaves like NOR flash: erasing sets whole pages to
+//! `0xff`, and writing can only clear bits.
+//! It is backed by RAM, and can optionally be persisted to a file.
+//!
+//! Faults can be injected to exercise recovery paths:
+//!
+//! - [`EmulatedFlash::power_loss_after()`] interrupts a later write or erase
+//!   part-way, as a power loss would.
+//! - [`EmulatedFlash::flip_bit()`] corrupts stored data.
+#![allow(
+    clippy::indexing_slicing,
+    reason = "accesses are checked against the size of the flash by `check()`"
+)]
+
+extern crate std;
+
+use std::{path::PathBuf, vec, vec::Vec};
+
+use embedded_storage_async::nor_flash::{
+    ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+/// Errors returned by [`EmulatedFlash`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmulatedFlashError {
+    /// The offset or length is not aligned to the read, write or erase size.
+    NotAligned,
+    /// The operation goes beyond the end of the flash.
+    OutOfBounds,
+    /// A simulated power loss interrupted the operation.
+    ///
+    /// All further operations fail this way until [`EmulatedFlash::power_cycle()`]
+    /// is called.
+    PowerLoss,
+}
+
+impl NorFlashError for EmulatedFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Self::NotAligned => NorFlashErrorKind::NotAligned,
+            Self::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Self::PowerLoss => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// RAM-backed NOR flash emulator with `PAGE_SIZE`-byte erase pages.
+///
+/// Writes happen in 4-byte words; reads are byte-granular.
+pub struct EmulatedFlash<const PAGE_SIZE: usize> {
+    data: Vec<u8>,
+    file: Option<PathBuf>,
+    /// Number of bytes that can still be written or erased before a simulated
+    /// power loss.
+    budget: Option<usize>,
+    powered: bool,
+    writes: usize,
+    erases: usize,
+}
+
+impl<const PAGE_SIZE: usize> EmulatedFlash<PAGE_SIZE> {
+    /// Creates an erased flash of `pages` pages.
+    #[must_use]
+    pub fn new(pages: usize) -> Self {
+        Self {
+            data: vec![0xff; pages * PAGE_SIZE],
+            file: None,
+            budget: None,
+            powered: true,
+            writes: 0,
+            erases: 0,
+        }
+    }
+
+    /// Creates a flash of `pages` pages that is persisted to `path`.
+    ///
+    /// If the file exists, the flash is initialized with its content, which
+    /// allows to emulate reboots across test runs; otherwise the flash starts
+    /// erased.
+    /// The file is rewritten after every modification.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the file cannot be read, or does not have the expected size.
+    #[must_use]
+    pub fn with_file(pages: usize, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let mut flash = Self::new(pages);
+        match std::fs::read(&path) {
+            Ok(data) => {
+                assert_eq!(data.len(), flash.data.len(), "flash image has wrong size");
+                flash.data = data;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => panic!("cannot read flash image: {err}"),
+        }
+        flash.file = Some(path);
+        flash
+    }
+
+    /// Returns the raw content of the flash.
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the number of write operations performed so far.
+    #[must_use]
+    pub fn writes(&self) -> usize {
+        self.writes
+    }
+
+    /// Returns the number of erase operations performed so far.
+    #[must_use]
+    pub fn erases(&self) -> usize {
+        self.erases
+    }
+
+    /// Simulates a power loss once `bytes` more bytes have been written or
+    /// erased.
+    ///
+    /// The operation that exhausts the budget is applied only partially, and
+    /// fails with [`EmulatedFlashError::PowerLoss`].
+    pub fn power_loss_after(&mut self, bytes: usize) {
+        self.budget = Some(bytes);
+    }
+
+    /// Restores power after a simulated power loss, keeping the flash content.
+    pub fn power_cycle(&mut self) {
+        self.budget = None;
+        self.powered = true;
+    }
+
+    /// Flips a single bit of the flash content, bypassing NOR semantics.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` is out of bounds or `bit > 7`.
+    pub fn flip_bit(&mut self, offset: usize, bit: u8) {
+        assert!(bit < 8);
+        self.data[offset] ^= 1 << bit;
+        self.persist();
+    }
+
+    fn check(&self, offset: u32, len: usize, align: usize) -> Result<usize, EmulatedFlashError> {
+        if !self.powered {
+            return Err(EmulatedFlashError::PowerLoss);
+        }
+        let offset = offset as usize;
+        if offset % align != 0 || len % align != 0 {
+            return Err(EmulatedFlashError::NotAligned);
+        }
+        if offset + len > self.data.len() {
+            return Err(EmulatedFlashError::OutOfBounds);
+        }
+        Ok(offset)
+    }
+
+    /// Consumes the fault injection budget, returning how many of `len`
+    /// bytes can be modified before power is lost.
+    fn consume(&mut self, len: usize) -> usize {
+        match self.budget {
+            Some(budget) if budget < len => {
+                self.budget = Some(0);
+                self.powered = false;
+                budget
+            }
+            Some(budget) => {
+                self.budget = Some(budget - len);
+                len
+            }
+            None => len,
+        }
+    }
+
+    fn persist(&self) {
+        if let Some(file) = &self.file {
+            std::fs::write(file, &self.data).expect("cannot write flash image");
+        }
+    }
+}
+
+impl<const PAGE_SIZE: usize> ErrorType for EmulatedFlash<PAGE_SIZE> {
+    type Error = EmulatedFlashError;
+}
+
+impl<const PAGE_SIZE: usize> ReadNorFlash for EmulatedFlash<PAGE_SIZE> {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = self.check(offset, bytes.len(), Self::READ_SIZE)?;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl<const PAGE_SIZE: usize> NorFlash for EmulatedFlash<PAGE_SIZE> {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = PAGE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let len = to
+            .checked_sub(from)
+            .ok_or(EmulatedFlashError::OutOfBounds)? as usize;
+        let from = self.check(from, len, Self::ERASE_SIZE)?;
+        self.erases += 1;
+
+        let erased = self.consume(len);
+        self.data[from..from + erased].fill(0xff);
+        self.persist();
+
+        if erased < len {
+            return Err(EmulatedFlashError::PowerLoss);
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = self.check(offset, bytes.len(), Self::WRITE_SIZE)?;
+        self.writes += 1;
+
+        let written = self.consume(bytes.len());
+        for (cell, byte) in self.data[offset..].iter_mut().zip(&bytes[..written]) {
+            // NOR flash can only clear bits.
+            *cell &= byte;
+        }
+        self.persist();
+
+        if written < bytes.len() {
+            return Err(EmulatedFlashError::PowerLoss);
+        }
+        Ok(())
+    }
+}
+
+impl<const PAGE_SIZE: usize> MultiwriteNorFlash for EmulatedFlash<PAGE_SIZE> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use embassy_futures::block_on;
+
+    #[test]
+    fn nor_semantics() {
+        let mut flash = EmulatedFlash::<256>::new(2);
+        block_on(flash.write(0, &[0x0f, 0xf0, 0xff, 0x00])).unwrap();
+        block_on(flash.write(0, &[0xff, 0x0f, 0x0f, 0xff])).unwrap();
+        assert_eq!(flash.as_bytes()[..4], [0x0f, 0x00, 0x0f, 0x00]);
+
+        block_on(flash.erase(0, 256)).unwrap();
+        assert!(flash.as_bytes()[..256].iter().all(|b| *b == 0xff));
+
+        assert_eq!(
+            block_on(flash.write(2, &[0; 4])),
+            Err(EmulatedFlashError::NotAligned)
+        );
+        assert_eq!(
+            block_on(flash.write(512, &[0; 4])),
+            Err(EmulatedFlashError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn power_loss() {
+        let mut flash = EmulatedFlash::<256>::new(1);
+        flash.power_loss_after(6);
+        block_on(flash.write(0, &[0; 4])).unwrap();
+        assert_eq!(
+            block_on(flash.write(4, &[0; 4])),
+            Err(EmulatedFlashError::PowerLoss)
+        );
+        // Only part of the second write made it.
+        assert_eq!(flash.as_bytes()[..8], [0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+
+        let mut buf = [0; 1];
+        assert_eq!(
+            block_on(flash.read(0, &mut buf)),
+            Err(EmulatedFlashError::PowerLoss)
+        );
+        flash.power_cycle();
+        block_on(flash.read(0, &mut buf)).unwrap();
+    }
+}
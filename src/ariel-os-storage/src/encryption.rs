@@ -0,0 +1,195 @@
+//! Authenticated encryption of stored values.
+use chacha20poly1305::{
+    aead::{AeadInPlace, KeyInit},
+    ChaCha20Poly1305, Key, Nonce, Tag,
+};
+use hkdf::Hkdf;
+use rand_core::RngCore;
+use sha2::Sha256;
+
+/// Length of the random nonce prepended to each sealed value.
+pub(crate) const NONCE_LEN: usize = 12;
+/// Length of the authentication tag appended to each sealed value.
+pub(crate) const TAG_LEN: usize = 16;
+/// Number of bytes sealing adds to a serialized value.
+pub const SEAL_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+/// HKDF `info` used for deriving value encryption keys.
+const KDF_INFO: &[u8] = b"ariel-os-storage value encryption";
+
+/// Encrypts and authenticates values before they are written to flash.
+///
+/// Values are sealed with ChaCha20-Poly1305 using a random nonce, and the
+/// storage key is used as associated data: a value that was tampered with,
+/// moved to another key, or written with another key (e.g., by a different
+/// device) is rejected when read back.
+pub struct Sealer {
+    aead: ChaCha20Poly1305,
+}
+
+impl Sealer {
+    /// Creates a [`Sealer`] from a 256-bit key, e.g., obtained from a hardware
+    /// key store.
+    #[must_use]
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            aead: ChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    /// Derives a device-unique [`Sealer`] using HKDF-SHA256.
+    ///
+    /// `device_id` is typically obtained from `ariel_os::identity::device_id_bytes()`.
+    /// `secret` is an application-supplied secret, which may be empty.
+    ///
+    /// <div class="warning">
+    /// Device identifiers are usually not secret.
+    /// Without a <code>secret</code> that is unknown to an attacker, the derived
+    /// key only binds values to the device, and does not protect their
+    /// confidentiality against someone who can read the flash.
+    /// </div>
+    #[expect(clippy::missing_panics_doc, reason = "does not panic")]
+    #[must_use]
+    pub fn from_device_id(device_id: &[u8], secret: &[u8]) -> Self {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(secret), device_id)
+            .expand(KDF_INFO, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Self::new(&key)
+    }
+
+    /// Seals `buffer[NONCE_LEN..NONCE_LEN + len]` in place.
+    ///
+    /// `buffer` needs room for the nonce in front and for the tag after the
+    /// plaintext. Returns the length of the sealed value.
+    pub(crate) fn seal(&self, aad: &[u8], buffer: &mut [u8], len: usize) -> Result<usize, ()> {
+        let (nonce, rest) = buffer.split_at_mut(NONCE_LEN);
+        if rest.len() < len + TAG_LEN {
+            return Err(());
+        }
+        ariel_os_random::crypto_rng().fill_bytes(nonce);
+
+        let (plaintext, rest) = rest.split_at_mut(len);
+        let tag = self
+            .aead
+            .encrypt_in_place_detached(Nonce::from_slice(nonce), aad, plaintext)
+            .map_err(|_| ())?;
+        rest.get_mut(..TAG_LEN).ok_or(())?.copy_from_slice(&tag);
+
+        Ok(NONCE_LEN + len + TAG_LEN)
+    }
+
+    /// Verifies and decrypts a sealed value in place.
+    ///
+    /// Returns the plaintext part of `sealed`, or an error if the value does
+    /// not authenticate.
+    pub(crate) fn open<'a>(&self, aad: &[u8], sealed: &'a mut [u8]) -> Result<&'a [u8], ()> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return Err(());
+        }
+        let (nonce, rest) = sealed.split_at_mut(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at_mut(rest.len() - TAG_LEN);
+        self.aead
+            .decrypt_in_place_detached(
+                Nonce::from_slice(nonce),
+                aad,
+                ciphertext,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| ())?;
+
+        Ok(ciphertext)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const AAD: &[u8] = b"key";
+    const PLAINTEXT: &[u8] = b"hello";
+    const SEALED_LEN: usize = PLAINTEXT.len() + SEAL_OVERHEAD;
+
+    /// Seals [`PLAINTEXT`] for [`AAD`] with `sealer`.
+    fn seal(sealer: &Sealer) -> [u8; SEALED_LEN] {
+        // Nonces are taken from the global RNG.
+        ariel_os_random::mix_seed(&[0; 32]);
+        let mut sealed = [0; SEALED_LEN];
+        sealed
+            .get_mut(NONCE_LEN..NONCE_LEN + PLAINTEXT.len())
+            .unwrap()
+            .copy_from_slice(PLAINTEXT);
+        assert_eq!(
+            sealer.seal(AAD, &mut sealed, PLAINTEXT.len()),
+            Ok(SEALED_LEN)
+        );
+        sealed
+    }
+
+    #[test]
+    fn round_trip() {
+        let sealer = Sealer::new(&[1; 32]);
+        let mut sealed = seal(&sealer);
+        assert_ne!(
+            sealed.get(NONCE_LEN..NONCE_LEN + PLAINTEXT.len()),
+            Some(PLAINTEXT)
+        );
+        assert_eq!(sealer.open(AAD, &mut sealed), Ok(PLAINTEXT));
+
+        // Without room for the tag, nothing is sealed.
+        let mut short = [0; SEALED_LEN - 1];
+        assert_eq!(sealer.seal(AAD, &mut short, PLAINTEXT.len()), Err(()));
+    }
+
+    #[test]
+    fn tampered() {
+        let sealer = Sealer::new(&[1; 32]);
+        let sealed = seal(&sealer);
+
+        // Any bit flipped in the nonce, ciphertext or tag is detected.
+        for offset in 0..SEALED_LEN {
+            for bit in 0..8 {
+                let mut tampered = sealed;
+                *tampered.get_mut(offset).unwrap() ^= 1 << bit;
+                assert_eq!(
+                    sealer.open(AAD, &mut tampered),
+                    Err(()),
+                    "offset {offset}, bit {bit}"
+                );
+            }
+        }
+
+        // Truncated values are rejected, down to ones shorter than the overhead.
+        for len in 0..SEALED_LEN {
+            let mut truncated = sealed;
+            assert_eq!(sealer.open(AAD, truncated.get_mut(..len).unwrap()), Err(()));
+        }
+
+        // Values moved to another storage key are rejected.
+        let mut moved = sealed;
+        assert_eq!(sealer.open(b"other key", &mut moved), Err(()));
+    }
+
+    #[test]
+    fn foreign_key() {
+        let sealed = seal(&Sealer::new(&[1; 32]));
+        let mut foreign = sealed;
+        assert_eq!(Sealer::new(&[2; 32]).open(AAD, &mut foreign), Err(()));
+
+        // Derived keys differ between devices, and between secrets.
+        let sealed = seal(&Sealer::from_device_id(b"device a", b"secret"));
+        for sealer in [
+            Sealer::from_device_id(b"device b", b"secret"),
+            Sealer::from_device_id(b"device a", b"other secret"),
+            Sealer::from_device_id(b"device a", b""),
+        ] {
+            let mut foreign = sealed;
+            assert_eq!(sealer.open(AAD, &mut foreign), Err(()));
+        }
+        let mut own = sealed;
+        assert_eq!(
+            Sealer::from_device_id(b"device a", b"secret").open(AAD, &mut own),
+            Ok(PLAINTEXT)
+        );
+    }
+}
@@ -2,14 +2,68 @@
 //!
 //! Currently the same type used for serializing must be used for deserializing.
 //! While not doing so won't cause unsafety, it might return garbage data, or panic.
+//!
+//! # Caching
+//!
+//! By default, every lookup scans the whole storage flash range.
+//! Enabling the `cache` Cargo feature makes the global storage keep a cache
+//! of page states and key locations in RAM, which speeds up lookups at the
+//! cost of some memory.
+//! The number of cached keys can be configured using the
+//! `CONFIG_STORAGE_CACHE_KEYS` environment variable (defaults to 8).
+//! Each partition has its own cache.
+//!
+//! # Encryption
+//!
+//! With the `encryption` Cargo feature, values can be stored encrypted and
+//! authenticated (using ChaCha20-Poly1305) through [`insert_encrypted()`] and
+//! [`get_encrypted()`], e.g., for credentials.
+//! Keys are provided through a [`Sealer`], which can be derived from the
+//! device identity.
+//!
+//! # Partitions
+//!
+//! The storage flash area can be split into several independently managed
+//! [`Partition`]s, so that e.g., a frequently written log does not cause
+//! configuration items to be garbage-collected.
+//! Partitions are declared at build time through the `CONFIG_STORAGE_PARTITIONS`
+//! environment variable (e.g., set through `CARGO_ENV` in laze), as a
+//! comma-separated list of `name:size[:kind]` entries, for instance
+//! `config:0x2000,calib:0x2000,logs:0x4000`.
+//! Sizes must be multiples of the flash page size.
+//!
+//! A partition named `default` always exists (by default 8 KiB, its size can be
+//! overridden by listing it) and is used by the global [`get()`],
+//! [`insert()`] and [`remove()`] functions.
+//! Other partitions are accessed through [`partition()`].
+//!
+//! # Queues
+//!
+//! Besides key-value maps, partitions can hold a persistent FIFO queue of
+//! byte records (see [`StorageQueue`]), e.g., to buffer sensor readings while
+//! offline.
+//! Such partitions are declared by appending the `queue` kind to their entry,
+//! as in `logs:0x4000:queue`, and are accessed through [`queue()`].
+//!
+//! # Testing
+//!
+//! The `emulated-flash` Cargo feature provides `emulated_flash::EmulatedFlash`
+//! (requires `std`), a host-side flash emulator with fault injection that can
+//! back a [`Storage`] or a [`StorageQueue`] in tests.
 
-#![cfg_attr(not(test), no_std)]
+#![no_std]
 #![deny(missing_docs)]
 #![deny(clippy::pedantic)]
 // TODO: overhaul errors
 #![expect(clippy::missing_errors_doc)]
 
+#[cfg(any(test, feature = "emulated-flash"))]
+pub mod emulated_flash;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod partition;
 mod postcard_value;
+mod queue;
 mod storage;
 
 use core::ops::Range;
@@ -23,10 +77,32 @@ use embassy_sync::{
     mutex::{Mutex, MutexGuard},
     once_lock::OnceLock,
 };
+use embedded_storage_async::nor_flash::ReadNorFlash;
 
+pub use partition::{Partition, QueuePartition, SharedFlash};
+pub use queue::*;
 pub use storage::*;
 
-static STORAGE: OnceLock<Mutex<CriticalSectionRawMutex, Storage<Flash>>> = OnceLock::new();
+include!(concat!(env!("OUT_DIR"), "/storage_config.rs"));
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "cache")] {
+        const CACHE_KEYS: usize = ariel_os_utils::usize_from_env_or!(
+            "CONFIG_STORAGE_CACHE_KEYS",
+            8,
+            "number of keys cached by the global storage"
+        );
+
+        type GlobalCache =
+            sequential_storage::cache::KeyPointerCache<STORAGE_PAGE_COUNT, StorageKey, CACHE_KEYS>;
+        type QueueCache = sequential_storage::cache::PagePointerCache<STORAGE_PAGE_COUNT>;
+    } else {
+        type GlobalCache = sequential_storage::cache::NoCache;
+        type QueueCache = sequential_storage::cache::NoCache;
+    }
+}
+
+static FLASH: OnceLock<Mutex<CriticalSectionRawMutex, Flash>> = OnceLock::new();
 
 /// Gets a [`Range`] from the linker that can be used for a global [`Storage`].
 ///
@@ -60,7 +136,15 @@ fn init_(p: &mut OptionalPeripherals) {
     info!("storage: using flash range {:#x}", &flash_range);
 
     let flash = flash_init(p);
-    let _ = STORAGE.init(Mutex::new(Storage::new(flash, flash_range)));
+    let capacity = flash.capacity();
+    let flash = FLASH.get_or_init(|| Mutex::new(flash));
+
+    for partition in &PARTITIONS {
+        partition.init(SharedFlash::new(flash, capacity), flash_range.start);
+    }
+    for queue in &QUEUES {
+        queue.init(SharedFlash::new(flash, capacity), flash_range.start);
+    }
 }
 
 /// Initializes the global storage.
@@ -84,20 +168,56 @@ pub async fn init(p: &mut OptionalPeripherals) {
     #[cfg(context = "rp")]
     embassy_time::Timer::after_millis(10).await;
 
-    // Use a marker to ensure that this storage is initialized.
-    match get::<u32>(MARKER_KEY).await {
-        Ok(Some(val)) if val == MARKER_VALUE => {
-            // all good
+    // Use a marker to ensure that each partition is initialized.
+    for partition in &PARTITIONS {
+        match partition.get::<u32>(MARKER_KEY).await {
+            Ok(Some(val)) if val == MARKER_VALUE => {
+                // all good
+            }
+            _ => {
+                ariel_os_debug::log::info!("storage: initializing partition {}", partition.name());
+                let mut s = partition.lock().await;
+                s.erase_all().await.unwrap();
+                s.insert(MARKER_KEY, MARKER_VALUE).await.unwrap();
+            }
         }
-        _ => {
-            ariel_os_debug::log::info!("storage: initializing");
-            let mut s = lock().await;
-            s.erase_all().await.unwrap();
-            s.insert(MARKER_KEY, MARKER_VALUE).await.unwrap();
+    }
+
+    // Queues cannot hold a marker, so only check that they can be parsed.
+    for queue in &QUEUES {
+        let mut q = queue.lock().await;
+        if q.find_max_fit().await.is_err() {
+            ariel_os_debug::log::info!("storage: initializing queue {}", queue.name());
+            q.erase_all().await.unwrap();
         }
     }
 }
 
+/// Returns the storage partition with the given name, if it exists.
+#[must_use]
+pub fn partition(name: &str) -> Option<&'static Partition> {
+    PARTITIONS.iter().find(|p| p.name() == name)
+}
+
+/// Returns the queue partition with the given name, if it exists.
+#[must_use]
+pub fn queue(name: &str) -> Option<&'static QueuePartition> {
+    QUEUES.iter().find(|q| q.name() == name)
+}
+
+/// Returns an iterator over all key-value storage partitions, starting with the default one.
+pub fn partitions() -> impl Iterator<Item = &'static Partition> {
+    PARTITIONS.iter()
+}
+
+/// Returns the default partition, used by the global [`get()`], [`insert()`]
+/// and [`remove()`] functions.
+#[must_use]
+pub fn default_partition() -> &'static Partition {
+    // The build script always puts the default partition first.
+    &PARTITIONS[0]
+}
+
 /// Stores a key-value pair into flash memory.
 ///
 /// It will overwrite the last value that has the same key.
@@ -105,7 +225,7 @@ pub async fn insert<'d, V>(key: &str, value: V) -> Result<(), sequential_storage
 where
     V: Serialize + Deserialize<'d> + Into<PostcardValue<V>>,
 {
-    lock().await.insert::<V>(key, value).await
+    default_partition().insert::<V>(key, value).await
 }
 
 /// Gets the last stored value from the flash that is associated with the given key.
@@ -117,7 +237,34 @@ pub async fn get<V>(key: &str) -> Result<Option<V>, sequential_storage::Error<Fl
 where
     V: Serialize + for<'d> Deserialize<'d> + Into<PostcardValue<V>>,
 {
-    lock().await.get(key).await
+    default_partition().get(key).await
+}
+
+/// Stores a key-value pair into flash memory, encrypted and authenticated
+/// using `sealer`.
+///
+/// See [`Storage::insert_encrypted()`].
+#[cfg(feature = "encryption")]
+pub async fn insert_encrypted<V: Serialize>(
+    key: &str,
+    value: &V,
+    sealer: &Sealer,
+) -> Result<(), sequential_storage::Error<FlashError>> {
+    default_partition()
+        .insert_encrypted(key, value, sealer)
+        .await
+}
+
+/// Gets the last stored value that is associated with the given key, and that
+/// was stored using [`insert_encrypted()`].
+///
+/// See [`Storage::get_encrypted()`].
+#[cfg(feature = "encryption")]
+pub async fn get_encrypted<V: for<'d> Deserialize<'d>>(
+    key: &str,
+    sealer: &Sealer,
+) -> Result<Option<V>, sequential_storage::Error<FlashError>> {
+    default_partition().get_encrypted(key, sealer).await
 }
 
 /// Deletes an item from flash.
@@ -132,10 +279,10 @@ where
 /// This is unlikely to be cached well.
 /// </div>
 pub async fn remove(key: &str) -> Result<(), sequential_storage::Error<FlashError>> {
-    lock().await.remove(key).await
+    default_partition().remove(key).await
 }
 
-/// Gets a [`MutexGuard`] of the global [`Storage`] object.
+/// Gets a [`MutexGuard`] of the [`Storage`] object of the default partition.
 ///
 /// This can be used to implement atomic RMW (like counters).
 /// *It is not needed for using the global [`get()`], [`insert()`] and [`remove()`] functions.*
@@ -154,6 +301,7 @@ pub async fn remove(key: &str) -> Result<(), sequential_storage::Error<FlashErro
 ///     s.insert("counter", value + 1).await.unwrap();
 /// }
 /// ```
-pub async fn lock() -> MutexGuard<'static, CriticalSectionRawMutex, storage::Storage<Flash>> {
-    STORAGE.get().await.lock().await
+pub async fn lock(
+) -> MutexGuard<'static, CriticalSectionRawMutex, storage::Storage<SharedFlash, GlobalCache>> {
+    default_partition().lock().await
 }
@@ -0,0 +1,258 @@
+//! Named storage partitions sharing the same flash.
+use core::ops::Range;
+
+use ariel_os_hal::storage::{Flash, FlashError};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    mutex::{Mutex, MutexGuard},
+    once_lock::OnceLock,
+};
+use embedded_storage_async::nor_flash::{ErrorType, MultiwriteNorFlash, NorFlash, ReadNorFlash};
+
+use crate::{
+    queue::StorageQueue, Deserialize, GlobalCache, PostcardValue, QueueCache, Serialize, Storage,
+};
+
+/// Handle to the flash shared by all [`Partition`]s.
+///
+/// Every operation locks the underlying flash driver for its duration only,
+/// so that partitions can be accessed independently.
+pub struct SharedFlash {
+    flash: &'static Mutex<CriticalSectionRawMutex, Flash>,
+    capacity: usize,
+}
+
+impl SharedFlash {
+    pub(crate) const fn new(
+        flash: &'static Mutex<CriticalSectionRawMutex, Flash>,
+        capacity: usize,
+    ) -> Self {
+        Self { flash, capacity }
+    }
+}
+
+impl ErrorType for SharedFlash {
+    type Error = FlashError;
+}
+
+impl ReadNorFlash for SharedFlash {
+    const READ_SIZE: usize = Flash::READ_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash.lock().await.read(offset, bytes).await
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl NorFlash for SharedFlash {
+    const WRITE_SIZE: usize = Flash::WRITE_SIZE;
+    const ERASE_SIZE: usize = Flash::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.flash.lock().await.erase(from, to).await
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.flash.lock().await.write(offset, bytes).await
+    }
+}
+
+impl MultiwriteNorFlash for SharedFlash {}
+
+/// A named storage partition.
+///
+/// Each partition is an independent key-value [`Storage`] covering its own
+/// flash range, with its own mutex.
+/// Partitions are declared at build time, see the [crate documentation](crate).
+pub struct Partition {
+    name: &'static str,
+    // Relative to the start of the storage section.
+    range: Range<u32>,
+    storage: OnceLock<Mutex<CriticalSectionRawMutex, Storage<SharedFlash, GlobalCache>>>,
+}
+
+impl Partition {
+    pub(crate) const fn new(name: &'static str, range: Range<u32>) -> Self {
+        Self {
+            name,
+            range,
+            storage: OnceLock::new(),
+        }
+    }
+
+    pub(crate) fn init(&self, flash: SharedFlash, storage_start: u32) {
+        let range = storage_start + self.range.start..storage_start + self.range.end;
+        let _ = self.storage.init(Mutex::new(Storage::with_cache(
+            flash,
+            range,
+            GlobalCache::new(),
+        )));
+    }
+
+    /// Returns the name of this partition.
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the size of this partition, in bytes.
+    #[must_use]
+    pub fn size(&self) -> u32 {
+        self.range.end - self.range.start
+    }
+
+    /// Stores a key-value pair into this partition.
+    ///
+    /// It will overwrite the last value that has the same key.
+    pub async fn insert<'d, V>(
+        &self,
+        key: &str,
+        value: V,
+    ) -> Result<(), sequential_storage::Error<FlashError>>
+    where
+        V: Serialize + Deserialize<'d> + Into<PostcardValue<V>>,
+    {
+        self.lock().await.insert::<V>(key, value).await
+    }
+
+    /// Gets the last stored value from this partition that is associated with the given key.
+    ///
+    /// If no value with the key is found, `None` is returned.
+    pub async fn get<V>(
+        &self,
+        key: &str,
+    ) -> Result<Option<V>, sequential_storage::Error<FlashError>>
+    where
+        V: Serialize + for<'d> Deserialize<'d> + Into<PostcardValue<V>>,
+    {
+        self.lock().await.get(key).await
+    }
+
+    /// Stores an encrypted key-value pair into this partition.
+    ///
+    /// See [`Storage::insert_encrypted()`].
+    #[cfg(feature = "encryption")]
+    pub async fn insert_encrypted<V: Serialize>(
+        &self,
+        key: &str,
+        value: &V,
+        sealer: &crate::Sealer,
+    ) -> Result<(), sequential_storage::Error<FlashError>> {
+        self.lock().await.insert_encrypted(key, value, sealer).await
+    }
+
+    /// Gets an encrypted value from this partition.
+    ///
+    /// See [`Storage::get_encrypted()`].
+    #[cfg(feature = "encryption")]
+    pub async fn get_encrypted<V: for<'d> Deserialize<'d>>(
+        &self,
+        key: &str,
+        sealer: &crate::Sealer,
+    ) -> Result<Option<V>, sequential_storage::Error<FlashError>> {
+        self.lock().await.get_encrypted(key, sealer).await
+    }
+
+    /// Deletes an item from this partition.
+    ///
+    /// See [`crate::remove()`] for caveats.
+    pub async fn remove(&self, key: &str) -> Result<(), sequential_storage::Error<FlashError>> {
+        self.lock().await.remove(key).await
+    }
+
+    /// Gets a [`MutexGuard`] of the [`Storage`] object of this partition.
+    ///
+    /// See [`crate::lock()`] for details.
+    pub async fn lock(
+        &self,
+    ) -> MutexGuard<'_, CriticalSectionRawMutex, Storage<SharedFlash, GlobalCache>> {
+        self.storage.get().await.lock().await
+    }
+}
+
+/// A named storage partition holding a [`StorageQueue`].
+///
+/// Queue partitions are declared at build time like other partitions, with
+/// the `queue` kind, see the [crate documentation](crate).
+pub struct QueuePartition {
+    name: &'static str,
+    // Relative to the start of the storage section.
+    range: Range<u32>,
+    queue: OnceLock<Mutex<CriticalSectionRawMutex, StorageQueue<SharedFlash, QueueCache>>>,
+}
+
+impl QueuePartition {
+    pub(crate) const fn new(name: &'static str, range: Range<u32>) -> Self {
+        Self {
+            name,
+            range,
+            queue: OnceLock::new(),
+        }
+    }
+
+    pub(crate) fn init(&self, flash: SharedFlash, storage_start: u32) {
+        let range = storage_start + self.range.start..storage_start + self.range.end;
+        let _ = self.queue.init(Mutex::new(StorageQueue::with_cache(
+            flash,
+            range,
+            QueueCache::new(),
+        )));
+    }
+
+    /// Returns the name of this partition.
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the size of this partition, in bytes.
+    #[must_use]
+    pub fn size(&self) -> u32 {
+        self.range.end - self.range.start
+    }
+
+    /// Pushes a record to the back of the queue.
+    ///
+    /// See [`StorageQueue::push()`].
+    pub async fn push(
+        &self,
+        data: &[u8],
+        allow_overwrite_old_data: bool,
+    ) -> Result<(), sequential_storage::Error<FlashError>> {
+        self.lock().await.push(data, allow_overwrite_old_data).await
+    }
+
+    /// Copies the oldest record into `data_buffer`, without removing it.
+    ///
+    /// See [`StorageQueue::peek()`].
+    pub async fn peek<'d>(
+        &self,
+        data_buffer: &'d mut [u8],
+    ) -> Result<Option<&'d mut [u8]>, sequential_storage::Error<FlashError>> {
+        self.lock().await.peek(data_buffer).await
+    }
+
+    /// Moves the oldest record into `data_buffer`, removing it from the queue.
+    ///
+    /// See [`StorageQueue::pop()`].
+    pub async fn pop<'d>(
+        &self,
+        data_buffer: &'d mut [u8],
+    ) -> Result<Option<&'d mut [u8]>, sequential_storage::Error<FlashError>> {
+        self.lock().await.pop(data_buffer).await
+    }
+
+    /// Gets a [`MutexGuard`] of the [`StorageQueue`] object of this partition.
+    ///
+    /// This is needed for iterating over the records, see [`StorageQueue::iter()`].
+    ///
+    /// Note: don't forget to drop the mutex guard returned by this.
+    pub async fn lock(
+        &self,
+    ) -> MutexGuard<'_, CriticalSectionRawMutex, StorageQueue<SharedFlash, QueueCache>> {
+        self.queue.get().await.lock().await
+    }
+}
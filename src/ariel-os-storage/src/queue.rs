@@ -0,0 +1,259 @@
+//! Queue module wrapping the FIFO queue of [`sequential_storage`] in an object
+//! together with a flash range and backend.
+use core::ops::Range;
+
+use embedded_storage_async::nor_flash::{ErrorType, MultiwriteNorFlash, NorFlash};
+use sequential_storage::{
+    cache::{CacheImpl, NoCache},
+    erase_all,
+    queue::{find_max_fit, iter, peek, pop, push, space_left},
+};
+
+pub use sequential_storage::queue::{QueueIterator, QueueIteratorEntry};
+
+/// Object holding an instance of a persistent FIFO queue of byte records.
+///
+/// Records survive power loss: an interrupted [`push()`](StorageQueue::push)
+/// or [`pop()`](StorageQueue::pop) leaves the queue either in the state before
+/// or after the operation.
+///
+/// You should probably look into using a queue partition, accessible via
+/// `ariel_os_storage::queue()`.
+pub struct StorageQueue<F, C = NoCache> {
+    flash: F,
+    storage_range: Range<u32>,
+    cache: C,
+}
+
+impl<F: NorFlash> StorageQueue<F> {
+    /// Creates a new [`StorageQueue`] instance without a cache.
+    pub const fn new(flash: F, storage_range: Range<u32>) -> StorageQueue<F> {
+        Self::with_cache(flash, storage_range, NoCache::new())
+    }
+}
+
+impl<F: NorFlash, C: CacheImpl + Default> StorageQueue<F, C> {
+    /// Creates a new [`StorageQueue`] instance using the given cache.
+    ///
+    /// The cache must be empty and sized for at least the number of flash pages in
+    /// `storage_range`.
+    pub const fn with_cache(flash: F, storage_range: Range<u32>, cache: C) -> StorageQueue<F, C> {
+        Self {
+            flash,
+            storage_range,
+            cache,
+        }
+    }
+
+    /// Pushes a record to the back of the queue.
+    ///
+    /// If the queue is full and `allow_overwrite_old_data` is `true`, the
+    /// oldest records are dropped to make room; otherwise
+    /// [`sequential_storage::Error::FullStorage`] is returned.
+    pub async fn push(
+        &mut self,
+        data: &[u8],
+        allow_overwrite_old_data: bool,
+    ) -> Result<(), sequential_storage::Error<<F as ErrorType>::Error>> {
+        push(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut self.cache,
+            data,
+            allow_overwrite_old_data,
+        )
+        .await
+    }
+
+    /// Copies the oldest record into `data_buffer`, without removing it.
+    ///
+    /// Returns the part of `data_buffer` holding the record, or `None` if the
+    /// queue is empty.
+    pub async fn peek<'d>(
+        &mut self,
+        data_buffer: &'d mut [u8],
+    ) -> Result<Option<&'d mut [u8]>, sequential_storage::Error<<F as ErrorType>::Error>> {
+        peek(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut self.cache,
+            data_buffer,
+        )
+        .await
+    }
+
+    /// Returns an iterator over the records, from oldest to newest.
+    ///
+    /// With a [`MultiwriteNorFlash`], entries returned by the iterator can be
+    /// popped individually.
+    pub async fn iter(
+        &mut self,
+    ) -> Result<QueueIterator<'_, F, C>, sequential_storage::Error<<F as ErrorType>::Error>> {
+        iter(&mut self.flash, self.storage_range.clone(), &mut self.cache).await
+    }
+
+    /// Returns the size of the largest record that can currently be pushed
+    /// without overwriting old data, or `None` if the queue is full.
+    pub async fn find_max_fit(
+        &mut self,
+    ) -> Result<Option<u32>, sequential_storage::Error<<F as ErrorType>::Error>> {
+        find_max_fit(&mut self.flash, self.storage_range.clone(), &mut self.cache).await
+    }
+
+    /// Returns the number of free bytes in the queue.
+    ///
+    /// Records have some overhead, so this is an upper bound for the size of
+    /// the data that can still be pushed.
+    pub async fn space_left(
+        &mut self,
+    ) -> Result<u32, sequential_storage::Error<<F as ErrorType>::Error>> {
+        space_left(&mut self.flash, self.storage_range.clone(), &mut self.cache).await
+    }
+
+    /// Resets the flash in the entire flash range of this [`StorageQueue`] instance.
+    pub async fn erase_all(
+        &mut self,
+    ) -> Result<(), sequential_storage::Error<<F as ErrorType>::Error>> {
+        // The cache does not know about the erase, so start over with an empty one.
+        self.cache = C::default();
+        erase_all(&mut self.flash, self.storage_range.clone()).await
+    }
+}
+
+impl<F: MultiwriteNorFlash, C: CacheImpl + Default> StorageQueue<F, C> {
+    /// Moves the oldest record into `data_buffer`, removing it from the queue.
+    ///
+    /// Returns the part of `data_buffer` holding the record, or `None` if the
+    /// queue is empty.
+    pub async fn pop<'d>(
+        &mut self,
+        data_buffer: &'d mut [u8],
+    ) -> Result<Option<&'d mut [u8]>, sequential_storage::Error<<F as ErrorType>::Error>> {
+        pop(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut self.cache,
+            data_buffer,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use embassy_futures::block_on;
+
+    use crate::emulated_flash::EmulatedFlash;
+
+    const PAGE_SIZE: usize = 1024;
+    const PAGES: usize = 4;
+
+    #[expect(clippy::cast_possible_truncation)]
+    fn queue() -> StorageQueue<EmulatedFlash<PAGE_SIZE>> {
+        StorageQueue::new(EmulatedFlash::new(PAGES), 0..(PAGES * PAGE_SIZE) as u32)
+    }
+
+    #[test]
+    fn push_peek_pop() {
+        let mut q = queue();
+        let mut buf = [0; 32];
+        block_on(async {
+            assert_eq!(q.peek(&mut buf).await.unwrap(), None);
+
+            q.push(&[1, 2, 3], false).await.unwrap();
+            q.push(&[4, 5], false).await.unwrap();
+
+            assert_eq!(
+                q.peek(&mut buf).await.unwrap().as_deref(),
+                Some(&[1, 2, 3][..])
+            );
+            assert_eq!(
+                q.pop(&mut buf).await.unwrap().as_deref(),
+                Some(&[1, 2, 3][..])
+            );
+            assert_eq!(q.pop(&mut buf).await.unwrap().as_deref(), Some(&[4, 5][..]));
+            assert_eq!(q.pop(&mut buf).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn iterate() {
+        let mut q = queue();
+        block_on(async {
+            for i in 0..5u8 {
+                q.push(&[i; 4], false).await.unwrap();
+            }
+
+            let mut iter = q.iter().await.unwrap();
+            let mut buf = [0; 32];
+            let mut expected = 0;
+            while let Some(entry) = iter.next(&mut buf).await.unwrap() {
+                assert_eq!(&*entry, &[expected; 4]);
+                // Pop the even entries.
+                if expected % 2 == 0 {
+                    entry.pop().await.unwrap();
+                }
+                expected += 1;
+            }
+            assert_eq!(expected, 5);
+
+            let mut buf = [0; 32];
+            assert_eq!(q.pop(&mut buf).await.unwrap().as_deref(), Some(&[1; 4][..]));
+            assert_eq!(q.pop(&mut buf).await.unwrap().as_deref(), Some(&[3; 4][..]));
+            assert_eq!(q.pop(&mut buf).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn full() {
+        let mut q = queue();
+        block_on(async {
+            let record = [0xaa; 100];
+            while q
+                .find_max_fit()
+                .await
+                .unwrap()
+                .is_some_and(|fit| fit >= 100)
+            {
+                q.push(&record, false).await.unwrap();
+            }
+            assert!(matches!(
+                q.push(&record, false).await,
+                Err(sequential_storage::Error::FullStorage)
+            ));
+            // Overwriting drops the oldest records instead.
+            q.push(&record, true).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn power_loss_during_push() {
+        for budget in (0..64).step_by(4) {
+            let mut q = queue();
+            let mut buf = [0; 32];
+            block_on(async {
+                q.push(&[1], false).await.unwrap();
+
+                q.flash.power_loss_after(budget);
+                let result = q.push(&[2, 2], false).await;
+                q.flash.power_cycle();
+
+                assert_eq!(q.pop(&mut buf).await.unwrap().as_deref(), Some(&[1][..]));
+                let next = q.pop(&mut buf).await.unwrap().map(|r| r.to_vec());
+                if result.is_ok() {
+                    assert_eq!(next.as_deref(), Some(&[2, 2][..]));
+                } else {
+                    assert!(
+                        matches!(next.as_deref(), None | Some(&[2, 2])),
+                        "budget {budget}"
+                    );
+                }
+
+                q.push(&[3], false).await.unwrap();
+                assert_eq!(q.pop(&mut buf).await.unwrap().as_deref(), Some(&[3][..]));
+            });
+        }
+    }
+}
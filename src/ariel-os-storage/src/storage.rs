@@ -5,11 +5,16 @@ use core::ops::Range;
 use arrayvec::ArrayString;
 use embedded_storage_async::nor_flash::{ErrorType, MultiwriteNorFlash, NorFlash};
 use sequential_storage::{
-    cache::NoCache,
+    cache::{KeyCacheImpl, NoCache},
     erase_all,
     map::{fetch_item, remove_item, store_item, Value},
 };
 
+#[cfg(feature = "encryption")]
+use sequential_storage::map::SerializationError;
+
+#[cfg(feature = "encryption")]
+pub use crate::encryption::{Sealer, SEAL_OVERHEAD};
 pub use crate::postcard_value::PostcardValue;
 pub use serde::{Deserialize, Serialize};
 
@@ -18,21 +23,40 @@ pub const MAX_KEY_LEN: usize = 64usize;
 /// Data buffer length.
 pub const DATA_BUFFER_SIZE: usize = 128usize;
 
+/// Key type used by [`Storage`].
+pub type StorageKey = ArrayString<MAX_KEY_LEN>;
+
 /// Object holding an instance of a key-value pair storage.
 ///
 /// You should probably look into using the global instance accessible via
 /// `ariel_os_storage::storage::{get,insert,remove}`.
-pub struct Storage<F> {
+///
+/// The cache type `C` defaults to [`NoCache`], which makes every lookup scan
+/// the whole flash range.
+/// See [`sequential_storage::cache`] for the available caches.
+pub struct Storage<F, C = NoCache> {
     flash: F,
     storage_range: Range<u32>,
+    cache: C,
 }
 
 impl<F: NorFlash> Storage<F> {
-    /// Creates a new [`Storage`] instance.
+    /// Creates a new [`Storage`] instance without a cache.
     pub const fn new(flash: F, storage_range: Range<u32>) -> Storage<F> {
+        Self::with_cache(flash, storage_range, NoCache::new())
+    }
+}
+
+impl<F: NorFlash, C: KeyCacheImpl<StorageKey> + Default> Storage<F, C> {
+    /// Creates a new [`Storage`] instance using the given cache.
+    ///
+    /// The cache must be empty and sized for at least the number of flash pages in
+    /// `storage_range`.
+    pub const fn with_cache(flash: F, storage_range: Range<u32>, cache: C) -> Storage<F, C> {
         Self {
             flash,
             storage_range,
+            cache,
         }
     }
 
@@ -45,13 +69,13 @@ impl<F: NorFlash> Storage<F> {
         &mut self,
         key: &str,
     ) -> Result<Option<V>, sequential_storage::Error<<F as ErrorType>::Error>> {
-        let key = ArrayString::<MAX_KEY_LEN>::from(key).unwrap();
+        let key = StorageKey::from(key).unwrap();
         let mut data_buffer = [0; DATA_BUFFER_SIZE];
 
         fetch_item::<_, V, _>(
             &mut self.flash,
             self.storage_range.clone(),
-            &mut NoCache::new(),
+            &mut self.cache,
             &mut data_buffer,
             &key,
         )
@@ -68,12 +92,12 @@ impl<F: NorFlash> Storage<F> {
         key: &str,
         value: V,
     ) -> Result<(), sequential_storage::Error<<F as ErrorType>::Error>> {
-        let key = ArrayString::<MAX_KEY_LEN>::from(key).unwrap();
+        let key = StorageKey::from(key).unwrap();
         let mut data_buffer = [0; DATA_BUFFER_SIZE];
         store_item(
             &mut self.flash,
             self.storage_range.clone(),
-            &mut NoCache::new(),
+            &mut self.cache,
             &mut data_buffer,
             &key,
             &value,
@@ -109,13 +133,13 @@ impl<F: NorFlash> Storage<F> {
     where
         V: Serialize + for<'d> Deserialize<'d> + Into<PostcardValue<V>>,
     {
-        let key = ArrayString::<MAX_KEY_LEN>::from(key).unwrap();
+        let key = StorageKey::from(key).unwrap();
         let mut data_buffer = [0; DATA_BUFFER_SIZE];
 
         let postcard_value = fetch_item::<_, PostcardValue<V>, _>(
             &mut self.flash,
             self.storage_range.clone(),
-            &mut NoCache::new(),
+            &mut self.cache,
             &mut data_buffer,
             &key,
         )
@@ -123,15 +147,108 @@ impl<F: NorFlash> Storage<F> {
         Ok(postcard_value.map(PostcardValue::into_inner))
     }
 
+    /// Stores a key-value pair into flash memory, encrypted and authenticated
+    /// using `sealer`.
+    ///
+    /// It will overwrite the last value that has the same key.
+    /// The sealed value needs to fit into [`DATA_BUFFER_SIZE`] together with
+    /// the key, and is [`SEAL_OVERHEAD`] bytes larger than the serialized value.
+    ///
+    /// # Panics
+    ///
+    /// Currently panics if `key.len() > MAX_KEY_LEN`.
+    #[cfg(feature = "encryption")]
+    pub async fn insert_encrypted<V: Serialize>(
+        &mut self,
+        key: &str,
+        value: &V,
+        sealer: &Sealer,
+    ) -> Result<(), sequential_storage::Error<<F as ErrorType>::Error>> {
+        use crate::encryption::NONCE_LEN;
+
+        let mut sealed = [0; DATA_BUFFER_SIZE];
+        let len = postcard::to_slice(value, &mut sealed[NONCE_LEN..])
+            .map_err(|_| {
+                sequential_storage::Error::SerializationError(SerializationError::BufferTooSmall)
+            })?
+            .len();
+        let len = sealer
+            .seal(key.as_bytes(), &mut sealed, len)
+            .map_err(|()| {
+                sequential_storage::Error::SerializationError(SerializationError::BufferTooSmall)
+            })?;
+
+        let sealed = sealed
+            .get(..len)
+            .ok_or(sequential_storage::Error::SerializationError(
+                SerializationError::BufferTooSmall,
+            ))?;
+
+        self.insert_raw(key, sealed).await
+    }
+
+    /// Gets the last stored value that is associated with the given key, and
+    /// that was stored using [`Storage::insert_encrypted()`].
+    ///
+    /// If no value with the key is found, `None` is returned.
+    /// Values that were tampered with, or that were not sealed by `sealer` for
+    /// this key, are rejected with
+    /// [`SerializationError::InvalidData`](sequential_storage::map::SerializationError::InvalidData).
+    ///
+    /// # Panics
+    ///
+    /// Currently panics if `key.len() > MAX_KEY_LEN`.
+    #[cfg(feature = "encryption")]
+    pub async fn get_encrypted<V: for<'d> Deserialize<'d>>(
+        &mut self,
+        key: &str,
+        sealer: &Sealer,
+    ) -> Result<Option<V>, sequential_storage::Error<<F as ErrorType>::Error>> {
+        let storage_key = StorageKey::from(key).unwrap();
+        let mut data_buffer = [0; DATA_BUFFER_SIZE];
+
+        let Some(stored) = fetch_item::<_, &[u8], _>(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut self.cache,
+            &mut data_buffer,
+            &storage_key,
+        )
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let mut buffer = [0; DATA_BUFFER_SIZE];
+        let Some(sealed) = buffer.get_mut(..stored.len()) else {
+            // `stored` was read into a buffer of the same size, so this does not happen.
+            return Err(sequential_storage::Error::SerializationError(
+                SerializationError::BufferTooSmall,
+            ));
+        };
+        sealed.copy_from_slice(stored);
+
+        let plaintext = sealer.open(key.as_bytes(), sealed).map_err(|()| {
+            sequential_storage::Error::SerializationError(SerializationError::InvalidData)
+        })?;
+        let value = postcard::from_bytes(plaintext).map_err(|_| {
+            sequential_storage::Error::SerializationError(SerializationError::Custom(0))
+        })?;
+
+        Ok(Some(value))
+    }
+
     /// Resets the flash in the entire flash range of this [`Storage`] instance.
     pub async fn erase_all(
         &mut self,
     ) -> Result<(), sequential_storage::Error<<F as ErrorType>::Error>> {
+        // The cache does not know about the erase, so start over with an empty one.
+        self.cache = C::default();
         erase_all(&mut self.flash, self.storage_range.clone()).await
     }
 }
 
-impl<F: MultiwriteNorFlash> Storage<F> {
+impl<F: MultiwriteNorFlash, C: KeyCacheImpl<StorageKey> + Default> Storage<F, C> {
     /// Deletes an item from flash.
     ///
     /// Additional calls to [`Storage::get()`] with the same key will return `None` until
@@ -151,15 +268,208 @@ impl<F: MultiwriteNorFlash> Storage<F> {
         &mut self,
         key: &str,
     ) -> Result<(), sequential_storage::Error<<F as ErrorType>::Error>> {
-        let key = ArrayString::<MAX_KEY_LEN>::from(key).unwrap();
+        let key = StorageKey::from(key).unwrap();
         let mut data_buffer = [0; DATA_BUFFER_SIZE];
         remove_item(
             &mut self.flash,
             self.storage_range.clone(),
-            &mut NoCache::new(),
+            &mut self.cache,
             &mut data_buffer,
             &key,
         )
         .await
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use embassy_futures::block_on;
+    use sequential_storage::cache::KeyPointerCache;
+
+    use crate::emulated_flash::EmulatedFlash;
+
+    const PAGE_SIZE: usize = 1024;
+    const PAGES: usize = 4;
+
+    #[expect(clippy::cast_possible_truncation)]
+    fn storage() -> Storage<EmulatedFlash<PAGE_SIZE>> {
+        Storage::new(EmulatedFlash::new(PAGES), 0..(PAGES * PAGE_SIZE) as u32)
+    }
+
+    #[test]
+    fn insert_get() {
+        let mut s = storage();
+        block_on(async {
+            assert_eq!(s.get::<u32>("missing").await.unwrap(), None);
+
+            s.insert("counter", 1u32).await.unwrap();
+            s.insert("name", "ariel").await.unwrap();
+            assert_eq!(s.get::<u32>("counter").await.unwrap(), Some(1));
+            assert_eq!(
+                s.get::<arrayvec::ArrayString<16>>("name")
+                    .await
+                    .unwrap()
+                    .as_deref(),
+                Some("ariel")
+            );
+
+            // The last value stored wins.
+            s.insert("counter", 2u32).await.unwrap();
+            assert_eq!(s.get::<u32>("counter").await.unwrap(), Some(2));
+        });
+    }
+
+    #[test]
+    fn remove() {
+        let mut s = storage();
+        block_on(async {
+            s.insert("a", 1u32).await.unwrap();
+            s.insert("b", 2u32).await.unwrap();
+            s.remove("a").await.unwrap();
+            assert_eq!(s.get::<u32>("a").await.unwrap(), None);
+            assert_eq!(s.get::<u32>("b").await.unwrap(), Some(2));
+        });
+    }
+
+    #[test]
+    fn erase_all() {
+        let mut s = storage();
+        block_on(async {
+            s.insert("a", 1u32).await.unwrap();
+            s.erase_all().await.unwrap();
+            assert_eq!(s.get::<u32>("a").await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn wraps_around() {
+        // Overwriting the same key many times forces garbage collection of pages.
+        let mut s = storage();
+        block_on(async {
+            s.insert("other", 42u32).await.unwrap();
+            for i in 0..1000u32 {
+                s.insert("counter", i).await.unwrap();
+            }
+            assert_eq!(s.get::<u32>("counter").await.unwrap(), Some(999));
+            assert_eq!(s.get::<u32>("other").await.unwrap(), Some(42));
+        });
+    }
+
+    #[test]
+    #[expect(clippy::cast_possible_truncation)]
+    fn cached() {
+        let mut s: Storage<_, KeyPointerCache<PAGES, StorageKey, 4>> = Storage::with_cache(
+            EmulatedFlash::<PAGE_SIZE>::new(PAGES),
+            0..(PAGES * PAGE_SIZE) as u32,
+            KeyPointerCache::new(),
+        );
+        block_on(async {
+            for i in 0..100u32 {
+                s.insert("a", i).await.unwrap();
+                s.insert("b", i * 2).await.unwrap();
+            }
+            s.remove("a").await.unwrap();
+            assert_eq!(s.get::<u32>("a").await.unwrap(), None);
+            assert_eq!(s.get::<u32>("b").await.unwrap(), Some(198));
+
+            s.erase_all().await.unwrap();
+            assert_eq!(s.get::<u32>("b").await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn encrypted() {
+        // Nonces are taken from the global RNG.
+        ariel_os_random::mix_seed(&[0; 32]);
+        let sealer = Sealer::new(&[1; 32]);
+        let mut s = storage();
+        block_on(async {
+            s.insert_encrypted("secret", b"plaintext", &sealer)
+                .await
+                .unwrap();
+            assert!(!s.flash.as_bytes().windows(9).any(|w| w == b"plaintext"));
+            assert_eq!(
+                s.get_encrypted::<[u8; 9]>("secret", &sealer).await.unwrap(),
+                Some(*b"plaintext")
+            );
+            assert_eq!(
+                s.get_encrypted::<[u8; 9]>("missing", &sealer)
+                    .await
+                    .unwrap(),
+                None
+            );
+
+            // Values sealed with another key are rejected.
+            assert!(matches!(
+                s.get_encrypted::<[u8; 9]>("secret", &Sealer::new(&[2; 32]))
+                    .await,
+                Err(sequential_storage::Error::SerializationError(
+                    SerializationError::InvalidData
+                ))
+            ));
+            // So are values that were not sealed at all.
+            s.insert("plain", 1u32).await.unwrap();
+            assert!(matches!(
+                s.get_encrypted::<u32>("plain", &sealer).await,
+                Err(sequential_storage::Error::SerializationError(
+                    SerializationError::InvalidData
+                ))
+            ));
+        });
+    }
+
+    #[test]
+    fn power_loss_during_insert() {
+        // Interrupt the insertion after every number of bytes written or erased, until it
+        // completes, and check that after a reboot, either the old or the new value is visible
+        // and the storage remains usable.
+        let completed = (0..=PAGES * PAGE_SIZE).find(|&budget| {
+            let mut s = storage();
+            block_on(async {
+                s.insert("key", 1u32).await.unwrap();
+
+                s.flash.power_loss_after(budget);
+                let result = s.insert("key", 2u32).await;
+                s.flash.power_cycle();
+
+                let value = s.get::<u32>("key").await.unwrap();
+                if result.is_ok() {
+                    assert_eq!(value, Some(2));
+                } else {
+                    assert!(matches!(value, Some(1 | 2)), "budget {budget}: {value:?}");
+                }
+
+                s.insert("key", 3u32).await.unwrap();
+                assert_eq!(s.get::<u32>("key").await.unwrap(), Some(3));
+                result.is_ok()
+            })
+        });
+        assert!(completed.is_some(), "the insertion never completed");
+    }
+
+    #[test]
+    fn bit_flip() {
+        let mut s = storage();
+        block_on(async {
+            s.insert("key", 1u32).await.unwrap();
+            s.insert("key", 0x1234_5678u32).await.unwrap();
+
+            // Corrupt the value of the most recent item: its checksum no longer matches, so it is
+            // skipped, and the previous value is returned instead of a damaged one.
+            let mut encoded = [0; 8];
+            let encoded = postcard::to_slice(&0x1234_5678u32, &mut encoded).unwrap();
+            let offset = s
+                .flash
+                .as_bytes()
+                .windows(encoded.len())
+                .rposition(|w| w == encoded)
+                .unwrap();
+            s.flash.flip_bit(offset, 0);
+
+            assert_eq!(s.get::<u32>("key").await.unwrap(), Some(1));
+        });
+    }
+}
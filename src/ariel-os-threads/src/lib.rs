@@ -13,6 +13,8 @@
 //!
 //! Optionally, the stacksize and a priority between 1 and [`SCHED_PRIO_LEVELS`] can be configured.
 //! By default, the stack size is 2048 bytes and priority is 1.
+//! With the `stack-usage` feature, stacks are filled with a pattern when threads are created, so
+//! that [`stack_usage()`] can tell how much of its stack a thread has used.
 //!
 //! # Synchronization
 //!
@@ -58,6 +60,7 @@ pub mod events {
 }
 
 pub use ariel_os_runqueue::{RunqueueId, ThreadId};
+pub use thread::ThreadState;
 pub use thread_flags as flags;
 
 #[cfg(feature = "core-affinity")]
@@ -66,7 +69,7 @@ pub use smp::CoreAffinity;
 use arch::{schedule, Arch, Cpu, ThreadData};
 use ariel_os_runqueue::RunQueue;
 use ensure_once::EnsureOnce;
-use thread::{Thread, ThreadState};
+use thread::Thread;
 
 #[cfg(feature = "multi-core")]
 use smp::{schedule_on_core, Multicore};
@@ -95,6 +98,10 @@ pub const IDLE_THREAD_STACK_SIZE: usize = smp::Chip::IDLE_THREAD_STACK_SIZE;
 
 static SCHEDULER: EnsureOnce<Scheduler> = EnsureOnce::new(Scheduler::new());
 
+/// Byte that stacks are filled with when their thread is created.
+#[cfg(feature = "stack-usage")]
+const STACK_PAINT: u8 = 0xcc;
+
 pub type ThreadFn = fn();
 
 #[linkme::distributed_slice]
@@ -191,6 +198,11 @@ impl Scheduler {
         _core_affinity: Option<CoreAffinity>,
     ) -> Option<ThreadId> {
         let (thread, pid) = self.get_unused()?;
+        #[cfg(feature = "stack-usage")]
+        {
+            stack.fill(STACK_PAINT);
+            thread.stack = (stack.as_ptr() as usize, stack.len());
+        }
         Cpu::setup_stack(thread, stack, func, arg);
         thread.prio = prio;
         thread.pid = pid;
@@ -720,3 +732,45 @@ pub fn get_priority(thread_id: ThreadId) -> Option<RunqueueId> {
 pub fn set_priority(thread_id: ThreadId, prio: RunqueueId) {
     SCHEDULER.with_mut(|mut scheduler| scheduler.set_priority(thread_id, prio))
 }
+
+/// Returns the state of a thread.
+///
+/// Returns `None` if this is not a valid thread.
+pub fn get_state(thread_id: ThreadId) -> Option<ThreadState> {
+    SCHEDULER.with(|scheduler| scheduler.get_state(thread_id))
+}
+
+/// Stack usage of a thread, see [`stack_usage()`].
+#[cfg(feature = "stack-usage")]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StackUsage {
+    /// Size of the stack, in bytes.
+    pub size: usize,
+    /// Largest number of bytes of the stack the thread has used so far.
+    pub used: usize,
+}
+
+/// Returns how much of its stack a thread has used so far.
+///
+/// The usage is found by looking for the lowest byte of the stack that differs from the pattern
+/// the stack was filled with; bytes the thread wrote with the pattern's value are not counted.
+///
+/// Returns `None` if this is not a valid thread.
+#[cfg(feature = "stack-usage")]
+pub fn stack_usage(thread_id: ThreadId) -> Option<StackUsage> {
+    let (start, size) = SCHEDULER.with(|scheduler| {
+        scheduler
+            .is_valid_pid(thread_id)
+            .then(|| scheduler.get_unchecked(thread_id).stack)
+    })?;
+    let unused = (start..start + size)
+        // SAFETY: The stack of a valid thread is a `'static` slice handed to the scheduler, which
+        // stays readable; a byte the thread writes concurrently is read as either value.
+        .take_while(|address| unsafe { (*address as *const u8).read_volatile() } == STACK_PAINT)
+        .count();
+    Some(StackUsage {
+        size,
+        used: size - unused,
+    })
+}
@@ -24,6 +24,9 @@ pub struct Thread {
     /// Core affinity of the thread.
     #[cfg(feature = "core-affinity")]
     pub core_affinity: crate::CoreAffinity,
+    /// Lowest address and size of the thread's stack.
+    #[cfg(feature = "stack-usage")]
+    pub(crate) stack: (usize, usize),
 }
 
 /// Possible states of a thread
@@ -60,6 +63,8 @@ impl Thread {
             pid: ThreadId::new(0),
             #[cfg(feature = "core-affinity")]
             core_affinity: crate::CoreAffinity::no_affinity(),
+            #[cfg(feature = "stack-usage")]
+            stack: (0, 0),
         }
     }
 }
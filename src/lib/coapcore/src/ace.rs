@@ -0,0 +1,457 @@
+//! Support for ACE-OAuth (RFC 9200) in the EDHOC and OSCORE profile.
+//!
+//! Clients that the device does not know in advance obtain an access token from an
+//! authorization server (AS), and post it to the `/authz-info` resource without any protection.
+//! The token is a CWT (RFC 8392) encrypted in a `COSE_Encrypt0` object with a key shared between
+//! the AS and the device. It contains the client's credential (in the `cnf` claim) and the
+//! permissions granted to the client (in the `scope` claim, as an AIF value, see
+//! [`AifValue`](crate::authorization::AifValue)).
+//!
+//! When the client then runs EDHOC with that credential, the resulting security context gets the
+//! permissions of the token.
+//!
+//! Tokens are only accepted if their audience (the `aud` claim) is the one configured for this
+//! device in its [`AuthorizationServer`]. Their expiration time (the `exp` claim) is not checked:
+//! devices generally have no synchronized clock to compare it to. Instead, tokens are kept only
+//! until [`MAX_TOKENS`] newer ones have been posted, and an established security context keeps the
+//! permissions it was given until it is evicted.
+use minicbor::{data::Type, decode::Decoder};
+
+use crate::authorization::ccs_kid_matches;
+
+/// Length of the key shared with the authorization server.
+pub const KEY_LEN: usize = 16;
+
+/// An ACE authorization server whose tokens are accepted.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthorizationServer<'a> {
+    /// Key shared between the authorization server and this device.
+    pub key: &'a [u8; KEY_LEN],
+    /// Audience by which the authorization server identifies this device in its tokens.
+    pub audience: &'a str,
+}
+
+/// Number of tokens that are kept; when a further token is posted, the oldest one is dropped.
+pub const MAX_TOKENS: usize = 4;
+
+/// Maximum length of a client credential in a token.
+pub(crate) const MAX_CCS_LEN: usize = 128;
+
+/// Maximum length of the scope of a token.
+pub(crate) const MAX_SCOPE_LEN: usize = 64;
+
+/// Maximum length of a token.
+const MAX_TOKEN_LEN: usize = 256;
+
+/// COSE algorithm AES-CCM-16-64-128, the only one supported for tokens.
+const COSE_ALG_AES_CCM_16_64_128: i64 = 10;
+const NONCE_LEN: usize = 13;
+const TAG_LEN: usize = 8;
+
+/// CWT claim keys (RFC 8392, RFC 8747, RFC 9200)
+const CLAIM_AUD: i64 = 3;
+const CLAIM_CNF: i64 = 8;
+const CLAIM_SCOPE: i64 = 9;
+/// Confirmation method holding a CCS (RFC 9528 Section 3.5.2)
+const CNF_KCCS: i64 = 14;
+
+/// COSE header parameters
+const HEADER_ALG: i64 = 1;
+const HEADER_IV: i64 = 5;
+
+/// Error processing an access token.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    /// The token is not a well-formed CWT in a `COSE_Encrypt0` object, or lacks required claims.
+    Malformed,
+    /// The token was not issued by the authorization server, is meant for a different audience, or
+    /// uses an unsupported algorithm.
+    Unauthorized,
+    /// The token or any of its claims is too large to be processed.
+    TooLarge,
+}
+
+impl From<minicbor::decode::Error> for TokenError {
+    fn from(_: minicbor::decode::Error) -> Self {
+        Self::Malformed
+    }
+}
+
+/// The claims of an accepted access token.
+#[derive(Debug)]
+pub(crate) struct Token {
+    /// The client's credential, as a CCS.
+    pub(crate) ccs: heapless::Vec<u8, MAX_CCS_LEN>,
+    /// The permissions of the client, as an AIF value.
+    pub(crate) scope: heapless::Vec<u8, MAX_SCOPE_LEN>,
+}
+
+impl Token {
+    /// Verifies and decrypts a token posted to `/authz-info`.
+    ///
+    /// The payload is the token, either as it is or wrapped in a CBOR byte string. Its expiration
+    /// time is not checked; see the [module level documentation](self).
+    pub(crate) fn process(
+        server: &AuthorizationServer<'_>,
+        payload: &[u8],
+    ) -> Result<Self, TokenError> {
+        let mut decoder = Decoder::new(payload);
+        let token = if decoder.datatype()? == Type::Bytes {
+            decoder.bytes()?
+        } else {
+            payload
+        };
+
+        let mut decoder = Decoder::new(token);
+        if decoder.datatype()? == Type::Tag {
+            // COSE_Encrypt0 tag
+            decoder.tag()?;
+        }
+        if decoder.array()? != Some(3) {
+            return Err(TokenError::Malformed);
+        }
+        let protected = decoder.bytes()?;
+        if find_int(&mut Decoder::new(protected), HEADER_ALG)? != Some(COSE_ALG_AES_CCM_16_64_128) {
+            return Err(TokenError::Unauthorized);
+        }
+        let nonce: &[u8; NONCE_LEN] = find_bytes(&mut decoder, HEADER_IV)?
+            .ok_or(TokenError::Malformed)?
+            .try_into()
+            .map_err(|_| TokenError::Malformed)?;
+        let ciphertext = decoder.bytes()?;
+
+        let mut aad = [0; 48];
+        let aad = enc_structure(protected, &mut aad)?;
+
+        let mut plaintext = [0; MAX_TOKEN_LEN];
+        let plaintext = decrypt(server.key, nonce, aad, ciphertext, &mut plaintext)?;
+
+        Self::from_claims(plaintext, server.audience)
+    }
+
+    fn from_claims(claims: &[u8], audience: &str) -> Result<Self, TokenError> {
+        let mut aud = None;
+        let mut ccs = None;
+        let mut scope = None;
+
+        let mut decoder = Decoder::new(claims);
+        let count = decoder.map()?.ok_or(TokenError::Malformed)?;
+        for _ in 0..count {
+            match int_key(&mut decoder)? {
+                Some(CLAIM_AUD) => aud = Some(decoder.str()?),
+                Some(CLAIM_SCOPE) => scope = Some(decoder.bytes()?),
+                Some(CLAIM_CNF) => {
+                    let count = decoder.map()?.ok_or(TokenError::Malformed)?;
+                    for _ in 0..count {
+                        if int_key(&mut decoder)? == Some(CNF_KCCS) {
+                            let start = decoder.position();
+                            decoder.skip()?;
+                            ccs = claims.get(start..decoder.position());
+                        } else {
+                            decoder.skip()?;
+                        }
+                    }
+                }
+                _ => decoder.skip()?,
+            }
+        }
+
+        if aud != Some(audience) {
+            return Err(TokenError::Unauthorized);
+        }
+
+        Ok(Self {
+            ccs: heapless::Vec::from_slice(ccs.ok_or(TokenError::Malformed)?)
+                .map_err(|()| TokenError::TooLarge)?,
+            scope: heapless::Vec::from_slice(scope.ok_or(TokenError::Malformed)?)
+                .map_err(|()| TokenError::TooLarge)?,
+        })
+    }
+}
+
+/// Tokens accepted through `/authz-info`, whose clients have not necessarily run EDHOC yet.
+#[derive(Default)]
+pub(crate) struct TokenPool {
+    tokens: heapless::Deque<Token, MAX_TOKENS>,
+}
+
+impl TokenPool {
+    /// Adds a token, replacing any earlier token for the same credential, or else the oldest
+    /// token if the pool is full.
+    pub(crate) fn insert(&mut self, token: Token) {
+        let mut kept = heapless::Deque::new();
+        while let Some(old) = self.tokens.pop_front() {
+            if old.ccs != token.ccs {
+                let _ = kept.push_back(old);
+            }
+        }
+        self.tokens = kept;
+        if self.tokens.is_full() {
+            self.tokens.pop_front();
+        }
+        let _ = self.tokens.push_back(token);
+    }
+
+    /// Finds the latest token for a credential that was sent in an `ID_CRED_x` by reference; see
+    /// [`PeerTable::find_by_kid()`](crate::authorization::PeerTable).
+    pub(crate) fn find_by_kid(&self, encoded_kid: &[u8]) -> Option<&Token> {
+        self.tokens
+            .iter()
+            .rev()
+            .find(|token| ccs_kid_matches(&token.ccs, encoded_kid))
+    }
+
+    /// Finds the latest token for a credential that was sent by value.
+    pub(crate) fn find_by_ccs(&self, ccs: &[u8]) -> Option<&Token> {
+        self.tokens.iter().rev().find(|token| token.ccs == ccs)
+    }
+}
+
+/// Decodes a map key, returning `None` for keys that are not integers.
+fn int_key(decoder: &mut Decoder<'_>) -> Result<Option<i64>, TokenError> {
+    if decoder.datatype()? == Type::String {
+        decoder.skip()?;
+        return Ok(None);
+    }
+    Ok(Some(decoder.i64()?))
+}
+
+/// Decodes a map, returning the integer value of the entry with key `key`.
+fn find_int(decoder: &mut Decoder<'_>, key: i64) -> Result<Option<i64>, TokenError> {
+    let mut found = None;
+    let count = decoder.map()?.ok_or(TokenError::Malformed)?;
+    for _ in 0..count {
+        if int_key(decoder)? == Some(key) {
+            found = Some(decoder.i64()?);
+        } else {
+            decoder.skip()?;
+        }
+    }
+    Ok(found)
+}
+
+/// Decodes a map, returning the byte string value of the entry with key `key`.
+fn find_bytes<'b>(decoder: &mut Decoder<'b>, key: i64) -> Result<Option<&'b [u8]>, TokenError> {
+    let mut found = None;
+    let count = decoder.map()?.ok_or(TokenError::Malformed)?;
+    for _ in 0..count {
+        if int_key(decoder)? == Some(key) {
+            found = Some(decoder.bytes()?);
+        } else {
+            decoder.skip()?;
+        }
+    }
+    Ok(found)
+}
+
+/// Builds the `Enc_structure` of a `COSE_Encrypt0` object without external AAD (RFC 9052
+/// Section 5.3) into `buffer`.
+fn enc_structure<'b>(protected: &[u8], buffer: &'b mut [u8]) -> Result<&'b [u8], TokenError> {
+    const PREFIX: &[u8] = b"\x83\x68Encrypt0";
+
+    let len = u8::try_from(protected.len()).map_err(|_| TokenError::TooLarge)?;
+    let short_head = [0x40 | len];
+    let long_head = [0x58, len];
+    let head: &[u8] = if len < 24 { &short_head } else { &long_head };
+    let total = PREFIX.len() + head.len() + protected.len() + 1;
+    let out = buffer.get_mut(..total).ok_or(TokenError::TooLarge)?;
+    let (prefix, rest) = out.split_at_mut(PREFIX.len());
+    prefix.copy_from_slice(PREFIX);
+    let (out_head, rest) = rest.split_at_mut(head.len());
+    out_head.copy_from_slice(head);
+    let (out_protected, external_aad) = rest.split_at_mut(protected.len());
+    out_protected.copy_from_slice(protected);
+    // Empty byte string
+    external_aad.copy_from_slice(&[0x40]);
+    Ok(out)
+}
+
+/// Decrypts and verifies `ciphertext` (which includes the tag) into `buffer`.
+fn decrypt<'b>(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    ciphertext: &[u8],
+    buffer: &'b mut [u8],
+) -> Result<&'b [u8], TokenError> {
+    use ccm::aead::{AeadInPlace, KeyInit};
+    type Aead = ccm::Ccm<aes::Aes128, ccm::consts::U8, ccm::consts::U13>;
+
+    let len = ciphertext
+        .len()
+        .checked_sub(TAG_LEN)
+        .ok_or(TokenError::Malformed)?;
+    let (ciphertext, tag) = ciphertext.split_at(len);
+    let plaintext = buffer.get_mut(..len).ok_or(TokenError::TooLarge)?;
+    plaintext.copy_from_slice(ciphertext);
+
+    Aead::new(key.into())
+        .decrypt_in_place_detached(nonce.into(), aad, plaintext, tag.into())
+        .map_err(|_| TokenError::Unauthorized)?;
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: [u8; KEY_LEN] = [0x42; KEY_LEN];
+
+    const SERVER: AuthorizationServer<'static> = AuthorizationServer {
+        key: &KEY,
+        audience: "device",
+    };
+
+    /// The credential of the peer used in the Ariel OS CoAP tests, whose KID is `h'2b'`.
+    const CCS: &[u8] = &hexlit::hex!("A2027734322D35302D33312D46462D45462D33372D33322D333908A101A5010202412B2001215820AC75E9ECE3E50BFC8ED60399889522405C47BF16DF96660A41298CB4307F7EB62258206E5DE611388A4B8A8211334AC7D37ECB52A387D257E6DB3C2A93DF21FF3AFFC8");
+
+    // [["/stdout", 1]]
+    const SCOPE: &[u8] = &hexlit::hex!("8182672f7374646f757401");
+
+    type Buffer = heapless::Vec<u8, MAX_TOKEN_LEN>;
+
+    fn push_head(out: &mut Buffer, major: u8, len: usize) {
+        let len = u8::try_from(len).unwrap();
+        if len < 24 {
+            out.push((major << 5) | len).unwrap();
+        } else {
+            out.extend_from_slice(&[(major << 5) | 0x18, len]).unwrap();
+        }
+    }
+
+    /// Issues a token the way an authorization server would, for the audience `aud` if given.
+    fn issue(key: &[u8; KEY_LEN], alg: u8, aud: Option<&str>, ccs: &[u8], scope: &[u8]) -> Buffer {
+        use ccm::aead::{AeadInPlace, KeyInit};
+        type Aead = ccm::Ccm<aes::Aes128, ccm::consts::U8, ccm::consts::U13>;
+
+        // {3: aud, 8: {14: ccs}, 9: scope}
+        let mut claims = Buffer::new();
+        if let Some(aud) = aud {
+            claims.extend_from_slice(&[0xa3, 0x03]).unwrap();
+            push_head(&mut claims, 3, aud.len());
+            claims.extend_from_slice(aud.as_bytes()).unwrap();
+        } else {
+            claims.push(0xa2).unwrap();
+        }
+        claims.extend_from_slice(&[0x08, 0xa1, 0x0e]).unwrap();
+        claims.extend_from_slice(ccs).unwrap();
+        claims.push(0x09).unwrap();
+        push_head(&mut claims, 2, scope.len());
+        claims.extend_from_slice(scope).unwrap();
+
+        let protected = [0xa1, 0x01, alg];
+        let nonce = [0x13; NONCE_LEN];
+        let mut aad = [0; 48];
+        let aad = enc_structure(&protected, &mut aad).unwrap();
+        let tag = Aead::new(key.into())
+            .encrypt_in_place_detached(&nonce.into(), aad, &mut claims)
+            .unwrap();
+
+        // 16([protected, {5: nonce}, ciphertext])
+        let mut token = Buffer::new();
+        token.extend_from_slice(&[0xd0, 0x83, 0x43]).unwrap();
+        token.extend_from_slice(&protected).unwrap();
+        token.extend_from_slice(&[0xa1, 0x05, 0x4d]).unwrap();
+        token.extend_from_slice(&nonce).unwrap();
+        push_head(&mut token, 2, claims.len() + TAG_LEN);
+        token.extend_from_slice(&claims).unwrap();
+        token.extend_from_slice(&tag).unwrap();
+        token
+    }
+
+    #[test]
+    fn process() {
+        let token = issue(&KEY, 10, Some("device"), CCS, SCOPE);
+        let processed = Token::process(&SERVER, &token).unwrap();
+        assert_eq!(processed.ccs, CCS);
+        assert_eq!(processed.scope, SCOPE);
+
+        // Wrapped in a byte string
+        let mut wrapped = heapless::Vec::<u8, { MAX_TOKEN_LEN + 2 }>::new();
+        wrapped
+            .extend_from_slice(&[0x58, u8::try_from(token.len()).unwrap()])
+            .unwrap();
+        wrapped.extend_from_slice(&token).unwrap();
+        assert!(Token::process(&SERVER, &wrapped).is_ok());
+    }
+
+    #[test]
+    fn rejected() {
+        let token = issue(&[0; KEY_LEN], 10, Some("device"), CCS, SCOPE);
+        assert_eq!(
+            Token::process(&SERVER, &token).unwrap_err(),
+            TokenError::Unauthorized
+        );
+
+        let token = issue(&KEY, 11, Some("device"), CCS, SCOPE);
+        assert_eq!(
+            Token::process(&SERVER, &token).unwrap_err(),
+            TokenError::Unauthorized
+        );
+
+        let mut token = issue(&KEY, 10, Some("device"), CCS, SCOPE);
+        *token.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            Token::process(&SERVER, &token).unwrap_err(),
+            TokenError::Unauthorized
+        );
+
+        // Meant for a different device, or for any
+        for aud in [Some("other"), None] {
+            let token = issue(&KEY, 10, aud, CCS, SCOPE);
+            assert_eq!(
+                Token::process(&SERVER, &token).unwrap_err(),
+                TokenError::Unauthorized
+            );
+        }
+
+        assert_eq!(
+            Token::process(&SERVER, &[0x83, 0x40]).unwrap_err(),
+            TokenError::Malformed
+        );
+    }
+
+    /// Returns [`CCS`] with its KID replaced by `kid`.
+    fn ccs_with_kid(kid: u8) -> heapless::Vec<u8, MAX_CCS_LEN> {
+        let mut ccs = heapless::Vec::from_slice(CCS).unwrap();
+        let position = ccs.windows(2).position(|w| *w == [0x41, 0x2b]).unwrap();
+        *ccs.get_mut(position + 1).unwrap() = kid;
+        ccs
+    }
+
+    #[test]
+    fn pool() {
+        let mut pool = TokenPool::default();
+        let insert = |pool: &mut TokenPool, kid| {
+            let token = issue(&KEY, 10, Some("device"), &ccs_with_kid(kid), SCOPE);
+            pool.insert(Token::process(&SERVER, &token).unwrap());
+        };
+        // Tokens for the same credential replace each other.
+        for _ in 0..=MAX_TOKENS {
+            insert(&mut pool, 0x2b);
+        }
+        assert_eq!(pool.tokens.len(), 1);
+        assert!(pool.find_by_kid(&[0x2b]).is_some());
+        assert!(pool.find_by_ccs(CCS).is_some());
+        assert!(pool.find_by_ccs(CCS.split_first().unwrap().1).is_none());
+
+        // When the pool is full, the oldest token is dropped.
+        let kids = 1..=u8::try_from(MAX_TOKENS).unwrap();
+        for kid in kids.clone() {
+            insert(&mut pool, kid);
+        }
+        assert_eq!(pool.tokens.len(), MAX_TOKENS);
+        assert!(pool.find_by_kid(&[0x2b]).is_none());
+        for kid in kids.clone() {
+            assert!(pool.find_by_ccs(&ccs_with_kid(kid)).is_some());
+        }
+
+        // Renewing a token does not drop another one.
+        insert(&mut pool, 1);
+        assert_eq!(pool.tokens.len(), MAX_TOKENS);
+        for kid in kids {
+            assert!(pool.find_by_kid(&[kid]).is_some());
+        }
+    }
+}
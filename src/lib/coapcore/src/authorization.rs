@@ -0,0 +1,536 @@
+//! Authorization of peers to access resources.
+//!
+//! Permissions are expressed in the REST-specific model of the Authorization Information Format
+//! (AIF, RFC 9237): a list of local resource paths, each with a set of allowed methods. A
+//! [`PeerTable`] maps the credentials of known peers to such permissions, and also holds those of
+//! peers that are not known, and of requests that are not protected at all.
+//!
+//! Permissions are checked by the [`OscoreEdhocHandler`](crate::seccontext::OscoreEdhocHandler)
+//! before any request data is extracted by the inner handler.
+use coap_message::{MessageOption, ReadableMessage};
+use minicbor::decode::Decoder;
+use sha2::{Digest, Sha256};
+
+use crate::ace::MAX_SCOPE_LEN;
+use crate::eviction::ContextAuthorization;
+
+/// Length of a credential thumbprint, see [`PeerCredential::Thumbprint`].
+pub const THUMBPRINT_LEN: usize = 32;
+
+/// An AIF value in the REST-specific model, encoded as CBOR: `[* [Toid: tstr, Tperm: uint]]`.
+///
+/// `Toid` is the path of a resource (e.g., `"/stdout"`, or `"/"` for the root resource), and
+/// `Tperm` the set of methods allowed on it, where GET is `1`, POST is `2`, PUT is `4`, DELETE is
+/// `8`, FETCH is `16`, PATCH is `32`, and iPATCH is `64`.
+///
+/// Values are not validated when constructed; a value that is not well-formed allows nothing.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AifValue<'a>(&'a [u8]);
+
+impl<'a> AifValue<'a> {
+    /// Wraps a CBOR encoded AIF value.
+    #[must_use]
+    pub const fn new(cbor: &'a [u8]) -> Self {
+        Self(cbor)
+    }
+
+    /// Returns the CBOR encoding of the value.
+    #[must_use]
+    pub fn as_cbor(&self) -> &'a [u8] {
+        self.0
+    }
+
+    /// Returns whether any entry of the value allows the method `code` on a path for which
+    /// `matches` returns true.
+    fn allows(&self, code: u8, mut matches: impl FnMut(&str) -> bool) -> bool {
+        let Some(method) = method_bit(code) else {
+            return false;
+        };
+
+        let mut decoder = Decoder::new(self.0);
+        let Ok(Some(count)) = decoder.array() else {
+            return false;
+        };
+        for _ in 0..count {
+            let Some((toid, tperm)) = decode_entry(&mut decoder) else {
+                return false;
+            };
+            if tperm & method != 0 && matches(toid) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Decodes a single `[Toid, Tperm]` entry of an AIF value.
+fn decode_entry<'b>(decoder: &mut Decoder<'b>) -> Option<(&'b str, u64)> {
+    if decoder.array().ok()? != Some(2) {
+        return None;
+    }
+    Some((decoder.str().ok()?, decoder.u64().ok()?))
+}
+
+/// Returns the `Tperm` bit of a request method code, if it has one.
+fn method_bit(code: u8) -> Option<u64> {
+    match code {
+        // GET to iPATCH
+        1..=7 => Some(1 << (code - 1)),
+        _ => None,
+    }
+}
+
+/// Returns whether the `Toid` of an AIF entry is the path given in the Uri-Path `options`.
+fn toid_matches<O: MessageOption>(toid: &str, mut options: impl Iterator<Item = O>) -> bool {
+    let Some(toid) = toid.strip_prefix('/') else {
+        return false;
+    };
+    if toid.is_empty() {
+        return options.next().is_none();
+    }
+
+    let mut segments = toid.split('/');
+    loop {
+        match (segments.next(), options.next()) {
+            (None, None) => return true,
+            (Some(segment), Some(option)) if segment.as_bytes() == option.value() => {}
+            _ => return false,
+        }
+    }
+}
+
+/// Returns whether the path given in the Uri-Path `options` is `path`, or below it.
+fn path_is_below<O: MessageOption>(path: &str, mut options: impl Iterator<Item = O>) -> bool {
+    let Some(path) = path.strip_prefix('/') else {
+        return false;
+    };
+    if path.is_empty() {
+        return true;
+    }
+
+    path.split('/').all(|segment| {
+        options
+            .next()
+            .is_some_and(|option| segment.as_bytes() == option.value())
+    })
+}
+
+/// Permissions granted to a peer.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope<'a> {
+    /// Any request is allowed.
+    AllowAll,
+    /// Requests are allowed as described by an AIF value.
+    Aif(AifValue<'a>),
+}
+
+impl Scope<'static> {
+    /// A scope that does not allow any request.
+    pub const DENY_ALL: Self = Self::Aif(AifValue::new(&[0x80]));
+}
+
+impl Scope<'_> {
+    /// Returns whether the scope allows the request.
+    pub fn request_is_allowed<M: ReadableMessage>(&self, request: &M) -> bool {
+        // FIXME: We're iterating over options without checking for critical options. If the
+        // resource handler router consumes any different set of options, that disagreement might
+        // give us a security issue.
+        match self {
+            Self::AllowAll => true,
+            Self::Aif(aif) => aif.allows(request.code().into(), |toid| {
+                toid_matches(
+                    toid,
+                    request
+                        .options()
+                        .filter(|o| o.number() == coap_numbers::option::URI_PATH),
+                )
+            }),
+        }
+    }
+}
+
+/// Credential by which a peer is recognized.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerCredential<'a> {
+    /// A CWT Claims Set (CCS, RFC 8392).
+    ///
+    /// The peer is recognized both when it sends its credential by value, and when it refers to
+    /// it through the KID of its COSE key.
+    Ccs(&'a [u8]),
+    /// The SHA-256 hash of a CCS.
+    ///
+    /// The peer is only recognized when it sends its credential by value.
+    Thumbprint([u8; THUMBPRINT_LEN]),
+}
+
+impl PeerCredential<'_> {
+    /// Returns the thumbprint of a CCS.
+    #[must_use]
+    pub fn thumbprint(ccs: &[u8]) -> [u8; THUMBPRINT_LEN] {
+        Sha256::digest(ccs).into()
+    }
+
+    /// Returns the thumbprint of the credential.
+    fn own_thumbprint(&self) -> [u8; THUMBPRINT_LEN] {
+        match self {
+            Self::Ccs(ccs) => Self::thumbprint(ccs),
+            Self::Thumbprint(thumbprint) => *thumbprint,
+        }
+    }
+
+    fn matches_ccs(&self, ccs: &[u8]) -> bool {
+        match self {
+            Self::Ccs(own) => *own == ccs,
+            Self::Thumbprint(thumbprint) => *thumbprint == Self::thumbprint(ccs),
+        }
+    }
+}
+
+/// A known peer, and its permissions.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer<'a> {
+    /// Credential by which the peer is recognized.
+    pub credential: PeerCredential<'a>,
+    /// Permissions of the peer.
+    pub scope: Scope<'a>,
+}
+
+/// Permissions of known peers, of unknown peers, and of unprotected requests.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerTable<'a> {
+    /// Known peers.
+    ///
+    /// If a peer matches several entries, the first one applies.
+    pub peers: &'a [Peer<'a>],
+    /// Permissions of peers that authenticated through EDHOC with a credential that is not in
+    /// [`peers`](Self::peers).
+    pub unauthenticated: Scope<'a>,
+    /// Permissions for requests that are not protected by OSCORE.
+    pub nosec: Scope<'a>,
+    /// Paths (e.g., `"/admin"`) at and below which only known peers and holders of access tokens
+    /// may access resources, whatever [`unauthenticated`](Self::unauthenticated) and
+    /// [`nosec`](Self::nosec) allow.
+    pub restricted: &'a [&'a str],
+}
+
+impl<'a> PeerTable<'a> {
+    /// Creates a table of known peers, where other peers and unprotected requests are allowed
+    /// nothing.
+    ///
+    /// Permissions for them need to be granted explicitly through
+    /// [`with_unauthenticated()`](Self::with_unauthenticated) and
+    /// [`with_nosec()`](Self::with_nosec).
+    #[must_use]
+    pub const fn new(peers: &'a [Peer<'a>]) -> Self {
+        Self {
+            peers,
+            unauthenticated: Scope::DENY_ALL,
+            nosec: Scope::DENY_ALL,
+            restricted: &[],
+        }
+    }
+
+    /// Sets the permissions of peers that are not known.
+    #[must_use]
+    pub const fn with_unauthenticated(mut self, scope: Scope<'a>) -> Self {
+        self.unauthenticated = scope;
+        self
+    }
+
+    /// Sets the permissions for requests that are not protected by OSCORE.
+    #[must_use]
+    pub const fn with_nosec(mut self, scope: Scope<'a>) -> Self {
+        self.nosec = scope;
+        self
+    }
+
+    /// Sets the paths that only known peers and holders of access tokens may access.
+    #[must_use]
+    pub const fn with_restricted(mut self, paths: &'a [&'a str]) -> Self {
+        self.restricted = paths;
+        self
+    }
+
+    /// Returns whether a request is allowed, given the authorization kept in its security context,
+    /// or `None` if it is not protected by OSCORE.
+    pub(crate) fn request_is_allowed<M: ReadableMessage>(
+        &self,
+        authorized: Option<&Authorized>,
+        request: &M,
+    ) -> bool {
+        let (scope, restricted) = match authorized {
+            None => (self.nosec, true),
+            Some(authorized) => (
+                self.scope(authorized),
+                matches!(authorized, Authorized::Unauthenticated),
+            ),
+        };
+        scope.request_is_allowed(request)
+            && !(restricted
+                && self.restricted.iter().any(|path| {
+                    path_is_below(
+                        path,
+                        request
+                            .options()
+                            .filter(|o| o.number() == coap_numbers::option::URI_PATH),
+                    )
+                }))
+    }
+
+    /// Finds the peer whose credential has the KID that was sent in an `ID_CRED_x` by reference.
+    ///
+    /// `encoded_kid` is the encoded value of the `ID_CRED_x`, i.e., the KID as a CBOR byte string,
+    /// or as a CBOR integer for KIDs that are a single byte encoding an integer (RFC 9528 Section
+    /// 3.5.3.2).
+    pub(crate) fn find_by_kid(&self, encoded_kid: &[u8]) -> Option<(usize, &'a [u8])> {
+        self.peers
+            .iter()
+            .enumerate()
+            .find_map(|(index, peer)| match peer.credential {
+                PeerCredential::Ccs(ccs) if ccs_kid_matches(ccs, encoded_kid) => Some((index, ccs)),
+                _ => None,
+            })
+    }
+
+    /// Finds the peer that sent its credential by value.
+    pub(crate) fn find_by_ccs(&self, ccs: &[u8]) -> Option<usize> {
+        self.peers
+            .iter()
+            .position(|peer| peer.credential.matches_ccs(ccs))
+    }
+
+    /// Finds the peer whose credential has the given thumbprint.
+    pub(crate) fn find_by_thumbprint(&self, thumbprint: &[u8; THUMBPRINT_LEN]) -> Option<usize> {
+        self.peers
+            .iter()
+            .position(|peer| peer.credential.own_thumbprint() == *thumbprint)
+    }
+
+    /// Returns the thumbprint of the credential of a peer.
+    pub(crate) fn thumbprint(&self, index: usize) -> Option<[u8; THUMBPRINT_LEN]> {
+        self.peers
+            .get(index)
+            .map(|peer| peer.credential.own_thumbprint())
+    }
+
+    /// Returns the permissions of an authorization kept in a security context.
+    pub(crate) fn scope<'b>(&self, authorized: &'b Authorized) -> Scope<'b>
+    where
+        'a: 'b,
+    {
+        match authorized {
+            Authorized::Unauthenticated => self.unauthenticated,
+            Authorized::Peer(index) => self
+                .peers
+                .get(*index)
+                .map_or(Scope::DENY_ALL, |peer| peer.scope),
+            Authorized::Token(scope) => Scope::Aif(AifValue::new(scope)),
+        }
+    }
+
+    /// Describes an authorization kept in a security context to an eviction policy.
+    pub(crate) fn context_authorization<'b>(
+        &'b self,
+        authorized: &'b Authorized,
+    ) -> ContextAuthorization<'b>
+    where
+        'a: 'b,
+    {
+        match authorized {
+            Authorized::Unauthenticated => ContextAuthorization::Unauthenticated,
+            Authorized::Peer(index) => self
+                .peers
+                .get(*index)
+                .map_or(ContextAuthorization::Unauthenticated, |peer| {
+                    ContextAuthorization::Peer(*index, peer)
+                }),
+            Authorized::Token(scope) => ContextAuthorization::Token(AifValue::new(scope)),
+        }
+    }
+}
+
+/// The authorization of a security context.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Authorized {
+    /// The peer is not known.
+    Unauthenticated,
+    /// The peer is a known peer, at this index into the [`PeerTable`].
+    Peer(usize),
+    /// The peer presented an access token with this scope, see [`ace`](crate::ace).
+    Token(heapless::Vec<u8, MAX_SCOPE_LEN>),
+}
+
+/// Returns whether the KID of the COSE key in a CCS (in its `cnf` claim) has the given encoding.
+pub(crate) fn ccs_kid_matches(ccs: &[u8], encoded_kid: &[u8]) -> bool {
+    let Some(kid) = ccs_kid(ccs) else {
+        return false;
+    };
+    match kid {
+        // Single-byte KIDs that are the encoding of an integer are sent as that integer.
+        [byte] if *byte <= 0x17 || (0x20..=0x37).contains(byte) => encoded_kid == kid,
+        _ => {
+            let mut decoder = Decoder::new(encoded_kid);
+            decoder.bytes().is_ok_and(|bytes| bytes == kid)
+                && decoder.position() == encoded_kid.len()
+        }
+    }
+}
+
+/// Extracts the KID from a CCS: `{ ..., 8 (cnf): { 1 (COSE_Key): { ..., 2 (kid): bstr } } }`.
+fn ccs_kid(ccs: &[u8]) -> Option<&[u8]> {
+    let mut decoder = Decoder::new(ccs);
+    enter_map_at(&mut decoder, 8)?;
+    enter_map_at(&mut decoder, 1)?;
+    let count = decoder.map().ok()??;
+    for _ in 0..count {
+        if decoder.i64().ok()? == 2 {
+            return decoder.bytes().ok();
+        }
+        decoder.skip().ok()?;
+    }
+    None
+}
+
+/// Advances a decoder that is at the start of a map to the value of the integer key `key`.
+fn enter_map_at(decoder: &mut Decoder<'_>, key: i64) -> Option<()> {
+    let count = decoder.map().ok()??;
+    for _ in 0..count {
+        // Keys of CCS claims and COSE keys are integers (or strings, which we skip over).
+        let found = match decoder.datatype().ok()? {
+            minicbor::data::Type::String => {
+                decoder.skip().ok()?;
+                false
+            }
+            _ => decoder.i64().ok()? == key,
+        };
+        if found {
+            return Some(());
+        }
+        decoder.skip().ok()?;
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The credential of the peer used in the Ariel OS CoAP tests, whose KID is `h'2b'`.
+    const CCS: &[u8] = &hexlit::hex!("A2027734322D35302D33312D46462D45462D33372D33322D333908A101A5010202412B2001215820AC75E9ECE3E50BFC8ED60399889522405C47BF16DF96660A41298CB4307F7EB62258206E5DE611388A4B8A8211334AC7D37ECB52A387D257E6DB3C2A93DF21FF3AFFC8");
+
+    // [["/stdout", 1], ["/", 5], ["/led/0", 4]]
+    const AIF: AifValue<'static> = AifValue::new(&hexlit::hex!(
+        "8382672f7374646f75740182612f0582662f6c65642f3004"
+    ));
+
+    fn allows(aif: AifValue<'_>, code: u8, path: &[&str]) -> bool {
+        aif.allows(code, |toid| {
+            let Some(toid) = toid.strip_prefix('/') else {
+                return false;
+            };
+            if toid.is_empty() {
+                return path.is_empty();
+            }
+            toid.split('/').eq(path.iter().copied())
+        })
+    }
+
+    #[test]
+    fn aif() {
+        assert!(allows(AIF, 1, &["stdout"]));
+        assert!(!allows(AIF, 3, &["stdout"]));
+        assert!(allows(AIF, 1, &[]));
+        assert!(allows(AIF, 3, &[]));
+        assert!(!allows(AIF, 2, &[]));
+        assert!(allows(AIF, 3, &["led", "0"]));
+        assert!(!allows(AIF, 3, &["led"]));
+        assert!(!allows(AIF, 3, &["led", "1"]));
+
+        // Not a request code
+        assert!(!allows(AIF, 0x45, &["stdout"]));
+        // Malformed values allow nothing.
+        assert!(!allows(AifValue::new(&[0x82, 0x82]), 1, &["stdout"]));
+        let Scope::Aif(deny_all) = Scope::DENY_ALL else {
+            unreachable!()
+        };
+        assert!(!allows(deny_all, 1, &[]));
+    }
+
+    #[test]
+    fn peers() {
+        let peers = [
+            Peer {
+                credential: PeerCredential::Thumbprint([0; THUMBPRINT_LEN]),
+                scope: Scope::DENY_ALL,
+            },
+            Peer {
+                credential: PeerCredential::Ccs(CCS),
+                scope: Scope::AllowAll,
+            },
+        ];
+        let table = PeerTable::new(&peers);
+        assert_eq!(table.unauthenticated, Scope::DENY_ALL);
+        assert_eq!(table.nosec, Scope::DENY_ALL);
+
+        assert_eq!(table.find_by_kid(&[0x2b]), Some((1, CCS)));
+        assert_eq!(table.find_by_kid(&[0x41, 0x2b]), None);
+        assert_eq!(table.find_by_kid(&[0x0a]), None);
+        assert_eq!(table.find_by_ccs(CCS), Some(1));
+        assert_eq!(table.find_by_ccs(CCS.split_first().unwrap().1), None);
+
+        let by_thumbprint = [Peer {
+            credential: PeerCredential::Thumbprint(PeerCredential::thumbprint(CCS)),
+            scope: Scope::AllowAll,
+        }];
+        let table = PeerTable::new(&by_thumbprint);
+        assert_eq!(table.find_by_ccs(CCS), Some(0));
+        assert_eq!(table.find_by_kid(&[0x2b]), None);
+
+        assert_eq!(table.scope(&Authorized::Peer(0)), Scope::AllowAll);
+        assert_eq!(table.scope(&Authorized::Peer(1)), Scope::DENY_ALL);
+        let token = Authorized::Token(heapless::Vec::from_slice(&[0x80]).unwrap());
+        assert_eq!(table.scope(&token), Scope::DENY_ALL);
+    }
+
+    #[test]
+    fn restricted() {
+        use coap_message::MinimalWritableMessage as _;
+
+        let peers = [Peer {
+            credential: PeerCredential::Ccs(CCS),
+            scope: Scope::AllowAll,
+        }];
+        let table = PeerTable::new(&peers)
+            .with_unauthenticated(Scope::AllowAll)
+            .with_nosec(Scope::AllowAll)
+            .with_restricted(&["/ariel"]);
+        let allowed = |authorized: Option<&Authorized>, path: &[&str]| {
+            let mut code = 0;
+            let mut buffer = [0; 64];
+            let mut request =
+                coap_message_implementations::inmemory_write::Message::new(&mut code, &mut buffer);
+            request.set_code(coap_numbers::code::GET);
+            for segment in path {
+                request
+                    .add_option(coap_numbers::option::URI_PATH, segment.as_bytes())
+                    .unwrap();
+            }
+            table.request_is_allowed(authorized, &request)
+        };
+
+        for authorized in [None, Some(&Authorized::Unauthenticated)] {
+            assert!(allowed(authorized, &["hello"]));
+            assert!(allowed(authorized, &[]));
+            assert!(allowed(authorized, &["arielx"]));
+            assert!(!allowed(authorized, &["ariel"]));
+            assert!(!allowed(authorized, &["ariel", "storage"]));
+        }
+        assert!(allowed(Some(&Authorized::Peer(0)), &["ariel", "storage"]));
+    }
+}
@@ -0,0 +1,927 @@
+//! Block-wise transfers (RFC 7959)
+//!
+//! This module provides the building blocks for moving representations that exceed a single
+//! message:
+//!
+//! * [`Block`] is the value of a Block1 or Block2 option.
+//! * [`Block2Handler`] and [`Block1Handler`] wrap a CoAP handler to serve its responses in blocks,
+//!   and to reassemble requests sent in blocks, respectively.
+//! * [`request_blockwise()`] sends a request through any [`coap_request::Stack`], splitting its
+//!   payload into Block1 blocks and collecting the response's Block2 blocks.
+//!
+//! # Interaction with OSCORE
+//!
+//! OSCORE (RFC 8613 Section 4.1.3.4) distinguishes inner and outer Block options:
+//!
+//! * Inner Block options are end-to-end: each block is a separate request that is protected on
+//!   its own. On the server side, the wrappers in this module are placed *inside* the
+//!   [`OscoreEdhocHandler`](crate::seccontext::OscoreEdhocHandler), where they see the decrypted
+//!   requests; on the client side, [`request_blockwise()`] is run over a
+//!   [`ProtectedStack`](crate::client::ProtectedStack). This is the recommended way to transfer
+//!   large protected representations.
+//! * Outer Block options fragment an already protected message, eg. when a proxy's messages are
+//!   smaller than the endpoints'. The `OscoreEdhocHandler` reassembles requests that carry an
+//!   outer Block1 option before verifying them; they still need to fit its message buffer.
+
+use coap_handler::Handler;
+use coap_message::{
+    error::RenderableOnMinimal, Code as _, MessageOption as _, MinimalWritableMessage,
+    MutableWritableMessage, ReadableMessage,
+};
+use coap_message_implementations::{inmemory, inmemory_write};
+use coap_message_utils::Error as CoAPError;
+use coap_numbers::{code, option};
+use coap_request::{Request, Stack};
+
+use crate::client::add_option;
+use crate::seccontext::OrInner;
+
+/// Size of the buffers that single messages are copied through.
+///
+/// This is the message size of embedded-nal-coap.
+const MAX_SIZE: usize = 1152;
+
+/// Largest size exponent (SZX) of a block, indicating 1024 byte blocks.
+pub const MAX_SZX: u8 = 6;
+
+/// Largest block number that can be expressed in a Block option.
+const MAX_NUM: u32 = (1 << 20) - 1;
+
+/// Space reserved for the encoded options of a request reassembled by a [`Block1Handler`].
+const MAX_OPTIONS_LEN: usize = 256;
+
+/// Value of a Block1 or Block2 option (RFC 7959 Section 2.2).
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    num: u32,
+    more: bool,
+    szx: u8,
+}
+
+impl Block {
+    /// Creates a block option value, or `None` if the number or size exponent is out of range.
+    pub fn new(num: u32, more: bool, szx: u8) -> Option<Self> {
+        (num <= MAX_NUM && szx <= MAX_SZX).then_some(Self { num, more, szx })
+    }
+
+    /// The block's number, counted in units of its size.
+    pub fn num(&self) -> u32 {
+        self.num
+    }
+
+    /// Whether more blocks follow this one.
+    pub fn more(&self) -> bool {
+        self.more
+    }
+
+    /// The block's size exponent.
+    pub fn szx(&self) -> u8 {
+        self.szx
+    }
+
+    /// The block's size in bytes (16 to 1024).
+    pub fn size(&self) -> usize {
+        16 << self.szx
+    }
+
+    /// The offset of the block's first byte in the whole representation.
+    pub fn start(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    /// The block that starts at the same offset with the smaller size exponent `szx`.
+    ///
+    /// This is how a peer asking for smaller blocks is followed; the size exponent is never
+    /// increased.
+    pub fn with_szx(self, szx: u8) -> Self {
+        if szx >= self.szx {
+            return self;
+        }
+        Self {
+            num: self.num << (self.szx - szx),
+            more: self.more,
+            szx,
+        }
+    }
+
+    /// Decodes the value of a Block option.
+    pub fn decode(value: &[u8]) -> Option<Self> {
+        if value.len() > 3 {
+            return None;
+        }
+        let raw = value
+            .iter()
+            .fold(0u32, |acc, byte| (acc << 8) | u32::from(*byte));
+        let szx = (raw & 0x7) as u8;
+        if szx == 7 {
+            // Reserved (BERT in RFC 8323, which is not supported)
+            return None;
+        }
+        Self::new(raw >> 4, raw & 0x8 != 0, szx)
+    }
+
+    /// Encodes the block into the shortest option value.
+    pub fn encode(&self) -> heapless::Vec<u8, 3> {
+        let raw = (self.num << 4) | (u32::from(self.more) << 3) | u32::from(self.szx);
+        let bytes = raw.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        #[allow(clippy::indexing_slicing, reason = "skip is at most 4")]
+        heapless::Vec::from_slice(&bytes[skip..]).expect("Block values fit in 3 bytes")
+    }
+
+    /// Reads the option `number` (Block1 or Block2) from a message.
+    ///
+    /// Returns `Some(Err(()))` if the option is present but malformed.
+    pub fn from_message(message: &impl ReadableMessage, number: u16) -> Option<Result<Self, ()>> {
+        message
+            .options()
+            .find(|o| o.number() == number)
+            .map(|o| Self::decode(o.value()).ok_or(()))
+    }
+}
+
+/// Size exponent of the largest block that is at most `size` bytes long (but at least 16 bytes).
+pub fn szx_for(size: usize) -> u8 {
+    let mut szx = 0;
+    while szx < MAX_SZX && 32 << szx <= size {
+        szx += 1;
+    }
+    szx
+}
+
+/// Error of [`Assembly::push()`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AssemblyError {
+    /// The block does not continue the assembled data, which is discarded (4.08 Request Entity
+    /// Incomplete).
+    Incomplete,
+    /// The assembled data exceeds the available space (4.13 Request Entity Too Large).
+    TooLarge,
+    /// The block's payload does not match its size (4.00 Bad Request).
+    BadBlock,
+}
+
+/// Storage for reassembling a payload that is sent in Block1 blocks.
+///
+/// The payload may be preceded by a fixed prefix, which is set when assembly starts; block offsets
+/// are counted from the end of the prefix.
+pub struct Assembly<const N: usize> {
+    data: heapless::Vec<u8, N>,
+    prefix: usize,
+    active: bool,
+}
+
+impl<const N: usize> Assembly<N> {
+    /// Creates an empty assembly.
+    pub const fn new() -> Self {
+        Self {
+            data: heapless::Vec::new(),
+            prefix: 0,
+            active: false,
+        }
+    }
+
+    /// Discards any previous data and starts assembling after `prefix`.
+    pub fn start(&mut self, prefix: &[u8]) -> Result<(), AssemblyError> {
+        self.data.clear();
+        self.active = false;
+        self.data
+            .extend_from_slice(prefix)
+            .map_err(|()| AssemblyError::TooLarge)?;
+        self.prefix = prefix.len();
+        self.active = true;
+        Ok(())
+    }
+
+    /// Whether an assembly was started and is not yet complete.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Appends the payload of a block.
+    ///
+    /// Returns `true` if this was the last block, after which [`Self::payload()`] holds the
+    /// complete payload. On error, assembly needs to be restarted.
+    pub fn push(&mut self, block: Block, payload: &[u8]) -> Result<bool, AssemblyError> {
+        if !self.active || block.start() != self.payload().len() {
+            self.active = false;
+            return Err(AssemblyError::Incomplete);
+        }
+        if payload.len() > block.size() || (block.more && payload.len() != block.size()) {
+            self.active = false;
+            return Err(AssemblyError::BadBlock);
+        }
+        if self.data.extend_from_slice(payload).is_err() {
+            self.active = false;
+            return Err(AssemblyError::TooLarge);
+        }
+        self.active = block.more;
+        Ok(!block.more)
+    }
+
+    /// The prefix given at start.
+    pub fn prefix(&self) -> &[u8] {
+        #[allow(clippy::indexing_slicing, reason = "the prefix is always in the data")]
+        &self.data[..self.prefix]
+    }
+
+    /// The payload assembled so far.
+    pub fn payload(&self) -> &[u8] {
+        #[allow(clippy::indexing_slicing, reason = "the prefix is always in the data")]
+        &self.data[self.prefix..]
+    }
+}
+
+impl<const N: usize> Default for Assembly<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Copies code, options and the given payload of a message into another, inserting a Block option
+/// `number` in sequence.
+fn copy_with_block<M: MinimalWritableMessage>(
+    from: &impl ReadableMessage,
+    to: &mut M,
+    number: u16,
+    block: Option<Block>,
+    payload: &[u8],
+) -> Result<(), M::UnionError> {
+    to.set_code(M::Code::new(from.code().into())?);
+    let mut pending = block.map(|b| b.encode());
+    for opt in from.options() {
+        if opt.number() > number {
+            if let Some(value) = pending.take() {
+                add_option(to, number, &value)?;
+            }
+        }
+        add_option(to, opt.number(), opt.value())?;
+    }
+    if let Some(value) = pending {
+        add_option(to, number, &value)?;
+    }
+    to.set_payload(payload)?;
+    Ok(())
+}
+
+/// Copies a request into `message` without its options `hidden`.
+fn copy_without(
+    from: &impl ReadableMessage,
+    message: &mut inmemory_write::Message<'_>,
+    hidden: &[u16],
+) -> Result<(), CoAPError> {
+    message.set_code(from.code().into());
+    for opt in from.options() {
+        if hidden.contains(&opt.number()) {
+            continue;
+        }
+        message
+            .add_option(opt.number(), opt.value())
+            .map_err(|_| CoAPError::bad_request())?;
+    }
+    message
+        .set_payload(from.payload())
+        .map_err(|_| CoAPError::bad_request())?;
+    Ok(())
+}
+
+/// Sets a bare response code.
+fn set_code<M: MinimalWritableMessage>(response: &mut M, code: u8) -> Result<(), M::UnionError> {
+    response.set_code(M::Code::new(code)?);
+    Ok(())
+}
+
+/// A handler that serves the responses of an inner handler in Block2 blocks.
+///
+/// The inner handler renders its complete response, which needs to fit in `N` bytes (options and
+/// payload), into a buffer for every request; the requested block is then cut out of it. Inner
+/// handlers should therefore produce the same representation for all blocks of a transfer, and
+/// may set an ETag option to help clients detect changes.
+///
+/// Responses that fit a single block are sent without a Block2 option unless one was requested.
+pub struct Block2Handler<H, const N: usize> {
+    inner: H,
+    szx: u8,
+}
+
+impl<H: Handler, const N: usize> Block2Handler<H, N> {
+    /// Wraps `inner`, serving blocks of at most 1024 bytes.
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner,
+            szx: MAX_SZX,
+        }
+    }
+
+    /// Limits the size of the served blocks to at most `size` bytes.
+    pub fn with_max_block_size(self, size: usize) -> Self {
+        Self {
+            szx: szx_for(size),
+            ..self
+        }
+    }
+}
+
+impl<H: Handler, const N: usize> Handler for Block2Handler<H, N> {
+    /// The requested block, and the inner request data.
+    type RequestData = (Option<Block>, H::RequestData);
+    type ExtractRequestError = OrInner<CoAPError, H::ExtractRequestError>;
+    type BuildResponseError<M: MinimalWritableMessage> = M::UnionError;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Self::ExtractRequestError> {
+        let requested = Block::from_message(request, option::BLOCK2)
+            .transpose()
+            .map_err(|()| OrInner::Own(CoAPError::bad_option(option::BLOCK2)))?;
+        if requested.is_none() {
+            let extracted = self
+                .inner
+                .extract_request_data(request)
+                .map_err(OrInner::Inner)?;
+            return Ok((None, extracted));
+        }
+
+        // The Block2 option is critical, so the inner handler must not see it.
+        let mut copy_code = 0;
+        let mut copy_buffer = [0u8; MAX_SIZE];
+        let mut copied = inmemory_write::Message::new(&mut copy_code, &mut copy_buffer[..]);
+        copy_without(request, &mut copied, &[option::BLOCK2]).map_err(OrInner::Own)?;
+        let extracted = self
+            .inner
+            .extract_request_data(&copied)
+            .map_err(OrInner::Inner)?;
+        Ok((requested, extracted))
+    }
+
+    fn estimate_length(&mut self, (_, request): &Self::RequestData) -> usize {
+        // Options are estimated generously, as they are not known before rendering.
+        self.inner
+            .estimate_length(request)
+            .min((16 << self.szx) + 64)
+    }
+
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        (requested, request): Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        let mut full_code = 0;
+        let mut full_buffer = [0u8; N];
+        let mut full = inmemory_write::Message::new(&mut full_code, &mut full_buffer[..]);
+        if self.inner.build_response(&mut full, request).is_err() {
+            // The error can not be rendered into the response, whose type differs.
+            return set_code(response, code::INTERNAL_SERVER_ERROR);
+        }
+
+        let payload = full.payload();
+        let block = match requested {
+            Some(requested) => requested.with_szx(self.szx),
+            None if payload.len() <= 16 << self.szx => {
+                return copy_with_block(&full, response, option::BLOCK2, None, payload);
+            }
+            None => Block {
+                num: 0,
+                more: true,
+                szx: self.szx,
+            },
+        };
+        let start = block.start();
+        if start > payload.len() || (start == payload.len() && start != 0) {
+            return CoAPError::bad_option(option::BLOCK2).render(response);
+        }
+        let end = payload.len().min(start + block.size());
+        let block = Block {
+            more: end < payload.len(),
+            ..block
+        };
+        #[allow(clippy::indexing_slicing, reason = "range checked above")]
+        copy_with_block(
+            &full,
+            response,
+            option::BLOCK2,
+            Some(block),
+            &payload[start..end],
+        )
+    }
+}
+
+impl<H: coap_handler::Reporting, const N: usize> coap_handler::Reporting for Block2Handler<H, N> {
+    type Record<'res>
+        = H::Record<'res>
+    where
+        Self: 'res;
+    type Reporter<'res>
+        = H::Reporter<'res>
+    where
+        Self: 'res;
+
+    fn report(&self) -> Self::Reporter<'_> {
+        self.inner.report()
+    }
+}
+
+/// Request data of a [`Block1Handler`].
+pub enum Block1Data<I> {
+    /// A block was stored, and the next one is expected.
+    Continue(Block),
+    /// The request is complete; it was sent in blocks if the last one is given.
+    Complete(Option<Block>, I),
+    /// The block was rejected with the given response code.
+    Rejected(u8),
+}
+
+/// A handler that reassembles requests sent in Block1 blocks before passing them to an inner
+/// handler.
+///
+/// The options and payload of a reassembled request need to fit in `N` bytes. Only one request is
+/// reassembled at a time: a new transfer (starting with block 0) replaces any incomplete one, whose
+/// further blocks are then rejected with 4.08 Request Entity Incomplete. Blocks are matched to
+/// their transfer by their code and options; OSCORE already ensures that they come from the same
+/// peer when this is placed inside an
+/// [`OscoreEdhocHandler`](crate::seccontext::OscoreEdhocHandler).
+pub struct Block1Handler<H, const N: usize> {
+    inner: H,
+    code: u8,
+    assembly: Assembly<N>,
+    szx: u8,
+}
+
+impl<H: Handler, const N: usize> Block1Handler<H, N> {
+    /// Wraps `inner`, accepting blocks of up to 1024 bytes.
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner,
+            code: 0,
+            assembly: Assembly::new(),
+            szx: MAX_SZX,
+        }
+    }
+
+    /// Asks clients to send blocks of at most `size` bytes.
+    pub fn with_max_block_size(self, size: usize) -> Self {
+        Self {
+            szx: szx_for(size),
+            ..self
+        }
+    }
+
+    /// Whether a request is a further block of the transfer being assembled.
+    ///
+    /// The prefix is the encoded options followed by a payload marker.
+    fn continues(&self, request: &impl ReadableMessage) -> bool {
+        let prefix = self.assembly.prefix();
+        let options = prefix.get(..prefix.len().saturating_sub(1)).unwrap_or(&[]);
+        let code: u8 = request.code().into();
+        if !self.assembly.is_active() || code != self.code {
+            return false;
+        }
+        let stored = inmemory::Message::new(self.code, options);
+        let mut stored_options = stored.options();
+        for opt in request.options() {
+            if matches!(opt.number(), option::BLOCK1 | option::SIZE1) {
+                continue;
+            }
+            let Some(stored_opt) = stored_options.next() else {
+                return false;
+            };
+            if stored_opt.number() != opt.number() || stored_opt.value() != opt.value() {
+                return false;
+            }
+        }
+        stored_options.next().is_none()
+    }
+
+    /// Starts assembling the request, storing its code and options.
+    fn start(&mut self, request: &impl ReadableMessage) -> Result<(), AssemblyError> {
+        let mut code = 0;
+        let mut buffer = [0u8; MAX_OPTIONS_LEN + 1];
+        let mut message = inmemory_write::Message::new(&mut code, &mut buffer[..MAX_OPTIONS_LEN]);
+        for opt in request.options() {
+            if matches!(opt.number(), option::BLOCK1 | option::SIZE1) {
+                continue;
+            }
+            message
+                .add_option(opt.number(), opt.value())
+                .map_err(|_| AssemblyError::TooLarge)?;
+        }
+        let len = message.finish();
+        self.code = code;
+        let prefix = buffer
+            .get_mut(..=len)
+            .expect("The buffer has room for the payload marker");
+        if let Some(marker) = prefix.last_mut() {
+            *marker = 0xff;
+        }
+        self.assembly.start(prefix)
+    }
+}
+
+impl<H: Handler, const N: usize> Handler for Block1Handler<H, N> {
+    type RequestData = Block1Data<H::RequestData>;
+    type ExtractRequestError = OrInner<CoAPError, H::ExtractRequestError>;
+    type BuildResponseError<M: MinimalWritableMessage> =
+        OrInner<M::UnionError, H::BuildResponseError<M>>;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Self::ExtractRequestError> {
+        let Some(block) = Block::from_message(request, option::BLOCK1) else {
+            let extracted = self
+                .inner
+                .extract_request_data(request)
+                .map_err(OrInner::Inner)?;
+            return Ok(Block1Data::Complete(None, extracted));
+        };
+        let block = block.map_err(|()| OrInner::Own(CoAPError::bad_option(option::BLOCK1)))?;
+
+        let pushed = if block.num == 0 {
+            self.start(request)
+                .and_then(|()| self.assembly.push(block, request.payload()))
+        } else if self.continues(request) {
+            self.assembly.push(block, request.payload())
+        } else {
+            Err(AssemblyError::Incomplete)
+        };
+        let complete = match pushed {
+            Ok(complete) => complete,
+            Err(e) => {
+                return Ok(Block1Data::Rejected(match e {
+                    AssemblyError::Incomplete => code::REQUEST_ENTITY_INCOMPLETE,
+                    AssemblyError::TooLarge => code::REQUEST_ENTITY_TOO_LARGE,
+                    AssemblyError::BadBlock => code::BAD_REQUEST,
+                }))
+            }
+        };
+        if !complete {
+            return Ok(Block1Data::Continue(block));
+        }
+
+        let data = self.assembly.data.as_slice();
+        let len = if self.assembly.payload().is_empty() {
+            // Without the payload marker
+            data.len() - 1
+        } else {
+            data.len()
+        };
+        #[allow(clippy::indexing_slicing, reason = "len is at most the data length")]
+        let assembled = inmemory::Message::new(self.code, &data[..len]);
+        let extracted = self
+            .inner
+            .extract_request_data(&assembled)
+            .map_err(OrInner::Inner)?;
+        Ok(Block1Data::Complete(Some(block), extracted))
+    }
+
+    fn estimate_length(&mut self, request: &Self::RequestData) -> usize {
+        match request {
+            Block1Data::Complete(_, request) => 4 + self.inner.estimate_length(request),
+            _ => 8,
+        }
+    }
+
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        request: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        match request {
+            Block1Data::Rejected(rejection) => {
+                set_code(response, rejection).map_err(OrInner::Own)?;
+                if rejection == code::REQUEST_ENTITY_TOO_LARGE {
+                    // Size1 indicates the largest acceptable payload (RFC 7959 Section 4).
+                    let size = u32::try_from(N - self.assembly.prefix().len())
+                        .unwrap_or(u32::MAX)
+                        .to_be_bytes();
+                    let skip = size.iter().take_while(|b| **b == 0).count();
+                    #[allow(clippy::indexing_slicing, reason = "skip is at most 4")]
+                    add_option(response, option::SIZE1, &size[skip..]).map_err(OrInner::Own)?;
+                }
+                Ok(())
+            }
+            Block1Data::Continue(block) => {
+                set_code(response, code::CONTINUE).map_err(OrInner::Own)?;
+                let block = Block {
+                    more: true,
+                    ..block.with_szx(self.szx)
+                };
+                add_option(response, option::BLOCK1, &block.encode()).map_err(OrInner::Own)?;
+                Ok(())
+            }
+            Block1Data::Complete(None, request) => self.inner.build_response(response, request),
+            Block1Data::Complete(Some(block), request) => {
+                let mut full_code = 0;
+                let mut full_buffer = [0u8; MAX_SIZE];
+                let mut full = inmemory_write::Message::new(&mut full_code, &mut full_buffer[..]);
+                if self.inner.build_response(&mut full, request).is_err() {
+                    // The error can not be rendered into the response, whose type differs.
+                    return set_code(response, code::INTERNAL_SERVER_ERROR).map_err(OrInner::Own);
+                }
+                copy_with_block(&full, response, option::BLOCK1, Some(block), full.payload())
+                    .map_err(OrInner::Own)
+            }
+        }
+    }
+}
+
+impl<H: coap_handler::Reporting, const N: usize> coap_handler::Reporting for Block1Handler<H, N> {
+    type Record<'res>
+        = H::Record<'res>
+    where
+        Self: 'res;
+    type Reporter<'res>
+        = H::Reporter<'res>
+    where
+        Self: 'res;
+
+    fn report(&self) -> Self::Reporter<'_> {
+        self.inner.report()
+    }
+}
+
+/// A request sent by [`request_blockwise()`].
+pub struct BlockwiseRequest<'a> {
+    /// The request code.
+    pub code: u8,
+    /// Options of the request, sorted by number. They must not include the block-wise options
+    /// (Block1, Block2, Size1, Size2).
+    pub options: &'a [(u16, &'a [u8])],
+    /// The request payload, which is sent in Block1 blocks if it exceeds a single block.
+    pub payload: &'a [u8],
+}
+
+/// Response to a [`request_blockwise()`], whose payload was placed in the response buffer.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BlockwiseResponse {
+    /// The response code (of the first block).
+    pub code: u8,
+    /// Length of the payload.
+    pub len: usize,
+}
+
+/// Error of [`request_blockwise()`].
+#[derive(Debug)]
+pub enum BlockwiseError<T> {
+    /// The underlying CoAP stack failed to send a request or to receive a response.
+    Transport(T),
+    /// The response payload exceeds the response buffer.
+    TooLarge,
+    /// The server's Block options do not match the transfer.
+    Protocol,
+}
+
+/// Sends a request through `stack`, placing the response payload in `response`.
+///
+/// The request payload is split into Block1 blocks of at most `max_block_size` bytes if it does
+/// not fit a single one, following any smaller block size the server asks for. A response that
+/// is sent in Block2 blocks is collected by repeating the request for the further blocks; their
+/// requests carry no payload if the original payload was sent in blocks.
+///
+/// To transfer protected representations, `stack` can be a
+/// [`ProtectedStack`](crate::client::ProtectedStack), in which case every block is protected
+/// separately (inner block-wise transfer).
+pub async fn request_blockwise<S: Stack>(
+    stack: &mut S,
+    request: &BlockwiseRequest<'_>,
+    max_block_size: usize,
+    response: &mut [u8],
+) -> Result<BlockwiseResponse, BlockwiseError<S::TransportError>> {
+    let mut szx = szx_for(max_block_size);
+    let fragmented = request.payload.len() > 16 << szx;
+
+    let mut offset = 0;
+    let first = loop {
+        let (block1, payload) = if fragmented {
+            let size = 16 << szx;
+            let end = request.payload.len().min(offset + size);
+            let block = Block::new(
+                u32::try_from(offset >> (4 + szx)).map_err(|_| BlockwiseError::TooLarge)?,
+                end < request.payload.len(),
+                szx,
+            )
+            .ok_or(BlockwiseError::TooLarge)?;
+            #[allow(clippy::indexing_slicing, reason = "offset is less than the length")]
+            (Some(block), &request.payload[offset..end])
+        } else {
+            (None, request.payload)
+        };
+        let exchanged = stack
+            .request(Exchange {
+                request,
+                block1,
+                block2: None,
+                payload,
+                response: &mut *response,
+                received: 0,
+            })
+            .await
+            .map_err(BlockwiseError::Transport)?
+            .map_err(|()| BlockwiseError::TooLarge)?;
+        let Some(block1) = block1 else {
+            break exchanged;
+        };
+        if exchanged.code != code::CONTINUE {
+            // The final response, or an error
+            break exchanged;
+        }
+        let Some(Ok(acknowledged)) = exchanged.block1 else {
+            return Err(BlockwiseError::Protocol);
+        };
+        if !block1.more || acknowledged.num != block1.num || acknowledged.szx > block1.szx {
+            return Err(BlockwiseError::Protocol);
+        }
+        offset += block1.size();
+        szx = acknowledged.szx;
+    };
+
+    let mut len = first.len;
+    let mut block2 = first
+        .block2
+        .transpose()
+        .map_err(|()| BlockwiseError::Protocol)?;
+    while let Some(received) = block2.filter(Block::more) {
+        let next = Block {
+            num: received.num + 1,
+            more: false,
+            szx: received.szx,
+        };
+        if next.start() != len {
+            return Err(BlockwiseError::Protocol);
+        }
+        let exchanged = stack
+            .request(Exchange {
+                request,
+                block1: None,
+                block2: Some(next),
+                payload: if fragmented { &[] } else { request.payload },
+                response: &mut *response,
+                received: len,
+            })
+            .await
+            .map_err(BlockwiseError::Transport)?
+            .map_err(|()| BlockwiseError::TooLarge)?;
+        block2 = match exchanged.block2 {
+            Some(Ok(block)) if exchanged.code == first.code && block.start() == len => Some(block),
+            _ => return Err(BlockwiseError::Protocol),
+        };
+        len += exchanged.len;
+    }
+
+    Ok(BlockwiseResponse {
+        code: first.code,
+        len,
+    })
+}
+
+/// A single request of a [`request_blockwise()`] transfer.
+struct Exchange<'a, 'r> {
+    request: &'a BlockwiseRequest<'a>,
+    block1: Option<Block>,
+    block2: Option<Block>,
+    payload: &'a [u8],
+    response: &'r mut [u8],
+    /// Length of the response payload received in earlier blocks
+    received: usize,
+}
+
+/// Outcome of an [`Exchange`], whose payload was copied into the response buffer.
+struct Exchanged {
+    code: u8,
+    block1: Option<Result<Block, ()>>,
+    block2: Option<Result<Block, ()>>,
+    len: usize,
+}
+
+impl<S: Stack> Request<S> for Exchange<'_, '_> {
+    type Carry = ();
+    /// The exchange's outcome, or an error if the payload exceeds the response buffer.
+    type Output = Result<Exchanged, ()>;
+
+    async fn build_request(
+        &mut self,
+        request: &mut S::RequestMessage<'_>,
+    ) -> Result<(), S::RequestUnionError> {
+        set_code(request, self.request.code)?;
+        let mut blocks = [(option::BLOCK2, self.block2), (option::BLOCK1, self.block1)]
+            .into_iter()
+            .filter_map(|(number, block)| Some((number, block?.encode())))
+            .peekable();
+        for (number, value) in self.request.options {
+            while let Some((block_number, block)) = blocks.next_if(|(n, _)| n < number) {
+                add_option(request, block_number, &block)?;
+            }
+            add_option(request, *number, value)?;
+        }
+        for (block_number, block) in blocks {
+            add_option(request, block_number, &block)?;
+        }
+        request.set_payload(self.payload)?;
+        Ok(())
+    }
+
+    async fn process_response(
+        &mut self,
+        response: &S::ResponseMessage<'_>,
+        (): (),
+    ) -> Self::Output {
+        let payload = response.payload();
+        self.response
+            .get_mut(self.received..self.received + payload.len())
+            .ok_or(())?
+            .copy_from_slice(payload);
+        Ok(Exchanged {
+            code: response.code().into(),
+            block1: Block::from_message(response, option::BLOCK1),
+            block2: Block::from_message(response, option::BLOCK2),
+            len: payload.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{szx_for, Assembly, AssemblyError, Block};
+
+    #[test]
+    fn options() {
+        for (value, num, more, szx) in [
+            (&[][..], 0, false, 0),
+            (&[0x0e][..], 0, true, 6),
+            (&[0x12][..], 1, false, 2),
+            (&[0x01, 0x0e][..], 16, true, 6),
+            (&[0xff, 0xff, 0xf6][..], (1 << 20) - 1, false, 6),
+        ] {
+            let block = Block::decode(value).unwrap();
+            assert_eq!(block, Block::new(num, more, szx).unwrap());
+            assert_eq!(block.encode(), value);
+        }
+        assert_eq!(Block::decode(&[0x07]), None);
+        assert_eq!(Block::decode(&[0, 0, 0, 0]), None);
+        assert_eq!(Block::new(1 << 20, false, 0), None);
+        assert_eq!(Block::new(0, false, 7), None);
+
+        let block = Block::new(3, true, 6).unwrap();
+        assert_eq!(block.start(), 3072);
+        assert_eq!(block.with_szx(4), Block::new(12, true, 4).unwrap());
+        assert_eq!(block.with_szx(4).start(), block.start());
+        assert_eq!(Block::new(3, true, 2).unwrap().with_szx(6).szx(), 2);
+
+        assert_eq!(szx_for(0), 0);
+        assert_eq!(szx_for(31), 0);
+        assert_eq!(szx_for(32), 1);
+        assert_eq!(szx_for(1000), 5);
+        assert_eq!(szx_for(1152), 6);
+    }
+
+    #[test]
+    fn assembly() {
+        let block = |num, more| Block::new(num, more, 0).unwrap();
+        let mut assembly = Assembly::<40>::new();
+        assert_eq!(
+            assembly.push(block(0, true), &[0; 16]),
+            Err(AssemblyError::Incomplete)
+        );
+
+        assembly.start(b"head").unwrap();
+        assert_eq!(assembly.push(block(0, true), &[1; 16]), Ok(false));
+        assert_eq!(assembly.push(block(1, false), &[2; 4]), Ok(true));
+        assert!(!assembly.is_active());
+        assert_eq!(assembly.prefix(), b"head");
+        assert_eq!(assembly.payload().len(), 20);
+        assert_eq!(assembly.payload().get(16..), Some(&[2; 4][..]));
+
+        // Blocks that are missing, short or too many
+        assembly.start(b"").unwrap();
+        assert_eq!(
+            assembly.push(block(1, true), &[0; 16]),
+            Err(AssemblyError::Incomplete)
+        );
+        assembly.start(b"").unwrap();
+        assert_eq!(
+            assembly.push(block(0, true), &[0; 15]),
+            Err(AssemblyError::BadBlock)
+        );
+        assembly.start(b"head").unwrap();
+        assert_eq!(assembly.push(block(0, true), &[0; 16]), Ok(false));
+        assert_eq!(assembly.push(block(1, true), &[0; 16]), Ok(false));
+        assert_eq!(
+            assembly.push(block(2, false), &[0; 16]),
+            Err(AssemblyError::TooLarge)
+        );
+        assert!(!assembly.is_active());
+
+        // A peer switching to smaller blocks
+        assembly.start(b"").unwrap();
+        assert_eq!(
+            assembly.push(Block::new(0, true, 1).unwrap(), &[0; 32]),
+            Ok(false)
+        );
+        assert_eq!(assembly.push(block(2, false), &[0; 3]), Ok(true));
+        assert_eq!(assembly.payload().len(), 35);
+    }
+}
@@ -0,0 +1,750 @@
+//! The client side of EDHOC and OSCORE.
+//!
+//! An [`OscoreEdhocClient`] runs EDHOC as initiator against a server, and places the resulting
+//! OSCORE context in the [`SecContextPool`] it shares with the
+//! [`OscoreEdhocHandler`](crate::seccontext::OscoreEdhocHandler). Requests sent through a
+//! [`ProtectedStack`] are then protected with that context, and their responses unprotected,
+//! without the request implementation noticing.
+//!
+//! EDHOC message 3 is not sent on its own, but combined with the first OSCORE request (RFC 9668).
+//! Until a protected response arrives, it is sent along with every request, so that a lost
+//! request does not leave the server without the context.
+use core::cell::RefCell;
+use core::marker::PhantomData;
+
+use coap_message::{
+    Code as _, MessageOption as _, MinimalWritableMessage, OptionNumber as _, ReadableMessage,
+};
+use coap_message_implementations::inmemory_write::Message;
+use coap_request::{Request, Stack};
+use defmt_or_log::{debug, info, warn};
+
+use crate::authorization::{Authorized, PeerTable};
+use crate::eviction::EvictionPolicy;
+use crate::seccontext::{
+    derive_oscore_context, COwn, SecContextPool, SecContextStage, SecContextState,
+    DEFAULT_MAX_CONTEXTS, LEVEL_EVICTABLE,
+};
+
+/// Size of the buffers that messages are copied through while being protected or unprotected.
+///
+/// This is the message size of embedded-nal-coap, as on the server side.
+const MAX_SIZE: usize = 1152;
+
+/// Error of [`OscoreEdhocClient::establish()`] and of requests sent through a [`ProtectedStack`].
+#[derive(Debug)]
+pub enum ClientError<T> {
+    /// The underlying CoAP stack failed to send the request or to receive a response.
+    Transport(T),
+    /// The EDHOC exchange failed.
+    Edhoc(lakers::EDHOCError),
+    /// The server sent EDHOC external authorization data that is marked critical; none is
+    /// supported.
+    CriticalEad,
+    /// The server did not authenticate with the expected credential.
+    UnexpectedPeer,
+    /// The server answered with an unexpected or unprotected response of the given code.
+    UnexpectedResponse(u8),
+    /// The security context was evicted from the pool; EDHOC needs to be run again.
+    ContextEvicted,
+    /// All contexts in the pool are pinned, so none can be evicted to make room for a new one;
+    /// see [`eviction`](crate::eviction).
+    PoolFull,
+    /// The request or the response did not fit into the buffers used for protection.
+    TooLarge,
+    /// Protecting the request or unprotecting the response failed.
+    Oscore,
+    /// The sequence numbers of the security context are used up until a new limit is stored (see
+    /// [`crate::persistence`]); the request can be retried later.
+    Unpersisted,
+}
+
+/// A security context established by [`OscoreEdhocClient::establish()`].
+///
+/// The OSCORE context itself resides in the pool; this is the handle to it.
+#[derive(Debug)]
+pub struct SecurityContext {
+    c_i: COwn,
+    /// EDHOC message 3, until the server has confirmed receiving it by answering with OSCORE.
+    message_3: Option<lakers::EdhocMessageBuffer>,
+}
+
+/// An EDHOC initiator and OSCORE client, sharing its security contexts with an
+/// [`OscoreEdhocHandler`](crate::seccontext::OscoreEdhocHandler).
+pub struct OscoreEdhocClient<'a, Crypto: lakers::Crypto, const N: usize = DEFAULT_MAX_CONTEXTS> {
+    pool: &'a RefCell<SecContextPool<Crypto, N>>,
+    own_identity: (&'a lakers::Credential, &'a lakers::BytesP256ElemLen),
+    peers: PeerTable<'a>,
+    crypto_factory: fn() -> Crypto,
+    eviction: EvictionPolicy,
+}
+
+impl<'a, Crypto: lakers::Crypto, const N: usize> OscoreEdhocClient<'a, Crypto, N> {
+    /// Creates a client authenticating as `own_identity`, that keeps its security contexts in
+    /// `pool`.
+    ///
+    /// The `peers` are only consulted to determine the permissions of the server if it sends
+    /// requests through an established context; they should match those of the handler sharing
+    /// the pool.
+    pub fn new(
+        own_identity: (&'a lakers::Credential, &'a lakers::BytesP256ElemLen),
+        peers: PeerTable<'a>,
+        pool: &'a RefCell<SecContextPool<Crypto, N>>,
+        crypto_factory: fn() -> Crypto,
+    ) -> Self {
+        Self {
+            pool,
+            own_identity,
+            peers,
+            crypto_factory,
+            eviction: crate::eviction::prefer_authenticated,
+        }
+    }
+
+    /// Decides the retention of the contexts this client establishes through `policy`, instead
+    /// of [`prefer_authenticated`](crate::eviction::prefer_authenticated); see
+    /// [`eviction`](crate::eviction).
+    ///
+    /// This should be the policy of the handler sharing the pool.
+    #[must_use]
+    pub fn with_eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.eviction = policy;
+        self
+    }
+
+    /// Runs EDHOC against the server that `stack` sends requests to, which needs to authenticate
+    /// with the credential `peer` (a CCS).
+    ///
+    /// On success, the OSCORE context is placed in the pool, and requests can be sent through it
+    /// using [`protect()`](Self::protect).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server could not be reached, if the EDHOC exchange failed, or if
+    /// the server authenticated with a different credential.
+    pub async fn establish<S: Stack>(
+        &self,
+        stack: &mut S,
+        peer: &[u8],
+    ) -> Result<SecurityContext, ClientError<S::TransportError>> {
+        let expected_cred_r =
+            lakers::Credential::parse_ccs(peer).map_err(|_| ClientError::UnexpectedPeer)?;
+
+        // The identifier is reserved in the pool for the duration of the exchange, so that the
+        // server side does not pick it as C_R in the meantime.
+        let c_i = {
+            let mut pool = self.pool.borrow_mut();
+            let c_i =
+                COwn::not_in_iter(pool.iter().filter_map(SecContextState::corresponding_cown));
+            if let Some(evicted) = pool
+                .insert_evicting(
+                    SecContextState::establishing(SecContextStage::EdhocInitiatorReserved { c_i }),
+                    LEVEL_EVICTABLE,
+                )
+                .map_err(|_| ClientError::PoolFull)?
+            {
+                warn!("To initiate EDHOC, evicted {}", evicted);
+            }
+            c_i
+        };
+
+        let result = self.run_edhoc(stack, c_i, expected_cred_r, peer).await;
+
+        if result.is_err() {
+            // Release the reservation; if it was evicted already, there is nothing to do.
+            self.pool.borrow_mut().lookup(
+                |c| matches!(c.protocol_stage, SecContextStage::EdhocInitiatorReserved { c_i: reserved } if reserved == c_i),
+                |c| *c = SecContextState::default(),
+            );
+        }
+
+        result
+    }
+
+    async fn run_edhoc<S: Stack>(
+        &self,
+        stack: &mut S,
+        c_i: COwn,
+        expected_cred_r: lakers::Credential,
+        peer: &[u8],
+    ) -> Result<SecurityContext, ClientError<S::TransportError>> {
+        info!("Initiating EDHOC with C_I = {:?}", c_i);
+        let (initiator, message_1) = lakers::EdhocInitiator::new(
+            (self.crypto_factory)(),
+            lakers::EDHOCMethod::StatStat,
+            lakers::EDHOCSuite::CipherSuite2,
+        )
+        .prepare_message_1(Some(c_i.into()), &None)
+        .map_err(ClientError::Edhoc)?;
+
+        let message_2 = stack
+            .request(EdhocMessage1 {
+                message_1: message_1.as_slice(),
+            })
+            .await
+            .map_err(ClientError::Transport)??;
+
+        let (mut initiator, c_r, id_cred_r, ead_2) = initiator
+            .parse_message_2(&message_2)
+            .map_err(ClientError::Edhoc)?;
+        if ead_2.is_some_and(|e| e.is_critical) {
+            return Err(ClientError::CriticalEad);
+        }
+
+        let cred_r = lakers::credential_check_or_fetch(Some(expected_cred_r), id_cred_r)
+            .map_err(|_| ClientError::UnexpectedPeer)?;
+        initiator.set_identity(*self.own_identity.1, *self.own_identity.0);
+        let initiator = initiator
+            .verify_message_2(cred_r)
+            .map_err(ClientError::Edhoc)?;
+        // Sending our ID by reference, for the same reasons as the responder does: servers that
+        // are to accept us know our credential.
+        let (mut initiator, message_3, _prk_out) = initiator
+            .prepare_message_3(lakers::CredentialTransfer::ByReference, &None)
+            .map_err(ClientError::Edhoc)?;
+
+        let (context, persistence) = derive_oscore_context(
+            |label, length| initiator.edhoc_exporter(label, &[], length),
+            c_r.as_slice(),
+            c_i,
+        );
+        let authorization = match self.peers.find_by_ccs(peer) {
+            Some(index) => Authorized::Peer(index),
+            None => Authorized::Unauthenticated,
+        };
+
+        self.pool
+            .borrow_mut()
+            .lookup(
+                |c| matches!(c.protocol_stage, SecContextStage::EdhocInitiatorReserved { c_i: reserved } if reserved == c_i),
+                |c| {
+                    *c = SecContextState::established(
+                        context,
+                        persistence,
+                        authorization,
+                        &self.peers,
+                        self.eviction,
+                    );
+                },
+            )
+            .ok_or(ClientError::ContextEvicted)?;
+        info!("Established OSCORE context with C_I = {:?}", c_i);
+
+        Ok(SecurityContext {
+            c_i,
+            message_3: Some(message_3),
+        })
+    }
+
+    /// Returns a stack through which requests are sent to the same server as through `stack`,
+    /// protected with OSCORE using the `context`.
+    pub fn protect<'s, S: Stack>(
+        &'s self,
+        stack: &'s mut S,
+        context: &'s mut SecurityContext,
+    ) -> ProtectedStack<'s, S, Crypto, N> {
+        ProtectedStack {
+            stack,
+            pool: self.pool,
+            context,
+        }
+    }
+}
+
+/// A CoAP stack that protects requests with OSCORE before sending them through an underlying
+/// stack; see [`OscoreEdhocClient::protect()`].
+///
+/// Requests see the plaintext messages, which are buffered in memory.
+pub struct ProtectedStack<'s, S, Crypto: lakers::Crypto, const N: usize = DEFAULT_MAX_CONTEXTS> {
+    stack: &'s mut S,
+    pool: &'s RefCell<SecContextPool<Crypto, N>>,
+    context: &'s mut SecurityContext,
+}
+
+impl<'s, S: Stack, Crypto: lakers::Crypto, const N: usize> Stack
+    for ProtectedStack<'s, S, Crypto, N>
+{
+    type RequestUnionError = <Message<'static> as MinimalWritableMessage>::UnionError;
+    type RequestMessage<'a>
+        = Message<'a>
+    where
+        Self: 'a;
+    type ResponseMessage<'a>
+        = Message<'a>
+    where
+        Self: 'a;
+    type TransportError = ClientError<S::TransportError>;
+
+    async fn request<Req: Request<Self>>(
+        &mut self,
+        mut request: Req,
+    ) -> Result<Req::Output, Self::TransportError> {
+        // Until liboscore can work on arbitrary messages, plaintext and ciphertext are built in
+        // buffers, and copied into the underlying stack's messages.
+        let mut plaintext_code = 0;
+        let mut plaintext_buffer = [0u8; MAX_SIZE];
+        let mut plaintext = Message::new(&mut plaintext_code, &mut plaintext_buffer[..]);
+        let carry = request
+            .build_request(&mut plaintext)
+            .await
+            .map_err(|_| ClientError::TooLarge)?;
+
+        let mut protected_code = 0;
+        let mut protected_buffer = [0u8; MAX_SIZE];
+        let mut protected = Message::new(&mut protected_code, &mut protected_buffer[..]);
+        let c_i = self.context.c_i;
+        let (correlation, copied) = self
+            .pool
+            .borrow_mut()
+            .lookup(
+                |c| c.corresponding_cown() == Some(c_i),
+                |matched| {
+                    let SecContextState {
+                        protocol_stage:
+                            SecContextStage::Oscore(ref mut oscore_context, ref mut persistence),
+                        ..
+                    } = matched
+                    else {
+                        return Err(ClientError::ContextEvicted);
+                    };
+                    let protected_result =
+                        liboscore::protect_request(&mut protected, oscore_context, |inner| {
+                            copy_message(&plaintext, inner)
+                        })
+                        .map_err(|_| ClientError::Oscore)?;
+                    let sequence_number = protected
+                        .options()
+                        .find(|o| o.number() == coap_numbers::option::OSCORE)
+                        .and_then(|o| crate::persistence::partial_iv(o.value()))
+                        .ok_or(ClientError::Oscore)?;
+                    if !persistence.send(sequence_number) {
+                        return Err(ClientError::Unpersisted);
+                    }
+                    Ok(protected_result)
+                },
+            )
+            .ok_or(ClientError::ContextEvicted)??;
+        copied.map_err(|_| ClientError::TooLarge)?;
+        if let Some(message_3) = &self.context.message_3 {
+            // The server reassembles requests only up to the same size.
+            let message_3 = message_3.as_slice();
+            let combined_len = encode_bstr_head(message_3.len()).len()
+                + message_3.len()
+                + protected.payload().len();
+            if combined_len > MAX_SIZE {
+                warn!("Combined EDHOC and OSCORE request exceeds buffer");
+                return Err(ClientError::TooLarge);
+            }
+        }
+
+        let output = self
+            .stack
+            .request(Protected {
+                request: &mut request,
+                carry: Some(carry),
+                protected: &protected,
+                message_3: self.context.message_3.as_ref().map(|m| m.as_slice()),
+                correlation,
+                pool: self.pool,
+                c_i,
+                _stack: PhantomData,
+            })
+            .await
+            .map_err(ClientError::Transport)??;
+
+        if self.context.message_3.take().is_some() {
+            debug!("Server confirmed the EDHOC exchange by answering with OSCORE");
+        }
+        Ok(output)
+    }
+}
+
+/// The request sent through the underlying stack for a request to a [`ProtectedStack`].
+///
+/// The inner request is built and protected before; this only copies the protected message (and
+/// EDHOC message 3, if it is still pending) into the outgoing request, and unprotects the
+/// response for the inner request to process.
+struct Protected<
+    'r,
+    's,
+    S,
+    Crypto: lakers::Crypto,
+    const N: usize,
+    Req: Request<ProtectedStack<'s, S, Crypto, N>>,
+> {
+    request: &'r mut Req,
+    carry: Option<Req::Carry>,
+    protected: &'r Message<'r>,
+    message_3: Option<&'r [u8]>,
+    correlation: liboscore::raw::oscore_requestid_t,
+    pool: &'r RefCell<SecContextPool<Crypto, N>>,
+    c_i: COwn,
+    _stack: PhantomData<ProtectedStack<'s, S, Crypto, N>>,
+}
+
+impl<
+        's,
+        S: Stack,
+        Crypto: lakers::Crypto,
+        const N: usize,
+        Req: Request<ProtectedStack<'s, S, Crypto, N>>,
+    > Request<S> for Protected<'_, 's, S, Crypto, N, Req>
+{
+    type Carry = ();
+    type Output = Result<Req::Output, ClientError<S::TransportError>>;
+
+    async fn build_request(
+        &mut self,
+        request: &mut S::RequestMessage<'_>,
+    ) -> Result<(), S::RequestUnionError> {
+        request.set_code(
+            <S::RequestMessage<'_> as MinimalWritableMessage>::Code::new(
+                self.protected.code().into(),
+            )?,
+        );
+
+        let mut edhoc_option_pending = self.message_3.is_some();
+        for option in self.protected.options() {
+            if edhoc_option_pending && option.number() > coap_numbers::option::EDHOC {
+                add_option(request, coap_numbers::option::EDHOC, &[])?;
+                edhoc_option_pending = false;
+            }
+            add_option(request, option.number(), option.value())?;
+        }
+        if edhoc_option_pending {
+            add_option(request, coap_numbers::option::EDHOC, &[])?;
+        }
+
+        let Some(message_3) = self.message_3 else {
+            request.set_payload(self.protected.payload())?;
+            return Ok(());
+        };
+        // In a combined request, the payload is message 3 as a CBOR byte string, followed by the
+        // OSCORE ciphertext.
+        let mut combined = [0u8; MAX_SIZE];
+        let head = encode_bstr_head(message_3.len());
+        let parts = [head.as_slice(), message_3, self.protected.payload()];
+        let mut written = 0;
+        for part in parts {
+            combined
+                .get_mut(written..written + part.len())
+                .expect("Size was checked before sending")
+                .copy_from_slice(part);
+            written += part.len();
+        }
+        #[allow(
+            clippy::indexing_slicing,
+            reason = "written is at most the buffer size"
+        )]
+        request.set_payload(&combined[..written])?;
+        Ok(())
+    }
+
+    async fn process_response(
+        &mut self,
+        response: &S::ResponseMessage<'_>,
+        (): (),
+    ) -> Self::Output {
+        let mut copy_code = 0;
+        let mut copy_buffer = [0u8; MAX_SIZE];
+        let mut copied_message = Message::new(&mut copy_code, &mut copy_buffer[..]);
+        let mut oscore_option = None;
+        copied_message.set_code(response.code().into());
+        for option in response.options() {
+            copied_message
+                .add_option(option.number(), option.value())
+                .map_err(|_| ClientError::TooLarge)?;
+            if option.number() == coap_numbers::option::OSCORE {
+                oscore_option = Some(
+                    heapless::Vec::<_, 16>::try_from(option.value())
+                        .map_err(|()| ClientError::Oscore)?,
+                );
+            }
+        }
+        copied_message
+            .set_payload(response.payload())
+            .map_err(|_| ClientError::TooLarge)?;
+
+        let Some(oscore_option) = oscore_option else {
+            // Errors in processing EDHOC or OSCORE are sent unprotected.
+            return Err(ClientError::UnexpectedResponse(response.code().into()));
+        };
+        let oscore_option =
+            liboscore::OscoreOption::parse(&oscore_option).map_err(|_| ClientError::Oscore)?;
+
+        let mut plaintext_code = 0;
+        let mut plaintext_buffer = [0u8; MAX_SIZE];
+        let mut plaintext = Message::new(&mut plaintext_code, &mut plaintext_buffer[..]);
+        let c_i = self.c_i;
+        let correlation = &mut self.correlation;
+        let copied = self
+            .pool
+            .borrow_mut()
+            .lookup(
+                |c| c.corresponding_cown() == Some(c_i),
+                |matched| {
+                    let SecContextState {
+                        protocol_stage: SecContextStage::Oscore(ref mut oscore_context, _),
+                        ..
+                    } = matched
+                    else {
+                        return Err(ClientError::ContextEvicted);
+                    };
+                    liboscore::unprotect_response(
+                        &mut copied_message,
+                        oscore_context,
+                        oscore_option,
+                        correlation,
+                        |inner| copy_message(inner, &mut plaintext),
+                    )
+                    .map_err(|_| ClientError::Oscore)
+                },
+            )
+            .ok_or(ClientError::ContextEvicted)??;
+        copied.map_err(|_| ClientError::TooLarge)?;
+
+        let carry = self
+            .carry
+            .take()
+            .expect("Responses are processed only once");
+        Ok(self.request.process_response(&plaintext, carry).await)
+    }
+}
+
+/// The request carrying EDHOC message 1 to the server's `/.well-known/edhoc` resource.
+struct EdhocMessage1<'m> {
+    message_1: &'m [u8],
+}
+
+impl<S: Stack> Request<S> for EdhocMessage1<'_> {
+    type Carry = ();
+    type Output = Result<lakers::EdhocMessageBuffer, ClientError<S::TransportError>>;
+
+    async fn build_request(
+        &mut self,
+        request: &mut S::RequestMessage<'_>,
+    ) -> Result<(), S::RequestUnionError> {
+        request.set_code(
+            <S::RequestMessage<'_> as MinimalWritableMessage>::Code::new(coap_numbers::code::POST)?,
+        );
+        add_option(request, coap_numbers::option::URI_PATH, b".well-known")?;
+        add_option(request, coap_numbers::option::URI_PATH, b"edhoc")?;
+
+        // Message 1 is prefixed with CBOR true, as there is no C_R yet (RFC 9528 Appendix A.2).
+        let mut payload = [0u8; lakers::MAX_BUFFER_LEN + 1];
+        let (first, rest) = payload
+            .split_first_mut()
+            .expect("Buffer is longer than 1 byte");
+        *first = 0xf5;
+        let rest = rest
+            .get_mut(..self.message_1.len())
+            .expect("Message 1 fits into a message buffer");
+        rest.copy_from_slice(self.message_1);
+        #[allow(clippy::indexing_slicing, reason = "length was checked above")]
+        request.set_payload(&payload[..=self.message_1.len()])?;
+        Ok(())
+    }
+
+    async fn process_response(
+        &mut self,
+        response: &S::ResponseMessage<'_>,
+        (): (),
+    ) -> Self::Output {
+        let code: u8 = response.code().into();
+        if code != coap_numbers::code::CHANGED {
+            return Err(ClientError::UnexpectedResponse(code));
+        }
+        lakers::EdhocMessageBuffer::new_from_slice(response.payload())
+            .map_err(|_| ClientError::TooLarge)
+    }
+}
+
+/// Adds an option to a generic message.
+pub(crate) fn add_option<M: MinimalWritableMessage>(
+    message: &mut M,
+    number: u16,
+    value: &[u8],
+) -> Result<(), M::UnionError> {
+    message.add_option(M::OptionNumber::new(number)?, value)?;
+    Ok(())
+}
+
+/// Copies code, options and payload of a message into another.
+fn copy_message<M: MinimalWritableMessage>(
+    from: &impl ReadableMessage,
+    to: &mut M,
+) -> Result<(), M::UnionError> {
+    to.set_code(M::Code::new(from.code().into())?);
+    for option in from.options() {
+        add_option(to, option.number(), option.value())?;
+    }
+    to.set_payload(from.payload())?;
+    Ok(())
+}
+
+/// Encodes the head of a CBOR byte string of the given length (which is less than 64 KiB, as it
+/// fits a message).
+#[expect(
+    clippy::cast_possible_truncation,
+    reason = "ranges are checked by the match arms"
+)]
+fn encode_bstr_head(len: usize) -> heapless::Vec<u8, 3> {
+    let head = match len {
+        0..=23 => heapless::Vec::from_slice(&[0x40 | len as u8]),
+        24..=0xff => heapless::Vec::from_slice(&[0x58, len as u8]),
+        _ => heapless::Vec::from_slice(&[0x59, (len >> 8) as u8, len as u8]),
+    };
+    head.expect("Heads of byte strings this short take at most 3 bytes")
+}
+
+#[cfg(test)]
+mod test {
+    use coap_handler::Handler as _;
+    use coap_message::{error::RenderableOnMinimal as _, Code as _};
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::authorization::{Peer, PeerCredential, Scope};
+    use crate::seccontext::OscoreEdhocHandler;
+    use crate::test_util::{
+        client_crypto, server_crypto, Hello, TestCrypto, CLIENT_CCS, CLIENT_KEY, SERVER_CCS,
+        SERVER_KEY,
+    };
+
+    /// A CoAP stack that passes requests to a handler in memory.
+    struct Loopback<'h, 'a>(&'h mut OscoreEdhocHandler<'a, Hello, TestCrypto, 4>);
+
+    impl Stack for Loopback<'_, '_> {
+        type RequestUnionError = <Message<'static> as MinimalWritableMessage>::UnionError;
+        type RequestMessage<'m>
+            = Message<'m>
+        where
+            Self: 'm;
+        type ResponseMessage<'m>
+            = Message<'m>
+        where
+            Self: 'm;
+        type TransportError = ();
+
+        async fn request<Req: Request<Self>>(
+            &mut self,
+            mut request: Req,
+        ) -> Result<Req::Output, ()> {
+            let mut request_code = 0;
+            let mut request_buffer = [0u8; MAX_SIZE];
+            let mut message = Message::new(&mut request_code, &mut request_buffer[..]);
+            let carry = request.build_request(&mut message).await.map_err(|_| ())?;
+            let extracted = self.0.extract_request_data(&message);
+
+            let mut code = 0;
+            let mut buffer = [0u8; MAX_SIZE];
+            let mut response = Message::new(&mut code, &mut buffer[..]);
+            let rendered = match extracted {
+                Ok(extracted) => self.0.build_response(&mut response, extracted).is_ok(),
+                Err(e) => e.render(&mut response).is_ok(),
+            };
+            assert!(rendered, "Response could not be built");
+            Ok(request.process_response(&response, carry).await)
+        }
+    }
+
+    /// A GET request to `/hello` with a payload, returning the response code and payload.
+    struct GetHello<'p>(&'p [u8]);
+
+    impl<S: Stack> Request<S> for GetHello<'_> {
+        type Carry = ();
+        type Output = (u8, heapless::Vec<u8, 16>);
+
+        async fn build_request(
+            &mut self,
+            request: &mut S::RequestMessage<'_>,
+        ) -> Result<(), S::RequestUnionError> {
+            request.set_code(
+                <S::RequestMessage<'_> as MinimalWritableMessage>::Code::new(
+                    coap_numbers::code::GET,
+                )?,
+            );
+            add_option(request, coap_numbers::option::URI_PATH, b"hello")?;
+            request.set_payload(self.0)?;
+            Ok(())
+        }
+
+        async fn process_response(
+            &mut self,
+            response: &S::ResponseMessage<'_>,
+            (): (),
+        ) -> Self::Output {
+            (
+                response.code().into(),
+                heapless::Vec::from_slice(response.payload()).unwrap(),
+            )
+        }
+    }
+
+    #[test]
+    fn loopback() {
+        let server_credential = lakers::Credential::parse_ccs(SERVER_CCS).unwrap();
+        let client_credential = lakers::Credential::parse_ccs(CLIENT_CCS).unwrap();
+        let server_peers = [Peer {
+            credential: PeerCredential::Ccs(CLIENT_CCS),
+            scope: Scope::AllowAll,
+        }];
+        let client_peers = [Peer {
+            credential: PeerCredential::Ccs(SERVER_CCS),
+            scope: Scope::AllowAll,
+        }];
+
+        // Client and server share the pool, as they would on a device that is both.
+        let pool = RefCell::new(SecContextPool::<TestCrypto, 4>::new());
+        let mut handler = OscoreEdhocHandler::new(
+            (&server_credential, &SERVER_KEY),
+            PeerTable::new(&server_peers),
+            &pool,
+            Hello,
+            server_crypto,
+        );
+        let client = OscoreEdhocClient::new(
+            (&client_credential, &CLIENT_KEY),
+            PeerTable::new(&client_peers),
+            &pool,
+            client_crypto,
+        );
+        let mut stack = Loopback(&mut handler);
+
+        let mut context = block_on(client.establish(&mut stack, SERVER_CCS)).unwrap();
+        assert!(context.message_3.is_some());
+
+        // The protected request fits, but not along with message 3.
+        let large = [0; 1124];
+        assert!(matches!(
+            block_on(
+                client
+                    .protect(&mut stack, &mut context)
+                    .request(GetHello(&large))
+            ),
+            Err(ClientError::TooLarge)
+        ));
+        assert!(context.message_3.is_some());
+
+        for _ in 0..2 {
+            let (code, payload) = block_on(
+                client
+                    .protect(&mut stack, &mut context)
+                    .request(GetHello(&[])),
+            )
+            .unwrap();
+            assert_eq!(code, coap_numbers::code::CONTENT);
+            assert_eq!(payload.as_slice(), b"world");
+            // The server confirmed receiving message 3.
+            assert!(context.message_3.is_none());
+        }
+    }
+
+    #[test]
+    fn bstr_head() {
+        assert_eq!(encode_bstr_head(0), [0x40]);
+        assert_eq!(encode_bstr_head(23), [0x57]);
+        assert_eq!(encode_bstr_head(24), [0x58, 24]);
+        assert_eq!(encode_bstr_head(0x123), [0x59, 0x01, 0x23]);
+    }
+}
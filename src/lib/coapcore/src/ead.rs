@@ -0,0 +1,89 @@
+//! Processing of External Authorization Data (EAD, RFC 9528 Section 3.8) in EDHOC.
+//!
+//! EAD items ride along with the EDHOC messages, and carry data for extensions such as
+//! voucher-based zero-touch enrollment (`draft-ietf-lake-authz`), where the initiator sends a
+//! voucher request in EAD_1, and the responder answers with the voucher in EAD_2.
+//!
+//! The [`OscoreEdhocHandler`](crate::seccontext::OscoreEdhocHandler) hands the EAD items it
+//! receives to an [`EadProcessor`], which can accept or reject the handshake, and provides the
+//! items to send in return.
+
+/// Error indicating that an EAD processor rejects the handshake.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EadRejected;
+
+/// Function processing a received EAD item (if any), and producing the item (if any) to send in
+/// the next message.
+pub type EadFn = fn(Option<&lakers::EADItem>) -> Result<Option<lakers::EADItem>, EadRejected>;
+
+/// Processors for the EAD items received by an EDHOC responder.
+///
+/// Processors run synchronously while the request is processed. Data they need from elsewhere
+/// (e.g., a voucher obtained from an enrollment server) needs to be available by then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EadProcessor {
+    /// Processes EAD_1 of message 1, and produces EAD_2 for message 2.
+    pub ead_1: EadFn,
+    /// Processes EAD_3 of message 3, and produces EAD_4 for message 4.
+    ///
+    /// The produced item is only used if message 4 is sent.
+    pub ead_3: EadFn,
+}
+
+impl EadProcessor {
+    /// Processors that accept elective items without acting on them, reject critical items, and
+    /// send no items.
+    ///
+    /// This is the behavior required by RFC 9528 of implementations that support no EAD items.
+    pub const NONE: Self = Self {
+        ead_1: ignore_elective,
+        ead_3: ignore_elective,
+    };
+}
+
+impl Default for EadProcessor {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+/// Accepts elective items without acting on them, and rejects critical items.
+///
+/// This can be used as a fallback by processors that only understand some items.
+///
+/// # Errors
+///
+/// Returns an error if the item is critical.
+pub fn ignore_elective(
+    item: Option<&lakers::EADItem>,
+) -> Result<Option<lakers::EADItem>, EadRejected> {
+    match item {
+        Some(item) if item.is_critical => Err(EadRejected),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn none() {
+        let item = |is_critical| lakers::EADItem {
+            label: 1,
+            is_critical,
+            value: None,
+        };
+
+        assert!(matches!((EadProcessor::NONE.ead_1)(None), Ok(None)));
+        assert!(matches!(
+            (EadProcessor::NONE.ead_1)(Some(&item(false))),
+            Ok(None)
+        ));
+        assert!(matches!(
+            (EadProcessor::NONE.ead_3)(Some(&item(true))),
+            Err(EadRejected)
+        ));
+    }
+}
@@ -0,0 +1,101 @@
+//! Policies deciding which security contexts are kept when the pool is full.
+//!
+//! The pool of security contexts has a fixed number of slots. When a new EDHOC session starts
+//! while all slots are taken, the least recently used context of the lowest [`Retention`] is
+//! evicted. An [`EvictionPolicy`] decides the retention of each context as it is established,
+//! based on how the peer is authorized.
+//!
+//! Contexts that are [`Retention::Pinned`] are never evicted: if all slots are taken by pinned
+//! contexts, new sessions are rejected instead. Pinning should be reserved for few peers, such as
+//! those of administrators, or the pool may fill up with contexts of peers that are long gone.
+use crate::authorization::{AifValue, Peer, Scope};
+
+/// How strongly a security context is retained when the pool is full.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    /// The context is never evicted.
+    Pinned,
+    /// The context is evicted only when no [`Evictable`](Self::Evictable) contexts are left.
+    Preferred,
+    /// The context is evicted first.
+    Evictable,
+}
+
+/// How the peer of a security context is authorized, as presented to an [`EvictionPolicy`].
+#[derive(Debug, Clone, Copy)]
+pub enum ContextAuthorization<'a> {
+    /// The peer is not known; it has the permissions of unknown peers.
+    Unauthenticated,
+    /// The peer is the known peer at this index into the
+    /// [`PeerTable`](crate::authorization::PeerTable).
+    Peer(usize, &'a Peer<'a>),
+    /// The peer presented an access token with this scope, see [`ace`](crate::ace).
+    Token(AifValue<'a>),
+}
+
+/// Function deciding the retention of a security context as it is established.
+pub type EvictionPolicy = fn(ContextAuthorization<'_>) -> Retention;
+
+/// Prefers contexts of known peers and of peers with access tokens over those of unknown peers,
+/// and pins none.
+///
+/// This is the default policy.
+#[must_use]
+pub fn prefer_authenticated(authorization: ContextAuthorization<'_>) -> Retention {
+    match authorization {
+        ContextAuthorization::Unauthenticated => Retention::Evictable,
+        ContextAuthorization::Peer(..) | ContextAuthorization::Token(_) => Retention::Preferred,
+    }
+}
+
+/// Pins contexts of known peers that are allowed all requests (i.e., administrators), and
+/// otherwise behaves like [`prefer_authenticated`].
+#[must_use]
+pub fn pin_admins(authorization: ContextAuthorization<'_>) -> Retention {
+    match authorization {
+        ContextAuthorization::Peer(
+            _,
+            Peer {
+                scope: Scope::AllowAll,
+                ..
+            },
+        ) => Retention::Pinned,
+        other => prefer_authenticated(other),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::authorization::PeerCredential;
+
+    #[test]
+    fn policies() {
+        let admin = Peer {
+            credential: PeerCredential::Thumbprint([0; 32]),
+            scope: Scope::AllowAll,
+        };
+        let user = Peer {
+            credential: PeerCredential::Thumbprint([1; 32]),
+            scope: Scope::DENY_ALL,
+        };
+
+        assert_eq!(
+            prefer_authenticated(ContextAuthorization::Peer(0, &admin)),
+            Retention::Preferred
+        );
+        assert_eq!(
+            pin_admins(ContextAuthorization::Peer(0, &admin)),
+            Retention::Pinned
+        );
+        assert_eq!(
+            pin_admins(ContextAuthorization::Peer(1, &user)),
+            Retention::Preferred
+        );
+        assert_eq!(
+            pin_admins(ContextAuthorization::Unauthenticated),
+            Retention::Evictable
+        );
+    }
+}
@@ -27,4 +27,18 @@
 // This is pub only to make the doctests run (but the crate's pub-ness needs a major overhaul
 // anyway)
 pub mod oluru;
+
+pub mod ace;
+pub mod authorization;
+pub mod block;
+pub mod client;
+pub mod ead;
+pub mod eviction;
+pub mod observe;
+pub mod persistence;
+pub mod rd;
 pub mod seccontext;
+pub mod tcp;
+
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,509 @@
+//! Observable resources (RFC 7641).
+//!
+//! A resource is observable if it is associated with an [`Observable`], through which changes to
+//! it are announced. Clients register by sending a GET request with an Observe option of 0; the
+//! transport keeps their registrations as [`Observation`]s in [`Observers`]. When a resource
+//! changed, a notification is rendered for each of its observations by running the registering
+//! request through the handler again, which is why the request's options are kept.
+//!
+//! Observations registered through OSCORE keep the [`Protection`] of the registering request, and
+//! their notifications are protected through the same security context, see
+//! [`OscoreEdhocHandler::build_protected_notification()`](crate::seccontext::OscoreEdhocHandler::build_protected_notification).
+//!
+//! Sending notifications is up to the transport: this module only provides the bookkeeping.
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use coap_message::{
+    MessageOption as _, MinimalWritableMessage, OptionNumber as _, ReadableMessage,
+};
+use coap_message_implementations::{inmemory, inmemory_write};
+
+use crate::seccontext::COwn;
+
+/// Longest token of an observation that can be kept.
+pub const MAX_TOKEN_LEN: usize = 8;
+
+/// Longest encoded options of a registering request that can be kept.
+///
+/// Registrations through longer requests are not accepted (and the request is answered as if it
+/// had not asked for an observation).
+pub const MAX_REQUEST_LEN: usize = 64;
+
+/// Values of the Observe option are 24 bits long (RFC 7641 Section 4.4).
+const SEQUENCE_MASK: u32 = 0x00ff_ffff;
+
+/// A resource that clients can observe.
+///
+/// This is typically a `static` that the code changing the resource marks as changed.
+#[derive(Debug)]
+pub struct Observable {
+    // Only loads and stores are used, as not all platforms have atomic read-modify-write
+    // operations; marking changes is the only operation that happens from other threads.
+    changed: AtomicBool,
+    sequence: AtomicU32,
+}
+
+impl Observable {
+    /// Creates an observable resource without any pending changes.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            changed: AtomicBool::new(false),
+            sequence: AtomicU32::new(0),
+        }
+    }
+
+    /// Marks the resource as changed, so that its observers get notified.
+    ///
+    /// This can be called from any thread, but does not wake the transport on its own.
+    pub fn mark_changed(&self) {
+        self.changed.store(true, Ordering::Release);
+    }
+
+    /// Returns whether the resource changed since the last call, and if so, advances the value of
+    /// its Observe option.
+    ///
+    /// This must only be called by the transport sending the notifications.
+    pub fn take_change(&self) -> bool {
+        if !self.changed.load(Ordering::Acquire) {
+            return false;
+        }
+        // Cleared before the notifications are rendered, so that a change marked while they are
+        // rendered is notified once more.
+        self.changed.store(false, Ordering::Release);
+        self.sequence.store(
+            self.sequence.load(Ordering::Relaxed).wrapping_add(1) & SEQUENCE_MASK,
+            Ordering::Relaxed,
+        );
+        true
+    }
+
+    /// Returns the value of the Observe option for responses representing the resource's current
+    /// state.
+    pub fn sequence(&self) -> u32 {
+        self.sequence.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Observable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What a request asks for through its Observe option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObserveRequest {
+    /// The client registers an observation, or renews it.
+    Register,
+    /// The client ends its observation.
+    Deregister,
+}
+
+/// Returns what a GET request asks for through its Observe option, if anything.
+pub fn observe_request(request: &impl ReadableMessage) -> Option<ObserveRequest> {
+    if request.code().into() != coap_numbers::code::GET {
+        return None;
+    }
+    let option = request
+        .options()
+        .find(|o| o.number() == coap_numbers::option::OBSERVE)?;
+    match decode_uint(option.value())? {
+        0 => Some(ObserveRequest::Register),
+        1 => Some(ObserveRequest::Deregister),
+        _ => None,
+    }
+}
+
+/// Adds an Observe option with the value `sequence` to a response.
+///
+/// # Errors
+///
+/// This fails if the message can not take the option.
+pub fn add_observe_option<M: MinimalWritableMessage>(
+    message: &mut M,
+    sequence: u32,
+) -> Result<(), M::UnionError> {
+    let encoded = encode_uint(sequence & SEQUENCE_MASK);
+    message.add_option(
+        M::OptionNumber::new(coap_numbers::option::OBSERVE)?,
+        &encoded,
+    )?;
+    Ok(())
+}
+
+/// Decodes a CoAP uint option value of up to 4 bytes.
+fn decode_uint(value: &[u8]) -> Option<u32> {
+    if value.len() > 4 {
+        return None;
+    }
+    Some(
+        value
+            .iter()
+            .fold(0, |acc, byte| (acc << 8) | u32::from(*byte)),
+    )
+}
+
+/// Encodes a CoAP uint option value, omitting leading zero bytes.
+fn encode_uint(value: u32) -> heapless::Vec<u8, 4> {
+    let bytes = value.to_be_bytes();
+    let leading_zeros = bytes.iter().take_while(|b| **b == 0).count();
+    bytes
+        .get(leading_zeros..)
+        .and_then(|significant| heapless::Vec::from_slice(significant).ok())
+        .expect("Significant bytes of a u32 fit in 4 bytes")
+}
+
+/// The OSCORE protection of an observation: the security context and the request it was
+/// registered through.
+pub struct Protection {
+    pub(crate) kid: COwn,
+    /// The correlation of the registering request, after its response was protected, so that
+    /// every notification uses its own Partial IV.
+    pub(crate) correlation: liboscore::raw::oscore_requestid_t,
+}
+
+impl core::fmt::Debug for Protection {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Protection")
+            .field("kid", &self.kid)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Error building a protected notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationError {
+    /// The security context of the observation is gone; the observation can not be continued.
+    ContextGone,
+    /// The peer is no longer allowed to read the resource.
+    NotAllowed,
+    /// The sequence numbers of the security context are used up until a new limit is stored (see
+    /// [`crate::persistence`]); the notification can be retried later.
+    Unpersisted,
+    /// The notification could not be protected.
+    Oscore,
+}
+
+/// An observation registered by a client.
+///
+/// `A` identifies the client to the transport, typically by its address.
+#[derive(Debug)]
+pub struct Observation<'r, A> {
+    resource: &'r Observable,
+    peer: A,
+    token: heapless::Vec<u8, MAX_TOKEN_LEN>,
+    /// Encoded options of the registering request.
+    request: heapless::Vec<u8, MAX_REQUEST_LEN>,
+    protection: Option<Protection>,
+    /// Message ID of the latest notification, by which a Reset ends the observation.
+    message_id: Option<u16>,
+}
+
+impl<'r, A> Observation<'r, A> {
+    /// Creates an observation of `resource` registered by the GET `request` that `peer` sent with
+    /// `token`.
+    ///
+    /// Returns `None` if the token or the request's options are too long to be kept.
+    pub fn new(
+        resource: &'r Observable,
+        peer: A,
+        token: &[u8],
+        request: &impl ReadableMessage,
+    ) -> Option<Self> {
+        let mut code = 0;
+        let mut buffer = [0; MAX_REQUEST_LEN];
+        let mut encoded = inmemory_write::Message::new(&mut code, &mut buffer);
+        for option in request.options() {
+            encoded.add_option(option.number(), option.value()).ok()?;
+        }
+        let len = encoded.finish();
+
+        Some(Self {
+            resource,
+            peer,
+            token: heapless::Vec::from_slice(token).ok()?,
+            request: heapless::Vec::from_slice(buffer.get(..len)?).ok()?,
+            protection: None,
+            message_id: None,
+        })
+    }
+
+    /// Returns the observed resource.
+    pub fn resource(&self) -> &'r Observable {
+        self.resource
+    }
+
+    /// Returns the peer that registered the observation.
+    pub fn peer(&self) -> &A {
+        &self.peer
+    }
+
+    /// Returns the token that notifications are sent with.
+    pub fn token(&self) -> &[u8] {
+        &self.token
+    }
+
+    /// Returns the request to render notifications from: a GET request with the options of the
+    /// registering request.
+    ///
+    /// For observations registered through OSCORE, these are the options of the inner request.
+    pub fn request(&self) -> impl ReadableMessage + '_ {
+        inmemory::Message::new(coap_numbers::code::GET, &self.request)
+    }
+
+    /// Returns the request to render notifications from (see [`Self::request()`]) along with
+    /// the OSCORE protection of the observation, if it was registered through OSCORE.
+    pub fn request_with_protection(
+        &mut self,
+    ) -> (impl ReadableMessage + '_, Option<&mut Protection>) {
+        (
+            inmemory::Message::new(coap_numbers::code::GET, &self.request),
+            self.protection.as_mut(),
+        )
+    }
+
+    /// Sets the OSCORE protection of the observation.
+    pub fn set_protection(&mut self, protection: Protection) {
+        self.protection = Some(protection);
+    }
+
+    /// Records the message ID of the latest notification.
+    pub fn set_message_id(&mut self, message_id: u16) {
+        self.message_id = Some(message_id);
+    }
+}
+
+/// The observations registered at a server, up to `N` of them.
+#[derive(Debug)]
+pub struct Observers<'r, A, const N: usize> {
+    observations: heapless::Vec<Observation<'r, A>, N>,
+}
+
+impl<'r, A: PartialEq, const N: usize> Observers<'r, A, N> {
+    /// Creates an empty set of observations.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            observations: heapless::Vec::new(),
+        }
+    }
+
+    /// Returns whether a registration of `peer` with `token` can be accepted, i.e., whether it
+    /// renews an observation or there is room for another.
+    pub fn accepts(&self, peer: &A, token: &[u8]) -> bool {
+        !self.observations.is_full() || self.position(peer, token).is_some()
+    }
+
+    /// Registers an observation, replacing any of the same peer and token.
+    ///
+    /// # Errors
+    ///
+    /// The observation is returned if there is no room for it.
+    pub fn register(&mut self, observation: Observation<'r, A>) -> Result<(), Observation<'r, A>> {
+        match self.position(&observation.peer, &observation.token) {
+            Some(index) => {
+                if let Some(existing) = self.observations.get_mut(index) {
+                    *existing = observation;
+                }
+                Ok(())
+            }
+            None => self.observations.push(observation),
+        }
+    }
+
+    /// Ends the observation of `peer` with `token`; returns whether there was one.
+    pub fn deregister(&mut self, peer: &A, token: &[u8]) -> bool {
+        let Some(index) = self.position(peer, token) else {
+            return false;
+        };
+        self.observations.swap_remove(index);
+        true
+    }
+
+    /// Ends the observation of `peer` whose latest notification had the given message ID, as the
+    /// peer rejected it with a Reset; returns whether there was one.
+    pub fn reset(&mut self, peer: &A, message_id: u16) -> bool {
+        let Some(index) = self
+            .observations
+            .iter()
+            .position(|o| &o.peer == peer && o.message_id == Some(message_id))
+        else {
+            return false;
+        };
+        self.observations.swap_remove(index);
+        true
+    }
+
+    /// Removes and returns the observations of all resources that changed (see
+    /// [`Observable::take_change()`]).
+    ///
+    /// Once their notifications are rendered, observations that continue are to be placed back
+    /// through [`Self::restore()`].
+    pub fn take_changed(&mut self) -> heapless::Vec<Observation<'r, A>, N> {
+        let mut changed: heapless::Vec<&'r Observable, N> = heapless::Vec::new();
+        for observation in &self.observations {
+            let resource = observation.resource;
+            if !changed.iter().any(|c| core::ptr::eq(*c, resource)) && resource.take_change() {
+                changed
+                    .push(resource)
+                    .expect("There are no more resources than observations");
+            }
+        }
+
+        let mut taken = heapless::Vec::new();
+        let mut index = 0;
+        while let Some(observation) = self.observations.get(index) {
+            if changed
+                .iter()
+                .any(|c| core::ptr::eq(*c, observation.resource))
+            {
+                taken
+                    .push(self.observations.swap_remove(index))
+                    .expect("Not more observations are taken than there are");
+            } else {
+                index += 1;
+            }
+        }
+        taken
+    }
+
+    /// Places an observation taken through [`Self::take_changed()`] back.
+    ///
+    /// If the peer registered again with the same token in the meantime, the newer registration
+    /// is kept.
+    ///
+    /// # Errors
+    ///
+    /// The observation is returned if other registrations took all room in the meantime; it ends
+    /// then.
+    pub fn restore(&mut self, observation: Observation<'r, A>) -> Result<(), Observation<'r, A>> {
+        if self
+            .position(&observation.peer, &observation.token)
+            .is_some()
+        {
+            return Ok(());
+        }
+        self.observations.push(observation)
+    }
+
+    fn position(&self, peer: &A, token: &[u8]) -> Option<usize> {
+        self.observations
+            .iter()
+            .position(|o| &o.peer == peer && o.token == token)
+    }
+}
+
+impl<A: PartialEq, const N: usize> Default for Observers<'_, A, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(observe: &[u8]) -> ([u8; 32], usize) {
+        let mut code = 0;
+        let mut buffer = [0; 32];
+        let mut message = inmemory_write::Message::new(&mut code, &mut buffer);
+        message.set_code(coap_numbers::code::GET);
+        message
+            .add_option(coap_numbers::option::OBSERVE, observe)
+            .unwrap();
+        message
+            .add_option(coap_numbers::option::URI_PATH, b"temp")
+            .unwrap();
+        let len = message.finish();
+        (buffer, len)
+    }
+
+    #[test]
+    fn options() {
+        let (buffer, len) = request(&[]);
+        let message = inmemory::Message::new(coap_numbers::code::GET, buffer.get(..len).unwrap());
+        assert_eq!(observe_request(&message), Some(ObserveRequest::Register));
+
+        let (buffer, len) = request(&[1]);
+        let message = inmemory::Message::new(coap_numbers::code::GET, buffer.get(..len).unwrap());
+        assert_eq!(observe_request(&message), Some(ObserveRequest::Deregister));
+
+        let message = inmemory::Message::new(coap_numbers::code::POST, buffer.get(..len).unwrap());
+        assert_eq!(observe_request(&message), None);
+
+        assert_eq!(encode_uint(0).as_slice(), &[]);
+        assert_eq!(encode_uint(0x1234).as_slice(), &[0x12, 0x34]);
+        assert_eq!(decode_uint(&[0x12, 0x34]), Some(0x1234));
+        assert_eq!(decode_uint(&[1, 2, 3, 4, 5]), None);
+    }
+
+    #[test]
+    fn registrations() {
+        static TEMPERATURE: Observable = Observable::new();
+        static HUMIDITY: Observable = Observable::new();
+
+        let (buffer, len) = request(&[]);
+        let message = inmemory::Message::new(coap_numbers::code::GET, buffer.get(..len).unwrap());
+        let observation = |resource, peer, token: &[u8]| {
+            Observation::new(resource, peer, token, &message).unwrap()
+        };
+
+        let mut observers: Observers<'_, u8, 2> = Observers::new();
+        observers
+            .register(observation(&TEMPERATURE, 1, b"a"))
+            .unwrap();
+        observers.register(observation(&HUMIDITY, 2, b"b")).unwrap();
+        // Renewing replaces the registration
+        observers
+            .register(observation(&TEMPERATURE, 1, b"a"))
+            .unwrap();
+        assert!(!observers.accepts(&3, b"c"));
+        assert!(observers
+            .register(observation(&TEMPERATURE, 3, b"c"))
+            .is_err());
+
+        assert!(observers.take_changed().is_empty());
+        TEMPERATURE.mark_changed();
+        let mut taken = observers.take_changed();
+        assert_eq!(taken.len(), 1);
+        assert_eq!(TEMPERATURE.sequence(), 1);
+        assert!(!TEMPERATURE.take_change());
+
+        let mut notified = taken.pop().unwrap();
+        assert_eq!(notified.token(), b"a");
+        assert_eq!(
+            observe_request(&notified.request()),
+            Some(ObserveRequest::Register)
+        );
+        notified.set_message_id(0x1234);
+        observers.restore(notified).unwrap();
+
+        assert!(!observers.reset(&1, 0x4321));
+        assert!(observers.reset(&1, 0x1234));
+        assert!(observers.deregister(&2, b"b"));
+        assert!(!observers.deregister(&2, b"b"));
+
+        // Registrations made while an observation is taken may leave no room for it.
+        observers
+            .register(observation(&TEMPERATURE, 1, b"a"))
+            .unwrap();
+        TEMPERATURE.mark_changed();
+        let taken = observers.take_changed().pop().unwrap();
+        observers.register(observation(&HUMIDITY, 2, b"b")).unwrap();
+        observers.register(observation(&HUMIDITY, 3, b"c")).unwrap();
+        assert!(observers.restore(taken).is_err());
+        // A renewed registration takes the place of the taken observation.
+        assert!(observers.deregister(&3, b"c"));
+        observers
+            .register(observation(&TEMPERATURE, 1, b"a"))
+            .unwrap();
+        TEMPERATURE.mark_changed();
+        let taken = observers.take_changed().pop().unwrap();
+        observers
+            .register(observation(&TEMPERATURE, 1, b"a"))
+            .unwrap();
+        assert!(!observers.accepts(&3, b"c"));
+        assert!(observers.restore(taken).is_ok());
+    }
+}
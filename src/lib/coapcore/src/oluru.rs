@@ -227,6 +227,23 @@ impl<T: PriorityLevel, const N: usize, const L: usize> OrderedPool<T, N, L> {
         }
     }
 
+    /// Inserts an element, evicting the least priority element only if its level is at least
+    /// `evictable_level`.
+    ///
+    /// This lies between [`Self::insert`] (which evicts only elements of the new element's level
+    /// or lower priority) and [`Self::force_insert`] (which evicts regardless of the level). If
+    /// the new element is not inserted, it is returned as an Err.
+    pub fn insert_evicting(&mut self, new: T, evictable_level: usize) -> Result<Option<T>, T> {
+        if self.entries.len() == N
+            && self.entries[usize::from(*self.sorted.last().expect("Full array is not empty"))]
+                .level()
+                < evictable_level
+        {
+            return Err(new);
+        }
+        Ok(self.force_insert(new))
+    }
+
     fn touch(&mut self, position: usize) {
         let level = self.entries[usize::from(self.sorted[position])].level();
         debug_assert!(level < L, "Level exceeds limit L={L} in type");
@@ -257,7 +274,7 @@ impl<T: PriorityLevel, const N: usize, const L: usize> OrderedPool<T, N, L> {
     }
 
     /// Returns an iterator visiting all items in arbitrary order.
-    pub fn iter(&self) -> impl Iterator<Item = &T> {
+    pub fn iter(&self) -> impl Iterator<Item = &T> + Clone {
         self.entries.iter()
     }
 }
@@ -0,0 +1,615 @@
+//! Persistence of OSCORE contexts across reboots (RFC 8613 Appendix B.1).
+//!
+//! OSCORE contexts established through EDHOC can be stored, so that peers do not need to run EDHOC
+//! again after the device rebooted. Reusing a context after a reboot is only safe if no sequence
+//! number is ever used twice, and no request is accepted twice. To avoid writing to storage for
+//! every message, the stored sequence numbers are upper limits, which are increased in windows:
+//!
+//! * The sender sequence number (SSN) is stored in increments of [`SEQUENCE_NUMBER_WINDOW`]
+//!   (Appendix B.1.1). A restored context continues sending at the stored limit.
+//! * The highest received sequence number is stored in increments of [`REPLAY_WINDOW`]
+//!   (Appendix B.1.2). A restored context rejects requests below the stored limit as replays,
+//!   which peers that had not used up the window before the reboot experience as a series of
+//!   failed requests.
+//!
+//! A context is not used beyond its stored limits: Until a new limit is stored, requests are not
+//! sent through it, and requests received through it are answered with 5.03 (Service
+//! Unavailable). The stored record is requested well before the limits are reached.
+//!
+//! This module does not access storage itself; the application (or the operating system) polls
+//! [`next_record()`], stores the record it returns, and then calls [`confirm()`]. At startup,
+//! stored records are passed to [`restore()`].
+//!
+//! Contexts authorized through an access token are not persisted, because the tokens are not
+//! either.
+// FIXME: Receiving could do without REPLAY_WINDOW if restored contexts verified freshness with
+// the Echo option (RFC 9175) instead.
+use core::cell::RefCell;
+
+use crate::authorization::{Authorized, PeerTable, THUMBPRINT_LEN};
+use crate::eviction::EvictionPolicy;
+use crate::seccontext::{
+    oscore_context, COwn, SecContextPool, SecContextStage, SecContextState, MAX_COWN_LEN,
+};
+
+/// Number of sender sequence numbers by which the stored limit is increased.
+pub const SEQUENCE_NUMBER_WINDOW: u64 = 256;
+
+/// Number of received sequence numbers by which the stored limit is increased.
+pub const REPLAY_WINDOW: u64 = 32;
+
+/// Length of a [`PersistedContext`] in its serialized form.
+pub const RECORD_LEN: usize = 86;
+
+/// Longest sender ID (the peer's connection identifier) of a context that is persisted.
+const MAX_SENDER_ID_LEN: usize = 8;
+const SECRET_LEN: usize = 16;
+const SALT_LEN: usize = 8;
+
+/// Version of the record layout.
+const RECORD_VERSION: u8 = 2;
+const AUTHORIZATION_UNAUTHENTICATED: u8 = 0;
+const AUTHORIZATION_PEER: u8 = 1;
+
+/// The material from which an OSCORE context was derived.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Material {
+    pub(crate) secret: [u8; SECRET_LEN],
+    pub(crate) salt: [u8; SALT_LEN],
+    pub(crate) sender_id: heapless::Vec<u8, MAX_SENDER_ID_LEN>,
+    pub(crate) recipient_id: COwn,
+}
+
+impl Material {
+    /// Keeps the material of a context, if it can be persisted.
+    pub(crate) fn new(
+        secret: &[u8],
+        salt: &[u8],
+        sender_id: &[u8],
+        recipient_id: COwn,
+    ) -> Option<Self> {
+        Some(Self {
+            secret: secret.try_into().ok()?,
+            salt: salt.try_into().ok()?,
+            sender_id: heapless::Vec::from_slice(sender_id).ok()?,
+            recipient_id,
+        })
+    }
+}
+
+/// The stored sequence number limits of a context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Limits {
+    /// First sender sequence number that may not be used.
+    send: u64,
+    /// First received sequence number that may not be accepted.
+    receive: u64,
+}
+
+/// Why a received request may not be processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReceiveRejected {
+    /// The sequence number may have been received before the context was restored.
+    Replay,
+    /// The sequence number is beyond the stored limit; the peer may retry later.
+    Unpersisted,
+}
+
+/// Persistence state of an OSCORE context in the pool.
+#[derive(Debug)]
+pub(crate) struct Persistence {
+    /// `None` if the context can not be persisted.
+    material: Option<Material>,
+    /// Limits that were stored.
+    confirmed: Option<Limits>,
+    /// Limits that are being stored.
+    pending: Option<Limits>,
+    /// Received sequence numbers below this were possibly accepted before the context was
+    /// restored.
+    replay_floor: u64,
+    next_sent: u64,
+    next_received: u64,
+}
+
+impl Persistence {
+    pub(crate) fn new(material: Option<Material>) -> Self {
+        Self {
+            material,
+            confirmed: None,
+            pending: None,
+            replay_floor: 0,
+            next_sent: 0,
+            next_received: 0,
+        }
+    }
+
+    /// Limits that may be used.
+    ///
+    /// Without stored limits, there are no limits yet: if the device reboots before the context
+    /// is stored, the context is not restored at all. Those of a first record then apply while it
+    /// is being stored, as a later one could not supersede it.
+    fn limits(&self) -> Option<Limits> {
+        self.confirmed.or(self.pending)
+    }
+
+    /// Records that a request was protected with a sender sequence number, and returns `false` if
+    /// it may not be sent.
+    ///
+    /// The sequence number counts as used either way: the context will not produce it again, and
+    /// the next stored limit needs to be above it.
+    pub(crate) fn send(&mut self, ssn: u64) -> bool {
+        self.next_sent = self.next_sent.max(ssn + 1);
+        !self.limits().is_some_and(|limits| ssn >= limits.send)
+    }
+
+    /// Checks whether a request with the given sequence number may be processed.
+    pub(crate) fn check_received(&self, ssn: u64) -> Result<(), ReceiveRejected> {
+        if ssn < self.replay_floor {
+            return Err(ReceiveRejected::Replay);
+        }
+        if self.limits().is_some_and(|limits| ssn >= limits.receive) {
+            return Err(ReceiveRejected::Unpersisted);
+        }
+        Ok(())
+    }
+
+    /// Records that a request with the given sequence number was accepted.
+    pub(crate) fn received(&mut self, ssn: u64) {
+        self.next_received = self.next_received.max(ssn + 1);
+    }
+
+    /// Returns the limits to store, if the context should be stored.
+    fn wanted(&self) -> Option<Limits> {
+        self.material.as_ref()?;
+        let wanted = Limits {
+            send: self.next_sent + SEQUENCE_NUMBER_WINDOW,
+            receive: self.next_received.max(self.replay_floor) + REPLAY_WINDOW,
+        };
+        match self.pending.or(self.confirmed) {
+            None => Some(wanted),
+            Some(latest)
+                if self.next_sent + SEQUENCE_NUMBER_WINDOW / 2 >= latest.send
+                    || self.next_received + REPLAY_WINDOW / 2 >= latest.receive =>
+            {
+                Some(wanted)
+            }
+            Some(_) => None,
+        }
+    }
+}
+
+/// An OSCORE context in the form in which it is stored.
+///
+/// The record contains the key material of the context: it needs to be stored as confidentially
+/// as the device's private key.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PersistedContext([u8; RECORD_LEN]);
+
+impl core::fmt::Debug for PersistedContext {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PersistedContext")
+            .field("recipient_id", &self.recipient_id())
+            .finish_non_exhaustive()
+    }
+}
+
+impl PersistedContext {
+    /// Returns the serialized form of the record.
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8; RECORD_LEN] {
+        &self.0
+    }
+
+    /// Reads a record from its serialized form.
+    ///
+    /// Returns `None` if the data is not a record that this version can restore.
+    #[must_use]
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let record = Self(bytes.try_into().ok()?);
+        // Also validates the remaining fields
+        record.decode()?;
+        Some(record)
+    }
+
+    /// Returns the recipient ID of the context, by which a stored record is superseded.
+    #[must_use]
+    pub fn recipient_id(&self) -> &[u8] {
+        let (_, rest) = self.0.split_at(2);
+        // Valid by construction
+        rest.get(..usize::from(self.0[1])).unwrap_or_default()
+    }
+
+    fn encode(material: &Material, limits: Limits, peer: Option<[u8; THUMBPRINT_LEN]>) -> Self {
+        let mut record = [0; RECORD_LEN];
+        #[expect(
+            clippy::cast_possible_truncation,
+            reason = "sender IDs are at most MAX_SENDER_ID_LEN long"
+        )]
+        let sender_id_len = material.sender_id.len() as u8;
+        let recipient_id = material.recipient_id.as_kid();
+        #[expect(
+            clippy::cast_possible_truncation,
+            reason = "own identifiers are at most MAX_COWN_LEN long"
+        )]
+        let recipient_id_len = recipient_id.len() as u8;
+        let (header, rest) = record.split_at_mut(2);
+        header.copy_from_slice(&[RECORD_VERSION, recipient_id_len]);
+        let (recipient_id_field, rest) = rest.split_at_mut(MAX_COWN_LEN);
+        let (recipient_id_field, _padding) = recipient_id_field.split_at_mut(recipient_id.len());
+        recipient_id_field.copy_from_slice(recipient_id);
+        let (sender_id_len_field, rest) = rest.split_at_mut(1);
+        sender_id_len_field.copy_from_slice(&[sender_id_len]);
+        let (sender_id, rest) = rest.split_at_mut(MAX_SENDER_ID_LEN);
+        let (sender_id, _padding) = sender_id.split_at_mut(material.sender_id.len());
+        sender_id.copy_from_slice(&material.sender_id);
+        let (secret, rest) = rest.split_at_mut(SECRET_LEN);
+        secret.copy_from_slice(&material.secret);
+        let (salt, rest) = rest.split_at_mut(SALT_LEN);
+        salt.copy_from_slice(&material.salt);
+        let (send, rest) = rest.split_at_mut(8);
+        send.copy_from_slice(&limits.send.to_be_bytes());
+        let (receive, rest) = rest.split_at_mut(8);
+        receive.copy_from_slice(&limits.receive.to_be_bytes());
+        let (tag, thumbprint) = rest.split_at_mut(1);
+        match peer {
+            None => tag.copy_from_slice(&[AUTHORIZATION_UNAUTHENTICATED]),
+            Some(peer) => {
+                tag.copy_from_slice(&[AUTHORIZATION_PEER]);
+                thumbprint.copy_from_slice(&peer);
+            }
+        }
+        Self(record)
+    }
+
+    fn decode(&self) -> Option<(Material, Limits, Option<[u8; THUMBPRINT_LEN]>)> {
+        let (header, rest) = self.0.split_at(2);
+        let [RECORD_VERSION, recipient_id_len] = *header else {
+            return None;
+        };
+        let (recipient_id, rest) = rest.split_at(MAX_COWN_LEN);
+        let recipient_id = COwn::from_kid(recipient_id.get(..usize::from(recipient_id_len))?)?;
+        let (sender_id_len, rest) = rest.split_first()?;
+        let (sender_id, rest) = rest.split_at(MAX_SENDER_ID_LEN);
+        let sender_id = sender_id.get(..usize::from(*sender_id_len))?;
+        let (secret, rest) = rest.split_at(SECRET_LEN);
+        let (salt, rest) = rest.split_at(SALT_LEN);
+        let material = Material::new(secret, salt, sender_id, recipient_id)?;
+        let (send, rest) = rest.split_at(8);
+        let (receive, rest) = rest.split_at(8);
+        let limits = Limits {
+            send: u64::from_be_bytes(send.try_into().ok()?),
+            receive: u64::from_be_bytes(receive.try_into().ok()?),
+        };
+        let (tag, thumbprint) = rest.split_first()?;
+        let peer = match *tag {
+            AUTHORIZATION_UNAUTHENTICATED => None,
+            AUTHORIZATION_PEER => Some(thumbprint.try_into().ok()?),
+            _ => return None,
+        };
+        Some((material, limits, peer))
+    }
+}
+
+/// Error restoring a [`PersistedContext`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreError {
+    /// The record is not valid.
+    Malformed,
+    /// A context with the same recipient ID is already in the pool.
+    Duplicate,
+}
+
+/// Returns a record that should be stored, if any.
+///
+/// Once the record is stored, [`confirm()`] needs to be called. If storing fails, the same record
+/// should be stored again.
+///
+/// Records of contexts with the same [`recipient_id`](PersistedContext::recipient_id) supersede
+/// each other.
+pub fn next_record<Crypto: lakers::Crypto, const N: usize>(
+    pool: &RefCell<SecContextPool<Crypto, N>>,
+    peers: &PeerTable<'_>,
+) -> Option<PersistedContext> {
+    pool.borrow_mut().lookup(
+        |c| match c {
+            SecContextState {
+                protocol_stage: SecContextStage::Oscore(_, persistence),
+                authorization: Authorized::Unauthenticated | Authorized::Peer(_),
+                ..
+            } => persistence.wanted().is_some(),
+            _ => false,
+        },
+        |c| {
+            let SecContextState {
+                protocol_stage: SecContextStage::Oscore(_, persistence),
+                authorization,
+                ..
+            } = c
+            else {
+                unreachable!("Checked by the lookup condition");
+            };
+            let limits = persistence
+                .wanted()
+                .expect("Checked by the lookup condition");
+            let peer = match authorization {
+                Authorized::Peer(index) => Some(
+                    peers
+                        .thumbprint(*index)
+                        .expect("Authorization refers to the peer table"),
+                ),
+                _ => None,
+            };
+            let material = persistence.material.as_ref().expect("Checked by wanted()");
+            let record = PersistedContext::encode(material, limits, peer);
+            persistence.pending = Some(limits);
+            record
+        },
+    )
+}
+
+/// Marks a record returned by [`next_record()`] as stored.
+///
+/// If the context has been evicted from the pool in the meantime, this does nothing.
+pub fn confirm<Crypto: lakers::Crypto, const N: usize>(
+    pool: &RefCell<SecContextPool<Crypto, N>>,
+    record: &PersistedContext,
+) {
+    let Some((material, limits, _)) = record.decode() else {
+        return;
+    };
+    pool.borrow_mut().lookup(
+        |c| matches!(&c.protocol_stage, SecContextStage::Oscore(_, persistence) if persistence.material.as_ref() == Some(&material)),
+        |c| {
+            let SecContextState {
+                protocol_stage: SecContextStage::Oscore(_, persistence),
+                ..
+            } = c
+            else {
+                unreachable!("Checked by the lookup condition");
+            };
+            // Records may be confirmed out of order; each limit only ever grows.
+            persistence.confirmed = Some(match persistence.confirmed {
+                Some(confirmed) => Limits {
+                    send: confirmed.send.max(limits.send),
+                    receive: confirmed.receive.max(limits.receive),
+                },
+                None => limits,
+            });
+            if persistence.pending == Some(limits) {
+                persistence.pending = None;
+            }
+        },
+    );
+}
+
+/// Returns whether the context of a stored record is still in the pool.
+///
+/// Storage slots of records that are not current can be reused.
+pub fn is_current<Crypto: lakers::Crypto, const N: usize>(
+    pool: &RefCell<SecContextPool<Crypto, N>>,
+    record: &PersistedContext,
+) -> bool {
+    let Some((material, _, _)) = record.decode() else {
+        return false;
+    };
+    pool.borrow().iter().any(|c| {
+        matches!(&c.protocol_stage, SecContextStage::Oscore(_, persistence) if persistence.material.as_ref() == Some(&material))
+    })
+}
+
+/// Places a stored context into the pool.
+///
+/// The peer's permissions are looked up in `peers` again; if the peer is not known any more, it
+/// has the permissions of an unknown peer. The context's retention is decided by `policy`, which
+/// should be the policy of the handler or client using the pool.
+///
+/// # Errors
+///
+/// Returns an error if the record is malformed, or if a context with the same recipient ID is
+/// already in the pool.
+pub fn restore<Crypto: lakers::Crypto, const N: usize>(
+    pool: &RefCell<SecContextPool<Crypto, N>>,
+    peers: &PeerTable<'_>,
+    policy: EvictionPolicy,
+    record: &PersistedContext,
+) -> Result<(), RestoreError> {
+    let (material, limits, peer) = record.decode().ok_or(RestoreError::Malformed)?;
+
+    let mut pool = pool.borrow_mut();
+    if pool
+        .iter()
+        .any(|c| c.corresponding_cown() == Some(material.recipient_id))
+    {
+        return Err(RestoreError::Duplicate);
+    }
+
+    let mut context = oscore_context(
+        &material.secret,
+        &material.salt,
+        &material.sender_id,
+        material.recipient_id,
+    );
+    context.set_sender_sequence_number(limits.send);
+
+    let authorization = peer
+        .and_then(|thumbprint| peers.find_by_thumbprint(&thumbprint))
+        .map_or(Authorized::Unauthenticated, Authorized::Peer);
+    let persistence = Persistence {
+        material: Some(material),
+        confirmed: Some(limits),
+        pending: None,
+        replay_floor: limits.receive,
+        next_sent: limits.send,
+        next_received: limits.receive,
+    };
+    // Restoring happens at startup, when there is nothing to evict.
+    let _ = pool.force_insert(SecContextState::established(
+        context,
+        persistence,
+        authorization,
+        peers,
+        policy,
+    ));
+    Ok(())
+}
+
+/// Extracts the partial IV (i.e., the sequence number) from the value of an OSCORE option.
+pub(crate) fn partial_iv(oscore_option: &[u8]) -> Option<u64> {
+    let (flags, rest) = oscore_option.split_first()?;
+    let length = usize::from(flags & 0x07);
+    if length == 0 || length > 5 {
+        return None;
+    }
+    let piv = rest.get(..length)?;
+    Some(
+        piv.iter()
+            .fold(0, |acc, byte| (acc << 8) | u64::from(*byte)),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TestCrypto;
+
+    fn material() -> Material {
+        Material::new(
+            &[1; 16],
+            &[2; 8],
+            &[0x2b, 0x01],
+            COwn::from_kid(&[0x0a]).unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn record() {
+        let limits = Limits {
+            send: 300,
+            receive: 40,
+        };
+        for peer in [None, Some([3; THUMBPRINT_LEN])] {
+            let record = PersistedContext::encode(&material(), limits, peer);
+            assert_eq!(record.recipient_id(), &[0x0a]);
+            let parsed = PersistedContext::from_bytes(record.as_bytes()).unwrap();
+            assert_eq!(parsed.decode(), Some((material(), limits, peer)));
+        }
+
+        let mut bytes = *PersistedContext::encode(&material(), limits, None).as_bytes();
+        // Unknown authorization
+        *bytes.get_mut(53).unwrap() = 7;
+        assert!(PersistedContext::from_bytes(&bytes).is_none());
+        *bytes.get_mut(53).unwrap() = AUTHORIZATION_UNAUTHENTICATED;
+        assert!(PersistedContext::from_bytes(&bytes).is_some());
+        // Unknown version
+        *bytes.first_mut().unwrap() = 0;
+        assert!(PersistedContext::from_bytes(&bytes).is_none());
+        assert!(PersistedContext::from_bytes(&[RECORD_VERSION]).is_none());
+    }
+
+    #[test]
+    fn windows() {
+        let mut persistence = Persistence::new(Some(material()));
+        // Before anything is stored, there are no limits.
+        assert!(persistence.send(1000));
+        assert_eq!(persistence.check_received(1000), Ok(()));
+        persistence.received(1000);
+
+        let wanted = persistence.wanted().unwrap();
+        assert_eq!(wanted.send, 1001 + SEQUENCE_NUMBER_WINDOW);
+        assert_eq!(wanted.receive, 1001 + REPLAY_WINDOW);
+        persistence.pending = Some(wanted);
+        assert_eq!(persistence.wanted(), None);
+        // The first pending limits apply while they are stored.
+        assert!(!persistence.send(wanted.send));
+        persistence.confirmed = persistence.pending.take();
+
+        // More is requested before the limits are reached, but the old ones apply until then.
+        assert!(persistence.send(wanted.send - SEQUENCE_NUMBER_WINDOW / 2));
+        let next = persistence.wanted().unwrap();
+        persistence.pending = Some(next);
+        assert!(!persistence.send(wanted.send));
+        assert_eq!(
+            persistence.check_received(wanted.receive),
+            Err(ReceiveRejected::Unpersisted)
+        );
+        persistence.confirmed = persistence.pending.take();
+        assert!(persistence.send(wanted.send));
+
+        let restored = Persistence {
+            replay_floor: 50,
+            ..Persistence::new(None)
+        };
+        assert_eq!(restored.check_received(49), Err(ReceiveRejected::Replay));
+        assert_eq!(restored.check_received(50), Ok(()));
+        assert_eq!(restored.wanted(), None);
+    }
+
+    #[test]
+    fn confirm_receive_only() {
+        // A server's context never advances its sender sequence number, as responses reuse the
+        // request's nonce; only the receive limit grows.
+        let pool = RefCell::new(SecContextPool::<TestCrypto, 2>::new());
+        let peers = PeerTable::new(&[]);
+        let context = oscore_context(&[1; 16], &[2; 8], &[0x2b, 0x01], material().recipient_id);
+        let _ = pool.borrow_mut().force_insert(SecContextState::established(
+            context,
+            Persistence::new(Some(material())),
+            Authorized::Unauthenticated,
+            &peers,
+            crate::eviction::prefer_authenticated,
+        ));
+        let receive = |ssn| {
+            pool.borrow_mut()
+                .lookup(
+                    |_| true,
+                    |c| {
+                        let SecContextStage::Oscore(_, persistence) = &mut c.protocol_stage else {
+                            unreachable!("Only established contexts are in the pool");
+                        };
+                        let checked = persistence.check_received(ssn);
+                        if checked.is_ok() {
+                            persistence.received(ssn);
+                        }
+                        checked
+                    },
+                )
+                .unwrap()
+        };
+
+        let mut ssn = 0;
+        for _ in 0..4 {
+            let record = next_record(&pool, &peers).unwrap();
+            // Nothing else is wanted while the record is stored.
+            assert!(next_record(&pool, &peers).is_none());
+            confirm(&pool, &record);
+            assert!(next_record(&pool, &peers).is_none());
+
+            let limit = ssn + REPLAY_WINDOW;
+            while ssn < limit - REPLAY_WINDOW / 2 {
+                assert_eq!(receive(ssn), Ok(()));
+                ssn += 1;
+            }
+        }
+        // Without storing a record, the limit is reached eventually.
+        let limit = ssn + REPLAY_WINDOW / 2;
+        while ssn < limit {
+            assert_eq!(receive(ssn), Ok(()));
+            ssn += 1;
+        }
+        assert_eq!(receive(ssn), Err(ReceiveRejected::Unpersisted));
+        // Storing a record lifts it.
+        let record = next_record(&pool, &peers).unwrap();
+        confirm(&pool, &record);
+        assert_eq!(receive(ssn), Ok(()));
+    }
+
+    #[test]
+    fn piv() {
+        assert_eq!(partial_iv(&[]), None);
+        assert_eq!(partial_iv(&[0x09, 0x05, 0x2b]), Some(5));
+        assert_eq!(partial_iv(&[0x02, 0x01, 0x00]), Some(256));
+        assert_eq!(partial_iv(&[0x03, 0x01]), None);
+    }
+}
@@ -0,0 +1,398 @@
+//! Registration at a CoAP Resource Directory (RFC 9176)
+//!
+//! This provides the client side of the registration interface: [`discover()`] finds an RD's
+//! registration resource, [`register()`] posts an endpoint's links there, and [`refresh()`] keeps
+//! the registration alive before its lifetime expires. The requests are sent through any
+//! [`coap_request::Stack`]; scheduling them (and choosing which address to send them to) is up to
+//! the caller.
+
+use core::fmt::Write as _;
+
+use coap_message::{MessageOption as _, ReadableMessage};
+use coap_numbers::{code, option};
+use coap_request::{Request, Stack};
+
+use crate::client::add_option;
+
+/// Lifetime of a registration unless specified otherwise, in seconds (RFC 9176 Section 5).
+pub const DEFAULT_LIFETIME: u32 = 90000;
+
+/// Longest path of a registration resource or registration that is supported.
+pub const MAX_PATH_LEN: usize = 64;
+
+/// Resource type of an RD's registration interface.
+const RT_REGISTRATION: &str = "core.rd";
+
+/// Content format of link-format documents (RFC 6690).
+const LINK_FORMAT: u8 = 40;
+
+/// An absolute path on the RD, eg. `/rd` or `/reg/1`.
+pub type Path = heapless::String<MAX_PATH_LEN>;
+
+/// Error of the RD operations.
+#[derive(Debug)]
+pub enum RdError<T> {
+    /// The underlying CoAP stack failed to send the request or to receive a response.
+    Transport(T),
+    /// The RD responded with an unexpected (typically error) response code.
+    ///
+    /// When refreshing, 4.04 Not Found indicates that the registration expired; the endpoint then
+    /// needs to register anew.
+    Rejected(u8),
+    /// The RD did not advertise a registration interface.
+    NoInterface,
+    /// A path or query exceeds the supported length.
+    TooLong,
+    /// The RD created a registration without indicating its location.
+    NoLocation,
+}
+
+/// Finds the path of the registration interface in a link-format document, typically the response
+/// to a `GET /.well-known/core?rt=core.rd` request.
+///
+/// Links given as full URIs are reduced to their path, as registrations are sent to the address
+/// that answered.
+pub fn registration_interface(links: &[u8]) -> Option<Path> {
+    let links = core::str::from_utf8(links).ok()?;
+    split_outside_quotes(links, ',').find_map(|link| {
+        let mut parts = split_outside_quotes(link, ';');
+        let target = parts.next()?.trim().strip_prefix('<')?.strip_suffix('>')?;
+        let registration = parts.any(|attribute| {
+            let Some((name, value)) = attribute.trim().split_once('=') else {
+                return false;
+            };
+            let value = value.trim_matches('"');
+            name == "rt" && value.split(' ').any(|rt| rt == RT_REGISTRATION)
+        });
+        if !registration {
+            return None;
+        }
+        let path = match target.split_once("://") {
+            Some((_scheme, rest)) => rest.find('/').and_then(|start| rest.get(start..))?,
+            None => target,
+        };
+        if !path.starts_with('/') {
+            return None;
+        }
+        Path::try_from(path).ok()
+    })
+}
+
+/// Splits `text` at `separator`, except inside quoted strings.
+fn split_outside_quotes(text: &str, separator: char) -> impl Iterator<Item = &str> {
+    let mut quoted = false;
+    text.split(move |c| {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        c == separator && !quoted
+    })
+}
+
+/// Finds the registration interface of the RD that `stack` sends requests to.
+pub async fn discover<S: Stack>(stack: &mut S) -> Result<Path, RdError<S::TransportError>> {
+    let mut query = Query::new();
+    write!(query, "rt={RT_REGISTRATION}").map_err(|_| RdError::TooLong)?;
+    let answer = stack
+        .request(RdRequest {
+            code: code::GET,
+            path: "/.well-known/core",
+            queries: [Some(query), None],
+            payload: None,
+        })
+        .await
+        .map_err(RdError::Transport)?;
+    match answer.code {
+        code::CONTENT => answer.interface.ok_or(RdError::NoInterface),
+        other => Err(RdError::Rejected(other)),
+    }
+}
+
+/// Registers the endpoint named `endpoint` with its `links` (in link-format) at the registration
+/// interface `interface`, for `lifetime` seconds.
+///
+/// Returns the path of the registration resource, which is used to [`refresh()`] it.
+pub async fn register<S: Stack>(
+    stack: &mut S,
+    interface: &str,
+    endpoint: &str,
+    lifetime: u32,
+    links: &[u8],
+) -> Result<Path, RdError<S::TransportError>> {
+    let mut ep = Query::new();
+    write!(ep, "ep={endpoint}").map_err(|_| RdError::TooLong)?;
+    let mut lt = Query::new();
+    write!(lt, "lt={lifetime}").map_err(|_| RdError::TooLong)?;
+    let answer = stack
+        .request(RdRequest {
+            code: code::POST,
+            path: interface,
+            queries: [Some(ep), Some(lt)],
+            payload: Some(links),
+        })
+        .await
+        .map_err(RdError::Transport)?;
+    match answer.code {
+        code::CREATED => answer.location.ok_or(RdError::NoLocation),
+        other => Err(RdError::Rejected(other)),
+    }
+}
+
+/// Refreshes the registration at `location`, extending it by its lifetime.
+pub async fn refresh<S: Stack>(
+    stack: &mut S,
+    location: &str,
+) -> Result<(), RdError<S::TransportError>> {
+    let answer = stack
+        .request(RdRequest {
+            code: code::POST,
+            path: location,
+            queries: [None, None],
+            payload: None,
+        })
+        .await
+        .map_err(RdError::Transport)?;
+    match answer.code {
+        code::CHANGED => Ok(()),
+        other => Err(RdError::Rejected(other)),
+    }
+}
+
+/// Removes the registration at `location`.
+pub async fn remove<S: Stack>(
+    stack: &mut S,
+    location: &str,
+) -> Result<(), RdError<S::TransportError>> {
+    let answer = stack
+        .request(RdRequest {
+            code: code::DELETE,
+            path: location,
+            queries: [None, None],
+            payload: None,
+        })
+        .await
+        .map_err(RdError::Transport)?;
+    match answer.code {
+        code::DELETED => Ok(()),
+        other => Err(RdError::Rejected(other)),
+    }
+}
+
+/// A single Uri-Query option value.
+type Query = heapless::String<MAX_PATH_LEN>;
+
+/// A request to the RD.
+struct RdRequest<'a> {
+    code: u8,
+    path: &'a str,
+    queries: [Option<Query>; 2],
+    /// Link-format payload
+    payload: Option<&'a [u8]>,
+}
+
+/// The parts of an RD's response that are of interest.
+struct Answer {
+    code: u8,
+    /// The Location-Path options, joined into a path
+    location: Option<Path>,
+    /// The registration interface found in the payload
+    interface: Option<Path>,
+}
+
+impl<S: Stack> Request<S> for RdRequest<'_> {
+    type Carry = ();
+    type Output = Answer;
+
+    async fn build_request(
+        &mut self,
+        request: &mut S::RequestMessage<'_>,
+    ) -> Result<(), S::RequestUnionError> {
+        use coap_message::{Code as _, MinimalWritableMessage};
+
+        request.set_code(<S::RequestMessage<'_> as MinimalWritableMessage>::Code::new(self.code)?);
+        for segment in self.path.split('/').filter(|s| !s.is_empty()) {
+            add_option(request, option::URI_PATH, segment.as_bytes())?;
+        }
+        if self.payload.is_some() {
+            add_option(request, option::CONTENT_FORMAT, &[LINK_FORMAT])?;
+        }
+        for query in self.queries.iter().flatten() {
+            add_option(request, option::URI_QUERY, query.as_bytes())?;
+        }
+        if let Some(payload) = self.payload {
+            request.set_payload(payload)?;
+        }
+        Ok(())
+    }
+
+    async fn process_response(
+        &mut self,
+        response: &S::ResponseMessage<'_>,
+        (): (),
+    ) -> Self::Output {
+        Answer {
+            code: response.code().into(),
+            location: location(response),
+            interface: registration_interface(response.payload()),
+        }
+    }
+}
+
+/// Joins the Location-Path options of a response into a path, or returns `None` if there are none
+/// or the path is too long.
+fn location(response: &impl ReadableMessage) -> Option<Path> {
+    let mut path = Path::new();
+    for segment in response
+        .options()
+        .filter(|o| o.number() == option::LOCATION_PATH)
+    {
+        path.push('/').ok()?;
+        path.push_str(core::str::from_utf8(segment.value()).ok()?)
+            .ok()?;
+    }
+    (!path.is_empty()).then_some(path)
+}
+
+#[cfg(test)]
+mod test {
+    use coap_message::{MessageOption as _, MinimalWritableMessage, ReadableMessage};
+    use coap_message_implementations::inmemory_write::Message;
+    use coap_numbers::{code, option};
+    use coap_request::{Request, Stack};
+    use embassy_futures::block_on;
+
+    use super::{discover, refresh, register, registration_interface, remove, RdError};
+
+    /// A stand-in Resource Directory that answers requests in memory.
+    #[derive(Default)]
+    struct StandIn {
+        /// Endpoint name and links of the registration, if registered
+        registered: Option<(heapless::String<16>, heapless::Vec<u8, 64>)>,
+        /// Number of registrations created so far
+        count: u8,
+    }
+
+    impl StandIn {
+        /// Answers a request, returning the response code, Location-Path and payload.
+        fn answer(
+            &mut self,
+            request: &Message<'_>,
+        ) -> (u8, &'static [&'static str], &'static [u8]) {
+            let path: heapless::Vec<heapless::Vec<u8, 16>, 2> = request
+                .options()
+                .filter(|o| o.number() == option::URI_PATH)
+                .map(|o| heapless::Vec::from_slice(o.value()).unwrap())
+                .collect();
+            let queries: heapless::Vec<heapless::Vec<u8, 16>, 2> = request
+                .options()
+                .filter(|o| o.number() == option::URI_QUERY)
+                .map(|o| heapless::Vec::from_slice(o.value()).unwrap())
+                .collect();
+            let path: heapless::Vec<&[u8], 2> = path.iter().map(|s| s.as_slice()).collect();
+            let registered = self.registered.is_some();
+            match (request.code(), path.as_slice()) {
+                (code::GET, [b".well-known", b"core"]) => {
+                    assert!(queries.iter().eq([b"rt=core.rd"]));
+                    let links = b"</rd-lookup/ep>;rt=core.rd-lookup-ep,</rd>;rt=\"core.rd\";ct=40";
+                    (code::CONTENT, &[], links)
+                }
+                (code::POST, [b"rd"]) => {
+                    let ep = queries.iter().find_map(|q| q.strip_prefix(b"ep=")).unwrap();
+                    assert!(queries.iter().any(|q| q == b"lt=60"));
+                    self.registered = Some((
+                        heapless::String::try_from(core::str::from_utf8(ep).unwrap()).unwrap(),
+                        heapless::Vec::from_slice(request.payload()).unwrap(),
+                    ));
+                    self.count += 1;
+                    (code::CREATED, &["reg", "1"], b"")
+                }
+                (code::POST, [b"reg", b"1"]) if registered => (code::CHANGED, &[], b""),
+                (code::DELETE, [b"reg", b"1"]) if registered => {
+                    self.registered = None;
+                    (code::DELETED, &[], b"")
+                }
+                _ => (code::NOT_FOUND, &[], b""),
+            }
+        }
+    }
+
+    impl Stack for StandIn {
+        type RequestUnionError = <Message<'static> as MinimalWritableMessage>::UnionError;
+        type RequestMessage<'a>
+            = Message<'a>
+        where
+            Self: 'a;
+        type ResponseMessage<'a>
+            = Message<'a>
+        where
+            Self: 'a;
+        type TransportError = ();
+
+        async fn request<Req: Request<Self>>(
+            &mut self,
+            mut request: Req,
+        ) -> Result<Req::Output, ()> {
+            let mut request_code = 0;
+            let mut request_buffer = [0u8; 1152];
+            let mut message = Message::new(&mut request_code, &mut request_buffer[..]);
+            let carry = request.build_request(&mut message).await.unwrap();
+            let (response_code, location, payload) = self.answer(&message);
+
+            let mut code = 0;
+            let mut buffer = [0u8; 1152];
+            let mut response = Message::new(&mut code, &mut buffer[..]);
+            response.set_code(response_code);
+            for segment in location {
+                response
+                    .add_option(option::LOCATION_PATH, segment.as_bytes())
+                    .unwrap();
+            }
+            response.set_payload(&payload).unwrap();
+            Ok(request.process_response(&response, carry).await)
+        }
+    }
+
+    #[test]
+    fn interface() {
+        assert_eq!(
+            registration_interface(b"</rd>;rt=core.rd").as_deref(),
+            Some("/rd")
+        );
+        assert_eq!(
+            registration_interface(
+                b"</x>;title=\"a,b;c\",<coap://[2001:db8::1]/rd/r>;ct=40;rt=\"core.rd core.x\""
+            )
+            .as_deref(),
+            Some("/rd/r")
+        );
+        assert_eq!(
+            registration_interface(b"</rd-lookup/res>;rt=core.rd-lookup-res"),
+            None
+        );
+        assert_eq!(registration_interface(b"</rd>;rt=core.rd-group"), None);
+        assert_eq!(registration_interface(b"\xff"), None);
+    }
+
+    #[test]
+    fn registration() {
+        let mut rd = StandIn::default();
+        let interface = block_on(discover(&mut rd)).unwrap();
+        assert_eq!(interface, "/rd");
+
+        let links = b"</hello>;ct=0";
+        let location = block_on(register(&mut rd, &interface, "node1", 60, links)).unwrap();
+        assert_eq!(location, "/reg/1");
+        let (endpoint, registered_links) = rd.registered.as_ref().unwrap();
+        assert_eq!(endpoint, "node1");
+        assert_eq!(registered_links, links);
+        assert_eq!(rd.count, 1);
+
+        block_on(refresh(&mut rd, &location)).unwrap();
+        block_on(remove(&mut rd, &location)).unwrap();
+        // An expired registration needs to be created anew.
+        assert!(matches!(
+            block_on(refresh(&mut rd, &location)),
+            Err(RdError::Rejected(code::NOT_FOUND))
+        ));
+    }
+}
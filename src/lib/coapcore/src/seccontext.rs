@@ -1,17 +1,39 @@
+use core::cell::RefCell;
+
 use coap_message::{
     error::RenderableOnMinimal, Code, MessageOption, MinimalWritableMessage,
-    MutableWritableMessage, ReadableMessage,
+    MutableWritableMessage, OptionNumber, ReadableMessage,
 };
 use coap_message_utils::{Error as CoAPError, OptionsExt as _};
 use defmt_or_log::{debug, error, info, warn, Debug2Format};
 
-// If this exceeds 47, COwn will need to be extended.
-const MAX_CONTEXTS: usize = 4;
+use crate::ace::{self, Token, TokenPool};
+use crate::authorization::{Authorized, PeerTable};
+use crate::block::{Assembly, AssemblyError, Block};
+use crate::ead::EadProcessor;
+use crate::eviction::{EvictionPolicy, Retention};
+use crate::observe::{NotificationError, ObserveRequest, Protection};
+use crate::persistence::{Material, Persistence, ReceiveRejected};
+
+/// Number of security contexts in a [`SecContextPool`] unless specified otherwise.
+pub const DEFAULT_MAX_CONTEXTS: usize = 4;
 
-/// A pool of security contexts shareable by several users inside a thread.
+/// A pool of `N` security contexts shareable by several users inside a thread.
 #[expect(private_interfaces, reason = "should be addressed eventually")]
-pub type SecContextPool<Crypto> =
-    crate::oluru::OrderedPool<SecContextState<Crypto>, MAX_CONTEXTS, LEVEL_COUNT>;
+pub type SecContextPool<Crypto, const N: usize = DEFAULT_MAX_CONTEXTS> =
+    crate::oluru::OrderedPool<SecContextState<Crypto>, N, LEVEL_COUNT>;
+
+/// Longest own identifier, see [`COwn`].
+pub(crate) const MAX_COWN_LEN: usize = 2;
+
+/// Size of the buffers that protected requests are copied into.
+///
+/// embedded-nal-coap uses this max size, and our messages are same size or smaller, so it's a
+/// guaranteed fit; requests reassembled from outer Block1 blocks are limited to this size.
+const MAX_SIZE: usize = 1152;
+
+/// Number of identifiers that can be expressed as a [`COwn`].
+const COWN_COUNT: usize = 48 + (1 << 16);
 
 /// An own identifier for a security context
 ///
@@ -19,30 +41,52 @@ pub type SecContextPool<Crypto> =
 /// in OSCORE.
 ///
 /// This type represents any of the 48 efficient identifiers that use CBOR one-byte integer
-/// encodings (see RFC9528 Section 3.3.2), or equivalently the 1-byte long OSCORE identifiers
+/// encodings (see RFC9528 Section 3.3.2), or equivalently the 1-byte long OSCORE identifiers, as
+/// well as any 2-byte long identifier. The latter are only selected once the former are used up,
+/// which only happens in pools of more than 47 contexts.
 ///
 /// Lakers supports a much larger value space for C_x, and coapcore processes larger values
 /// selected by the peer -- but on its own, will select only those that fit in this type.
-// FIXME Could even limit to positive values if MAX_CONTEXTS < 24
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Copy, Clone, PartialEq, Debug)]
-struct COwn(u8);
+pub(crate) struct COwn {
+    /// The identifier, padded with zeros
+    bytes: [u8; MAX_COWN_LEN],
+    len: u8,
+}
 
 impl COwn {
+    const fn one_byte(byte: u8) -> Self {
+        Self {
+            bytes: [byte, 0],
+            len: 1,
+        }
+    }
+
+    const fn two_byte(value: u16) -> Self {
+        Self {
+            bytes: value.to_be_bytes(),
+            len: 2,
+        }
+    }
+
     /// Find a value of self that is not found in the iterator.
     ///
     /// This asserts that the iterator is (known to be) short enough that this will always succeed.
-    fn not_in_iter(iterator: impl Iterator<Item = Self>) -> Self {
+    pub(crate) fn not_in_iter(iterator: impl Iterator<Item = Self> + Clone) -> Self {
         // In theory, this would allow the compiler to see that the unreachable below is indeed
         // unreachable
         assert!(
-            iterator.size_hint().1.is_some_and(|v| v < 48),
+            iterator.size_hint().1.is_some_and(|v| v < COWN_COUNT),
             "Too many slots to reliably assign connection identifier"
         );
         let mut seen_pos = 0u32;
         let mut seen_neg = 0u32;
-        for i in iterator {
-            let major = i.0 >> 5;
+        for i in iterator.clone() {
+            let [byte] = *i.as_kid() else {
+                continue;
+            };
+            let major = byte >> 5;
             // Let's not make unsafe assumptions on the own value range
             let target = if major == 0 {
                 &mut seen_pos
@@ -51,89 +95,104 @@ impl COwn {
             };
             // Convenienlty, masking to the minor part puts us in the very range that allows u32
             // shifting
-            *target |= 1 << (i.0 & 0x1f);
+            *target |= 1 << (byte & 0x1f);
         }
         // trailing_ones = n implies that bit 1<<n is a zero and thus COwn(n) is free
         let pos_to = seen_pos.trailing_ones();
         if pos_to < 24 {
-            return Self(pos_to as u8);
+            return Self::one_byte(pos_to as u8);
         }
         let neg_to = seen_neg.trailing_ones();
         if neg_to < 24 {
-            return Self(0x20 | neg_to as u8);
+            return Self::one_byte(0x20 | neg_to as u8);
         }
-        unreachable!("Iterator is not long enough to set this many bits.");
+        // Only reached with more than 47 entries; a linear search suffices for the pool sizes
+        // where that happens.
+        (0..=u16::MAX)
+            .map(Self::two_byte)
+            .find(|candidate| !iterator.clone().any(|i| i == *candidate))
+            .expect("Iterator is not long enough to use up all identifiers.")
     }
 
     /// Given an OSCORE Key ID (kid), find the corresponding context identifier value
-    fn from_kid(kid: &[u8]) -> Option<Self> {
+    pub(crate) fn from_kid(kid: &[u8]) -> Option<Self> {
         match kid {
-            [first] if *first <= 0x17 || (*first >= 0x20 && *first <= 0x37) => Some(Self(*first)),
+            [first] if *first <= 0x17 || (*first >= 0x20 && *first <= 0x37) => {
+                Some(Self::one_byte(*first))
+            }
+            [first, second] => Some(Self::two_byte(u16::from_be_bytes([*first, *second]))),
             _ => None,
         }
     }
+
+    /// Given data starting with a connection identifier in its CBOR encoding (RFC 9528 Section
+    /// 3.3.2), find the corresponding context identifier value and the remaining data.
+    pub(crate) fn from_cbor_prefix(data: &[u8]) -> Option<(Self, &[u8])> {
+        match data {
+            // A 2-byte byte string
+            [0x42, first, second, rest @ ..] => Some((Self::from_kid(&[*first, *second])?, rest)),
+            // Identifiers that are expressed as integers
+            [first, rest @ ..] => Some((Self::from_kid(core::slice::from_ref(first))?, rest)),
+            [] => None,
+        }
+    }
+
+    /// The OSCORE Key ID (kid) corresponding to this identifier
+    pub(crate) fn as_kid(&self) -> &[u8] {
+        let (kid, _padding) = self.bytes.split_at(usize::from(self.len));
+        kid
+    }
 }
 
 impl From<COwn> for lakers::ConnId {
     fn from(cown: COwn) -> Self {
-        lakers::ConnId::from_slice(&[cown.0])
+        lakers::ConnId::from_slice(cown.as_kid())
             .expect("ConnId is always big enough for at least COwn")
     }
 }
 
-/// A representation of an RFC9237 using the REST-specific model in a CRI variation (Toid =
-/// [*path], Tperm = u32).
-///
-/// FIXME: At the moment, this always represents an authorization that allows everything, and only
-/// has runtime information about whether or not stdout is allowed. On the long run, this will
-/// likely be a CBOR item with pre-verified structure.
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Debug)]
-struct AifStaticRest {
-    may_use_stdout: bool,
+pub(crate) struct SecContextState<Crypto: lakers::Crypto> {
+    // FIXME: Should also include timeout. How do? Store expiry, do raytime in not-even-RTC mode,
+    // and whenever there is a new time stamp from AS, remove old ones?
+    pub(crate) authorization: Authorized,
+    pub(crate) protocol_stage: SecContextStage<Crypto>,
+    /// Retention of the context once it is in the OSCORE stage, as decided by the
+    /// [`EvictionPolicy`] when it was established.
+    pub(crate) retention: Retention,
 }
 
-impl AifStaticRest {
-    fn request_is_allowed<M: ReadableMessage>(&self, request: &M) -> bool {
-        // BIG FIXME: We're iterating over options without checking for critical options. If the
-        // resource handler router consumes any different set of options, that disagreement might
-        // give us a security issue.
-        //
-        // and FIXME this is using block-lists, but at this point it should be obvious that this is
-        // just a stupid stand-in.
-
-        let mut uri_path_options = request
-            .options()
-            .filter(|o| o.number() == coap_numbers::option::URI_PATH);
-        if uri_path_options
-            .next()
-            .is_some_and(|o| o.value() == b"stdout")
-            && uri_path_options.next().is_none()
-        {
-            self.may_use_stdout
-        } else {
-            true
+impl<Crypto: lakers::Crypto> SecContextState<Crypto> {
+    /// Creates the state of a context that is not established yet.
+    pub(crate) fn establishing(protocol_stage: SecContextStage<Crypto>) -> Self {
+        Self {
+            authorization: Authorized::Unauthenticated,
+            protocol_stage,
+            retention: Retention::Evictable,
         }
     }
-}
 
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[derive(Debug)]
-struct SecContextState<Crypto: lakers::Crypto> {
-    // FIXME: Should also include timeout. How do? Store expiry, do raytime in not-even-RTC mode,
-    // and whenever there is a new time stamp from AS, remove old ones?
-    authorization: AifStaticRest,
-    protocol_stage: SecContextStage<Crypto>,
+    /// Creates the state of an established context, with the retention the `policy` decides.
+    pub(crate) fn established(
+        context: liboscore::PrimitiveContext,
+        persistence: Persistence,
+        authorization: Authorized,
+        peers: &PeerTable<'_>,
+        policy: EvictionPolicy,
+    ) -> Self {
+        let retention = policy(peers.context_authorization(&authorization));
+        Self {
+            authorization,
+            protocol_stage: SecContextStage::Oscore(context, persistence),
+            retention,
+        }
+    }
 }
 
 impl<Crypto: lakers::Crypto> Default for SecContextState<Crypto> {
     fn default() -> Self {
-        Self {
-            authorization: AifStaticRest {
-                may_use_stdout: false,
-            },
-            protocol_stage: SecContextStage::Empty,
-        }
+        Self::establishing(SecContextStage::Empty)
     }
 }
 
@@ -142,9 +201,15 @@ impl<Crypto: lakers::Crypto> Default for SecContextState<Crypto> {
     clippy::large_enum_variant,
     reason = "requiring more memory during connection setup is expected, but the complexity of an inhmogenous pool is currently impractical"
 )]
-enum SecContextStage<Crypto: lakers::Crypto> {
+pub(crate) enum SecContextStage<Crypto: lakers::Crypto> {
     Empty,
 
+    /// An initiator (see [`crate::client`]) is running EDHOC with C_I; the initiator state is kept
+    /// by the client, this only reserves the identifier.
+    EdhocInitiatorReserved {
+        c_i: COwn,
+    },
+
     // if we have time to spare, we can have empty-but-prepared-with-single-use-random-key entries
     // :-)
 
@@ -155,6 +220,7 @@ enum SecContextStage<Crypto: lakers::Crypto> {
         // all
         c_r: COwn,
         c_i: lakers::ConnId,
+        ead_2: Option<lakers::EADItem>,
     },
     //
     EdhocResponderSentM2 {
@@ -163,8 +229,16 @@ enum SecContextStage<Crypto: lakers::Crypto> {
         c_i: lakers::ConnId,
     },
 
+    // actionable in response building, if message 4 is sent
+    EdhocResponderProcessedM3 {
+        responder: lakers::EdhocResponderProcessedM3<Crypto>,
+        c_r: COwn,
+        c_i: lakers::ConnId,
+        ead_4: Option<lakers::EADItem>,
+    },
+
     // FIXME: Also needs a flag for whether M4 was received; if not, it's GC'able
-    Oscore(liboscore::PrimitiveContext),
+    Oscore(liboscore::PrimitiveContext, Persistence),
 }
 
 #[cfg(feature = "defmt")]
@@ -172,13 +246,19 @@ impl<Crypto: lakers::Crypto> defmt::Format for SecContextStage<Crypto> {
     fn format(&self, f: defmt::Formatter) {
         match self {
             SecContextStage::Empty => defmt::write!(f, "Empty"),
+            SecContextStage::EdhocInitiatorReserved { c_i } => {
+                defmt::write!(f, "EdhocInitiatorReserved {{ c_i: {:?} }}", c_i)
+            }
             SecContextStage::EdhocResponderProcessedM1 { c_r, .. } => {
                 defmt::write!(f, "EdhocResponderProcessedM1 {{ c_r: {:?}, ... }}", c_r)
             }
             SecContextStage::EdhocResponderSentM2 { c_r, .. } => {
                 defmt::write!(f, "EdhocResponderSentM2 {{ c_r: {:?}, ... }}", c_r)
             }
-            SecContextStage::Oscore(primitive_context) => defmt::write!(
+            SecContextStage::EdhocResponderProcessedM3 { c_r, .. } => {
+                defmt::write!(f, "EdhocResponderProcessedM3 {{ c_r: {:?}, ... }}", c_r)
+            }
+            SecContextStage::Oscore(primitive_context, _) => defmt::write!(
                 f,
                 "Oscore(with recipient_id {:?})",
                 primitive_context.recipient_id()
@@ -192,33 +272,41 @@ impl<Crypto: lakers::Crypto> core::fmt::Display for SecContextState<Crypto> {
         use SecContextStage::*;
         match &self.protocol_stage {
             Empty => f.write_str("empty"),
+            EdhocInitiatorReserved { c_i } => write!(f, "Initiating, C_I = {:?}", c_i),
             EdhocResponderProcessedM1 { c_r, .. } => write!(f, "ProcessedM1, C_R = {:?}", c_r),
             EdhocResponderSentM2 { c_r, .. } => write!(f, "SentM3, C_R = {:?}", c_r),
-            Oscore(ctx) => write!(
+            EdhocResponderProcessedM3 { c_r, .. } => write!(f, "ProcessedM3, C_R = {:?}", c_r),
+            Oscore(ctx, _) => write!(
                 f,
                 "OSCORE, C_R = {:?}",
                 COwn::from_kid(ctx.recipient_id()).unwrap()
             ),
         }?;
-        write!(
-            f,
-            " authorized to read stdin? {}",
-            self.authorization.may_use_stdout
-        )?;
+        write!(f, " authorized as {:?}", self.authorization)?;
         Ok(())
     }
 }
 
-const LEVEL_ADMIN: usize = 0;
-const LEVEL_AUTHENTICATED: usize = 1;
-const LEVEL_ONGOING: usize = 2;
-const LEVEL_EMPTY: usize = 3;
-const LEVEL_COUNT: usize = 4;
+const LEVEL_PINNED: usize = 0;
+const LEVEL_ADMIN: usize = 1;
+const LEVEL_AUTHENTICATED: usize = 2;
+const LEVEL_ONGOING: usize = 3;
+const LEVEL_EMPTY: usize = 4;
+const LEVEL_COUNT: usize = 5;
+
+/// Lowest numeric level of contexts that may be evicted to make room for a new EDHOC session,
+/// i.e. all but pinned ones.
+pub(crate) const LEVEL_EVICTABLE: usize = LEVEL_ADMIN;
 
 impl<Crypto: lakers::Crypto> crate::oluru::PriorityLevel for SecContextState<Crypto> {
     fn level(&self) -> usize {
         match &self.protocol_stage {
             SecContextStage::Empty => LEVEL_EMPTY,
+            SecContextStage::EdhocInitiatorReserved { .. } => {
+                // Nothing was received from the peer yet; like an ongoing responder, this is
+                // evicted only in favor of established contexts.
+                LEVEL_ONGOING
+            }
             SecContextStage::EdhocResponderProcessedM1 { .. } => {
                 // If this is ever tested, means we're outbound message limited, so let's try to
                 // get one through rather than pointlessly sending errors
@@ -229,26 +317,30 @@ impl<Crypto: lakers::Crypto> crate::oluru::PriorityLevel for SecContextState<Cry
                 // even that)
                 LEVEL_ONGOING
             }
-            SecContextStage::Oscore(_) => {
-                if self.authorization.may_use_stdout {
-                    LEVEL_ADMIN
-                } else {
-                    LEVEL_AUTHENTICATED
-                }
+            SecContextStage::EdhocResponderProcessedM3 { .. } => {
+                // Only kept until message 4 is sent in the response
+                LEVEL_ONGOING
             }
+            SecContextStage::Oscore(..) => match self.retention {
+                Retention::Pinned => LEVEL_PINNED,
+                Retention::Preferred => LEVEL_ADMIN,
+                Retention::Evictable => LEVEL_AUTHENTICATED,
+            },
         }
     }
 }
 
 impl<Crypto: lakers::Crypto> SecContextState<Crypto> {
-    fn corresponding_cown(&self) -> Option<COwn> {
+    pub(crate) fn corresponding_cown(&self) -> Option<COwn> {
         match &self.protocol_stage {
             SecContextStage::Empty => None,
+            SecContextStage::EdhocInitiatorReserved { c_i } => Some(*c_i),
             // We're keeping a c_r in there assigned early so that we can find the context when
             // building the response; nothing in the responder is tied to c_r yet.
             SecContextStage::EdhocResponderProcessedM1 { c_r, .. } => Some(*c_r),
             SecContextStage::EdhocResponderSentM2 { c_r, .. } => Some(*c_r),
-            SecContextStage::Oscore(ctx) => COwn::from_kid(ctx.recipient_id()),
+            SecContextStage::EdhocResponderProcessedM3 { c_r, .. } => Some(*c_r),
+            SecContextStage::Oscore(ctx, _) => COwn::from_kid(ctx.recipient_id()),
         }
     }
 }
@@ -258,12 +350,37 @@ impl<Crypto: lakers::Crypto> SecContextState<Crypto> {
 /// While the EDHOC part could be implemented as a handler that is to be added into the tree, the
 /// OSCORE part needs to wrap the inner handler anyway, and EDHOC and OSCORE are intertwined rather
 /// strongly in processing the EDHOC option.
-pub struct OscoreEdhocHandler<'a, H: coap_handler::Handler, Crypto: lakers::Crypto> {
-    // It'd be tempted to have sharing among multiple handlers for multiple CoAP stacks, but
-    // locks for such sharing could still be acquired in a factory (at which point it may make
-    // sense to make this a &mut).
-    pool: SecContextPool<Crypto>,
+///
+/// Security contexts are kept in a pool of `N` slots.
+pub struct OscoreEdhocHandler<
+    'a,
+    H: coap_handler::Handler,
+    Crypto: lakers::Crypto,
+    const N: usize = DEFAULT_MAX_CONTEXTS,
+> {
+    // Shared with the OscoreEdhocClient, which places the contexts it establishes in here. Borrows
+    // are never held across await points, and the CoAP stack runs in a single thread.
+    pool: &'a RefCell<SecContextPool<Crypto, N>>,
     own_identity: (&'a lakers::Credential, &'a lakers::BytesP256ElemLen),
+    peers: PeerTable<'a>,
+    /// The ACE authorization server, if tokens are accepted.
+    authorization_server: Option<ace::AuthorizationServer<'a>>,
+    tokens: TokenPool,
+    ead: EadProcessor,
+    /// Whether to send EDHOC message 4 in response to a message 3 that is not combined with an
+    /// OSCORE request.
+    send_message_4: bool,
+    eviction: EvictionPolicy,
+    /// Protection of the latest response, if it was protected with OSCORE and answered a request
+    /// registering an observation.
+    observation_protection: Option<Protection>,
+    /// Protected request that is being reassembled from outer Block1 blocks, prefixed with the
+    /// KID of its security context.
+    ///
+    /// One buffer is shared by all contexts, as few peers send outer blocks: A transfer for one
+    /// context replaces an incomplete one of another. Only blocks for contexts in the pool are
+    /// reassembled, so blocks with made-up KIDs do not disturb a transfer.
+    outer_block1: Assembly<{ MAX_SIZE + MAX_COWN_LEN }>,
 
     // FIXME: This currently bakes in the assumption that there is a single tree both for
     // unencrypted and encrypted resources. We may later generalize this by making this a factory,
@@ -279,34 +396,267 @@ pub struct OscoreEdhocHandler<'a, H: coap_handler::Handler, Crypto: lakers::Cryp
     crypto_factory: fn() -> Crypto,
 }
 
-impl<'a, H: coap_handler::Handler, Crypto: lakers::Crypto> OscoreEdhocHandler<'a, H, Crypto> {
-    // FIXME: Apart from an own identity, this will also need a function to convert ID_CRED_I into
-    // a (CRED_I, AifStaticRest) pair.
+impl<'a, H: coap_handler::Handler, Crypto: lakers::Crypto, const N: usize>
+    OscoreEdhocHandler<'a, H, Crypto, N>
+{
+    /// Creates a handler authenticating as `own_identity`, and granting access to `inner`
+    /// according to `peers`.
+    ///
+    /// Security contexts are kept in `pool`, which can be shared with an
+    /// [`OscoreEdhocClient`](crate::client::OscoreEdhocClient).
     pub fn new(
         own_identity: (&'a lakers::Credential, &'a lakers::BytesP256ElemLen),
+        peers: PeerTable<'a>,
+        pool: &'a RefCell<SecContextPool<Crypto, N>>,
         inner: H,
         crypto_factory: fn() -> Crypto,
     ) -> Self {
         Self {
-            pool: Default::default(),
+            pool,
             own_identity,
+            peers,
+            authorization_server: None,
+            tokens: TokenPool::default(),
+            ead: EadProcessor::NONE,
+            send_message_4: false,
+            eviction: crate::eviction::prefer_authenticated,
+            observation_protection: None,
+            outer_block1: Assembly::new(),
             inner,
             crypto_factory,
         }
     }
 
-    // FIXME: this should be configurable
-    fn unauthenticated_edhoc_user_authorization(&self) -> AifStaticRest {
-        AifStaticRest {
-            may_use_stdout: false,
-        }
+    /// Accepts access tokens posted to `/authz-info` that were issued for this device by the ACE
+    /// authorization `server`; see [`ace`](crate::ace).
+    #[must_use]
+    pub fn with_authorization_server(mut self, server: ace::AuthorizationServer<'a>) -> Self {
+        self.authorization_server = Some(server);
+        self
+    }
+
+    /// Processes EAD items of incoming EDHOC messages with `ead`, instead of rejecting any critical
+    /// items; see [`ead`](crate::ead).
+    #[must_use]
+    pub fn with_ead_processor(mut self, ead: EadProcessor) -> Self {
+        self.ead = ead;
+        self
+    }
+
+    /// Sends EDHOC message 4 in response to a message 3 that is posted to `/.well-known/edhoc` on
+    /// its own (rather than combined with an OSCORE request).
+    ///
+    /// Without this, such a message 3 is answered with an empty response, and the initiator
+    /// learns that EDHOC completed only from the first OSCORE response.
+    #[must_use]
+    pub fn with_message_4(mut self) -> Self {
+        self.send_message_4 = true;
+        self
+    }
+
+    /// Decides which security contexts are kept when the pool is full through `policy`, instead
+    /// of [`prefer_authenticated`](crate::eviction::prefer_authenticated); see
+    /// [`eviction`](crate::eviction).
+    ///
+    /// Contexts established by an [`OscoreEdhocClient`](crate::client::OscoreEdhocClient) sharing
+    /// the pool are subject to the client's policy.
+    #[must_use]
+    pub fn with_eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.eviction = policy;
+        self
+    }
+
+    /// Returns the protection of the response built last, if it was protected with OSCORE and
+    /// answered a request registering an observation; see [`observe`](crate::observe).
+    ///
+    /// The transport keeps this with the observation, so that its notifications can be built
+    /// through [`build_protected_notification()`](Self::build_protected_notification).
+    pub fn take_observation_protection(&mut self) -> Option<Protection> {
+        self.observation_protection.take()
+    }
+
+    /// Builds a notification of an observation that was registered through OSCORE into
+    /// `response`.
+    ///
+    /// The `request` is the observation's inner request, which is run through the inner handler
+    /// again if the peer is still allowed to send it. The notification is protected with the
+    /// security context of the registration, with a Partial IV of its own, and carries an outer
+    /// Observe option of `sequence`.
+    ///
+    /// # Errors
+    ///
+    /// This fails if the notification can not be built; unless the error is
+    /// [`NotificationError::Unpersisted`], the observation can not be continued.
+    pub fn build_protected_notification(
+        &mut self,
+        protection: &mut Protection,
+        request: &impl ReadableMessage,
+        sequence: u32,
+        response: &mut coap_message_implementations::inmemory_write::Message<'_>,
+    ) -> Result<(), NotificationError> {
+        let kid = protection.kid;
+        self.pool
+            .borrow_mut()
+            .lookup(
+                |c| c.corresponding_cown() == Some(kid),
+                |matched| {
+                    let SecContextState {
+                        protocol_stage:
+                            SecContextStage::Oscore(ref mut oscore_context, ref mut persistence),
+                        ref authorization,
+                        ..
+                    } = matched
+                    else {
+                        return Err(NotificationError::ContextGone);
+                    };
+                    if !self.peers.request_is_allowed(Some(authorization), request) {
+                        return Err(NotificationError::NotAllowed);
+                    }
+                    let extracted = self.inner.extract_request_data(request);
+
+                    response.set_code(coap_numbers::code::CHANGED);
+                    crate::observe::add_observe_option(response, sequence)
+                        .map_err(|_| NotificationError::Oscore)?;
+                    // The correlation was used for the registration's response already, so
+                    // liboscore takes a new sequence number for this one.
+                    liboscore::protect_response(
+                        response,
+                        oscore_context,
+                        &mut protection.correlation,
+                        |response| {
+                            let rendered = match extracted {
+                                Ok(extracted) => {
+                                    self.inner.build_response(response, extracted).map_err(|e| {
+                                        error!(
+                                            "Rendering notification failed with {:?}",
+                                            Debug2Format(&e)
+                                        );
+                                    })
+                                }
+                                Err(e) => e.render(response).map_err(|e| {
+                                    error!(
+                                        "Rendering notification error failed with {:?}",
+                                        Debug2Format(&e)
+                                    );
+                                }),
+                            };
+                            if rendered.is_err() {
+                                // FIXME rewind message
+                                response.set_code(coap_numbers::code::INTERNAL_SERVER_ERROR);
+                            }
+                        },
+                    )
+                    .map_err(|_| NotificationError::Oscore)?;
+
+                    let sequence_number = response
+                        .options()
+                        .find(|o| o.number() == coap_numbers::option::OSCORE)
+                        .and_then(|o| crate::persistence::partial_iv(o.value()))
+                        .ok_or(NotificationError::Oscore)?;
+                    if !persistence.send(sequence_number) {
+                        return Err(NotificationError::Unpersisted);
+                    }
+                    Ok(())
+                },
+            )
+            .ok_or(NotificationError::ContextGone)?
     }
 
-    // FIXME: this should be configurable
-    fn nosec_authorization(&self) -> AifStaticRest {
-        AifStaticRest {
-            may_use_stdout: false,
+    /// Creates the state of a context this handler established.
+    fn established(
+        &self,
+        (context, persistence): (liboscore::PrimitiveContext, Persistence),
+        authorization: Authorized,
+    ) -> SecContextState<Crypto> {
+        SecContextState::established(
+            context,
+            persistence,
+            authorization,
+            &self.peers,
+            self.eviction,
+        )
+    }
+
+    /// Processes EDHOC message 3 for a responder that sent message 2, and authorizes the peer.
+    ///
+    /// On success, this returns the responder, the authorization of the peer and EAD_4.
+    #[expect(
+        clippy::type_complexity,
+        reason = "a dedicated type would be used only here"
+    )]
+    fn process_message_3(
+        &self,
+        responder: lakers::EdhocResponderWaitM3<Crypto>,
+        original_authorization: Authorized,
+        message_3: &lakers::EdhocMessageBuffer,
+    ) -> Result<
+        (
+            lakers::EdhocResponderProcessedM3<Crypto>,
+            Authorized,
+            Option<lakers::EADItem>,
+        ),
+        CoAPError,
+    > {
+        let (responder, id_cred_i, ead_3) =
+            responder.parse_message_3(message_3).map_err(render_error)?;
+
+        let ead_4 = (self.ead.ead_3)(ead_3.as_ref()).map_err(|_| {
+            warn!("EAD_3 rejected");
+            // FIXME: send error message
+            CoAPError::bad_request()
+        })?;
+
+        let cred_i;
+        let authorization;
+
+        if id_cred_i.reference_only() {
+            let kid = id_cred_i.as_encoded_value();
+            let ccs;
+            if let Some((index, peer_ccs)) = self.peers.find_by_kid(kid) {
+                info!("Peer indicates use of known peer {}", index);
+                ccs = peer_ccs;
+                authorization = Authorized::Peer(index);
+            } else if let Some(token) = self.tokens.find_by_kid(kid) {
+                info!("Peer indicates use of a credential from an access token");
+                ccs = token.ccs.as_slice();
+                authorization = Authorized::Token(token.scope.clone());
+            } else {
+                // FIXME: send better message
+                return Err(CoAPError::bad_request());
+            }
+
+            cred_i = lakers::Credential::parse_ccs(ccs)
+                // FIXME What kind of error do we send here?
+                .map_err(|_| CoAPError::bad_request())?;
+        } else {
+            let ccs = id_cred_i
+                .get_ccs()
+                .expect("Lakers only knows IdCred as reference or as credential");
+            info!(
+                "Got credential CCS by value: {:?}..",
+                &ccs.bytes.get_slice(0, 5)
+            );
+
+            cred_i = lakers::Credential::parse_ccs(ccs.bytes.as_slice())
+                // FIXME What kind of error do we send here?
+                .map_err(|_| CoAPError::bad_request())?;
+
+            let ccs = ccs.bytes.as_slice();
+            authorization = if let Some(index) = self.peers.find_by_ccs(ccs) {
+                Authorized::Peer(index)
+            } else if let Some(token) = self.tokens.find_by_ccs(ccs) {
+                Authorized::Token(token.scope.clone())
+            } else {
+                // Continuing with the privileges of an unknown peer (allowing
+                // opportunistic encryption b/c we have enough slots to spare for
+                // some low-priority connections)
+                original_authorization
+            };
         }
+
+        let (responder, _prk_out) = responder.verify_message_3(cred_i).map_err(render_error)?;
+
+        Ok((responder, authorization, ead_4))
     }
 }
 
@@ -322,12 +672,25 @@ pub enum EdhocResponse<I> {
     // Taking a small state here: We already have a slot in the pool, storing the big data there
     #[expect(private_interfaces, reason = "should be addressed eventually")]
     OkSend2(COwn),
+    /// A message 3 was processed, and message 4 is to be sent from the pool slot.
+    #[expect(private_interfaces, reason = "should be addressed eventually")]
+    OkSend4(COwn),
+    /// A message 3 was processed, and EDHOC completed without message 4.
+    Message3Processed,
+    /// An access token was accepted through `/authz-info`.
+    AceTokenAccepted,
     // Could have a state Message3Processed -- but do we really want to implement that? (like, just
     // use the EDHOC option)
+    /// A block of a request fragmented with outer Block1 options was stored.
+    OuterBlockContinue(Block),
+    /// A block of a request fragmented with outer Block1 options was rejected with this code.
+    OuterBlockRejected(u8),
     OscoreRequest {
         #[expect(private_interfaces, reason = "should be addressed eventually")]
         kid: COwn,
         correlation: liboscore::raw::oscore_requestid_t,
+        /// Whether the inner request registers an observation.
+        registering: bool,
         extracted: AuthorizationChecked<I>,
     },
 }
@@ -359,6 +722,91 @@ fn render_error(_e: lakers::EDHOCError) -> CoAPError {
     CoAPError::bad_request()
 }
 
+/// Extracts the kid from the value of an OSCORE option (RFC 8613 Section 6.1).
+fn oscore_option_kid(value: &[u8]) -> Option<&[u8]> {
+    let (flags, rest) = value.split_first()?;
+    if flags & 0x08 == 0 {
+        return None;
+    }
+    let rest = rest.get(usize::from(flags & 0x07)..)?;
+    if flags & 0x10 == 0 {
+        return Some(rest);
+    }
+    let (context_len, rest) = rest.split_first()?;
+    rest.get(usize::from(*context_len)..)
+}
+
+/// Derives the OSCORE context from a completed EDHOC session (RFC 9528 Appendix A.1).
+///
+/// The `exporter` is the `edhoc_exporter` of the finished initiator or responder, called with a
+/// label and a length. The sender ID is the peer's connection identifier; ours is the recipient
+/// ID.
+///
+/// The context is returned along with the state needed to persist it.
+pub(crate) fn derive_oscore_context<E: AsRef<[u8]>>(
+    mut exporter: impl FnMut(u8, usize) -> E,
+    sender_id: &[u8],
+    recipient_id: COwn,
+) -> (liboscore::PrimitiveContext, Persistence) {
+    let oscore_secret = exporter(0u8, 16); // label is 0
+    let oscore_salt = exporter(1u8, 8); // label is 1
+    #[allow(
+        clippy::indexing_slicing,
+        reason = "exporter output is at least as long as requested"
+    )]
+    let oscore_secret = &oscore_secret.as_ref()[..16];
+    #[allow(
+        clippy::indexing_slicing,
+        reason = "exporter output is at least as long as requested"
+    )]
+    let oscore_salt = &oscore_salt.as_ref()[..8];
+    #[allow(
+        clippy::indexing_slicing,
+        reason = "secret necessarily contains more than 40 bits"
+    )]
+    {
+        debug!("OSCORE secret: {:?}...", &oscore_secret[..5]);
+    }
+    debug!("OSCORE salt: {:?}", &oscore_salt);
+
+    (
+        oscore_context(oscore_secret, oscore_salt, sender_id, recipient_id),
+        Persistence::new(Material::new(
+            oscore_secret,
+            oscore_salt,
+            sender_id,
+            recipient_id,
+        )),
+    )
+}
+
+/// Derives an OSCORE context from its master secret and salt, starting at sequence number 0.
+pub(crate) fn oscore_context(
+    oscore_secret: &[u8],
+    oscore_salt: &[u8],
+    sender_id: &[u8],
+    recipient_id: COwn,
+) -> liboscore::PrimitiveContext {
+    // FIXME probe cipher suite
+    let hkdf = liboscore::HkdfAlg::from_number(5).unwrap();
+    let aead = liboscore::AeadAlg::from_number(10).unwrap();
+
+    let immutables = liboscore::PrimitiveImmutables::derive(
+        hkdf,
+        oscore_secret,
+        oscore_salt,
+        None,
+        aead,
+        sender_id,
+        // FIXME need KID form (but for all that's supported that works still)
+        recipient_id.as_kid(),
+    )
+    // FIXME convert error
+    .unwrap();
+
+    liboscore::PrimitiveContext::new_from_fresh_material(immutables)
+}
+
 #[derive(Debug)]
 pub enum OrInner<O, I> {
     Own(O),
@@ -388,8 +836,8 @@ impl<O: RenderableOnMinimal, I: RenderableOnMinimal> RenderableOnMinimal for OrI
     }
 }
 
-impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
-    for OscoreEdhocHandler<'_, H, Crypto>
+impl<H: coap_handler::Handler, Crypto: lakers::Crypto, const N: usize> coap_handler::Handler
+    for OscoreEdhocHandler<'_, H, Crypto, N>
 {
     type RequestData = OrInner<
         EdhocResponse<Result<H::RequestData, H::ExtractRequestError>>,
@@ -406,18 +854,22 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
     ) -> Result<Self::RequestData, Self::ExtractRequestError> {
         use OrInner::{Inner, Own};
 
+        self.observation_protection = None;
+
         #[derive(Default, Copy, Clone, Debug)]
         enum Recognition {
             #[default]
             Start,
-            /// Seen an OSCORE option
-            Oscore { kid: u8 },
+            /// Seen an OSCORE option (whose kid is `None` if it is not a COwn)
+            Oscore { kid: Option<COwn> },
             /// Seen an OSCORE option and an EDHOC option
-            Edhoc { kid: u8 },
+            Edhoc { kid: Option<COwn> },
             /// Seen path ".well-known" (after not having seen an OSCORE option)
             WellKnown,
             /// Seen path ".well-known" and "edhoc"
             WellKnownEdhoc,
+            /// Seen path "authz-info" (after not having seen an OSCORE option)
+            AuthzInfo,
             /// Seen anything else (where the request handler, or more likely the ACL filter, will
             /// trip over the critical options)
             Unencrypted,
@@ -432,15 +884,22 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                 use coap_numbers::option;
 
                 match (self, o.number(), o.value()) {
-                    // FIXME: Store full value (but a single one is sufficient while we do EDHOC
-                    // extraction)
-                    (Start, option::OSCORE, [.., kid]) => (Oscore { kid: *kid }, false),
+                    (Start, option::OSCORE, value) => (
+                        Oscore {
+                            kid: oscore_option_kid(value).and_then(COwn::from_kid),
+                        },
+                        false,
+                    ),
                     (Start, option::URI_PATH, b".well-known") => (WellKnown, false),
+                    (Start, option::URI_PATH, b"authz-info") => (AuthzInfo, false),
                     (Start, option::URI_PATH, _) => (Unencrypted, true /* doesn't matter */),
                     (Oscore { kid }, option::EDHOC, b"") => {
                         (Edhoc { kid }, true /* doesn't matter */)
                     }
                     (WellKnown, option::URI_PATH, b"edhoc") => (WellKnownEdhoc, false),
+                    (WellKnown | AuthzInfo, option::URI_PATH, _) => {
+                        (Unencrypted, true /* doesn't matter */)
+                    }
                     (any, _, _) => (any, true),
                 }
             }
@@ -460,7 +919,12 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
             // FIXME: This aborts early on critical options, even when the result is later ignored
             .ignore_elective_others();
 
-        if let (Err(error), WellKnownEdhoc) = (extra_options, state) {
+        if self.authorization_server.is_none() && matches!(state, AuthzInfo) {
+            // Without an authorization server, this is just a resource of the inner handler.
+            state = Unencrypted;
+        }
+
+        if let (Err(error), WellKnownEdhoc | AuthzInfo) = (extra_options, state) {
             // Critical options in all other cases are handled by the Unencrypted or Oscore
             // handlers
             return Err(Own(error));
@@ -468,7 +932,7 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
 
         match state {
             Start | WellKnown | Unencrypted => {
-                if self.nosec_authorization().request_is_allowed(request) {
+                if self.peers.request_is_allowed(None, request) {
                     self.inner
                         .extract_request_data(request)
                         .map(|extracted| Inner(AuthorizationChecked::Allowed(extracted)))
@@ -477,6 +941,26 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                     Ok(Inner(AuthorizationChecked::NotAllowed))
                 }
             }
+            AuthzInfo => {
+                if request.code().into() != coap_numbers::code::POST {
+                    return Err(Own(CoAPError::method_not_allowed()));
+                }
+                let server = self
+                    .authorization_server
+                    .expect("State is only reached with an authorization server");
+
+                let token = Token::process(&server, request.payload()).map_err(|e| {
+                    warn!("Rejecting access token: {:?}", e);
+                    match e {
+                        ace::TokenError::Unauthorized => Own(CoAPError::unauthorized()),
+                        _ => Own(CoAPError::bad_request()),
+                    }
+                })?;
+                info!("Accepted access token");
+                self.tokens.insert(token);
+
+                Ok(Own(EdhocResponse::AceTokenAccepted))
+            }
             WellKnownEdhoc => {
                 if request.code().into() != coap_numbers::code::POST {
                     return Err(Own(CoAPError::method_not_allowed()));
@@ -502,15 +986,17 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                     .process_message_1(message_1)
                     .map_err(render_error)?;
 
-                    if ead_1.is_some_and(|e| e.is_critical) {
+                    let ead_2 = (self.ead.ead_1)(ead_1.as_ref()).map_err(|_| {
+                        warn!("EAD_1 rejected");
                         // FIXME: send error message
-                        return Err(Own(CoAPError::bad_request()));
-                    }
+                        Own(CoAPError::bad_request())
+                    })?;
 
                     // Let's pick one now already: this allows us to use the identifier in our
                     // request data.
                     let c_r = COwn::not_in_iter(
                         self.pool
+                            .borrow()
                             .iter()
                             .filter_map(|entry| entry.corresponding_cown())
                             // C_R does not only need to be unique, it also must not be identical
@@ -519,22 +1005,31 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                             .chain(COwn::from_kid(c_i.as_slice()).as_slice().iter().cloned()),
                     );
 
+                    let mut pool = self.pool.borrow_mut();
                     debug!("Entries in pool:");
-                    for (i, e) in self.pool.entries.iter().enumerate() {
+                    for (i, e) in pool.entries.iter().enumerate() {
                         debug!("{}. {}", i, e);
                     }
                     debug!("Sequence:");
-                    for index in self.pool.sorted.iter() {
+                    for index in pool.sorted.iter() {
                         debug!("* {}", index);
                     }
-                    let evicted = self.pool.force_insert(SecContextState {
-                        protocol_stage: SecContextStage::EdhocResponderProcessedM1 {
-                            c_r,
-                            c_i,
-                            responder,
-                        },
-                        authorization: self.unauthenticated_edhoc_user_authorization(),
-                    });
+                    let evicted = pool
+                        .insert_evicting(
+                            SecContextState::establishing(
+                                SecContextStage::EdhocResponderProcessedM1 {
+                                    c_r,
+                                    c_i,
+                                    responder,
+                                    ead_2,
+                                },
+                            ),
+                            LEVEL_EVICTABLE,
+                        )
+                        .map_err(|_| {
+                            warn!("Pool is full of pinned contexts, rejecting new EDHOC");
+                            Own(CoAPError::service_unavailable())
+                        })?;
                     if let Some(evicted) = evicted {
                         warn!("To insert new EDHOC, evicted {}", evicted);
                     } else {
@@ -543,32 +1038,148 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
 
                     Ok(Own(EdhocResponse::OkSend2(c_r)))
                 } else {
-                    // for the time being we'll only take the EDHOC option
-                    Err(Own(CoAPError::bad_request()))
+                    // Message 3 on its own, prefixed with C_R (RFC 9528 Appendix A.2)
+                    info!("Processing incoming EDHOC message 3");
+                    let (c_r, message_3) = COwn::from_cbor_prefix(request.payload())
+                        // not one we could have assigned
+                        .ok_or_else(CoAPError::bad_request)?;
+                    let message_3 =
+                        lakers::EdhocMessageBuffer::new_from_slice(message_3).map_err(too_small)?;
+
+                    // As with the combined request, a message 3 that can not be processed drops
+                    // the state.
+                    let taken = self
+                        .pool
+                        .borrow_mut()
+                        .lookup(|c| c.corresponding_cown() == Some(c_r), core::mem::take)
+                        .ok_or_else(CoAPError::bad_request)?;
+                    let SecContextState {
+                        protocol_stage:
+                            SecContextStage::EdhocResponderSentM2 {
+                                responder,
+                                c_r: _,
+                                c_i,
+                            },
+                        authorization: original_authorization,
+                        ..
+                    } = taken
+                    else {
+                        // Not waiting for message 3 (any more)
+                        return Err(Own(CoAPError::bad_request()));
+                    };
+
+                    let (mut responder, authorization, ead_4) =
+                        self.process_message_3(responder, original_authorization, &message_3)?;
+
+                    let (state, response) = if self.send_message_4 {
+                        (
+                            SecContextState {
+                                authorization,
+                                ..SecContextState::establishing(
+                                    SecContextStage::EdhocResponderProcessedM3 {
+                                        responder,
+                                        c_r,
+                                        c_i,
+                                        ead_4,
+                                    },
+                                )
+                            },
+                            EdhocResponse::OkSend4(c_r),
+                        )
+                    } else {
+                        let context = derive_oscore_context(
+                            |label, length| responder.edhoc_exporter(label, &[], length),
+                            c_i.as_slice(),
+                            c_r,
+                        );
+                        (
+                            self.established(context, authorization),
+                            EdhocResponse::Message3Processed,
+                        )
+                    };
+                    let evicted = self.pool.borrow_mut().force_insert(state);
+                    debug_assert!(matches!(evicted, Some(SecContextState { protocol_stage: SecContextStage::Empty, .. }) | None), "A Default (Empty) was placed when an item was taken, which should have the lowest priority");
+
+                    Ok(Own(response))
                 }
             }
             Edhoc { kid } | Oscore { kid } => {
                 let payload = request.payload();
 
                 // This whole loop-and-tree could become a single take_responder_wait3 method?
-                let kid = COwn::from_kid(&[kid])
+                let kid = kid
                     // same as if it's not found in the pool
                     .ok_or_else(CoAPError::bad_request)?;
+
+                // Outer block-wise transfer (RFC 8613 Section 4.1.3.4.2): the protected request
+                // is reassembled before it can be verified. Inner Block options are left to the
+                // inner handler.
+                let outer_block1 = Block::from_message(request, coap_numbers::option::BLOCK1)
+                    .transpose()
+                    .map_err(|()| CoAPError::bad_option(coap_numbers::option::BLOCK1))?;
+                let reassembled = match outer_block1 {
+                    None => None,
+                    Some(_) if matches!(state, Edhoc { .. }) => {
+                        // Combined requests sent in blocks are not supported.
+                        return Err(Own(CoAPError::bad_option(coap_numbers::option::BLOCK1)));
+                    }
+                    Some(block) => {
+                        if self
+                            .pool
+                            .borrow_mut()
+                            .lookup(|c| c.corresponding_cown() == Some(kid), |_| ())
+                            .is_none()
+                        {
+                            // following RFC8613 Section 8.2 item 2.2, as below
+                            return Err(Own(CoAPError::bad_request()));
+                        }
+                        let pushed = if block.num() == 0 {
+                            self.outer_block1
+                                .start(kid.as_kid())
+                                .and_then(|()| self.outer_block1.push(block, payload))
+                        } else if self.outer_block1.is_active()
+                            && self.outer_block1.prefix() == kid.as_kid()
+                        {
+                            self.outer_block1.push(block, payload)
+                        } else {
+                            Err(AssemblyError::Incomplete)
+                        };
+                        match pushed {
+                            Ok(true) => Some(
+                                heapless::Vec::<u8, MAX_SIZE>::from_slice(
+                                    self.outer_block1.payload(),
+                                )
+                                .map_err(|()| CoAPError::bad_request())?,
+                            ),
+                            Ok(false) => return Ok(Own(EdhocResponse::OuterBlockContinue(block))),
+                            Err(e) => {
+                                return Ok(Own(EdhocResponse::OuterBlockRejected(match e {
+                                    AssemblyError::Incomplete => {
+                                        coap_numbers::code::REQUEST_ENTITY_INCOMPLETE
+                                    }
+                                    AssemblyError::TooLarge => {
+                                        coap_numbers::code::REQUEST_ENTITY_TOO_LARGE
+                                    }
+                                    AssemblyError::BadBlock => coap_numbers::code::BAD_REQUEST,
+                                })))
+                            }
+                        }
+                    }
+                };
+                let payload = reassembled.as_deref().unwrap_or(payload);
                 // If we don't make progress, we're dropping it altogether. Unless we use the
                 // responder we might legally continue (because we didn't send data to EDHOC), but
                 // once we've received something that (as we now know) looks like a message 3 and
                 // isn't processable, it's unlikely that another one would come up and be.
                 let mut taken = self
                     .pool
+                    .borrow_mut()
                     .lookup(|c| c.corresponding_cown() == Some(kid), core::mem::take)
                     // following RFC8613 Section 8.2 item 2.2
                     // FIXME unauthorized (unreleased in coap-message-utils)
                     .ok_or_else(CoAPError::bad_request)?;
 
                 let front_trim_payload = if matches!(state, Edhoc { .. }) {
-                    // We're not supporting block-wise here -- but could later, to the extent we support
-                    // outer block-wise.
-
                     // Workaround for https://github.com/openwsn-berkeley/lakers/issues/255
                     let mut decoder = minicbor::decode::Decoder::new(payload);
                     let _ = decoder
@@ -583,7 +1194,8 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                                 c_r,
                                 c_i,
                             },
-                        authorization: original_authorization, // So far, this is self.unauthenticated_edhoc_user_authorization()
+                        authorization: original_authorization, // So far, this is Authorized::Unauthenticated
+                        ..
                     } = taken
                     {
                         debug_assert_eq!(c_r, kid, "State was looked up by KID");
@@ -591,104 +1203,17 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                         let msg_3 = lakers::EdhocMessageBuffer::new_from_slice(&payload[..cutoff])
                             .map_err(|e| Own(too_small(e)))?;
 
-                        let (responder, id_cred_i, ead_3) =
-                            responder.parse_message_3(&msg_3).map_err(render_error)?;
-
-                        if ead_3.is_some_and(|e| e.is_critical) {
-                            // FIXME: send error message
-                            return Err(Own(CoAPError::bad_request()));
-                        }
-
-                        let cred_i;
-                        let authorization;
-
-                        if id_cred_i.reference_only() {
-                            match id_cred_i.as_encoded_value() {
-                                &[43] => {
-                                    info!("Peer indicates use of the one preconfigured key");
-
-                                    use hexlit::hex;
-                                    const CRED_I: &[u8] = &hex!("A2027734322D35302D33312D46462D45462D33372D33322D333908A101A5010202412B2001215820AC75E9ECE3E50BFC8ED60399889522405C47BF16DF96660A41298CB4307F7EB62258206E5DE611388A4B8A8211334AC7D37ECB52A387D257E6DB3C2A93DF21FF3AFFC8");
-
-                                    cred_i = lakers::Credential::parse_ccs(CRED_I)
-                                        .expect("Static credential is not processable");
-
-                                    // FIXME: learn from CRED_I
-                                    authorization = AifStaticRest {
-                                        may_use_stdout: true,
-                                    };
-                                }
-                                _ => {
-                                    // FIXME: send better message
-                                    return Err(Own(CoAPError::bad_request()));
-                                }
-                            }
-                        } else {
-                            let ccs = id_cred_i
-                                .get_ccs()
-                                .expect("Lakers only knows IdCred as reference or as credential");
-                            info!(
-                                "Got credential CCS by value: {:?}..",
-                                &ccs.bytes.get_slice(0, 5)
-                            );
-
-                            cred_i = lakers::Credential::parse_ccs(ccs.bytes.as_slice())
-                                // FIXME What kind of error do we send here?
-                                .map_err(|_| Own(CoAPError::bad_request()))?;
-
-                            // FIXME: Do we want to continue at all? At least we don't allow
-                            // stdout, but let's otherwise continue with the privileges of an
-                            // unencrypted peer (allowing opportunistic encryption b/c we have
-                            // enough slots to spare for some low-priority connections)
-                            //
-                            // The original_authorization may even have a hint (like, we might
-                            // continue if it is not completely empty)
-                            authorization = original_authorization;
-                        }
-
-                        let (mut responder, _prk_out) =
-                            responder.verify_message_3(cred_i).map_err(render_error)?;
-
-                        let oscore_secret = responder.edhoc_exporter(0u8, &[], 16); // label is 0
-                        let oscore_salt = responder.edhoc_exporter(1u8, &[], 8); // label is 1
-                        let oscore_secret = &oscore_secret[..16];
-                        let oscore_salt = &oscore_salt[..8];
-                        #[allow(
-                            clippy::indexing_slicing,
-                            reason = "secret necessarily contains more than 40 bits"
-                        )]
-                        {
-                            debug!("OSCORE secret: {:?}...", &oscore_secret[..5]);
-                        }
-                        debug!("OSCORE salt: {:?}", &oscore_salt);
-
-                        let sender_id = c_i.as_slice();
-                        let recipient_id = kid.0;
-
-                        // FIXME probe cipher suite
-                        let hkdf = liboscore::HkdfAlg::from_number(5).unwrap();
-                        let aead = liboscore::AeadAlg::from_number(10).unwrap();
-
-                        let immutables = liboscore::PrimitiveImmutables::derive(
-                            hkdf,
-                            oscore_secret,
-                            oscore_salt,
-                            None,
-                            aead,
-                            sender_id,
-                            // FIXME need KID form (but for all that's supported that works still)
-                            &[recipient_id],
-                        )
-                        // FIXME convert error
-                        .unwrap();
+                        // Message 4 is not sent: the OSCORE response confirms the key.
+                        let (mut responder, authorization, _ead_4) =
+                            self.process_message_3(responder, original_authorization, &msg_3)?;
 
-                        let context =
-                            liboscore::PrimitiveContext::new_from_fresh_material(immutables);
+                        let context = derive_oscore_context(
+                            |label, length| responder.edhoc_exporter(label, &[], length),
+                            c_i.as_slice(),
+                            kid,
+                        );
 
-                        taken = SecContextState {
-                            protocol_stage: SecContextStage::Oscore(context),
-                            authorization,
-                        };
+                        taken = self.established(context, authorization);
                     } else {
                         // Return the state. Best bet is that it was already advanced to an OSCORE
                         // state, and the peer sent message 3 with multiple concurrent in-flight
@@ -707,8 +1232,9 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                 };
 
                 let SecContextState {
-                    protocol_stage: SecContextStage::Oscore(mut oscore_context),
+                    protocol_stage: SecContextStage::Oscore(mut oscore_context, mut persistence),
                     authorization,
+                    retention,
                 } = taken
                 else {
                     // FIXME: How'd we even get there?
@@ -723,9 +1249,6 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                 // libOSCORE knows, but that's not why we do it, that's what downcasting would be
                 // for.)
 
-                // embedded-nal-coap uses this max size, and our messages are same size or smaller,
-                // so it's a guaranteed fit.
-                const MAX_SIZE: usize = 1152;
                 let mut read_copy = [0u8; MAX_SIZE];
                 let mut code_copy = 0;
                 let mut copied_message = coap_message_implementations::inmemory_write::Message::new(
@@ -744,7 +1267,12 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                 // (typically, and concretely in Ariel OS), it is given. (And it's not like we have
                 // a fallback: inmemory_write has no more expensive option for reshuffling).
                 for opt in request.options() {
-                    if opt.number() == coap_numbers::option::EDHOC {
+                    if matches!(
+                        opt.number(),
+                        coap_numbers::option::EDHOC
+                            | coap_numbers::option::BLOCK1
+                            | coap_numbers::option::SIZE1
+                    ) {
                         continue;
                     }
                     copied_message
@@ -760,37 +1288,69 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                 }
                 // We know this to not fail b/c we only got here due to its presence
                 let oscore_option = oscore_option.unwrap();
+                let sequence_number = crate::persistence::partial_iv(&oscore_option);
                 let oscore_option = liboscore::OscoreOption::parse(&oscore_option)
                     .map_err(|_| CoAPError::bad_option(coap_numbers::option::OSCORE))?;
+
+                if let Err(rejected) =
+                    sequence_number.map_or(Ok(()), |ssn| persistence.check_received(ssn))
+                {
+                    let evicted = self.pool.borrow_mut().force_insert(SecContextState {
+                        protocol_stage: SecContextStage::Oscore(oscore_context, persistence),
+                        authorization,
+                        retention,
+                    });
+                    debug_assert!(matches!(evicted, Some(SecContextState { protocol_stage: SecContextStage::Empty, .. }) | None), "A Default (Empty) was placed when an item was taken, which should have the lowest priority");
+                    return Err(Own(match rejected {
+                        // The request might have been processed before the context was restored.
+                        ReceiveRejected::Replay => CoAPError::unauthorized(),
+                        // Retrying will work once the next limit is stored.
+                        ReceiveRejected::Unpersisted => CoAPError::service_unavailable(),
+                    }));
+                }
                 #[allow(clippy::indexing_slicing, reason = "slice fits by construction")]
                 copied_message
                     .set_payload(&payload[front_trim_payload..])
                     .unwrap();
 
+                let peers = &self.peers;
+                let authorized = &authorization;
                 let decrypted = liboscore::unprotect_request(
                     &mut copied_message,
                     oscore_option,
                     &mut oscore_context,
                     |request| {
-                        if authorization.request_is_allowed(request) {
-                            AuthorizationChecked::Allowed(self.inner.extract_request_data(request))
+                        let registering = crate::observe::observe_request(request)
+                            == Some(ObserveRequest::Register);
+                        if peers.request_is_allowed(Some(authorized), request) {
+                            (
+                                registering,
+                                AuthorizationChecked::Allowed(
+                                    self.inner.extract_request_data(request),
+                                ),
+                            )
                         } else {
-                            AuthorizationChecked::NotAllowed
+                            (registering, AuthorizationChecked::NotAllowed)
                         }
                     },
                 );
 
+                if let (Ok(_), Some(ssn)) = (&decrypted, sequence_number) {
+                    persistence.received(ssn);
+                }
+
                 // With any luck, this never moves out.
                 //
                 // Storing it even on decryption failure to avoid DoS from the first message (but
                 // FIXME, should we increment an error count and lower priority?)
-                let evicted = self.pool.force_insert(SecContextState {
-                    protocol_stage: SecContextStage::Oscore(oscore_context),
+                let evicted = self.pool.borrow_mut().force_insert(SecContextState {
+                    protocol_stage: SecContextStage::Oscore(oscore_context, persistence),
                     authorization,
+                    retention,
                 });
                 debug_assert!(matches!(evicted, Some(SecContextState { protocol_stage: SecContextStage::Empty, .. }) | None), "A Default (Empty) was placed when an item was taken, which should have the lowest priority");
 
-                let Ok((correlation, extracted)) = decrypted else {
+                let Ok((correlation, (registering, extracted))) = decrypted else {
                     // FIXME is that the right code?
                     error!("Decryption failure");
                     return Err(Own(CoAPError::unauthorized()));
@@ -799,6 +1359,7 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                 Ok(Own(EdhocResponse::OscoreRequest {
                     kid,
                     correlation,
+                    registering,
                     extracted,
                 }))
             }
@@ -819,13 +1380,87 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
         use OrInner::{Inner, Own};
 
         match req {
+            Own(EdhocResponse::Message3Processed) => {
+                response.set_code(
+                    M::Code::new(coap_numbers::code::CHANGED).map_err(|x| Own(x.into()))?,
+                );
+            }
+            Own(EdhocResponse::OkSend4(c_r)) => {
+                let message_4 = self.pool.borrow_mut().lookup(
+                    |c| c.corresponding_cown() == Some(c_r),
+                    |matched| {
+                        // On any error, the slot is left empty: the exchange can not be completed.
+                        let taken = core::mem::take(matched);
+                        let SecContextState {
+                            protocol_stage:
+                                SecContextStage::EdhocResponderProcessedM3 {
+                                    responder,
+                                    c_r: _,
+                                    c_i,
+                                    ead_4,
+                                },
+                            authorization,
+                            ..
+                        } = taken
+                        else {
+                            // The slot was reused in the meantime.
+                            return Err(CoAPError::service_unavailable());
+                        };
+                        let (mut responder, message_4) =
+                            responder.prepare_message_4(&ead_4).map_err(render_error)?;
+                        let context = derive_oscore_context(
+                            |label, length| responder.edhoc_exporter(label, &[], length),
+                            c_i.as_slice(),
+                            c_r,
+                        );
+                        *matched = self.established(context, authorization);
+                        Ok(message_4)
+                    },
+                );
+
+                match message_4.unwrap_or_else(|| Err(CoAPError::service_unavailable())) {
+                    Ok(message_4) => {
+                        response.set_code(
+                            M::Code::new(coap_numbers::code::CHANGED).map_err(|x| Own(x.into()))?,
+                        );
+                        response
+                            .set_payload(message_4.as_slice())
+                            .map_err(|x| Own(x.into()))?;
+                    }
+                    Err(e) => {
+                        warn!("Message 4 could not be sent, dropping the EDHOC state");
+                        e.render(response).map_err(Own)?;
+                    }
+                }
+            }
+            Own(EdhocResponse::OuterBlockContinue(block)) => {
+                // Not protected: the request can only be verified once it is complete.
+                response.set_code(
+                    M::Code::new(coap_numbers::code::CONTINUE).map_err(|x| Own(x.into()))?,
+                );
+                response
+                    .add_option(
+                        M::OptionNumber::new(coap_numbers::option::BLOCK1)
+                            .map_err(|x| Own(x.into()))?,
+                        &block.encode(),
+                    )
+                    .map_err(|x| Own(x.into()))?;
+            }
+            Own(EdhocResponse::OuterBlockRejected(code)) => {
+                response.set_code(M::Code::new(code).map_err(|x| Own(x.into()))?);
+            }
+            Own(EdhocResponse::AceTokenAccepted) => {
+                response.set_code(
+                    M::Code::new(coap_numbers::code::CREATED).map_err(|x| Own(x.into()))?,
+                );
+            }
             Own(EdhocResponse::OkSend2(c_r)) => {
                 // FIXME: Why does the From<O> not do the map_err?
                 response.set_code(
                     M::Code::new(coap_numbers::code::CHANGED).map_err(|x| Own(x.into()))?,
                 );
 
-                let message_2 = self.pool.lookup(
+                let message_2 = self.pool.borrow_mut().lookup(
                     |c| c.corresponding_cown() == Some(c_r),
                     |matched| {
                         // temporary default will not live long (and may be only constructed if
@@ -837,8 +1472,10 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                                     c_r: matched_c_r,
                                     c_i,
                                     responder: taken,
+                                    ead_2,
                                 },
                             authorization,
+                            retention,
                         } = taken
                         else {
                             todo!();
@@ -855,7 +1492,7 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                             .prepare_message_2(
                                 lakers::CredentialTransfer::ByReference,
                                 Some(c_r.into()),
-                                &None,
+                                &ead_2,
                             )
                             // FIXME error handling
                             .unwrap();
@@ -866,6 +1503,7 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                                 c_r,
                             },
                             authorization,
+                            retention,
                         };
                         message_2
                     },
@@ -885,17 +1523,19 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
             Own(EdhocResponse::OscoreRequest {
                 kid,
                 mut correlation,
+                registering,
                 extracted,
             }) => {
                 response.set_code(
                     M::Code::new(coap_numbers::code::CHANGED).map_err(|x| Own(x.into()))?,
                 );
 
-                self.pool
+                let protected = self.pool
+                    .borrow_mut()
                     .lookup(|c| c.corresponding_cown() == Some(kid), |matched| {
                         // Not checking authorization any more: we don't even have access to the
                         // request any more, that check was done.
-                        let SecContextState { protocol_stage: SecContextStage::Oscore(ref mut oscore_context), .. } = matched else {
+                        let SecContextState { protocol_stage: SecContextStage::Oscore(ref mut oscore_context, _), .. } = matched else {
                             // FIXME render late error (it'd help if CoAPError also offered a type that unions it
                             // with an arbitrary other error). As it is, depending on the CoAP stack, there may be
                             // DoS if a peer can send many requests before the server starts rendering responses.
@@ -906,6 +1546,13 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                             .expect("OSCORE handler currently requires a response message implementation that is of fixed type");
 
                         response.set_code(coap_numbers::code::CHANGED);
+                        if registering {
+                            // The Observe option is also an outer option (RFC 8613 Section
+                            // 4.1.3.5), for the transport to keep the token alive. The inner one
+                            // is set by the resource if it accepts the registration; this one is
+                            // set regardless, as it has to precede the OSCORE option.
+                            response.add_option(coap_numbers::option::OBSERVE, &[]).map_err(|_| ())?;
+                        }
 
                         if liboscore::protect_response(
                             response,
@@ -972,8 +1619,13 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
                         {
                             error!("Oups, responding with weird state");
                             // todo!("Thanks to the protect API we've lost access to our response");
+                            return Err(());
                         }
+                        Ok(())
                     });
+                if registering && matches!(protected, Some(Ok(()))) {
+                    self.observation_protection = Some(Protection { kid, correlation });
+                }
             }
             Inner(AuthorizationChecked::Allowed(i)) => {
                 self.inner.build_response(response, i).map_err(Inner)?
@@ -987,3 +1639,378 @@ impl<H: coap_handler::Handler, Crypto: lakers::Crypto> coap_handler::Handler
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use coap_handler::Handler as _;
+    use coap_message_implementations::inmemory_write::Message;
+
+    use super::*;
+    use crate::authorization::{Peer, PeerCredential, Scope};
+    use crate::ead::{ignore_elective, EadRejected};
+    use crate::test_util::{
+        client_crypto, server_crypto, Hello, TestCrypto, CLIENT_CCS, CLIENT_KEY, SERVER_CCS,
+        SERVER_KEY,
+    };
+
+    type TestHandler<'a> = OscoreEdhocHandler<'a, Hello, TestCrypto, 4>;
+    /// What the [`TestHandler`] extracted from a request.
+    type Extracted = Result<
+        <TestHandler<'static> as coap_handler::Handler>::RequestData,
+        <TestHandler<'static> as coap_handler::Handler>::ExtractRequestError,
+    >;
+
+    const PEERS: &[Peer<'static>] = &[Peer {
+        credential: PeerCredential::Ccs(CLIENT_CCS),
+        scope: Scope::AllowAll,
+    }];
+
+    /// Answers an elective EAD item with label 42 with one with label 43, and processes other
+    /// items as if no EAD was supported.
+    fn answer_42(item: Option<&lakers::EADItem>) -> Result<Option<lakers::EADItem>, EadRejected> {
+        match item {
+            Some(item) if item.label == 42 && !item.is_critical => Ok(Some(lakers::EADItem {
+                label: 43,
+                is_critical: false,
+                value: None,
+            })),
+            _ => ignore_elective(item),
+        }
+    }
+
+    /// Passes a POST request to `/.well-known/edhoc` with `payload` to `handler`.
+    fn extract_edhoc(handler: &mut TestHandler<'_>, payload: &[u8]) -> Extracted {
+        let mut code = 0;
+        let mut buffer = [0; MAX_SIZE];
+        let mut request = Message::new(&mut code, &mut buffer[..]);
+        request.set_code(coap_numbers::code::POST);
+        request
+            .add_option(coap_numbers::option::URI_PATH, b".well-known")
+            .unwrap();
+        request
+            .add_option(coap_numbers::option::URI_PATH, b"edhoc")
+            .unwrap();
+        request.set_payload(payload).unwrap();
+        handler.extract_request_data(&request)
+    }
+
+    /// Builds the response of `handler` to what it `extracted`, returning its code and payload.
+    fn respond(
+        handler: &mut TestHandler<'_>,
+        extracted: Extracted,
+    ) -> (u8, heapless::Vec<u8, MAX_SIZE>) {
+        let mut code = 0;
+        let mut buffer = [0; MAX_SIZE];
+        let mut response = Message::new(&mut code, &mut buffer[..]);
+        let rendered = match extracted {
+            Ok(extracted) => handler.build_response(&mut response, extracted).is_ok(),
+            Err(e) => e.render(&mut response).is_ok(),
+        };
+        assert!(rendered, "Response could not be built");
+        let code = response.code().into();
+        (code, heapless::Vec::from_slice(response.payload()).unwrap())
+    }
+
+    /// Runs EDHOC against `handler` up to message 3, sending the EAD items given.
+    ///
+    /// Returns C_R, EAD_2 and what the handler extracted from message 3 (sent on its own), or the
+    /// code of the response to message 1 if that was an error.
+    fn run_edhoc(
+        handler: &mut TestHandler<'_>,
+        ead_1: Option<lakers::EADItem>,
+        ead_3: Option<lakers::EADItem>,
+    ) -> Result<(COwn, Option<lakers::EADItem>, Extracted), u8> {
+        let server = lakers::Credential::parse_ccs(SERVER_CCS).unwrap();
+        let client = lakers::Credential::parse_ccs(CLIENT_CCS).unwrap();
+
+        let (initiator, message_1) = lakers::EdhocInitiator::new(
+            client_crypto(),
+            lakers::EDHOCMethod::StatStat,
+            lakers::EDHOCSuite::CipherSuite2,
+        )
+        .prepare_message_1(Some(COwn::from_kid(&[0x10]).unwrap().into()), &ead_1)
+        .unwrap();
+        let mut payload = heapless::Vec::<u8, MAX_SIZE>::new();
+        payload.push(0xf5).unwrap();
+        payload.extend_from_slice(message_1.as_slice()).unwrap();
+        let extracted = extract_edhoc(handler, &payload);
+        let (code, message_2) = respond(handler, extracted);
+        if code != coap_numbers::code::CHANGED {
+            return Err(code);
+        }
+
+        let (mut initiator, c_r, id_cred_r, ead_2) = initiator
+            .parse_message_2(&lakers::EdhocMessageBuffer::new_from_slice(&message_2).unwrap())
+            .unwrap();
+        let cred_r = lakers::credential_check_or_fetch(Some(server), id_cred_r).unwrap();
+        initiator.set_identity(CLIENT_KEY, client);
+        let (_initiator, message_3, _prk_out) = initiator
+            .verify_message_2(cred_r)
+            .unwrap()
+            .prepare_message_3(lakers::CredentialTransfer::ByReference, &ead_3)
+            .unwrap();
+
+        let c_r = COwn::from_kid(c_r.as_slice()).unwrap();
+        // A one-byte C_R is encoded as the CBOR integer of that byte.
+        let mut payload = heapless::Vec::<u8, MAX_SIZE>::new();
+        payload.extend_from_slice(c_r.as_kid()).unwrap();
+        payload.extend_from_slice(message_3.as_slice()).unwrap();
+        Ok((c_r, ead_2, extract_edhoc(handler, &payload)))
+    }
+
+    /// Passes an OSCORE request for the context `kid` to `handler`, which carries the outer Block1
+    /// option `block` and `payload`.
+    fn extract_outer_block(
+        handler: &mut TestHandler<'_>,
+        kid: COwn,
+        block: Block,
+        payload: &[u8],
+    ) -> Extracted {
+        let mut code = 0;
+        let mut buffer = [0; MAX_SIZE];
+        let mut request = Message::new(&mut code, &mut buffer[..]);
+        request.set_code(coap_numbers::code::POST);
+        // Partial IV 0x00, followed by the kid
+        let mut oscore = heapless::Vec::<u8, 4>::from_slice(&[0x09, 0x00]).unwrap();
+        oscore.extend_from_slice(kid.as_kid()).unwrap();
+        request
+            .add_option(coap_numbers::option::OSCORE, &oscore)
+            .unwrap();
+        request
+            .add_option(coap_numbers::option::BLOCK1, &block.encode())
+            .unwrap();
+        request.set_payload(payload).unwrap();
+        handler.extract_request_data(&request)
+    }
+
+    /// Returns whether an OSCORE context was established for the C_R `c_r`.
+    fn established(pool: &RefCell<SecContextPool<TestCrypto, 4>>, c_r: COwn) -> bool {
+        pool.borrow_mut()
+            .lookup(
+                |c| c.corresponding_cown() == Some(c_r),
+                |c| matches!(c.protocol_stage, SecContextStage::Oscore(..)),
+            )
+            .unwrap_or(false)
+    }
+
+    #[test]
+    fn cown_allocation() {
+        let first = COwn::not_in_iter(core::iter::empty());
+        assert_eq!(first.as_kid(), &[0x00]);
+
+        let one_byte: heapless::Vec<COwn, 48> = (0x00..=0x17)
+            .chain(0x20..=0x37)
+            .map(|byte| COwn::from_kid(&[byte]).unwrap())
+            .collect();
+        let next = COwn::not_in_iter(one_byte.iter().copied());
+        assert_eq!(next.as_kid(), &[0x00, 0x00]);
+        let after = COwn::not_in_iter(one_byte.iter().copied().chain([next]));
+        assert_eq!(after.as_kid(), &[0x00, 0x01]);
+
+        assert!(COwn::from_kid(&[0x18]).is_none());
+        assert!(COwn::from_kid(&[1, 2, 3]).is_none());
+    }
+
+    #[test]
+    fn cown_cbor() {
+        let (c_r, rest) = COwn::from_cbor_prefix(&[0x37, 0x58]).unwrap();
+        assert_eq!((c_r.as_kid(), rest), (&[0x37][..], &[0x58][..]));
+        let (c_r, rest) = COwn::from_cbor_prefix(&[0x42, 0x01, 0x02, 0x58]).unwrap();
+        assert_eq!((c_r.as_kid(), rest), (&[0x01, 0x02][..], &[0x58][..]));
+        assert!(COwn::from_cbor_prefix(&[0x41, 0x18]).is_none());
+        assert!(COwn::from_cbor_prefix(&[]).is_none());
+    }
+
+    #[test]
+    fn option_kid() {
+        // Partial IV 0x05, kid 0x0a
+        assert_eq!(oscore_option_kid(&[0x09, 0x05, 0x0a]), Some(&[0x0a][..]));
+        // Two-byte kid after a kid context
+        assert_eq!(
+            oscore_option_kid(&[0x19, 0x05, 0x01, 0xff, 0x01, 0x02]),
+            Some(&[0x01, 0x02][..])
+        );
+        // No kid
+        assert_eq!(oscore_option_kid(&[0x01, 0x05]), None);
+        assert_eq!(oscore_option_kid(&[]), None);
+    }
+
+    #[test]
+    fn message_4() {
+        let server = lakers::Credential::parse_ccs(SERVER_CCS).unwrap();
+        let pool = RefCell::new(SecContextPool::<TestCrypto, 4>::new());
+        let mut handler = TestHandler::new(
+            (&server, &SERVER_KEY),
+            PeerTable::new(PEERS),
+            &pool,
+            Hello,
+            server_crypto,
+        )
+        .with_message_4()
+        .with_ead_processor(EadProcessor {
+            ead_1: answer_42,
+            ead_3: answer_42,
+        });
+
+        let elective = |label| lakers::EADItem {
+            label,
+            is_critical: false,
+            value: None,
+        };
+        let (c_r, ead_2, extracted) =
+            run_edhoc(&mut handler, Some(elective(42)), Some(elective(42))).unwrap();
+        assert_eq!(ead_2.map(|e| e.label), Some(43));
+        assert!(!established(&pool, c_r));
+        let (code, message_4) = respond(&mut handler, extracted);
+        assert_eq!(code, coap_numbers::code::CHANGED);
+        assert!(!message_4.is_empty());
+        assert!(established(&pool, c_r));
+
+        // The state is gone when it comes to sending message 4: the slot is released, and the
+        // error is sent instead.
+        let (c_r, _, extracted) = run_edhoc(&mut handler, None, None).unwrap();
+        assert!(matches!(
+            extracted,
+            Ok(OrInner::Own(EdhocResponse::OkSend4(matched))) if matched == c_r
+        ));
+        pool.borrow_mut().lookup(
+            |c| c.corresponding_cown() == Some(c_r),
+            |c| *c = SecContextState::default(),
+        );
+        let (code, _) = respond(&mut handler, extracted);
+        assert_eq!(code, coap_numbers::code::SERVICE_UNAVAILABLE);
+        assert!(!established(&pool, c_r));
+    }
+
+    #[test]
+    fn ead() {
+        let server = lakers::Credential::parse_ccs(SERVER_CCS).unwrap();
+        let pool = RefCell::new(SecContextPool::<TestCrypto, 4>::new());
+        let mut handler = TestHandler::new(
+            (&server, &SERVER_KEY),
+            PeerTable::new(PEERS),
+            &pool,
+            Hello,
+            server_crypto,
+        );
+
+        let item = |label, is_critical| lakers::EADItem {
+            label,
+            is_critical,
+            value: None,
+        };
+
+        // Without a processor, elective items are ignored, and critical ones rejected.
+        let (c_r, ead_2, extracted) =
+            run_edhoc(&mut handler, Some(item(42, false)), Some(item(7, false))).unwrap();
+        assert!(ead_2.is_none());
+        assert_eq!(
+            respond(&mut handler, extracted).0,
+            coap_numbers::code::CHANGED
+        );
+        assert!(established(&pool, c_r));
+        assert!(matches!(
+            run_edhoc(&mut handler, Some(item(7, true)), None),
+            Err(coap_numbers::code::BAD_REQUEST)
+        ));
+        let (c_r, _, extracted) = run_edhoc(&mut handler, None, Some(item(7, true))).unwrap();
+        assert_eq!(
+            respond(&mut handler, extracted).0,
+            coap_numbers::code::BAD_REQUEST
+        );
+        assert!(!established(&pool, c_r));
+
+        // A processor answers the items it understands.
+        let mut handler = handler.with_ead_processor(EadProcessor {
+            ead_1: answer_42,
+            ead_3: answer_42,
+        });
+        let (c_r, ead_2, extracted) =
+            run_edhoc(&mut handler, Some(item(42, false)), Some(item(42, false))).unwrap();
+        assert_eq!(ead_2.map(|e| e.label), Some(43));
+        assert_eq!(
+            respond(&mut handler, extracted).0,
+            coap_numbers::code::CHANGED
+        );
+        assert!(established(&pool, c_r));
+        assert!(matches!(
+            run_edhoc(&mut handler, Some(item(42, true)), None),
+            Err(coap_numbers::code::BAD_REQUEST)
+        ));
+    }
+
+    #[test]
+    fn outer_block1() {
+        let server = lakers::Credential::parse_ccs(SERVER_CCS).unwrap();
+        let pool = RefCell::new(SecContextPool::<TestCrypto, 4>::new());
+        let mut handler = TestHandler::new(
+            (&server, &SERVER_KEY),
+            PeerTable::new(PEERS),
+            &pool,
+            Hello,
+            server_crypto,
+        );
+        let [first, second] = [(), ()].map(|()| {
+            let (c_r, _, extracted) = run_edhoc(&mut handler, None, None).unwrap();
+            assert_eq!(
+                respond(&mut handler, extracted).0,
+                coap_numbers::code::CHANGED
+            );
+            c_r
+        });
+
+        let block = |num, more| Block::new(num, more, 0).unwrap();
+        let continued = |extracted: Extracted| {
+            matches!(
+                extracted,
+                Ok(OrInner::Own(EdhocResponse::OuterBlockContinue(_)))
+            )
+        };
+
+        assert!(continued(extract_outer_block(
+            &mut handler,
+            first,
+            block(0, true),
+            &[1; 16]
+        )));
+        // Blocks for contexts that do not exist are rejected, and leave the transfer intact.
+        let unknown = COwn::from_kid(&[0x37]).unwrap();
+        assert!(unknown != first && unknown != second);
+        assert!(matches!(
+            extract_outer_block(&mut handler, unknown, block(0, true), &[3; 16]),
+            Err(OrInner::Own(_))
+        ));
+        assert!(continued(extract_outer_block(
+            &mut handler,
+            first,
+            block(1, true),
+            &[1; 16]
+        )));
+        // Blocks only continue the transfer of their own context.
+        assert!(matches!(
+            extract_outer_block(&mut handler, second, block(2, true), &[2; 16]),
+            Ok(OrInner::Own(EdhocResponse::OuterBlockRejected(
+                coap_numbers::code::REQUEST_ENTITY_INCOMPLETE
+            )))
+        ));
+        assert!(continued(extract_outer_block(
+            &mut handler,
+            first,
+            block(2, true),
+            &[1; 16]
+        )));
+        // A transfer of another context replaces the incomplete one.
+        assert!(continued(extract_outer_block(
+            &mut handler,
+            second,
+            block(0, true),
+            &[2; 16]
+        )));
+        assert!(matches!(
+            extract_outer_block(&mut handler, first, block(3, true), &[1; 16]),
+            Ok(OrInner::Own(EdhocResponse::OuterBlockRejected(
+                coap_numbers::code::REQUEST_ENTITY_INCOMPLETE
+            )))
+        ));
+    }
+}
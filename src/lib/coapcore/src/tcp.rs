@@ -0,0 +1,496 @@
+//! CoAP over TCP (RFC 8323)
+//!
+//! This provides the framing of CoAP messages on reliable transports, and [`serve()`], which
+//! serves requests arriving on a connection with a [`coap_handler::Handler`] (typically an
+//! [`OscoreEdhocHandler`](crate::seccontext::OscoreEdhocHandler), through which OSCORE and EDHOC
+//! work just as over UDP). Establishing the connection is up to the caller; the connection only
+//! needs to implement [`embedded_io_async::Read`] and [`embedded_io_async::Write`].
+//!
+//! Only the server role is implemented: Responses arriving on the connection are ignored.
+//! Signaling messages are processed as far as required: A Capabilities and Settings Message (CSM)
+//! is sent when serving starts, pings are answered, and release or abort messages end serving.
+
+use core::ops::Range;
+
+use coap_handler::Handler;
+use coap_message::{error::RenderableOnMinimal as _, MinimalWritableMessage as _};
+use coap_message_implementations::{inmemory, inmemory_write};
+use coap_numbers::code;
+use embedded_io_async::{Read, Write};
+
+/// Longest header of a message: the length and token length, up to 4 bytes of extended length,
+/// the code, and up to 8 bytes of token.
+pub const MAX_HEADER_LEN: usize = 1 + 4 + 1 + MAX_TOKEN_LEN;
+
+/// Longest token of a message.
+const MAX_TOKEN_LEN: usize = 8;
+
+/// Signaling code 7.01 Capabilities and Settings Message (CSM)
+const CSM: u8 = 0xe1;
+/// Signaling code 7.02 Ping
+const PING: u8 = 0xe2;
+/// Signaling code 7.03 Pong
+const PONG: u8 = 0xe3;
+/// Signaling code 7.04 Release
+const RELEASE: u8 = 0xe4;
+/// Signaling code 7.05 Abort
+const ABORT: u8 = 0xe5;
+
+/// Max-Message-Size option of a CSM
+const MAX_MESSAGE_SIZE: u16 = 2;
+
+/// Error in the framing of a message.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The message is not well-formed.
+    Malformed,
+    /// The message exceeds the buffer.
+    TooLarge,
+}
+
+/// Error of [`serve()`].
+#[derive(Debug)]
+pub enum TcpError<E> {
+    /// Reading from or writing to the connection failed.
+    Io(E),
+    /// The peer closed the connection.
+    Closed,
+    /// The peer sent a message that could not be processed; the connection needs to be closed.
+    Frame(FrameError),
+}
+
+impl<E> From<FrameError> for TcpError<E> {
+    fn from(error: FrameError) -> Self {
+        TcpError::Frame(error)
+    }
+}
+
+/// A message as framed on a reliable transport.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Frame<'a> {
+    /// The message code.
+    pub code: u8,
+    /// The token.
+    pub token: &'a [u8],
+    /// The options and payload, encoded as in any CoAP message.
+    pub tail: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Parses a complete frame, as read by [`read_frame()`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if `frame` is not exactly one well-formed message header, followed by as many bytes
+    /// as it indicates.
+    pub fn parse(frame: &'a [u8]) -> Result<Self, FrameError> {
+        let Needed::Complete(len) = needed(frame)? else {
+            return Err(FrameError::Malformed);
+        };
+        if len != frame.len() {
+            return Err(FrameError::Malformed);
+        }
+        let (&first, _) = frame.split_first().ok_or(FrameError::Malformed)?;
+        let header = 1 + extended_len(first);
+        let token_len = usize::from(first & 0x0f);
+        let code = *frame.get(header).ok_or(FrameError::Malformed)?;
+        let token = frame
+            .get(header + 1..header + 1 + token_len)
+            .ok_or(FrameError::Malformed)?;
+        let tail = frame
+            .get(header + 1 + token_len..)
+            .ok_or(FrameError::Malformed)?;
+        Ok(Self { code, token, tail })
+    }
+}
+
+/// Bytes of a frame that need to be read.
+enum Needed {
+    /// The header is incomplete, and needs at least that many bytes.
+    Header(usize),
+    /// The frame is that long.
+    Complete(usize),
+}
+
+/// Number of bytes of the extended length indicated by the first byte of a frame.
+fn extended_len(first: u8) -> usize {
+    match first >> 4 {
+        13 => 1,
+        14 => 2,
+        15 => 4,
+        _ => 0,
+    }
+}
+
+/// Determines how many bytes the frame starting with `data` needs.
+fn needed(data: &[u8]) -> Result<Needed, FrameError> {
+    let Some((&first, rest)) = data.split_first() else {
+        return Ok(Needed::Header(1));
+    };
+    let token_len = usize::from(first & 0x0f);
+    if token_len > MAX_TOKEN_LEN {
+        return Err(FrameError::Malformed);
+    }
+    let extended = extended_len(first);
+    let Some(extended_bytes) = rest.get(..extended) else {
+        return Ok(Needed::Header(1 + extended));
+    };
+    let mut value = [0; 4];
+    #[allow(clippy::indexing_slicing, reason = "extended is at most 4")]
+    value[4 - extended..].copy_from_slice(extended_bytes);
+    let value = usize::try_from(u32::from_be_bytes(value)).map_err(|_| FrameError::TooLarge)?;
+    let body_len = match first >> 4 {
+        13 => value + 13,
+        14 => value + 269,
+        15 => value.checked_add(65805).ok_or(FrameError::TooLarge)?,
+        short => usize::from(short),
+    };
+    (1 + extended + 1 + token_len)
+        .checked_add(body_len)
+        .map(Needed::Complete)
+        .ok_or(FrameError::TooLarge)
+}
+
+/// Encodes the header of a message with `code`, `token`, and `body_len` bytes of options and
+/// payload.
+///
+/// # Panics
+///
+/// Panics if the token is longer than 8 bytes.
+#[must_use]
+pub fn encode_header(body_len: usize, code: u8, token: &[u8]) -> heapless::Vec<u8, MAX_HEADER_LEN> {
+    let token_len = u8::try_from(token.len())
+        .ok()
+        .filter(|len| usize::from(*len) <= MAX_TOKEN_LEN)
+        .expect("Tokens are at most 8 bytes long");
+    let mut header = heapless::Vec::new();
+    // None of these can exceed the capacity.
+    #[allow(
+        clippy::cast_possible_truncation,
+        reason = "the length is checked against the range of each form"
+    )]
+    match body_len {
+        0..13 => {
+            let _ = header.push(((body_len as u8) << 4) | token_len);
+        }
+        13..269 => {
+            let _ = header.push((13 << 4) | token_len);
+            let _ = header.push((body_len - 13) as u8);
+        }
+        269..65805 => {
+            let _ = header.push((14 << 4) | token_len);
+            let _ = header.extend_from_slice(&((body_len - 269) as u16).to_be_bytes());
+        }
+        _ => {
+            let _ = header.push((15 << 4) | token_len);
+            let _ = header.extend_from_slice(&((body_len - 65805) as u32).to_be_bytes());
+        }
+    }
+    let _ = header.push(code);
+    let _ = header.extend_from_slice(token);
+    header
+}
+
+/// Reads a single frame from `connection` into `buffer`, and returns its length.
+///
+/// This does not read beyond the frame, so that the next frame can be read by the next call.
+///
+/// # Errors
+///
+/// Fails if reading fails, the connection was closed, or the frame exceeds `buffer`.
+pub async fn read_frame<C: Read>(
+    connection: &mut C,
+    buffer: &mut [u8],
+) -> Result<usize, TcpError<C::Error>> {
+    let mut filled = 0;
+    loop {
+        #[allow(
+            clippy::indexing_slicing,
+            reason = "filled never exceeds what was needed"
+        )]
+        let wanted = match needed(&buffer[..filled])? {
+            Needed::Complete(len) if len <= filled => return Ok(len),
+            Needed::Complete(len) | Needed::Header(len) => len,
+        };
+        let unfilled = buffer.get_mut(filled..wanted).ok_or(FrameError::TooLarge)?;
+        let read = connection.read(unfilled).await.map_err(TcpError::Io)?;
+        if read == 0 {
+            return Err(TcpError::Closed);
+        }
+        filled += read;
+    }
+}
+
+/// What to do after processing a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Processed {
+    /// Send the response that was placed in this range of the output buffer.
+    Respond(Range<usize>),
+    /// Nothing needs to be sent.
+    Nothing,
+    /// The peer released or aborted the connection, which is to be closed.
+    Close,
+}
+
+/// Processes a received `frame`, passing requests to `handler`, and placing any response in
+/// `output`.
+///
+/// `output` needs to be at least [`MAX_HEADER_LEN`] bytes longer than the longest response.
+pub fn process<H: Handler>(handler: &mut H, frame: &Frame<'_>, output: &mut [u8]) -> Processed {
+    match frame.code {
+        PING => respond(output, PONG, frame.token, |_| Ok(())),
+        RELEASE | ABORT => Processed::Close,
+        // Requests
+        1..32 => respond(output, code::CONTENT, frame.token, |response| {
+            let request = inmemory::Message::new(frame.code, frame.tail);
+            match handler.extract_request_data(&request) {
+                Ok(extracted) => handler.build_response(response, extracted).map_err(|_| ()),
+                Err(error) => error.render(response).map_err(|_| ()),
+            }
+        }),
+        // Empty messages, responses to requests this side did not send, CSMs (whose settings
+        // are only relevant to longer messages than this side sends), pongs, and unknown
+        // signaling
+        _ => Processed::Nothing,
+    }
+}
+
+/// Builds a message with `token` in `output`.
+///
+/// The message is built by `build`, with `code` preset. If that fails, the message is sent as
+/// a bare 5.00 Internal Server Error instead.
+fn respond(
+    output: &mut [u8],
+    code: u8,
+    token: &[u8],
+    build: impl FnOnce(&mut inmemory_write::Message<'_>) -> Result<(), ()>,
+) -> Processed {
+    let Some((header_space, body)) = output.split_at_mut_checked(MAX_HEADER_LEN) else {
+        return Processed::Nothing;
+    };
+    let mut response_code = code;
+    let mut message = inmemory_write::Message::new(&mut response_code, body);
+    message.set_code(code);
+    let len = if build(&mut message).is_ok() {
+        message.finish()
+    } else {
+        response_code = code::INTERNAL_SERVER_ERROR;
+        0
+    };
+
+    let header = encode_header(len, response_code, token);
+    let start = MAX_HEADER_LEN - header.len();
+    #[allow(
+        clippy::indexing_slicing,
+        reason = "the header is at most MAX_HEADER_LEN long"
+    )]
+    header_space[start..].copy_from_slice(&header);
+    Processed::Respond(start..MAX_HEADER_LEN + len)
+}
+
+/// Serves requests arriving on `connection` with `handler`, until the peer closes or releases the
+/// connection.
+///
+/// Frames are read into `buffer`, whose length is announced as the maximum message size in the
+/// CSM sent when starting; responses are built in `output`, see [`process()`].
+///
+/// # Errors
+///
+/// Fails if reading from or writing to the connection fails, or the peer sent a message that could
+/// not be processed. The connection should be closed then.
+pub async fn serve<C: Read + Write, H: Handler>(
+    connection: &mut C,
+    handler: &mut H,
+    buffer: &mut [u8],
+    output: &mut [u8],
+) -> Result<(), TcpError<C::Error>> {
+    send_csm(connection, buffer.len()).await?;
+    loop {
+        let len = read_frame(connection, buffer).await?;
+        #[allow(
+            clippy::indexing_slicing,
+            reason = "the frame was read into the buffer"
+        )]
+        let frame = Frame::parse(&buffer[..len])?;
+        match process(handler, &frame, output) {
+            Processed::Respond(range) => {
+                let response = output.get(range).ok_or(FrameError::TooLarge)?;
+                connection.write_all(response).await.map_err(TcpError::Io)?;
+                connection.flush().await.map_err(TcpError::Io)?;
+            }
+            Processed::Nothing => (),
+            Processed::Close => return Ok(()),
+        }
+    }
+}
+
+/// Sends the CSM that starts a connection, announcing `max_message_size`.
+async fn send_csm<C: Write>(
+    connection: &mut C,
+    max_message_size: usize,
+) -> Result<(), TcpError<C::Error>> {
+    let mut body = [0u8; 8];
+    let mut body_code = CSM;
+    let mut message = inmemory_write::Message::new(&mut body_code, &mut body[..]);
+    let size = u32::try_from(max_message_size)
+        .unwrap_or(u32::MAX)
+        .to_be_bytes();
+    let skip = size.iter().take_while(|b| **b == 0).count();
+    #[allow(clippy::indexing_slicing, reason = "skip is at most 4")]
+    message
+        .add_option(MAX_MESSAGE_SIZE, &size[skip..])
+        .map_err(|_| FrameError::TooLarge)?;
+    let len = message.finish();
+    #[allow(
+        clippy::indexing_slicing,
+        reason = "length was written into the buffer"
+    )]
+    let body = &body[..len];
+
+    let header = encode_header(body.len(), CSM, &[]);
+    connection.write_all(&header).await.map_err(TcpError::Io)?;
+    connection.write_all(body).await.map_err(TcpError::Io)?;
+    connection.flush().await.map_err(TcpError::Io)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::io::{Read as _, Write as _};
+    use std::net::{TcpListener, TcpStream};
+
+    use coap_message::MinimalWritableMessage as _;
+    use coap_message_implementations::inmemory_write;
+    use coap_numbers::{code, option};
+    use embassy_futures::block_on;
+
+    use super::{encode_header, needed, serve, Frame, Needed, ABORT, CSM, PING, PONG, RELEASE};
+    use crate::test_util::Hello;
+
+    /// A TCP connection, with the blocking I/O of the standard library.
+    struct Connection(TcpStream);
+
+    impl embedded_io_async::ErrorType for Connection {
+        type Error = embedded_io_async::ErrorKind;
+    }
+
+    impl embedded_io_async::Read for Connection {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            self.0
+                .read(buf)
+                .map_err(|_| embedded_io_async::ErrorKind::Other)
+        }
+    }
+
+    impl embedded_io_async::Write for Connection {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.0
+                .write(buf)
+                .map_err(|_| embedded_io_async::ErrorKind::Other)
+        }
+    }
+
+    /// Sends a message with `code`, `token` and a single Uri-Path `path` to `stream`.
+    fn send(stream: &mut TcpStream, code: u8, token: &[u8], path: Option<&[u8]>) {
+        let mut body_code = code;
+        let mut body = [0u8; 32];
+        let mut message = inmemory_write::Message::new(&mut body_code, &mut body[..]);
+        if let Some(path) = path {
+            message.add_option(option::URI_PATH, path).unwrap();
+        }
+        let len = message.finish();
+        stream.write_all(&encode_header(len, code, token)).unwrap();
+        stream.write_all(&body[..len]).unwrap();
+    }
+
+    /// Receives a message from `stream`, returning its code, token and tail.
+    fn receive(stream: &mut TcpStream) -> (u8, std::vec::Vec<u8>, std::vec::Vec<u8>) {
+        let mut frame = std::vec::Vec::new();
+        loop {
+            match needed(&frame).unwrap() {
+                Needed::Complete(len) if len == frame.len() => break,
+                Needed::Complete(len) | Needed::Header(len) => {
+                    let start = frame.len();
+                    frame.resize(len, 0);
+                    stream.read_exact(&mut frame[start..]).unwrap();
+                }
+            }
+        }
+        let parsed = Frame::parse(&frame).unwrap();
+        (parsed.code, parsed.token.into(), parsed.tail.into())
+    }
+
+    #[test]
+    fn header() {
+        for (len, encoded) in [
+            (0, &[0x01, 0x45, 0xaa][..]),
+            (12, &[0xc1, 0x45, 0xaa]),
+            (13, &[0xd1, 0x00, 0x45, 0xaa]),
+            (268, &[0xd1, 0xff, 0x45, 0xaa]),
+            (269, &[0xe1, 0x00, 0x00, 0x45, 0xaa]),
+            (65805, &[0xf1, 0x00, 0x00, 0x00, 0x00, 0x45, 0xaa]),
+        ] {
+            let header = encode_header(len, code::CONTENT, &[0xaa]);
+            assert_eq!(&header[..], encoded);
+            let Needed::Complete(total) = needed(&header).unwrap() else {
+                panic!("Header is complete");
+            };
+            assert_eq!(total, header.len() + len);
+        }
+        assert!(matches!(needed(&[0xe1, 0x00]), Ok(Needed::Header(3))));
+        // Token length 9 is reserved.
+        assert!(needed(&[0x09]).is_err());
+    }
+
+    #[test]
+    fn stand_in() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // The stand-in of a cloud endpoint, to which the device connects.
+        let cloud = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (csm_code, _, _) = receive(&mut stream);
+            assert_eq!(csm_code, CSM);
+            send(&mut stream, CSM, &[], None);
+
+            send(&mut stream, code::GET, &[1, 2], Some(b"hello"));
+            let (response_code, token, tail) = receive(&mut stream);
+            assert_eq!(response_code, code::CONTENT);
+            assert_eq!(token, [1, 2]);
+            assert_eq!(tail, b"\xffworld");
+
+            send(&mut stream, code::POST, &[3], Some(b"hello"));
+            let (response_code, token, _) = receive(&mut stream);
+            assert_eq!(response_code, code::METHOD_NOT_ALLOWED);
+            assert_eq!(token, [3]);
+
+            send(&mut stream, PING, &[4], None);
+            let (pong, token, _) = receive(&mut stream);
+            assert_eq!(pong, PONG);
+            assert_eq!(token, [4]);
+
+            send(&mut stream, RELEASE, &[], None);
+        });
+
+        let mut connection = Connection(TcpStream::connect(address).unwrap());
+        let mut buffer = [0u8; 256];
+        let mut output = [0u8; 256];
+        block_on(serve(&mut connection, &mut Hello, &mut buffer, &mut output)).unwrap();
+        cloud.join().unwrap();
+
+        // Aborts end serving just like releases do.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let cloud = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            receive(&mut stream);
+            send(&mut stream, ABORT, &[], None);
+        });
+        let mut connection = Connection(TcpStream::connect(address).unwrap());
+        block_on(serve(&mut connection, &mut Hello, &mut buffer, &mut output)).unwrap();
+        cloud.join().unwrap();
+    }
+}
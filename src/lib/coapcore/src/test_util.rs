@@ -0,0 +1,107 @@
+//! Helpers shared by the tests of several modules.
+use coap_handler::Handler;
+use coap_message::{
+    Code as _, MessageOption as _, MinimalWritableMessage, MutableWritableMessage, ReadableMessage,
+};
+use coap_numbers::{code, option};
+
+/// The credential of the server in the tests, the one of the Ariel OS CoAP examples (KID `h'0a'`).
+pub(crate) const SERVER_CCS: &[u8] = &hexlit::hex!("A2026008A101A5010202410A2001215820BBC34960526EA4D32E940CAD2A234148DDC21791A12AFBCBAC93622046DD44F02258204519E257236B2A0CE2023F0931F1F386CA7AFDA64FCDE0108C224C51EABF6072");
+/// The private key of [`SERVER_CCS`].
+pub(crate) const SERVER_KEY: [u8; 32] =
+    hexlit::hex!("72cc4761dbd4c78f758931aa589d348d1ef874a7e303ede2f140dcf3e6aa4aac");
+
+/// The credential of the client in the tests, `CRED_I` of RFC 9529 (KID `h'2b'`).
+pub(crate) const CLIENT_CCS: &[u8] = &hexlit::hex!("A2027734322D35302D33312D46462D45462D33372D33322D333908A101A5010202412B2001215820AC75E9ECE3E50BFC8ED60399889522405C47BF16DF96660A41298CB4307F7EB62258206E5DE611388A4B8A8211334AC7D37ECB52A387D257E6DB3C2A93DF21FF3AFFC8");
+/// The private key of [`CLIENT_CCS`].
+pub(crate) const CLIENT_KEY: [u8; 32] =
+    hexlit::hex!("fb13adeb6518cee5f88417660841142e830a81fe334380a953406a1305e8706b");
+
+/// A deterministic random number generator, which is only good enough to let tests run.
+pub(crate) struct TestRng(u64);
+
+impl TestRng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed | 1)
+    }
+}
+
+impl rand_core::RngCore for TestRng {
+    fn next_u32(&mut self) -> u32 {
+        #[expect(clippy::cast_possible_truncation, reason = "taking the upper half")]
+        let value = (self.next_u64() >> 32) as u32;
+        value
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        // xorshift64
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl rand_core::CryptoRng for TestRng {}
+
+/// Cryptography backend of the tests.
+pub(crate) type TestCrypto = lakers_crypto_rustcrypto::Crypto<TestRng>;
+
+/// Creates the cryptography backend of a server in the tests.
+pub(crate) fn server_crypto() -> TestCrypto {
+    lakers_crypto_rustcrypto::Crypto::new(TestRng::new(1))
+}
+
+/// Creates the cryptography backend of a client in the tests.
+pub(crate) fn client_crypto() -> TestCrypto {
+    lakers_crypto_rustcrypto::Crypto::new(TestRng::new(2))
+}
+
+/// A handler that answers GET requests to `/hello`.
+pub(crate) struct Hello;
+
+impl Handler for Hello {
+    type RequestData = u8;
+    type ExtractRequestError = coap_message_utils::Error;
+    type BuildResponseError<M: MinimalWritableMessage> = M::UnionError;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<u8, Self::ExtractRequestError> {
+        let hello = request
+            .options()
+            .any(|o| o.number() == option::URI_PATH && o.value() == b"hello");
+        let method: u8 = request.code().into();
+        match (method, hello) {
+            (code::GET, true) => Ok(code::CONTENT),
+            (_, true) => Err(coap_message_utils::Error::method_not_allowed()),
+            _ => Ok(code::NOT_FOUND),
+        }
+    }
+
+    fn estimate_length(&mut self, _request: &u8) -> usize {
+        16
+    }
+
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        request: u8,
+    ) -> Result<(), M::UnionError> {
+        response.set_code(M::Code::new(request)?);
+        if request == code::CONTENT {
+            response.set_payload(b"world")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,24 @@
+#![no_main]
+#![no_std]
+#![feature(used_with_arg)]
+
+use ariel_os::{asynch::blocker::block_on, debug::log::*, storage};
+
+const KEYS: [&str; 4] = ["bench_0", "bench_1", "bench_2", "bench_3"];
+
+#[ariel_os::thread(autostart)]
+fn main() {
+    // Fill the storage with a couple of items, so that lookups need to skip
+    // over other keys.
+    for (i, key) in (0u32..).zip(KEYS) {
+        block_on(storage::insert(key, i)).unwrap();
+    }
+
+    match ariel_os::bench::benchmark(100, || {
+        let value: Option<u32> = block_on(storage::get(KEYS[0])).unwrap();
+        core::hint::black_box(value);
+    }) {
+        Ok(ticks) => info!("took {} ticks per lookup", ticks),
+        Err(_) => warn!("benchmark returned error"),
+    }
+}
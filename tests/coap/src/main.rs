@@ -14,6 +14,35 @@ const NETWORK_CONFIG: embassy_net::Config = {
     })
 };
 
+// The credential that `client.diag` expects; without this, a credential would be generated at
+// first boot, and could be retrieved through `ariel_os::coap::own_credential()`.
+//
+// The client credential of `client.diag` is allowed everything; other clients may only discover
+// resources.
+#[ariel_os::config(coap)]
+const COAP_CONFIG: ariel_os::coap::Config = {
+    use ariel_os::coap::{AifValue, Config, OwnCredential, Peer, PeerCredential, PeerTable, Scope};
+
+    // [["/.well-known/core", 1]]
+    const DISCOVERY: Scope<'static> = Scope::Aif(AifValue::new(&hexlit::hex!(
+        "8182712f2e77656c6c2d6b6e6f776e2f636f726501"
+    )));
+
+    Config::new()
+        .with_own_credential(OwnCredential {
+            ccs: &hexlit::hex!("A2026008A101A5010202410A2001215820BBC34960526EA4D32E940CAD2A234148DDC21791A12AFBCBAC93622046DD44F02258204519E257236B2A0CE2023F0931F1F386CA7AFDA64FCDE0108C224C51EABF6072"),
+            private_key: &hexlit::hex!("72cc4761dbd4c78f758931aa589d348d1ef874a7e303ede2f140dcf3e6aa4aac"),
+        })
+        .with_peers(
+            PeerTable::new(&[Peer {
+                credential: PeerCredential::Ccs(&hexlit::hex!("A2027734322D35302D33312D46462D45462D33372D33322D333908A101A5010202412B2001215820AC75E9ECE3E50BFC8ED60399889522405C47BF16DF96660A41298CB4307F7EB62258206E5DE611388A4B8A8211334AC7D37ECB52A387D257E6DB3C2A93DF21FF3AFFC8")),
+                scope: Scope::AllowAll,
+            }])
+            .with_unauthenticated(DISCOVERY)
+            .with_nosec(DISCOVERY),
+        )
+};
+
 #[ariel_os::task(autostart)]
 async fn coap_run() {
     use coap_handler_implementations::HandlerBuilder;